@@ -0,0 +1,135 @@
+//! Throughput of replication in a three peer cluster on a `labrpc::Network`,
+//! sending one command per AppendEntries or batching and pipelining them.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::executor::block_on;
+use futures::StreamExt;
+use prost_derive::Message;
+
+use labrpc::{Network, ServerBuilder};
+use raft::proto::raftpb::{add_raft_service, RaftClient};
+use raft::raft::storage::MemStorage;
+use raft::raft::{ApplyMsg, Node, Raft, RaftConfig};
+
+const PEERS: usize = 3;
+// commands proposed together in an iteration.
+const COMMANDS: u64 = 1000;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Command {
+    #[prost(uint64, tag = "1")]
+    pub x: u64,
+}
+
+struct Cluster {
+    _net: Network,
+    nodes: Vec<Node>,
+    apply_chs: Vec<UnboundedReceiver<ApplyMsg>>,
+}
+
+impl Cluster {
+    fn new(config: RaftConfig) -> Cluster {
+        let net = Network::new();
+        let mut nodes = Vec::with_capacity(PEERS);
+        let mut apply_chs = Vec::with_capacity(PEERS);
+        for i in 0..PEERS {
+            let peers = (0..PEERS)
+                .map(|j| {
+                    let name = format!("{}-{}", i, j);
+                    let client = RaftClient::new(net.create_client(name.clone()));
+                    net.connect(&name, &format!("{}", j));
+                    net.enable(&name, true);
+                    client
+                })
+                .collect();
+            let (tx, apply_ch) = unbounded();
+            let storage = Box::new(MemStorage::new());
+            let node = Node::new(Raft::new(peers, i, storage, tx, config.clone()));
+            let mut builder = ServerBuilder::new(format!("{}", i));
+            add_raft_service(node.clone(), &mut builder).unwrap();
+            net.add_server(builder.build());
+            nodes.push(node);
+            apply_chs.push(apply_ch);
+        }
+        Cluster {
+            _net: net,
+            nodes,
+            apply_chs,
+        }
+    }
+
+    fn leader(&self) -> usize {
+        loop {
+            if let Some(leader) = self.nodes.iter().position(Node::is_leader) {
+                return leader;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Proposes the commands to the leader, and waits for every peer to
+    // apply them.
+    fn replicate(&mut self, commands: u64) -> Duration {
+        let leader = self.leader();
+        let start = Instant::now();
+        for x in 0..commands {
+            self.nodes[leader]
+                .start(&Command { x })
+                .expect("leader changed");
+        }
+        for apply_ch in &mut self.apply_chs {
+            block_on(async {
+                let mut applied = 0;
+                while applied < commands {
+                    let msg = apply_ch.next().await.expect("peer stopped");
+                    if let ApplyMsg::Command { .. } = msg {
+                        applied += 1;
+                    }
+                }
+            });
+        }
+        start.elapsed()
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node in &self.nodes {
+            node.kill();
+        }
+    }
+}
+
+fn bench_replication(c: &mut Criterion) {
+    let mut group = c.benchmark_group("replication");
+    group.throughput(Throughput::Elements(COMMANDS));
+    group.sample_size(10);
+    for &(max_append_entries, max_inflight_appends) in &[(1, 1), (64, 1), (64, 8)] {
+        let config = RaftConfig {
+            max_append_entries,
+            max_inflight_appends,
+            ..RaftConfig::default()
+        };
+        let id = BenchmarkId::new(
+            format!("batch {}", max_append_entries),
+            format!("inflight {}", max_inflight_appends),
+        );
+        // a fresh cluster per iteration, since the persisters rewrite the
+        // whole log on every append, which would get slower as it grows.
+        group.bench_function(id, |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| Cluster::new(config.clone()).replicate(COMMANDS))
+                    .sum()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_replication);
+criterion_main!(benches);
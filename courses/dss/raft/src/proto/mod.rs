@@ -4,6 +4,11 @@ pub mod raftpb {
     labrpc::service! {
         service raft {
             rpc request_vote(RequestVoteArgs) returns (RequestVoteReply);
+            rpc append_entries(AppendEntriesArgs) returns (AppendEntriesReply);
+            // asks whether a vote would be granted, without changing any term.
+            rpc pre_vote(RequestVoteArgs) returns (RequestVoteReply);
+            rpc timeout_now(TimeoutNowArgs) returns (TimeoutNowReply);
+            rpc install_snapshot(InstallSnapshotArgs) returns (InstallSnapshotReply);
 
             // Your code here if more rpc desired.
             // rpc xxx(yyy) returns (zzz)
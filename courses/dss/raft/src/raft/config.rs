@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -12,6 +12,7 @@ use rand::Rng;
 use crate::proto::raftpb::*;
 use crate::raft;
 use crate::raft::persister::*;
+use crate::raft::storage::PersisterLogStorage;
 
 static ID: AtomicUsize = AtomicUsize::new(0);
 
@@ -26,9 +27,29 @@ pub struct Entry {
     pub x: u64,
 }
 
+/// The state of a server, as in the snapshots it takes.
+#[derive(Clone, PartialEq, Message)]
+struct SnapshotState {
+    #[prost(uint64, tag = "1")]
+    index: u64,
+    #[prost(map = "uint64, message", tag = "2")]
+    log: HashMap<u64, Entry>,
+    #[prost(uint64, repeated, tag = "3")]
+    conf_changes: Vec<u64>,
+}
+
+/// how many commands a server applies between snapshots.
+pub const SNAPSHOT_INTERVAL: u64 = 10;
+
 pub struct Storage {
     // copy of each server's committed entries
     logs: Vec<HashMap<u64, Entry>>,
+    // indexes of each server's committed membership changes
+    conf_changes: Vec<HashSet<u64>>,
+    // the leader of each term, as servers learned it
+    leaders: HashMap<u64, u64>,
+    // the term of each committed entry, as servers applied it
+    terms: HashMap<u64, u64>,
     max_index: u64,
     max_index0: u64,
 }
@@ -53,6 +74,18 @@ impl Storage {
         }
         (count, cmd)
     }
+
+    // check that the entry at index was committed in the same term
+    // everywhere.
+    fn check_term(&mut self, i: usize, index: u64, term: u64) {
+        let old = *self.terms.entry(index).or_insert(term);
+        if old != term {
+            panic!(
+                "server {} applied index {} in term {}, but it was {}",
+                i, index, term, old
+            );
+        }
+    }
 }
 
 fn init_logger() {
@@ -64,6 +97,9 @@ fn init_logger() {
 pub struct Config {
     pub net: labrpc::Network,
     n: usize,
+    // servers 0..members start in the cluster, the others are spares
+    // which can be added later.
+    members: usize,
     // use boxed slice to prohibit grow capacity.
     pub rafts: Arc<Mutex<Box<[Option<raft::Node>]>>>,
     // whether each server is on the net
@@ -73,6 +109,9 @@ pub struct Config {
     endnames: Box<[Box<[String]>]>,
 
     pub storage: Arc<Mutex<Storage>>,
+    raft_config: raft::RaftConfig,
+    // whether servers snapshot their state every SNAPSHOT_INTERVAL commands
+    snapshots: bool,
 
     // time at which make_config() was called
     start: Instant,
@@ -89,13 +128,54 @@ pub struct Config {
 
 impl Config {
     pub fn new(n: usize, unreliable: bool) -> Config {
+        Config::with_raft_config(n, unreliable, raft::RaftConfig::default())
+    }
+
+    /// like new(), with the options every server is started with.
+    pub fn with_raft_config(n: usize, unreliable: bool, raft_config: raft::RaftConfig) -> Config {
+        Config::build(n, 0, unreliable, raft_config, false)
+    }
+
+    /// like new(), with servers which snapshot their state every few
+    /// commands, so that lagging servers are sent snapshots, in several
+    /// chunks.
+    pub fn with_snapshots(n: usize, unreliable: bool) -> Config {
+        let raft_config = raft::RaftConfig {
+            snapshot_chunk_size: 1024,
+            ..raft::RaftConfig::default()
+        };
+        Config::build(n, 0, unreliable, raft_config, true)
+    }
+
+    /// like new(), with spare servers n..n+spares which aren't started,
+    /// to be added with add_server().
+    pub fn with_spares(n: usize, spares: usize, unreliable: bool) -> Config {
+        let raft_config = raft::RaftConfig {
+            voters: Some((0..n as u64).collect()),
+            ..raft::RaftConfig::default()
+        };
+        Config::build(n, spares, unreliable, raft_config, false)
+    }
+
+    fn build(
+        members: usize,
+        spares: usize,
+        unreliable: bool,
+        raft_config: raft::RaftConfig,
+        snapshots: bool,
+    ) -> Config {
         init_logger();
 
+        let n = members + spares;
+
         let net = labrpc::Network::new();
         net.set_reliable(!unreliable);
         net.set_long_delays(true);
         let storage = Storage {
             logs: vec![HashMap::new(); n],
+            conf_changes: vec![HashSet::new(); n],
+            leaders: HashMap::new(),
+            terms: HashMap::new(),
             max_index: 0,
             max_index0: 0,
         };
@@ -105,14 +185,19 @@ impl Config {
             endnames.push(vec![String::new(); n].into_boxed_slice());
             saved.push(Arc::new(SimplePersister::new()));
         }
+        let mut connected = vec![true; members];
+        connected.resize(n, false);
         let mut cfg = Config {
             net,
             n,
+            members,
             rafts: Arc::new(Mutex::new(vec![None; n].into_boxed_slice())),
-            connected: vec![true; n].into_boxed_slice(),
+            connected: connected.into_boxed_slice(),
             saved: saved.into_boxed_slice(),
             endnames: endnames.into_boxed_slice(),
             storage: Arc::new(Mutex::new(storage)),
+            raft_config,
+            snapshots,
 
             start: Instant::now(),
             t0: Instant::now(),
@@ -120,11 +205,11 @@ impl Config {
             cmds0: 0,
         };
 
-        for i in 0..n {
+        for i in 0..members {
             cfg.start1(i);
         }
 
-        for i in 0..n {
+        for i in 0..members {
             cfg.connect(i);
         }
 
@@ -222,6 +307,15 @@ impl Config {
         s.n_committed(index)
     }
 
+    /// the size of the largest raft state saved by a server.
+    pub fn log_size(&self) -> usize {
+        self.saved
+            .iter()
+            .map(|p| p.raft_state().len())
+            .max()
+            .unwrap_or(0)
+    }
+
     // wait for at least n servers to commit.
     // but don't wait forever.
     pub fn wait(&self, index: u64, n: usize, start_term: Option<u64>) -> Option<Entry> {
@@ -375,35 +469,119 @@ impl Config {
             self.net.connect(name, &format!("{}", j));
         }
 
+        // the state of the service restarts from its last snapshot.
+        let snapshot = self.saved[i].snapshot();
+        if !snapshot.is_empty() {
+            let state = labcodec::decode(&snapshot).expect("invalid snapshot");
+            restore(&mut self.storage.lock().unwrap(), i, state);
+        }
+
         // listen to messages from Raft indicating newly committed messages.
         let (tx, apply_ch) = unbounded();
         let storage = self.storage.clone();
-        let apply = apply_ch.for_each(move |cmd: raft::ApplyMsg| {
-            if !cmd.command_valid {
-                // ignore other types of ApplyMsg
-                return future::ready(());
-            }
-            match labcodec::decode(&cmd.command) {
+        let snapshots = self.snapshots;
+        // the raft this applier belongs to, once started.
+        let node: Arc<Mutex<Option<raft::Node>>> = Arc::default();
+        let applier_node = node.clone();
+        let apply = apply_ch.for_each(move |msg: raft::ApplyMsg| {
+            let (command_index, command) = match msg {
+                raft::ApplyMsg::Command { index, term, data } => {
+                    storage.lock().unwrap().check_term(i, index, term);
+                    (index, data)
+                }
+                raft::ApplyMsg::Snapshot { index, term, data } => {
+                    let node = applier_node.lock().unwrap().clone();
+                    let installed = match node {
+                        Some(node) => node.cond_install_snapshot(term, index, &data),
+                        None => false,
+                    };
+                    if !installed {
+                        return future::ready(());
+                    }
+                    let state: SnapshotState = labcodec::decode(&data).expect("invalid snapshot");
+                    if state.index != index {
+                        panic!(
+                            "server {} got a snapshot of index {} at index {}",
+                            i, state.index, index
+                        );
+                    }
+                    let mut s = storage.lock().unwrap();
+                    if s.terms.get(&index) != Some(&term) {
+                        panic!(
+                            "server {} got a snapshot of index {} in term {}, but it was {:?}",
+                            i,
+                            index,
+                            term,
+                            s.terms.get(&index)
+                        );
+                    }
+                    restore(&mut s, i, state);
+                    return future::ready(());
+                }
+                raft::ApplyMsg::ConfigChange { index, term, .. } => {
+                    // the next command follows the membership change.
+                    let mut s = storage.lock().unwrap();
+                    s.check_term(i, index, term);
+                    s.conf_changes[i].insert(index);
+                    return future::ready(());
+                }
+                raft::ApplyMsg::LeaderChanged { term, leader } => {
+                    let mut s = storage.lock().unwrap();
+                    let old = *s.leaders.entry(term).or_insert(leader);
+                    if old != leader {
+                        panic!(
+                            "server {} learned leader {} in term {}, but {} was",
+                            i, leader, term, old
+                        );
+                    }
+                    return future::ready(());
+                }
+            };
+            match labcodec::decode(&command) {
                 Ok(entry) => {
                     let mut s = storage.lock().unwrap();
                     for (j, log) in s.logs.iter().enumerate() {
-                        if let Some(old) = log.get(&cmd.command_index) {
+                        if let Some(old) = log.get(&command_index) {
                             if *old != entry {
                                 // some server has already committed a different value for this entry!
                                 panic!(
                                     "commit index={:?} server={:?} {:?} != server={:?} {:?}",
-                                    cmd.command_index, i, entry, j, old
+                                    command_index, i, entry, j, old
                                 );
                             }
                         }
                     }
-                    let log = &mut s.logs[i];
-                    if cmd.command_index > 1 && log.get(&(cmd.command_index - 1)).is_none() {
-                        panic!("server {} apply out of order {}", i, cmd.command_index);
+                    let prev = command_index - 1;
+                    if command_index > 1
+                        && !s.logs[i].contains_key(&prev)
+                        && !s.conf_changes[i].contains(&prev)
+                    {
+                        panic!("server {} apply out of order {}", i, command_index);
                     }
-                    log.insert(cmd.command_index, entry);
-                    if cmd.command_index > s.max_index {
-                        s.max_index = cmd.command_index;
+                    s.logs[i].insert(command_index, entry);
+                    if command_index > s.max_index {
+                        s.max_index = command_index;
+                    }
+                    if snapshots && command_index % SNAPSHOT_INTERVAL == 0 {
+                        let state = SnapshotState {
+                            index: command_index,
+                            log: s.logs[i]
+                                .iter()
+                                .filter(|&(&index, _)| index <= command_index)
+                                .map(|(&index, entry)| (index, entry.clone()))
+                                .collect(),
+                            conf_changes: s.conf_changes[i]
+                                .iter()
+                                .copied()
+                                .filter(|&index| index <= command_index)
+                                .collect(),
+                        };
+                        drop(s);
+                        let mut data = vec![];
+                        labcodec::encode(&state, &mut data).unwrap();
+                        if let Some(ref node) = *applier_node.lock().unwrap() {
+                            node.snapshot(command_index, &data);
+                        }
                     }
                 }
                 Err(e) => {
@@ -414,16 +592,95 @@ impl Config {
         });
         self.net.spawn_poller(apply);
 
-        let rf = raft::Raft::new(clients, i, Box::new(self.saved[i].clone()), tx);
-        let node = raft::Node::new(rf);
-        self.rafts.lock().unwrap()[i] = Some(node.clone());
+        let mut raft_config = self.raft_config.clone();
+        if i >= self.members {
+            // a spare joins a running cluster.
+            raft_config.voters = Some(vec![]);
+        }
+        let storage = PersisterLogStorage::new(self.saved[i].clone())
+            .unwrap_or_else(|e| panic!("failed to restore the raft state: {}", e));
+        let rf = raft::Raft::new(clients, i, Box::new(storage), tx, raft_config);
+        let rf = raft::Node::new(rf);
+        *node.lock().unwrap() = Some(rf.clone());
+        self.rafts.lock().unwrap()[i] = Some(rf.clone());
 
         let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
-        raft::add_raft_service(node, &mut builder).unwrap();
+        raft::add_raft_service(rf, &mut builder).unwrap();
         let srv = builder.build();
         self.net.add_server(srv);
     }
 
+    /// start spare server i, and add it to the cluster as a learner,
+    /// then as a voter once it caught up with the leader.
+    pub fn add_server(&mut self, i: usize) {
+        self.start1(i);
+        self.connect(i);
+        self.change_conf(ConfChangeType::AddLearner, i);
+        self.change_conf(ConfChangeType::AddNode, i);
+    }
+
+    /// remove server i from the cluster, then shut it down.
+    pub fn remove_server(&mut self, i: usize) {
+        self.change_conf(ConfChangeType::RemoveNode, i);
+        self.crash1(i);
+    }
+
+    /// propose a membership change to whichever server is the leader,
+    /// until it takes effect on the leader.
+    pub fn change_conf(&self, change_type: ConfChangeType, i: usize) {
+        let change = ConfChange {
+            change_type: change_type as i32,
+            node_id: i as u64,
+        };
+        let done = |conf: &ConfState| {
+            let (voter, learner) = (
+                conf.voters.contains(&(i as u64)),
+                conf.learners.contains(&(i as u64)),
+            );
+            match change_type {
+                ConfChangeType::AddNode => voter,
+                ConfChangeType::AddLearner => learner,
+                ConfChangeType::RemoveNode => !voter && !learner,
+            }
+        };
+        let t0 = Instant::now();
+        while t0.elapsed() < Duration::from_secs(10) {
+            for (j, connected) in self.connected.iter().enumerate() {
+                if !*connected {
+                    continue;
+                }
+                let rf = match &self.rafts.lock().unwrap()[j] {
+                    Some(rf) => rf.clone(),
+                    None => continue,
+                };
+                match rf.propose_conf_change(change.clone()) {
+                    Ok((index, _)) => {
+                        // wait a while for the change to be committed.
+                        let t1 = Instant::now();
+                        while t1.elapsed() < Duration::from_secs(2) {
+                            if done(&rf.conf_state()) && self.n_committed_conf(index) > 0 {
+                                return;
+                            }
+                            thread::sleep(Duration::from_millis(20));
+                        }
+                    }
+                    Err(e) => debug!("change {:?} failed: {:?}", change, e),
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("membership change {:?} failed", change);
+    }
+
+    // how many servers applied the membership change at index?
+    fn n_committed_conf(&self, index: u64) -> usize {
+        let s = self.storage.lock().unwrap();
+        s.conf_changes
+            .iter()
+            .filter(|indexes| indexes.contains(&index))
+            .count()
+    }
+
     /// shut down a Raft server but save its persistent state.
     pub fn crash1(&mut self, i: usize) {
         self.disconnect(i);
@@ -435,7 +692,7 @@ impl Config {
         // but copy old persister's content so that we always
         // pass Make() the last persisted state.
         let p = SimplePersister::new();
-        p.save_raft_state(self.saved[i].raft_state());
+        p.save_state_and_snapshot(self.saved[i].raft_state(), self.saved[i].snapshot());
         self.saved[i] = Arc::new(p);
 
         if let Some(rf) = self.rafts.lock().unwrap()[i].take() {
@@ -485,6 +742,25 @@ impl Config {
     }
 }
 
+// replaces what server i applied with the state in a snapshot.
+fn restore(s: &mut Storage, i: usize, state: SnapshotState) {
+    for (&index, entry) in &state.log {
+        for (j, log) in s.logs.iter().enumerate() {
+            if let Some(old) = log.get(&index) {
+                if old != entry {
+                    panic!(
+                        "snapshot index={:?} server={:?} {:?} != server={:?} {:?}",
+                        index, i, entry, j, old
+                    );
+                }
+            }
+        }
+    }
+    s.max_index = s.max_index.max(state.index);
+    s.logs[i] = state.log;
+    s.conf_changes[i] = state.conf_changes.into_iter().collect();
+}
+
 impl Drop for Config {
     fn drop(&mut self) {
         if let Ok(rafts) = self.rafts.try_lock() {
@@ -6,6 +6,14 @@ pub enum Error {
     Decode(labcodec::DecodeError),
     Rpc(labrpc::Error),
     NotLeader,
+    /// The target of a leadership transfer did not become the leader in time.
+    TransferFailed,
+    /// A membership change is proposed before the previous one is committed.
+    ConfChangeInProgress,
+    /// The membership change doesn't apply to the current members.
+    InvalidConfChange,
+    /// A learner is promoted before its log caught up with the leader.
+    LearnerNotCaughtUp,
 }
 
 impl fmt::Display for Error {
@@ -1,23 +1,56 @@
-use std::sync::mpsc::{sync_channel, Receiver};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use futures::channel::mpsc::UnboundedSender;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use rand::Rng;
 
 #[cfg(test)]
 pub mod config;
 pub mod errors;
 pub mod persister;
+pub mod snapshot;
+pub mod status;
+pub mod storage;
 #[cfg(test)]
 mod tests;
 
 use self::errors::*;
-use self::persister::*;
+use self::snapshot::{IncomingSnapshot, OutgoingSnapshot, Snapshot};
+use self::status::{Event, EventBus, Progress, Role, Status};
+use self::storage::{HardState, LogStorage};
 use crate::proto::raftpb::*;
 
-pub struct ApplyMsg {
-    pub command_valid: bool,
-    pub command: Vec<u8>,
-    pub command_index: u64,
+/// A message sent by a Raft peer to its service, in the order of the log.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApplyMsg {
+    /// A committed command, to apply to the state of the service.
+    Command {
+        index: u64,
+        term: u64,
+        data: Vec<u8>,
+    },
+    /// A snapshot received from the leader, replacing the state of the service
+    /// up to `index`. The service installs it only if
+    /// [`Node::cond_install_snapshot`] agrees.
+    Snapshot {
+        index: u64,
+        term: u64,
+        data: Vec<u8>,
+    },
+    /// A committed membership change, and the members after it.
+    ConfigChange {
+        index: u64,
+        term: u64,
+        change: ConfChange,
+        conf_state: ConfState,
+    },
+    /// This peer learned that `leader` was elected in `term`.
+    LeaderChanged { term: u64, leader: u64 },
 }
 
 /// State of a raft peer.
@@ -38,181 +71,1517 @@ impl State {
     }
 }
 
+/// Options of a raft peer.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// Before starting an election, a candidate asks the other peers whether
+    /// they would vote for it with a `pre_vote` RPC, and only increments its
+    /// term if a majority would. A peer rejoining after a partition then
+    /// can't force the leader to step down with a greater term.
+    pub pre_vote: bool,
+    /// A leader which hasn't heard from a majority of the peers within an
+    /// election timeout steps down, so that a partitioned leader stops
+    /// serving stale requests.
+    pub check_quorum: bool,
+    /// The voters of a new cluster, every peer if `None`. A server joining a
+    /// running cluster starts with no voters, and waits for the leader to add
+    /// it. Ignored once the members are persisted.
+    pub voters: Option<Vec<u64>>,
+    /// How the leader confirms its leadership to serve reads.
+    pub read_mode: ReadMode,
+    /// The most entries sent in one AppendEntries. The commands started
+    /// while a follower is being sent entries are batched in the next one.
+    pub max_append_entries: usize,
+    /// The most AppendEntries sent to a follower without waiting for their
+    /// replies. After a rejection, the leader probes the follower with one
+    /// AppendEntries at a time until it finds where their logs match, then
+    /// pipelines them again.
+    pub max_inflight_appends: usize,
+    /// The most bytes of a snapshot sent in one InstallSnapshot.
+    pub snapshot_chunk_size: usize,
+}
+
+/// How a leader confirms it is still the leader in [`Node::read_index`], so
+/// that no other leader may have committed entries it doesn't know about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadMode {
+    /// A majority acknowledges a round of heartbeats sent after the read.
+    ReadIndex,
+    /// No message is sent while the leader holds a lease: the followers which
+    /// acknowledged a heartbeat don't vote for another candidate until their
+    /// election timeout elapses, so the lease lasts the minimum election
+    /// timeout minus `max_clock_drift` from when that heartbeat was sent.
+    /// It is only safe with `check_quorum`, which `Raft::new` requires, and
+    /// if the clocks of the peers don't drift apart by more than
+    /// `max_clock_drift` over that time. The target of a leadership transfer
+    /// is voted for regardless, so the lease is lost once it is sent a
+    /// `TimeoutNow`, until a majority acknowledges a later heartbeat.
+    Lease { max_clock_drift: Duration },
+}
+
+impl Default for RaftConfig {
+    fn default() -> RaftConfig {
+        RaftConfig {
+            pre_vote: true,
+            check_quorum: true,
+            voters: None,
+            read_mode: ReadMode::ReadIndex,
+            max_append_entries: 64,
+            max_inflight_appends: 8,
+            snapshot_chunk_size: 64 * 1024,
+        }
+    }
+}
+
+// How often the timers of a peer are checked.
+const TICK: Duration = Duration::from_millis(10);
+// How often the leader sends heartbeats, well within the election timeout
+// while keeping to the tester's limit of tens of RPCs per second.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+// Election timeouts are drawn between these bounds, so that peers rarely
+// start elections at the same time.
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(300);
+const ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(600);
+// An AppendEntries without a reply after this long is deemed lost.
+const APPEND_TIMEOUT: Duration = Duration::from_millis(500);
+
+// An RPC for another peer, sent by the node once the raft lock is released.
+enum Msg {
+    RequestVote(RequestVoteArgs),
+    PreVote(RequestVoteArgs),
+    AppendEntries {
+        args: AppendEntriesArgs,
+        // an empty AppendEntries at the last entry known to match, which
+        // doesn't take part in finding where the logs match.
+        heartbeat: bool,
+    },
+    TimeoutNow(TimeoutNowArgs),
+    InstallSnapshot(InstallSnapshotArgs),
+}
+
+// How the leader replicates its log to another peer.
+struct Replication {
+    // the next entry to send, past the entries in flight.
+    next_index: u64,
+    // the last entry known to be replicated.
+    match_index: u64,
+    // whether the leader is still looking for the last entry matching its
+    // log, sending one AppendEntries at a time, which the first successful
+    // reply ends.
+    probing: bool,
+    // when the AppendEntries waiting for a reply were sent, and their last
+    // entries, oldest first.
+    inflight: VecDeque<(Instant, u64)>,
+    // when the last AppendEntries the peer replied to was sent, before which
+    // the peer acknowledged the leadership of this peer.
+    acked: Option<Instant>,
+    // the index of the snapshot being sent to the peer, and the offset of the
+    // chunk it expects next.
+    snapshot: Option<(u64, u64)>,
+}
+
+impl Replication {
+    fn new(last_index: u64) -> Replication {
+        Replication {
+            next_index: last_index + 1,
+            match_index: 0,
+            probing: true,
+            inflight: VecDeque::new(),
+            acked: None,
+            snapshot: None,
+        }
+    }
+
+    // Whether another AppendEntries can be sent without waiting for replies.
+    fn can_send(&self, last_index: u64, max_inflight: usize) -> bool {
+        if self.probing {
+            self.inflight.is_empty()
+        } else {
+            self.next_index <= last_index && self.inflight.len() < max_inflight
+        }
+    }
+
+    // Looks for where the logs match again from next_index, forgetting the
+    // AppendEntries in flight.
+    fn probe(&mut self, next_index: u64) {
+        self.probing = true;
+        self.next_index = next_index.max(self.match_index + 1);
+        self.inflight.clear();
+    }
+}
+
+// A read waiting for the leadership of this peer to be confirmed.
+struct Read {
+    // the leader must be acknowledged by a majority after this.
+    since: Instant,
+    done: oneshot::Sender<Result<u64>>,
+}
+
+// A leadership transfer in progress.
+struct Transfer {
+    target: u64,
+    // the term of the leader handing its leadership over.
+    term: u64,
+    deadline: Instant,
+    done: oneshot::Sender<Result<()>>,
+}
+
 // A single Raft peer.
 pub struct Raft {
     // RPC end points of all peers
     peers: Vec<RaftClient>,
-    // Object to hold this peer's persisted state
-    persister: Box<dyn Persister>,
+    // the log, term, vote and snapshot of this peer
+    storage: Box<dyn LogStorage>,
     // this peer's index into peers[]
     me: usize,
-    state: Arc<State>,
-    // Your data here (2A, 2B, 2C).
-    // Look at the paper's Figure 2 for a description of what
-    // state a Raft server must maintain.
+    config: RaftConfig,
+    apply_ch: UnboundedSender<ApplyMsg>,
+
+    // the term and vote, as saved in storage
+    term: u64,
+    voted_for: Option<u64>,
+    role: Role,
+    // the leader of term, if known
+    leader: Option<u64>,
+    commit_index: u64,
+    // the last entry sent to apply_ch
+    last_applied: u64,
+    // the index and term of the snapshot sent to apply_ch, which the service
+    // hasn't installed yet. no entry is applied until it does.
+    pending_snapshot: Option<(u64, u64)>,
+    // the members as of the last entry of the log, and the last CONF_CHANGE
+    // entry, or the snapshot if there is none.
+    conf: ConfState,
+    conf_index: u64,
+
+    // when a follower or candidate starts an election.
+    election_deadline: Instant,
+    // when the leader sends the next heartbeats.
+    heartbeat_deadline: Instant,
+    // when this peer last heard from the leader of its term.
+    leader_contact: Option<Instant>,
+    // the peers which voted, or would vote, for this candidate.
+    votes: HashSet<u64>,
+    // the peers which replied to the leader since the last check of its
+    // quorum, and when the next check is due.
+    active: HashSet<u64>,
+    quorum_check_deadline: Instant,
+    // the replication to every other peer, on the leader.
+    progress: HashMap<u64, Replication>,
+    transfer: Option<Transfer>,
+    // when the last TimeoutNow was sent, the lease of the leader only counting
+    // the heartbeats sent after.
+    timeout_now_sent: Instant,
+    reads: Vec<Read>,
+    // the snapshot sent to the peers whose next entry is compacted, and the
+    // one being received from the leader.
+    outgoing: Option<OutgoingSnapshot>,
+    incoming: IncomingSnapshot,
+    // RPCs to send once the lock on this peer is released.
+    msgs: Vec<(u64, Msg)>,
+    events: EventBus,
+    killed: bool,
 }
 
 impl Raft {
     /// the service or tester wants to create a Raft server. the ports
     /// of all the Raft servers (including this one) are in peers. this
     /// server's port is peers[me]. all the servers' peers arrays
-    /// have the same order. persister is a place for this server to
-    /// save its persistent state, and also initially holds the most
-    /// recent saved state, if any. apply_ch is a channel on which the
-    /// tester or service expects Raft to send ApplyMsg messages.
+    /// have the same order. storage is a place for this server to
+    /// save its log, term, vote and snapshot, and also initially holds
+    /// the most recent saved state, if any. apply_ch is a channel on which the
+    /// tester or service expects Raft to send ApplyMsg messages. config
+    /// holds the options of this peer.
     /// This method must return quickly.
     pub fn new(
         peers: Vec<RaftClient>,
         me: usize,
-        persister: Box<dyn Persister>,
+        storage: Box<dyn LogStorage>,
         apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
     ) -> Raft {
-        let raft_state = persister.raft_state();
-
-        // Your initialization code here (2A, 2B, 2C).
+        if let ReadMode::Lease { .. } = config.read_mode {
+            assert!(config.check_quorum, "lease reads require check_quorum");
+        }
+        // initialize from state persisted before a crash
+        let hard_state = storage.hard_state();
+        let snapshot_index = storage.first_index() - 1;
+        let now = Instant::now();
         let mut rf = Raft {
             peers,
-            persister,
+            storage,
             me,
-            state: Arc::default(),
+            config,
+            apply_ch,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            role: Role::Follower,
+            leader: None,
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            pending_snapshot: None,
+            conf: ConfState::default(),
+            conf_index: snapshot_index,
+            election_deadline: now,
+            heartbeat_deadline: now,
+            leader_contact: None,
+            votes: HashSet::new(),
+            active: HashSet::new(),
+            quorum_check_deadline: now,
+            progress: HashMap::new(),
+            transfer: None,
+            timeout_now_sent: now,
+            reads: Vec::new(),
+            outgoing: None,
+            incoming: IncomingSnapshot::new(),
+            msgs: Vec::new(),
+            events: EventBus::new(),
+            killed: false,
         };
+        rf.load_conf();
+        rf.reset_election_deadline();
+        rf
+    }
 
-        // initialize from state persisted before a crash
-        rf.restore(&raft_state);
+    fn id(&self) -> u64 {
+        self.me as u64
+    }
+
+    fn is_voter(&self, id: u64) -> bool {
+        self.conf.voters.contains(&id)
+    }
 
-        crate::your_code_here((rf, apply_ch))
+    // Whether the given peers are a majority of the voters.
+    fn is_quorum<'a, I>(&self, ids: I) -> bool
+    where
+        I: IntoIterator<Item = &'a u64>,
+    {
+        let n = ids.into_iter().filter(|&&id| self.is_voter(id)).count();
+        n > self.conf.voters.len() / 2
     }
 
-    /// save Raft's persistent state to stable storage,
-    /// where it can later be retrieved after a crash and restart.
-    /// see paper's Figure 2 for a description of what should be persistent.
+    // The members other than this peer, which the leader replicates to.
+    fn others(&self) -> Vec<u64> {
+        let members = self.conf.voters.iter().chain(&self.conf.learners);
+        members.copied().filter(|&id| id != self.id()).collect()
+    }
+
+    fn other_voters(&self) -> Vec<u64> {
+        let voters = self.conf.voters.iter();
+        voters.copied().filter(|&id| id != self.id()).collect()
+    }
+
+    // The members as of the last entry compacted in the log, which a new
+    // cluster starts with.
+    fn base_conf(&self) -> ConfState {
+        self.storage
+            .hard_state()
+            .conf_state
+            .unwrap_or_else(|| ConfState {
+                voters: match self.config.voters {
+                    Some(ref voters) => voters.clone(),
+                    None => (0..self.peers.len() as u64).collect(),
+                },
+                learners: Vec::new(),
+            })
+    }
+
+    // The members as of the entry at index, and the last change up to it.
+    fn conf_at(&self, index: u64) -> (ConfState, u64) {
+        let mut conf = self.base_conf();
+        let mut conf_index = self.storage.first_index() - 1;
+        for entry in self.storage.entries(conf_index + 1, index + 1) {
+            if let Some(change) = conf_change(&entry) {
+                apply_conf_change(&mut conf, &change);
+                conf_index = entry.index;
+            }
+        }
+        (conf, conf_index)
+    }
+
+    fn load_conf(&mut self) {
+        let (conf, conf_index) = self.conf_at(self.storage.last_index());
+        self.conf = conf;
+        self.conf_index = conf_index;
+        self.update_progress();
+    }
+
+    // Starts or stops replicating to the peers which joined or left.
+    fn update_progress(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let others = self.others();
+        self.progress.retain(|id, _| others.contains(id));
+        let last_index = self.storage.last_index();
+        for id in others {
+            self.progress
+                .entry(id)
+                .or_insert_with(|| Replication::new(last_index));
+        }
+    }
+
+    // Appends entries to the log. The members change as soon as a
+    // CONF_CHANGE entry is appended, whether it's committed or not.
+    fn append(&mut self, entries: Vec<LogEntry>) {
+        let mut changed = false;
+        for entry in &entries {
+            if let Some(change) = conf_change(entry) {
+                apply_conf_change(&mut self.conf, &change);
+                self.conf_index = entry.index;
+                changed = true;
+            }
+        }
+        must(self.storage.append(entries));
+        if changed {
+            self.update_progress();
+        }
+    }
+
+    // Removes the entries from index on, with the changes of members in them.
+    fn truncate(&mut self, index: u64) {
+        let last_index = self.storage.last_index();
+        must(self.storage.truncate_suffix(index));
+        self.events.send(Event::LogTruncated { index, last_index });
+        if self.conf_index >= index {
+            self.load_conf();
+        }
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.storage.term(self.storage.last_index()).unwrap()
+    }
+
+    /// save the term and vote to stable storage, before answering the RPC
+    /// which changed them.
     fn persist(&mut self) {
-        // Your code here (2C).
-        // Example:
-        // labcodec::encode(&self.xxx, &mut data).unwrap();
-        // labcodec::encode(&self.yyy, &mut data).unwrap();
-        // self.persister.save_raft_state(data);
+        let state = HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+            ..self.storage.hard_state()
+        };
+        must(self.storage.save_hard_state(state));
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let timeout = rand::thread_rng().gen_range(
+            ELECTION_TIMEOUT_MIN.as_millis() as u64,
+            ELECTION_TIMEOUT_MAX.as_millis() as u64,
+        );
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+
+    // Called every TICK to start elections, and send heartbeats and the new
+    // entries.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if let Some(ref transfer) = self.transfer {
+            if now >= transfer.deadline {
+                self.finish_transfer(Err(Error::TransferFailed));
+            }
+        }
+        if self
+            .reads
+            .iter()
+            .any(|read| now >= read.since + ELECTION_TIMEOUT_MAX)
+        {
+            // the leader can't reach a majority, which may have elected
+            // another leader.
+            let (expired, reads) = mem::take(&mut self.reads)
+                .into_iter()
+                .partition(|read| now >= read.since + ELECTION_TIMEOUT_MAX);
+            self.reads = reads;
+            fail_reads(expired);
+        }
+        if self.role == Role::Leader {
+            if self.config.check_quorum && now >= self.quorum_check_deadline {
+                self.check_quorum();
+            }
+            if self.role == Role::Leader && now >= self.heartbeat_deadline {
+                self.broadcast_heartbeats();
+            } else if self.role == Role::Leader {
+                for id in self.others() {
+                    self.send_append(id);
+                }
+            }
+        } else if now >= self.election_deadline {
+            if !self.is_voter(self.id()) {
+                // learners and removed servers don't campaign.
+                self.reset_election_deadline();
+            } else if self.config.pre_vote {
+                self.pre_campaign();
+            } else {
+                self.campaign(false);
+            }
+        }
+    }
+
+    // Steps down unless a majority replied to this leader since the last
+    // check, so that a leader partitioned away stops acting as one.
+    fn check_quorum(&mut self) {
+        let mut active = mem::take(&mut self.active);
+        active.insert(self.id());
+        self.quorum_check_deadline = Instant::now() + ELECTION_TIMEOUT_MAX;
+        if !self.is_quorum(&active) {
+            info!(
+                "{} steps down in term {}, only {:?} are active",
+                self.me, self.term, active
+            );
+            self.leader = None;
+            self.become_follower(self.term);
+        }
+    }
+
+    // Whether this peer heard from a leader recently enough that no other
+    // peer may have been elected meanwhile.
+    fn in_lease(&self) -> bool {
+        self.role == Role::Leader
+            || self
+                .leader_contact
+                .filter(|contact| contact.elapsed() < ELECTION_TIMEOUT_MIN)
+                .is_some()
+    }
+
+    fn become_follower(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+            self.persist();
+        }
+        if self.role == Role::Leader {
+            self.progress.clear();
+            fail_reads(mem::take(&mut self.reads));
+            self.reset_election_deadline();
+        }
+        self.set_role(Role::Follower);
+    }
+
+    fn set_role(&mut self, role: Role) {
+        if self.role != role {
+            self.events.send(Event::RoleChanged {
+                term: self.term,
+                from: self.role,
+                to: role,
+            });
+            self.role = role;
+        }
     }
 
-    /// restore previously persisted state.
-    fn restore(&mut self, data: &[u8]) {
-        if data.is_empty() {
-            // bootstrap without any state?
+    // Records the leader of the current term, which this peer heard from.
+    fn set_leader(&mut self, leader: u64) {
+        if self.leader == Some(leader) {
             return;
         }
-        // Your code here (2C).
-        // Example:
-        // match labcodec::decode(data) {
-        //     Ok(o) => {
-        //         self.xxx = o.xxx;
-        //         self.yyy = o.yyy;
-        //     }
-        //     Err(e) => {
-        //         panic!("{:?}", e);
-        //     }
-        // }
-    }
-
-    /// example code to send a RequestVote RPC to a server.
-    /// server is the index of the target server in peers.
-    /// expects RPC arguments in args.
-    ///
-    /// The labrpc package simulates a lossy network, in which servers
-    /// may be unreachable, and in which requests and replies may be lost.
-    /// This method sends a request and waits for a reply. If a reply arrives
-    /// within a timeout interval, This method returns Ok(_); otherwise
-    /// this method returns Err(_). Thus this method may not return for a while.
-    /// An Err(_) return can be caused by a dead server, a live server that
-    /// can't be reached, a lost request, or a lost reply.
-    ///
-    /// This method is guaranteed to return (perhaps after a delay) *except* if
-    /// the handler function on the server side does not return.  Thus there
-    /// is no need to implement your own timeouts around this method.
-    ///
-    /// look at the comments in ../labrpc/src/lib.rs for more details.
-    fn send_request_vote(
-        &self,
-        server: usize,
+        self.leader = Some(leader);
+        let _ = self.apply_ch.unbounded_send(ApplyMsg::LeaderChanged {
+            term: self.term,
+            leader,
+        });
+        self.events.send(Event::LeaderChanged {
+            term: self.term,
+            leader,
+        });
+        let transferred = match self.transfer {
+            Some(ref transfer) if self.term > transfer.term => Some(transfer.target == leader),
+            _ => None,
+        };
+        match transferred {
+            Some(true) => self.finish_transfer(Ok(())),
+            Some(false) => self.finish_transfer(Err(Error::TransferFailed)),
+            None => {}
+        }
+    }
+
+    // Asks the other peers whether they would vote for this peer in the next
+    // term, before starting an election there.
+    fn pre_campaign(&mut self) {
+        self.set_role(Role::PreCandidate);
+        self.leader = None;
+        self.votes = Some(self.id()).into_iter().collect();
+        self.reset_election_deadline();
+        debug!("{} asks for pre-votes in term {}", self.me, self.term + 1);
+        self.events.send(Event::ElectionStarted {
+            term: self.term + 1,
+            pre_vote: true,
+        });
+        if self.is_quorum(&self.votes) {
+            self.campaign(false);
+            return;
+        }
+        let args = self.request_vote_args(self.term + 1, false);
+        for id in self.other_voters() {
+            self.msgs.push((id, Msg::PreVote(args.clone())));
+        }
+    }
+
+    fn campaign(&mut self, leader_transfer: bool) {
+        self.term += 1;
+        self.voted_for = Some(self.id());
+        self.persist();
+        self.set_role(Role::Candidate);
+        self.leader = None;
+        self.votes = Some(self.id()).into_iter().collect();
+        self.reset_election_deadline();
+        debug!("{} starts an election in term {}", self.me, self.term);
+        self.events.send(Event::ElectionStarted {
+            term: self.term,
+            pre_vote: false,
+        });
+        if self.is_quorum(&self.votes) {
+            self.become_leader();
+            return;
+        }
+        let args = self.request_vote_args(self.term, leader_transfer);
+        for id in self.other_voters() {
+            self.msgs.push((id, Msg::RequestVote(args.clone())));
+        }
+    }
+
+    fn request_vote_args(&self, term: u64, leader_transfer: bool) -> RequestVoteArgs {
+        RequestVoteArgs {
+            term,
+            candidate_id: self.id(),
+            last_log_index: self.storage.last_index(),
+            last_log_term: self.last_log_term(),
+            leader_transfer,
+        }
+    }
+
+    // Whether the log of a candidate holds every entry this log may have
+    // committed.
+    fn is_up_to_date(&self, args: &RequestVoteArgs) -> bool {
+        (args.last_log_term, args.last_log_index)
+            >= (self.last_log_term(), self.storage.last_index())
+    }
+
+    fn become_leader(&mut self) {
+        debug!("{} is the leader of term {}", self.me, self.term);
+        self.set_role(Role::Leader);
+        self.set_leader(self.id());
+        let last_index = self.storage.last_index();
+        self.progress = self
+            .others()
+            .into_iter()
+            .map(|id| (id, Replication::new(last_index)))
+            .collect();
+        self.active.clear();
+        self.quorum_check_deadline = Instant::now() + ELECTION_TIMEOUT_MAX;
+        self.broadcast_heartbeats();
+    }
+
+    fn handle_request_vote(&mut self, args: RequestVoteArgs) -> RequestVoteReply {
+        if self.config.check_quorum && !args.leader_transfer && self.in_lease() {
+            // the leader is alive, the candidate is likely partitioned from it.
+            return RequestVoteReply {
+                term: self.term,
+                vote_granted: false,
+            };
+        }
+        if args.term > self.term {
+            self.become_follower(args.term);
+        }
+        let granted = args.term == self.term
+            && self.voted_for.unwrap_or(args.candidate_id) == args.candidate_id
+            && self.is_up_to_date(&args);
+        if granted {
+            if self.voted_for.is_none() {
+                self.voted_for = Some(args.candidate_id);
+                self.persist();
+            }
+            self.reset_election_deadline();
+        }
+        RequestVoteReply {
+            term: self.term,
+            vote_granted: granted,
+        }
+    }
+
+    fn handle_request_vote_reply(
+        &mut self,
+        from: u64,
         args: RequestVoteArgs,
-    ) -> Receiver<Result<RequestVoteReply>> {
-        // Your code here if you want the rpc becomes async.
-        // Example:
-        // ```
-        // let peer = &self.peers[server];
-        // let peer_clone = peer.clone();
-        // let (tx, rx) = channel();
-        // peer.spawn(async move {
-        //     let res = peer_clone.request_vote(&args).await.map_err(Error::Rpc);
-        //     tx.send(res);
-        // });
-        // rx
-        // ```
-        let (tx, rx) = sync_channel::<Result<RequestVoteReply>>(1);
-        crate::your_code_here((server, args, tx, rx))
-    }
-
-    fn start<M>(&self, command: &M) -> Result<(u64, u64)>
-    where
-        M: labcodec::Message,
-    {
-        let index = 0;
-        let term = 0;
-        let is_leader = true;
-        let mut buf = vec![];
-        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
-        // Your code here (2B).
+        reply: RequestVoteReply,
+    ) {
+        if reply.term > self.term {
+            self.become_follower(reply.term);
+            return;
+        }
+        if self.role != Role::Candidate || args.term != self.term {
+            return;
+        }
+        self.events.send(Event::VoteReceived {
+            term: args.term,
+            from,
+            granted: reply.vote_granted,
+        });
+        if !reply.vote_granted {
+            return;
+        }
+        self.votes.insert(from);
+        if self.is_quorum(&self.votes) {
+            self.become_leader();
+        }
+    }
 
-        if is_leader {
-            Ok((index, term))
+    fn handle_pre_vote(&self, args: RequestVoteArgs) -> RequestVoteReply {
+        RequestVoteReply {
+            term: self.term,
+            vote_granted: args.term > self.term && self.is_up_to_date(&args) && !self.in_lease(),
+        }
+    }
+
+    fn handle_pre_vote_reply(&mut self, from: u64, args: RequestVoteArgs, reply: RequestVoteReply) {
+        if !reply.vote_granted && reply.term > self.term {
+            self.become_follower(reply.term);
+            return;
+        }
+        if self.role != Role::PreCandidate || args.term != self.term + 1 {
+            return;
+        }
+        self.events.send(Event::VoteReceived {
+            term: args.term,
+            from,
+            granted: reply.vote_granted,
+        });
+        if !reply.vote_granted {
+            return;
+        }
+        self.votes.insert(from);
+        if self.is_quorum(&self.votes) {
+            self.campaign(false);
+        }
+    }
+
+    fn start(&mut self, data: Vec<u8>) -> Result<(u64, u64)> {
+        if self.role != Role::Leader || self.transfer.is_some() {
+            return Err(Error::NotLeader);
+        }
+        Ok(self.propose(EntryType::Normal, data))
+    }
+
+    fn propose_conf_change(&mut self, change: ConfChange) -> Result<(u64, u64)> {
+        if self.role != Role::Leader || self.transfer.is_some() {
+            return Err(Error::NotLeader);
+        }
+        if self.conf_index > self.commit_index {
+            return Err(Error::ConfChangeInProgress);
+        }
+        let id = change.node_id;
+        let is_learner = self.conf.learners.contains(&id);
+        let is_member = is_learner || self.is_voter(id);
+        match change.change_type() {
+            ConfChangeType::AddLearner if is_member || id >= self.peers.len() as u64 => {
+                return Err(Error::InvalidConfChange);
+            }
+            ConfChangeType::AddNode if !is_learner => return Err(Error::InvalidConfChange),
+            ConfChangeType::AddNode if self.progress[&id].match_index < self.commit_index => {
+                return Err(Error::LearnerNotCaughtUp);
+            }
+            ConfChangeType::RemoveNode if !is_member || self.conf.voters == [id] => {
+                return Err(Error::InvalidConfChange);
+            }
+            _ => {}
+        }
+        info!("{} proposes {:?}", self.me, change);
+        let mut data = Vec::new();
+        labcodec::encode(&change, &mut data).map_err(Error::Encode)?;
+        Ok(self.propose(EntryType::ConfChange, data))
+    }
+
+    // Appends an entry to the log of the leader, to be replicated on the next
+    // tick.
+    fn propose(&mut self, entry_type: EntryType, data: Vec<u8>) -> (u64, u64) {
+        let entry = LogEntry {
+            index: self.storage.last_index() + 1,
+            term: self.term,
+            data,
+            entry_type: entry_type as i32,
+        };
+        let (index, term) = (entry.index, entry.term);
+        // the entries started until the next tick, or while a peer is being
+        // sent the previous ones, are sent together.
+        self.append(vec![entry]);
+        self.advance_commit();
+        (index, term)
+    }
+
+    // Sends the entries a peer is missing, up to max_append_entries per
+    // AppendEntries and max_inflight_appends AppendEntries without a reply,
+    // or probes where its log matches with a single one.
+    fn send_append(&mut self, id: u64) {
+        let last_index = self.storage.last_index();
+        let max_entries = self.config.max_append_entries.max(1) as u64;
+        let max_inflight = self.config.max_inflight_appends.max(1);
+        loop {
+            let progress = match self.progress.get_mut(&id) {
+                Some(progress) => progress,
+                // this peer stepped down, or the peer was removed.
+                None => return,
+            };
+            if !progress.can_send(last_index, max_inflight) {
+                return;
+            }
+            if progress.next_index < self.storage.first_index() {
+                // the entries it misses are compacted.
+                self.send_snapshot(id);
+                return;
+            }
+            let prev_log_index = progress.next_index - 1;
+            let last = last_index.min(prev_log_index + max_entries);
+            progress.inflight.push_back((Instant::now(), last));
+            if !progress.probing {
+                progress.next_index = last + 1;
+            }
+            let mut args = AppendEntriesArgs {
+                term: self.term,
+                leader_id: self.id(),
+                prev_log_index,
+                prev_log_term: self.storage.term(prev_log_index).unwrap(),
+                entries: self.storage.entries(prev_log_index + 1, last + 1),
+                leader_commit: self.commit_index,
+                conf_state: None,
+            };
+            if prev_log_index == self.storage.first_index() - 1 {
+                args.conf_state = Some(self.base_conf());
+            }
+            self.msgs.push((
+                id,
+                Msg::AppendEntries {
+                    args,
+                    heartbeat: false,
+                },
+            ));
+        }
+    }
+
+    // Sends the chunk of the snapshot a peer expects next, one at a time.
+    fn send_snapshot(&mut self, id: u64) {
+        let snapshot_index = self.storage.first_index() - 1;
+        // the snapshot changes along with the index it replaces the log up to.
+        if self.outgoing.as_ref().map(OutgoingSnapshot::index) != Some(snapshot_index) {
+            let chunk_size = self.config.snapshot_chunk_size.max(1);
+            self.outgoing = Some(OutgoingSnapshot::new(
+                &*self.storage,
+                snapshot_index,
+                self.storage.term(snapshot_index).unwrap(),
+                Some(self.base_conf()),
+                chunk_size,
+            ));
+        }
+        let progress = self.progress.get_mut(&id).unwrap();
+        if !progress.inflight.is_empty() {
+            return;
+        }
+        let offset = match progress.snapshot {
+            Some((index, offset)) if index == snapshot_index => offset,
+            _ => 0,
+        };
+        progress
+            .inflight
+            .push_back((Instant::now(), snapshot_index));
+        let outgoing = self.outgoing.as_ref().unwrap();
+        let args = outgoing.chunk(&*self.storage, self.term, self.id(), offset);
+        self.msgs.push((id, Msg::InstallSnapshot(args)));
+    }
+
+    fn broadcast_heartbeats(&mut self) {
+        self.heartbeat_deadline = Instant::now() + HEARTBEAT_INTERVAL;
+        for id in self.others() {
+            self.send_heartbeat(id);
+        }
+        self.maybe_send_timeout_now();
+    }
+
+    // Lets a peer know the leader is alive, sending it entries instead if it
+    // misses some and more can be in flight.
+    fn send_heartbeat(&mut self, id: u64) {
+        let last_index = self.storage.last_index();
+        let max_inflight = self.config.max_inflight_appends.max(1);
+        let progress = self.progress.get_mut(&id).unwrap();
+        if let Some(&(sent, _)) = progress.inflight.front() {
+            if sent.elapsed() >= APPEND_TIMEOUT {
+                // an AppendEntries or its reply is lost, look for where the
+                // logs match again.
+                let next_index = progress.match_index + 1;
+                progress.probe(next_index);
+            }
+        }
+        if progress.can_send(last_index, max_inflight) {
+            self.send_append(id);
+            return;
+        }
+        let prev_log_index = progress.match_index;
+        let args = AppendEntriesArgs {
+            term: self.term,
+            leader_id: self.id(),
+            prev_log_index,
+            // a compacted entry is matched by the snapshot of the follower.
+            prev_log_term: self.storage.term(prev_log_index).unwrap_or_default(),
+            entries: Vec::new(),
+            leader_commit: self.commit_index,
+            conf_state: None,
+        };
+        self.msgs.push((
+            id,
+            Msg::AppendEntries {
+                args,
+                heartbeat: true,
+            },
+        ));
+    }
+
+    // Follows the leader of term, which this peer just heard from.
+    fn follow(&mut self, term: u64, leader: u64) {
+        if term > self.term || self.role != Role::Follower {
+            self.become_follower(term);
+        }
+        self.set_leader(leader);
+        self.leader_contact = Some(Instant::now());
+        self.reset_election_deadline();
+    }
+
+    fn handle_append_entries(&mut self, args: AppendEntriesArgs) -> AppendEntriesReply {
+        let mut reply = AppendEntriesReply {
+            term: self.term,
+            ..Default::default()
+        };
+        if args.term < self.term {
+            return reply;
+        }
+        self.follow(args.term, args.leader_id);
+        reply.term = self.term;
+
+        let last_index = self.storage.last_index();
+        if args.prev_log_index > last_index {
+            reply.conflict_index = last_index + 1;
+            return reply;
+        }
+        let mut prev_log_index = args.prev_log_index;
+        let mut prev_log_term = args.prev_log_term;
+        let mut entries = args.entries;
+        let snapshot_index = self.storage.first_index() - 1;
+        if let Some(conf_state) = args.conf_state {
+            // the log of this peer starts where the log of the leader does,
+            // with the same members, which a new server doesn't know yet.
+            let mut hard_state = self.storage.hard_state();
+            if prev_log_index == snapshot_index && hard_state.conf_state != Some(conf_state.clone())
+            {
+                hard_state.conf_state = Some(conf_state);
+                must(self.storage.save_hard_state(hard_state));
+                self.load_conf();
+            }
+        }
+        if prev_log_index < snapshot_index {
+            // the entries compacted into the snapshot are committed, so they
+            // match the log of the leader.
+            entries.retain(|entry| entry.index > snapshot_index);
+            prev_log_index = snapshot_index;
+            prev_log_term = self.storage.term(snapshot_index).unwrap();
+        }
+        let term = self.storage.term(prev_log_index).unwrap();
+        if term != prev_log_term {
+            let mut index = prev_log_index;
+            while index > self.storage.first_index() && self.storage.term(index - 1) == Some(term) {
+                index -= 1;
+            }
+            reply.conflict_index = index;
+            reply.conflict_term = term;
+            return reply;
+        }
+
+        let last_new_index = prev_log_index + entries.len() as u64;
+        let conflict = entries
+            .iter()
+            .position(|entry| self.storage.term(entry.index) != Some(entry.term));
+        if let Some(i) = conflict {
+            let index = entries[i].index;
+            if index <= last_index {
+                assert!(
+                    index > self.commit_index,
+                    "{} truncating committed entry {}",
+                    self.me,
+                    index
+                );
+                self.truncate(index);
+            }
+            self.append(entries.split_off(i));
+        }
+        if args.leader_commit > self.commit_index {
+            self.commit_to(args.leader_commit.min(last_new_index));
+        }
+        reply.success = true;
+        reply
+    }
+
+    fn handle_append_entries_reply(
+        &mut self,
+        from: u64,
+        args: AppendEntriesArgs,
+        heartbeat: bool,
+        sent: Instant,
+        reply: AppendEntriesReply,
+    ) {
+        if reply.term > self.term {
+            self.become_follower(reply.term);
+            return;
+        }
+        if self.role != Role::Leader || args.term != self.term {
+            return;
+        }
+        self.acknowledge(from, sent);
+        if heartbeat {
+            // a heartbeat doesn't tell where the logs match.
+            return;
+        }
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            // the peer was removed meanwhile.
+            None => return,
+        };
+        let last_index = args.prev_log_index + args.entries.len() as u64;
+        progress.inflight.retain(|&(_, last)| last != last_index);
+        if reply.success {
+            progress.match_index = progress.match_index.max(last_index);
+            progress.next_index = progress.next_index.max(progress.match_index + 1);
+            progress.probing = false;
+            let match_index = progress.match_index;
+            progress.inflight.retain(|&(_, last)| last > match_index);
+            self.advance_commit();
+            self.maybe_send_timeout_now();
+        } else if args.prev_log_index >= progress.match_index {
+            // the appends in flight after this one are rejected too.
+            let next_index = self.next_index_after_conflict(&reply);
+            self.progress.get_mut(&from).unwrap().probe(next_index);
+        }
+        self.send_append(from);
+    }
+
+    // Records that a peer replied to an RPC of this leader sent at sent.
+    fn acknowledge(&mut self, from: u64, sent: Instant) {
+        self.active.insert(from);
+        if let Some(progress) = self.progress.get_mut(&from) {
+            progress.acked = progress.acked.max(Some(sent));
+            self.resolve_reads();
+        }
+    }
+
+    fn handle_install_snapshot(&mut self, args: InstallSnapshotArgs) -> InstallSnapshotReply {
+        if args.term < self.term {
+            return InstallSnapshotReply {
+                term: self.term,
+                next_offset: 0,
+            };
+        }
+        self.follow(args.term, args.leader_id);
+        let (next_offset, snapshot) = self.incoming.receive(args);
+        if let Some(snapshot) = snapshot {
+            self.install_snapshot(snapshot);
+        }
+        InstallSnapshotReply {
+            term: self.term,
+            next_offset,
+        }
+    }
+
+    // Replaces the log up to the snapshot of the leader, unless its entries
+    // are committed here already, and sends it to the service to install.
+    fn install_snapshot(&mut self, snapshot: Snapshot) {
+        if snapshot.index <= self.commit_index {
+            return;
+        }
+        info!(
+            "{} installs the snapshot at index {} of term {}",
+            self.me, snapshot.index, snapshot.term
+        );
+        let conf_state = match snapshot.conf_state {
+            Some(conf_state) => conf_state,
+            None => self.base_conf(),
+        };
+        must(self.storage.save_snapshot(
+            snapshot.index,
+            snapshot.term,
+            conf_state,
+            snapshot.data.clone(),
+        ));
+        self.load_conf();
+        self.commit_index = snapshot.index;
+        self.pending_snapshot = Some((snapshot.index, snapshot.term));
+        self.events.send(Event::SnapshotInstalled {
+            index: snapshot.index,
+            term: snapshot.term,
+        });
+        let _ = self.apply_ch.unbounded_send(ApplyMsg::Snapshot {
+            index: snapshot.index,
+            term: snapshot.term,
+            data: snapshot.data,
+        });
+    }
+
+    fn handle_install_snapshot_reply(
+        &mut self,
+        from: u64,
+        args: InstallSnapshotArgs,
+        sent: Instant,
+        reply: InstallSnapshotReply,
+    ) {
+        if reply.term > self.term {
+            self.become_follower(reply.term);
+            return;
+        }
+        if self.role != Role::Leader || args.term != self.term {
+            return;
+        }
+        self.acknowledge(from, sent);
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        let index = args.last_included_index;
+        progress.inflight.retain(|&(_, last)| last != index);
+        if reply.next_offset < args.size {
+            progress.snapshot = Some((index, reply.next_offset));
         } else {
-            Err(Error::NotLeader)
+            // the follower holds the snapshot, or committed its entries.
+            progress.snapshot = None;
+            progress.match_index = progress.match_index.max(index);
+            progress.next_index = progress.next_index.max(progress.match_index + 1);
+            progress.probing = false;
+            self.advance_commit();
+            self.maybe_send_timeout_now();
+        }
+        self.send_append(from);
+    }
+
+    // Where to resume replicating to a follower which rejected entries, from
+    // the conflicting term it replied with.
+    fn next_index_after_conflict(&self, reply: &AppendEntriesReply) -> u64 {
+        if reply.conflict_term != 0 {
+            // skip to the last entry of that term in this log, if any.
+            let mut index = self.storage.last_index();
+            while index >= self.storage.first_index() {
+                match self.storage.term(index) {
+                    Some(term) if term == reply.conflict_term => return index + 1,
+                    Some(term) if term < reply.conflict_term => break,
+                    _ => index -= 1,
+                }
+            }
+        }
+        reply.conflict_index
+    }
+
+    // Commits the last entry of the current term replicated on a majority of
+    // the voters.
+    fn advance_commit(&mut self) {
+        let mut matched: Vec<u64> = self
+            .conf
+            .voters
+            .iter()
+            .map(|id| match self.progress.get(id) {
+                Some(progress) => progress.match_index,
+                None => self.storage.last_index(),
+            })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.conf.voters.len() / 2];
+        if index > self.commit_index && self.storage.term(index) == Some(self.term) {
+            self.commit_to(index);
+        }
+        if self.commit_index >= self.conf_index && !self.is_voter(self.id()) {
+            info!("{} steps down, it was removed", self.me);
+            self.leader = None;
+            self.become_follower(self.term);
+        }
+    }
+
+    fn commit_to(&mut self, index: u64) {
+        if index <= self.commit_index {
+            return;
+        }
+        self.commit_index = index;
+        self.events.send(Event::CommitAdvanced {
+            commit_index: index,
+        });
+        self.apply();
+        if self.role == Role::Leader {
+            self.resolve_reads();
+        }
+    }
+
+    fn read_index(&mut self) -> Result<oneshot::Receiver<Result<u64>>> {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader);
+        }
+        let (tx, rx) = oneshot::channel();
+        let now = Instant::now();
+        if let ReadMode::Lease { max_clock_drift } = self.config.read_mode {
+            // while transferring its leadership, the leader may no longer
+            // be the only one.
+            let in_lease = match self.quorum_acked() {
+                Some(acked) => {
+                    acked > self.timeout_now_sent
+                        && acked + ELECTION_TIMEOUT_MIN > now + max_clock_drift
+                }
+                None => false,
+            };
+            if in_lease && self.transfer.is_none() && self.committed_in_term() {
+                let _ = tx.send(Ok(self.commit_index));
+                return Ok(rx);
+            }
+        }
+        self.reads.push(Read {
+            since: now,
+            done: tx,
+        });
+        self.broadcast_heartbeats();
+        self.resolve_reads();
+        Ok(rx)
+    }
+
+    // Whether an entry of the current term was committed, after which the
+    // commit index of the leader covers every committed entry.
+    fn committed_in_term(&self) -> bool {
+        self.storage.term(self.commit_index) == Some(self.term)
+    }
+
+    // The latest time before which a majority of the voters acknowledged
+    // this leader.
+    fn quorum_acked(&self) -> Option<Instant> {
+        let now = Instant::now();
+        let mut acked: Vec<Option<Instant>> = self
+            .conf
+            .voters
+            .iter()
+            .map(|id| match self.progress.get(id) {
+                Some(progress) => progress.acked,
+                None => Some(now),
+            })
+            .collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        acked[self.conf.voters.len() / 2]
+    }
+
+    // Serves the reads confirmed by a majority since they started.
+    fn resolve_reads(&mut self) {
+        if self.reads.is_empty() || !self.committed_in_term() {
+            return;
+        }
+        let acked = match self.quorum_acked() {
+            Some(acked) => acked,
+            None => return,
+        };
+        let (confirmed, reads) = mem::take(&mut self.reads)
+            .into_iter()
+            .partition(|read: &Read| read.since <= acked);
+        self.reads = reads;
+        for read in confirmed {
+            // the reader may be gone.
+            let _ = read.done.send(Ok(self.commit_index));
+        }
+    }
+
+    // Sends the committed entries to the service, once it installed the
+    // snapshot it was sent.
+    fn apply(&mut self) {
+        if self.pending_snapshot.is_some() || self.last_applied >= self.commit_index {
+            return;
+        }
+        for entry in self
+            .storage
+            .entries(self.last_applied + 1, self.commit_index + 1)
+        {
+            self.last_applied = entry.index;
+            let msg = match conf_change(&entry) {
+                Some(change) => ApplyMsg::ConfigChange {
+                    index: entry.index,
+                    term: entry.term,
+                    change,
+                    conf_state: self.conf_at(entry.index).0,
+                },
+                None => ApplyMsg::Command {
+                    index: entry.index,
+                    term: entry.term,
+                    data: entry.data,
+                },
+            };
+            let _ = self.apply_ch.unbounded_send(msg);
+        }
+    }
+
+    // Whether the service installs the snapshot it was sent, which is already
+    // saved in the log, unless a newer one was received since.
+    fn cond_install_snapshot(&mut self, term: u64, index: u64) -> bool {
+        if self.pending_snapshot != Some((index, term)) {
+            return false;
+        }
+        self.pending_snapshot = None;
+        let installed = index > self.last_applied;
+        if installed {
+            self.last_applied = index;
+        }
+        self.apply();
+        installed
+    }
+
+    // Compacts the log up to index, which the service took a snapshot of.
+    fn snapshot(&mut self, index: u64, data: Vec<u8>) {
+        if index < self.storage.first_index() || index > self.last_applied {
+            return;
+        }
+        debug!("{} compacts its log up to {}", self.me, index);
+        let term = self.storage.term(index).unwrap();
+        let (conf_state, _) = self.conf_at(index);
+        must(self.storage.save_snapshot(index, term, conf_state, data));
+        self.events.send(Event::LogCompacted { index });
+    }
+
+    fn status(&self) -> Status {
+        let role = match self.role {
+            Role::Follower if self.conf.learners.contains(&self.id()) => Role::Learner,
+            role => role,
+        };
+        let progress = self
+            .progress
+            .iter()
+            .map(|(&id, progress)| {
+                let progress = Progress {
+                    match_index: progress.match_index,
+                    next_index: progress.next_index,
+                };
+                (id, progress)
+            })
+            .collect();
+        Status {
+            id: self.id(),
+            term: self.term,
+            role,
+            leader: self.leader,
+            commit_index: self.commit_index,
+            applied_index: self.last_applied,
+            last_log_index: self.storage.last_index(),
+            last_log_term: self.last_log_term(),
+            snapshot_index: self.storage.first_index() - 1,
+            progress,
+        }
+    }
+
+    fn transfer_leadership(&mut self, target: u64) -> Result<oneshot::Receiver<Result<()>>> {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader);
+        }
+        let (tx, rx) = oneshot::channel();
+        if target == self.id() {
+            let _ = tx.send(Ok(()));
+            return Ok(rx);
+        }
+        if !self.is_voter(target) {
+            let _ = tx.send(Err(Error::TransferFailed));
+            return Ok(rx);
+        }
+        // a new transfer replaces the one in progress.
+        self.finish_transfer(Err(Error::TransferFailed));
+        self.transfer = Some(Transfer {
+            target,
+            term: self.term,
+            deadline: Instant::now() + ELECTION_TIMEOUT_MAX,
+            done: tx,
+        });
+        self.send_append(target);
+        self.maybe_send_timeout_now();
+        Ok(rx)
+    }
+
+    // Tells the target of the leadership transfer to start an election once
+    // its log is up to date.
+    fn maybe_send_timeout_now(&mut self) {
+        let target = match self.transfer {
+            Some(ref transfer) if self.role == Role::Leader => transfer.target,
+            _ => return,
+        };
+        let caught_up = match self.progress.get(&target) {
+            Some(progress) => progress.match_index == self.storage.last_index(),
+            None => false,
+        };
+        if caught_up {
+            let args = TimeoutNowArgs {
+                term: self.term,
+                leader_id: self.id(),
+            };
+            self.timeout_now_sent = Instant::now();
+            self.msgs.push((target, Msg::TimeoutNow(args)));
+        }
+    }
+
+    fn finish_transfer(&mut self, result: Result<()>) {
+        if let Some(transfer) = self.transfer.take() {
+            debug!(
+                "{} transferring its leadership to {}: {:?}",
+                self.me, transfer.target, result
+            );
+            let _ = transfer.done.send(result);
+        }
+    }
+
+    fn handle_timeout_now(&mut self, args: TimeoutNowArgs) -> TimeoutNowReply {
+        if args.term > self.term {
+            self.become_follower(args.term);
+        }
+        if args.term == self.term && self.role == Role::Follower && self.is_voter(self.id()) {
+            debug!(
+                "{} times out on a transfer from {}",
+                self.me, args.leader_id
+            );
+            self.campaign(true);
+        }
+        TimeoutNowReply { term: self.term }
+    }
+
+    fn handle_timeout_now_reply(&mut self, reply: TimeoutNowReply) {
+        if reply.term > self.term {
+            self.become_follower(reply.term);
         }
     }
 }
 
-impl Raft {
-    /// Only for suppressing deadcode warnings.
-    #[doc(hidden)]
-    pub fn __suppress_deadcode(&mut self) {
-        let _ = self.start(&0);
-        let _ = self.send_request_vote(0, Default::default());
-        self.persist();
-        let _ = &self.state;
-        let _ = &self.me;
-        let _ = &self.persister;
-        let _ = &self.peers;
+fn fail_reads(reads: Vec<Read>) {
+    for read in reads {
+        let _ = read.done.send(Err(Error::NotLeader));
+    }
+}
+
+// The membership change in a CONF_CHANGE entry.
+fn conf_change(entry: &LogEntry) -> Option<ConfChange> {
+    if entry.entry_type() != EntryType::ConfChange {
+        return None;
     }
+    let change = labcodec::decode(&entry.data)
+        .unwrap_or_else(|e| panic!("invalid CONF_CHANGE entry {}: {:?}", entry.index, e));
+    Some(change)
+}
+
+fn apply_conf_change(conf: &mut ConfState, change: &ConfChange) {
+    let id = change.node_id;
+    conf.voters.retain(|&voter| voter != id);
+    conf.learners.retain(|&learner| learner != id);
+    match change.change_type() {
+        ConfChangeType::AddNode => conf.voters.push(id),
+        ConfChangeType::AddLearner => conf.learners.push(id),
+        ConfChangeType::RemoveNode => {}
+    }
+}
+
+// Raft can't go on without saving its state, since it would forget a vote it
+// granted or an entry it acknowledged.
+fn must<T>(result: io::Result<T>) -> T {
+    result.unwrap_or_else(|e| panic!("failed to save the raft state: {}", e))
 }
 
-// Choose concurrency paradigm.
-//
-// You can either drive the raft state machine by the rpc framework,
-//
-// ```rust
-// struct Node { raft: Arc<Mutex<Raft>> }
-// ```
-//
-// or spawn a new thread runs the raft state machine and communicate via
-// a channel.
-//
-// ```rust
-// struct Node { sender: Sender<Msg> }
-// ```
+// The raft state machine is driven by the rpc framework and a ticker thread,
+// which share it behind a lock. RPCs to other peers are queued while it is
+// held, and sent once it is released, each reply taking the lock again.
 #[derive(Clone)]
 pub struct Node {
-    // Your code here.
+    raft: Arc<Mutex<Raft>>,
 }
 
 impl Node {
     /// Create a new raft service.
     pub fn new(raft: Raft) -> Node {
-        // Your code here.
-        crate::your_code_here(raft)
+        let name = format!("raft-{}", raft.me);
+        let node = Node {
+            raft: Arc::new(Mutex::new(raft)),
+        };
+        let ticker = node.clone();
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || ticker.run())
+            .unwrap();
+        node
+    }
+
+    // Checks the timers of the peer every tick until it is killed.
+    fn run(&self) {
+        loop {
+            thread::sleep(TICK);
+            if self.with_raft(Raft::tick).is_err() {
+                return;
+            }
+        }
+    }
+
+    // Runs f on the raft state machine, then sends the RPCs it queued.
+    // Fails once the peer is killed.
+    fn with_raft<T, F>(&self, f: F) -> labrpc::Result<T>
+    where
+        F: FnOnce(&mut Raft) -> T,
+    {
+        let mut rf = self.raft.lock().unwrap();
+        if rf.killed {
+            return Err(labrpc::Error::Stopped);
+        }
+        let result = f(&mut rf);
+        for (to, msg) in mem::take(&mut rf.msgs) {
+            self.send(&rf.peers[to as usize], to, msg);
+        }
+        Ok(result)
+    }
+
+    fn send(&self, peer: &RaftClient, to: u64, msg: Msg) {
+        let node = self.clone();
+        match msg {
+            Msg::RequestVote(args) => {
+                let reply = peer.request_vote(&args);
+                peer.spawn(async move {
+                    if let Ok(reply) = reply.await {
+                        let _ = node.with_raft(|rf| rf.handle_request_vote_reply(to, args, reply));
+                    }
+                });
+            }
+            Msg::PreVote(args) => {
+                let reply = peer.pre_vote(&args);
+                peer.spawn(async move {
+                    if let Ok(reply) = reply.await {
+                        let _ = node.with_raft(|rf| rf.handle_pre_vote_reply(to, args, reply));
+                    }
+                });
+            }
+            Msg::AppendEntries { args, heartbeat } => {
+                let sent = Instant::now();
+                let reply = peer.append_entries(&args);
+                peer.spawn(async move {
+                    if let Ok(reply) = reply.await {
+                        let _ = node.with_raft(|rf| {
+                            rf.handle_append_entries_reply(to, args, heartbeat, sent, reply)
+                        });
+                    }
+                });
+            }
+            Msg::TimeoutNow(args) => {
+                let reply = peer.timeout_now(&args);
+                peer.spawn(async move {
+                    if let Ok(reply) = reply.await {
+                        let _ = node.with_raft(|rf| rf.handle_timeout_now_reply(reply));
+                    }
+                });
+            }
+            Msg::InstallSnapshot(args) => {
+                let sent = Instant::now();
+                let reply = peer.install_snapshot(&args);
+                peer.spawn(async move {
+                    if let Ok(reply) = reply.await {
+                        let _ = node.with_raft(|rf| {
+                            rf.handle_install_snapshot_reply(to, args, sent, reply)
+                        });
+                    }
+                });
+            }
+        }
     }
 
     /// the service using Raft (e.g. a k/v server) wants to start
     /// agreement on the next command to be appended to Raft's log. if this
-    /// server isn't the leader, returns [`Error::NotLeader`]. otherwise start
+    /// server isn't the leader, or is handing its leadership over to another
+    /// server, returns [`Error::NotLeader`]. otherwise start
     /// the agreement and return immediately. there is no guarantee that this
     /// command will ever be committed to the Raft log, since the leader
     /// may fail or lose an election. even if the Raft instance has been killed,
@@ -226,26 +1595,105 @@ impl Node {
     where
         M: labcodec::Message,
     {
-        // Your code here.
-        // Example:
-        // self.raft.start(command)
-        crate::your_code_here(command)
+        let mut buf = vec![];
+        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
+        self.with_raft(|rf| rf.start(buf))
+            .unwrap_or(Err(Error::NotLeader))
+    }
+
+    /// Proposes to change the members of the cluster, one server at a time
+    /// as described in chapter 4 of the Raft thesis. Like [`Node::start`], it
+    /// returns the index and term of the `CONF_CHANGE` entry without waiting
+    /// for it to be committed.
+    ///
+    /// Each server uses the latest members in its log, committed or not, so a
+    /// change can only be proposed once the previous one is committed, which
+    /// otherwise fails with [`Error::ConfChangeInProgress`]. A new server is
+    /// added as a learner first, which receives the log without voting nor
+    /// counting towards commitment, and promoted with `ADD_NODE` once its log
+    /// caught up, otherwise failing with [`Error::LearnerNotCaughtUp`]. A
+    /// leader removing itself steps down once the change is committed.
+    pub fn propose_conf_change(&self, change: ConfChange) -> Result<(u64, u64)> {
+        self.with_raft(|rf| rf.propose_conf_change(change))
+            .unwrap_or(Err(Error::NotLeader))
+    }
+
+    /// The members of the cluster, as of the last entry of the log of this
+    /// peer.
+    pub fn conf_state(&self) -> ConfState {
+        self.raft.lock().unwrap().conf.clone()
+    }
+
+    /// Returns an index such that a read served once the entries up to it
+    /// are applied is linearizable, without appending anything to the log
+    /// (section 6.4 of the Raft thesis).
+    ///
+    /// The leader takes its commit index, after waiting for an entry of its
+    /// term to be committed, and confirms that it is still the leader as
+    /// the [`ReadMode`] of its config says. It fails with
+    /// [`Error::NotLeader`] if this peer is not the leader, or loses its
+    /// leadership in the meantime.
+    pub async fn read_index(&self) -> Result<u64> {
+        let done = self
+            .with_raft(Raft::read_index)
+            .unwrap_or(Err(Error::NotLeader))?;
+        done.await.unwrap_or(Err(Error::NotLeader))
+    }
+
+    /// The service took a snapshot of its state with the entries up to `index`
+    /// applied. Raft discards its log up to there, and sends the snapshot to
+    /// the followers missing the discarded entries. A snapshot which isn't
+    /// past the last one, or includes entries not applied yet, is ignored.
+    pub fn snapshot(&self, index: u64, snapshot: &[u8]) {
+        let _ = self.with_raft(|rf| rf.snapshot(index, snapshot.to_vec()));
+    }
+
+    /// The service received [`ApplyMsg::Snapshot`] and asks whether to
+    /// install it. Returns true if Raft dropped its log up to
+    /// `last_included_index` in favor of the snapshot, in which case the
+    /// service replaces its state with it.
+    ///
+    /// Raft refuses a snapshot that isn't past its last applied entry, or
+    /// which a newer snapshot replaced since it was sent, after which the
+    /// service ignores it. Raft sends no command past the snapshot until this
+    /// returns, so that both agree on the state.
+    pub fn cond_install_snapshot(
+        &self,
+        last_included_term: u64,
+        last_included_index: u64,
+        _snapshot: &[u8],
+    ) -> bool {
+        // the snapshot was saved along with the log when it was received.
+        self.with_raft(|rf| rf.cond_install_snapshot(last_included_term, last_included_index))
+            .unwrap_or(false)
+    }
+
+    /// Hands the leadership of this peer over to `target`, following the
+    /// TimeoutNow mechanism of the Raft thesis (section 3.10).
+    ///
+    /// The leader stops accepting new commands, replicates its log to the
+    /// target until it is up to date, then sends it a `TimeoutNow` RPC so that
+    /// it starts an election right away instead of waiting for its election
+    /// timeout. The future resolves once the target is elected. It fails with
+    /// [`Error::NotLeader`] if this peer is not the leader, and with
+    /// [`Error::TransferFailed`] if the target is not elected within an
+    /// election timeout, after which this peer accepts commands again if it
+    /// is still the leader.
+    pub async fn transfer_leadership(&self, target: usize) -> Result<()> {
+        let done = self
+            .with_raft(|rf| rf.transfer_leadership(target as u64))
+            .unwrap_or(Err(Error::NotLeader))?;
+        done.await.unwrap_or(Err(Error::NotLeader))
     }
 
     /// The current term of this peer.
     pub fn term(&self) -> u64 {
-        // Your code here.
-        // Example:
-        // self.raft.term
-        crate::your_code_here(())
+        self.raft.lock().unwrap().term
     }
 
     /// Whether this peer believes it is the leader.
     pub fn is_leader(&self) -> bool {
-        // Your code here.
-        // Example:
-        // self.raft.leader_id == self.id
-        crate::your_code_here(())
+        self.raft.lock().unwrap().role == Role::Leader
     }
 
     /// The current state of this peer.
@@ -256,6 +1704,20 @@ impl Node {
         }
     }
 
+    /// The role of this peer, the progress of its log and, on the leader,
+    /// the progress of the logs of the other members.
+    pub fn status(&self) -> Status {
+        self.raft.lock().unwrap().status()
+    }
+
+    /// A stream of the events of this peer from now on, which ends when
+    /// the peer is killed. Events are sent to a
+    /// [`status::EventBus`](status::EventBus) as they happen.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        self.with_raft(|rf| rf.events.subscribe())
+            .unwrap_or_else(|_| unbounded().1)
+    }
+
     /// the tester calls kill() when a Raft instance won't be
     /// needed again. you are not required to do anything in
     /// kill(), but it might be convenient to (for example)
@@ -265,7 +1727,11 @@ impl Node {
     /// a VIRTUAL crash in tester, so take care of background
     /// threads you generated with this Raft Node.
     pub fn kill(&self) {
-        // Your code here, if desired.
+        let mut rf = self.raft.lock().unwrap();
+        rf.killed = true;
+        rf.finish_transfer(Err(Error::NotLeader));
+        rf.apply_ch.close_channel();
+        rf.events.close();
     }
 }
 
@@ -275,7 +1741,37 @@ impl RaftService for Node {
     //
     // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
     async fn request_vote(&self, args: RequestVoteArgs) -> labrpc::Result<RequestVoteReply> {
-        // Your code here (2A, 2B).
-        crate::your_code_here(args)
+        self.with_raft(|rf| rf.handle_request_vote(args))
+    }
+
+    // AppendEntries RPC handler, appends the entries following the leader's
+    // entry at prev_log_index, if this log holds it.
+    async fn append_entries(&self, args: AppendEntriesArgs) -> labrpc::Result<AppendEntriesReply> {
+        self.with_raft(|rf| rf.handle_append_entries(args))
+    }
+
+    // PreVote RPC handler, grants the vote if a RequestVote RPC with the same
+    // arguments would, but without updating the term or the vote of this
+    // peer. a peer which has heard from a leader within an election timeout
+    // doesn't grant it.
+    async fn pre_vote(&self, args: RequestVoteArgs) -> labrpc::Result<RequestVoteReply> {
+        self.with_raft(|rf| rf.handle_pre_vote(args))
+    }
+
+    // InstallSnapshot RPC handler, adds the chunk to the snapshot::
+    // IncomingSnapshot being received. once complete and valid, a snapshot
+    // past the commit index replaces the log, and is sent to the service as
+    // an ApplyMsg::Snapshot.
+    async fn install_snapshot(
+        &self,
+        args: InstallSnapshotArgs,
+    ) -> labrpc::Result<InstallSnapshotReply> {
+        self.with_raft(|rf| rf.handle_install_snapshot(args))
+    }
+
+    // TimeoutNow RPC handler, the target of a leadership transfer starts an
+    // election without waiting for its election timeout.
+    async fn timeout_now(&self, args: TimeoutNowArgs) -> labrpc::Result<TimeoutNowReply> {
+        self.with_raft(|rf| rf.handle_timeout_now(args))
     }
 }
@@ -5,6 +5,9 @@
 //! so, while you can modify this code to help you debug, please
 //! test with the original before submitting.
 
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 pub trait Persister: Send + 'static {
@@ -79,6 +82,178 @@ impl Persister for SimplePersister {
     }
 }
 
+const RAFT_STATE_FILE: &str = "raft_state";
+const SNAPSHOT_PREFIX: &str = "snapshot.";
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// A persister saving raft state and snapshot in a directory, so that they
+/// survive a restart of the process.
+///
+/// Every file is written to a temporary file, synced and renamed over the old
+/// one. The raft state file starts with the generation of the snapshot it goes
+/// with, and `save_state_and_snapshot` writes the new snapshot under a new
+/// generation before the raft state, whose rename commits both. A crash in
+/// between leaves the old pair, and the orphaned snapshot is removed on the
+/// next start.
+///
+/// The methods of `Persister` can't report errors, and Raft must not go on
+/// if its state is not saved, so an I/O error panics.
+pub struct FilePersister {
+    dir: PathBuf,
+    states: Mutex<FileStates>,
+}
+
+#[derive(Default)]
+struct FileStates {
+    raft_state: Vec<u8>,
+    snapshot: Vec<u8>,
+    // generation of the snapshot file, 0 if there is none
+    generation: u64,
+}
+
+impl FilePersister {
+    /// Opens the persister in the given directory, creating it if needed, and
+    /// loads the raft state and snapshot saved in it.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FilePersister> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut states = FileStates::default();
+        match fs::read(dir.join(RAFT_STATE_FILE)) {
+            Ok(data) => {
+                if data.len() < 8 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated raft state file",
+                    ));
+                }
+                let mut generation = [0; 8];
+                generation.copy_from_slice(&data[..8]);
+                states.generation = u64::from_le_bytes(generation);
+                states.raft_state = data[8..].to_vec();
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if states.generation > 0 {
+            states.snapshot = fs::read(dir.join(snapshot_file(states.generation)))?;
+        }
+        // remove what a crash left behind, leaving the files of others alone
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if is_orphaned(&name, states.generation) {
+                fs::remove_file(dir.join(&*name))?;
+            }
+        }
+        Ok(FilePersister {
+            dir,
+            states: Mutex::new(states),
+        })
+    }
+
+    /// The directory holding the files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn write_raft_state(&self, generation: u64, state: &[u8]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(8 + state.len());
+        data.extend_from_slice(&generation.to_le_bytes());
+        data.extend_from_slice(state);
+        write_atomically(&self.dir, RAFT_STATE_FILE, &data)
+    }
+
+    fn save(&self, state: Vec<u8>, snapshot: Option<Vec<u8>>) -> io::Result<()> {
+        let mut states = self.states.lock().unwrap();
+        let old_generation = states.generation;
+        let generation = match snapshot {
+            Some(ref snapshot) => {
+                let generation = old_generation + 1;
+                write_atomically(&self.dir, &snapshot_file(generation), snapshot)?;
+                generation
+            }
+            None => old_generation,
+        };
+        self.write_raft_state(generation, &state)?;
+        // the new pair is committed, and a leftover snapshot is removed on the
+        // next start
+        if generation != old_generation && old_generation > 0 {
+            let path = self.dir.join(snapshot_file(old_generation));
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("failed to remove the superseded snapshot {:?}: {}", path, e);
+            }
+        }
+        states.raft_state = state;
+        states.generation = generation;
+        if let Some(snapshot) = snapshot {
+            states.snapshot = snapshot;
+        }
+        Ok(())
+    }
+}
+
+impl Persister for FilePersister {
+    fn raft_state(&self) -> Vec<u8> {
+        self.states.lock().unwrap().raft_state.clone()
+    }
+
+    fn save_raft_state(&self, state: Vec<u8>) {
+        if let Err(e) = self.save(state, None) {
+            panic!("failed to save raft state in {:?}: {}", self.dir, e);
+        }
+    }
+
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+        if let Err(e) = self.save(state, Some(snapshot)) {
+            panic!(
+                "failed to save raft state and snapshot in {:?}: {}",
+                self.dir, e
+            );
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.states.lock().unwrap().snapshot.clone()
+    }
+}
+
+fn snapshot_file(generation: u64) -> String {
+    format!("{}{}", SNAPSHOT_PREFIX, generation)
+}
+
+/// Whether `name` is a temporary file or a snapshot other than the one of
+/// `generation`, written by a `FilePersister`.
+fn is_orphaned(name: &str, generation: u64) -> bool {
+    if name == format!("{}{}", RAFT_STATE_FILE, TEMP_SUFFIX) {
+        return true;
+    }
+    let rest = match name.strip_prefix(SNAPSHOT_PREFIX) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    match rest.strip_suffix(TEMP_SUFFIX) {
+        Some(rest) => rest.parse::<u64>().is_ok(),
+        None => matches!(rest.parse::<u64>(), Ok(g) if g != generation),
+    }
+}
+
+/// Replaces `dir/name` with `data`, leaving either the old or the new content
+/// after a crash.
+pub(super) fn write_atomically(dir: &Path, name: &str, data: &[u8]) -> io::Result<()> {
+    let path = dir.join(name);
+    let temp_path = dir.join(format!("{}{}", name, TEMP_SUFFIX));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)?;
+    // make the rename itself durable
+    File::open(dir)?.sync_all()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,4 +281,66 @@ mod tests {
         let obj: Arc<dyn Persister + Sync> = Arc::new(sp);
         let _box_obj: Box<dyn Persister> = Box::new(obj);
     }
+
+    #[test]
+    fn test_file_persister_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = FilePersister::new(dir.path()).unwrap();
+        assert!(fp.raft_state().is_empty());
+        assert!(fp.snapshot().is_empty());
+
+        fp.save_raft_state(vec![111]);
+        let fp = FilePersister::new(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![111]);
+        assert!(fp.snapshot().is_empty());
+
+        fp.save_state_and_snapshot(vec![222], vec![123]);
+        fp.save_raft_state(vec![233]);
+        let fp = FilePersister::new(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![233]);
+        assert_eq!(fp.snapshot(), vec![123]);
+
+        fp.save_state_and_snapshot(vec![244], vec![124, 125]);
+        let obj: Box<dyn Persister> = Box::new(FilePersister::new(dir.path()).unwrap());
+        assert_eq!(obj.raft_state(), vec![244]);
+        assert_eq!(obj.snapshot(), vec![124, 125]);
+        // only the latest snapshot is kept
+        let mut files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["raft_state", "snapshot.2"]);
+    }
+
+    #[test]
+    fn test_file_persister_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = FilePersister::new(dir.path()).unwrap();
+        fp.save_state_and_snapshot(vec![111], vec![1]);
+
+        // crash after writing the next snapshot, before the raft state
+        fs::write(dir.path().join("snapshot.2"), vec![2]).unwrap();
+        fs::write(dir.path().join("raft_state.tmp"), vec![0; 3]).unwrap();
+        fs::write(dir.path().join("snapshot.3.tmp"), vec![3]).unwrap();
+        // files the persister did not write
+        fs::write(dir.path().join("notes.tmp"), vec![4]).unwrap();
+        fs::write(dir.path().join("snapshot.old"), vec![5]).unwrap();
+        let fp = FilePersister::new(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![111]);
+        assert_eq!(fp.snapshot(), vec![1]);
+        assert!(!dir.path().join("snapshot.2").exists());
+        assert!(!dir.path().join("raft_state.tmp").exists());
+        assert!(!dir.path().join("snapshot.3.tmp").exists());
+        assert!(dir.path().join("notes.tmp").exists());
+        assert!(dir.path().join("snapshot.old").exists());
+
+        fp.save_state_and_snapshot(vec![222], vec![2]);
+        let fp = FilePersister::new(dir.path()).unwrap();
+        assert_eq!(fp.raft_state(), vec![222]);
+        assert_eq!(fp.snapshot(), vec![2]);
+
+        fs::write(dir.path().join("raft_state"), vec![1, 2]).unwrap();
+        assert!(FilePersister::new(dir.path()).is_err());
+    }
 }
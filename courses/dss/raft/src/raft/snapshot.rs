@@ -0,0 +1,299 @@
+//! Snapshots sent to lagging followers in chunks.
+//!
+//! A snapshot may not fit in one RPC, so the leader sends it with
+//! `install_snapshot` RPCs carrying the chunk at a byte offset. The follower
+//! appends the chunks in order and replies with the offset it expects next,
+//! from which the leader resumes after a lost chunk or reply. Once the last
+//! chunk is received, the size and CRC-32 of the whole snapshot are checked
+//! before it is handed to Raft.
+//!
+//! The leader reads each chunk from its `LogStorage` rather than keeping a
+//! copy of the snapshot while sending it. The follower assembles the chunks in
+//! memory, as the snapshot is handed whole to the service in
+//! `ApplyMsg::Snapshot`, so it holds one copy of the snapshot being received.
+
+use super::storage::{crc32, crc32_update, LogStorage};
+use crate::proto::raftpb::{ConfState, InstallSnapshotArgs};
+
+/// A complete snapshot, replacing the log up to `index`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    /// The members of the cluster as of `index`.
+    pub conf_state: Option<ConfState>,
+    pub data: Vec<u8>,
+}
+
+/// The snapshot in the storage of the leader, split into chunks of at most
+/// `chunk_size` bytes.
+pub struct OutgoingSnapshot {
+    index: u64,
+    term: u64,
+    conf_state: Option<ConfState>,
+    size: u64,
+    checksum: u32,
+    chunk_size: usize,
+}
+
+impl OutgoingSnapshot {
+    /// Starts sending the snapshot of `storage`, which replaces the log up to
+    /// `index`. The snapshot must not change while it is sent.
+    pub fn new<S: LogStorage + ?Sized>(
+        storage: &S,
+        index: u64,
+        term: u64,
+        conf_state: Option<ConfState>,
+        chunk_size: usize,
+    ) -> OutgoingSnapshot {
+        assert!(chunk_size > 0, "empty chunks");
+        let size = storage.snapshot_size();
+        let mut checksum = 0;
+        let mut offset = 0;
+        while offset < size {
+            let chunk = storage.snapshot_chunk(offset, chunk_size);
+            checksum = crc32_update(checksum, &chunk);
+            offset += chunk.len() as u64;
+        }
+        OutgoingSnapshot {
+            index,
+            term,
+            conf_state,
+            size,
+            checksum,
+            chunk_size,
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// The arguments of an `install_snapshot` RPC sending the chunk at
+    /// `offset`, which a follower replied it expects next, read from the
+    /// storage the snapshot was created from.
+    pub fn chunk<S: LogStorage + ?Sized>(
+        &self,
+        storage: &S,
+        term: u64,
+        leader_id: u64,
+        offset: u64,
+    ) -> InstallSnapshotArgs {
+        let start = offset.min(self.size);
+        let data = storage.snapshot_chunk(start, self.chunk_size);
+        let end = start + data.len() as u64;
+        InstallSnapshotArgs {
+            term,
+            leader_id,
+            last_included_index: self.index,
+            last_included_term: self.term,
+            conf_state: self.conf_state.clone(),
+            size: self.size,
+            checksum: self.checksum,
+            offset: start,
+            data,
+            done: end == self.size,
+        }
+    }
+}
+
+/// The chunks of a snapshot received by a follower.
+#[derive(Default)]
+pub struct IncomingSnapshot {
+    // the first chunk of the snapshot being received, without its data.
+    header: Option<InstallSnapshotArgs>,
+    data: Vec<u8>,
+}
+
+impl IncomingSnapshot {
+    pub fn new() -> IncomingSnapshot {
+        IncomingSnapshot::default()
+    }
+
+    /// Adds a chunk, and returns the offset of the chunk expected next, along
+    /// with the snapshot if it is the last one.
+    ///
+    /// A chunk of another snapshot than the one being received starts over
+    /// from it if it is the first chunk, otherwise the first chunk is asked
+    /// for. Chunks at other offsets than the expected one are ignored. A
+    /// snapshot whose size or checksum doesn't match is discarded, and asked
+    /// for again from the start.
+    pub fn receive(&mut self, mut args: InstallSnapshotArgs) -> (u64, Option<Snapshot>) {
+        if !self.is_receiving(&args) {
+            self.header = None;
+            self.data.clear();
+            if args.offset != 0 {
+                return (0, None);
+            }
+        }
+        if args.offset != self.data.len() as u64 {
+            return (self.data.len() as u64, None);
+        }
+        self.data.append(&mut args.data);
+        let done = args.done;
+        if self.header.is_none() {
+            self.header = Some(args);
+        }
+        let header = self.header.as_ref().unwrap();
+        if (self.data.len() as u64) < header.size && !done {
+            return (self.data.len() as u64, None);
+        }
+
+        let header = self.header.take().unwrap();
+        let data = std::mem::take(&mut self.data);
+        if !done || data.len() as u64 != header.size || crc32(&data) != header.checksum {
+            warn!(
+                "discarding snapshot at index {}: {} bytes, expected {}",
+                header.last_included_index,
+                data.len(),
+                header.size
+            );
+            return (0, None);
+        }
+        let snapshot = Snapshot {
+            index: header.last_included_index,
+            term: header.last_included_term,
+            conf_state: header.conf_state,
+            data,
+        };
+        (header.size, Some(snapshot))
+    }
+
+    // whether the chunk belongs to the snapshot being received.
+    fn is_receiving(&self, args: &InstallSnapshotArgs) -> bool {
+        match self.header {
+            Some(ref header) => {
+                header.last_included_index == args.last_included_index
+                    && header.last_included_term == args.last_included_term
+                    && header.size == args.size
+                    && header.checksum == args.checksum
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::storage::MemStorage;
+
+    // A leader sending a snapshot from its storage.
+    struct Sender {
+        storage: MemStorage,
+        outgoing: OutgoingSnapshot,
+    }
+
+    impl Sender {
+        fn new(snapshot: Snapshot, chunk_size: usize) -> Sender {
+            let mut storage = MemStorage::new();
+            let conf_state = snapshot.conf_state.clone().unwrap();
+            storage
+                .save_snapshot(snapshot.index, snapshot.term, conf_state, snapshot.data)
+                .unwrap();
+            let outgoing = OutgoingSnapshot::new(
+                &storage,
+                snapshot.index,
+                snapshot.term,
+                snapshot.conf_state,
+                chunk_size,
+            );
+            Sender { storage, outgoing }
+        }
+
+        fn chunk(&self, term: u64, leader_id: u64, offset: u64) -> InstallSnapshotArgs {
+            self.outgoing.chunk(&self.storage, term, leader_id, offset)
+        }
+    }
+
+    fn snapshot(index: u64, len: usize) -> Snapshot {
+        Snapshot {
+            index,
+            term: 2,
+            conf_state: Some(ConfState {
+                voters: vec![0, 1, 2],
+                learners: vec![],
+            }),
+            data: (0..len).map(|i| (i * 7) as u8).collect(),
+        }
+    }
+
+    // Sends the whole snapshot, returning it as the follower assembled it.
+    fn send(outgoing: &Sender, incoming: &mut IncomingSnapshot) -> Snapshot {
+        let mut offset = 0;
+        for _ in 0..1000 {
+            match incoming.receive(outgoing.chunk(1, 0, offset)) {
+                (_, Some(snapshot)) => return snapshot,
+                (next, None) => offset = next,
+            }
+        }
+        panic!("the snapshot is never received");
+    }
+
+    #[test]
+    fn test_chunks() {
+        for &(len, chunk_size) in &[(0, 4), (3, 4), (16, 4), (1000, 64)] {
+            let outgoing = Sender::new(snapshot(10, len), chunk_size);
+            let mut incoming = IncomingSnapshot::new();
+            assert_eq!(send(&outgoing, &mut incoming), snapshot(10, len));
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        let outgoing = Sender::new(snapshot(10, 100), 10);
+        let mut incoming = IncomingSnapshot::new();
+        assert_eq!(incoming.receive(outgoing.chunk(1, 0, 0)), (10, None));
+        // a lost chunk, the next one is ignored.
+        assert_eq!(incoming.receive(outgoing.chunk(1, 0, 20)), (10, None));
+        // a duplicate is ignored too.
+        assert_eq!(incoming.receive(outgoing.chunk(1, 0, 0)), (10, None));
+        assert_eq!(send(&outgoing, &mut incoming), snapshot(10, 100));
+
+        // a new leader resumes the same snapshot.
+        let mut incoming = IncomingSnapshot::new();
+        incoming.receive(outgoing.chunk(1, 0, 0));
+        incoming.receive(outgoing.chunk(1, 0, 10));
+        assert_eq!(incoming.receive(outgoing.chunk(2, 1, 20)), (30, None));
+    }
+
+    #[test]
+    fn test_other_snapshot() {
+        let old = Sender::new(snapshot(10, 100), 10);
+        let new = Sender::new(snapshot(20, 100), 10);
+        let mut incoming = IncomingSnapshot::new();
+        incoming.receive(old.chunk(1, 0, 0));
+        incoming.receive(old.chunk(1, 0, 10));
+        // a chunk of another snapshot in the middle asks for its start.
+        assert_eq!(incoming.receive(new.chunk(1, 0, 20)), (0, None));
+        assert_eq!(send(&new, &mut incoming), snapshot(20, 100));
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let outgoing = Sender::new(snapshot(10, 100), 10);
+        let mut incoming = IncomingSnapshot::new();
+        let mut offset = 0;
+        loop {
+            let mut args = outgoing.chunk(1, 0, offset);
+            if offset == 50 {
+                args.data[3] ^= 1;
+            }
+            let (next, snapshot) = incoming.receive(args);
+            assert!(snapshot.is_none(), "a corrupted snapshot is received");
+            if next == 0 {
+                break;
+            }
+            offset = next;
+        }
+        // it is sent again from the start.
+        assert_eq!(send(&outgoing, &mut incoming), snapshot(10, 100));
+
+        // a snapshot longer than its size is discarded too.
+        let mut args = outgoing.chunk(1, 0, 0);
+        args.size = 5;
+        args.done = true;
+        let mut incoming = IncomingSnapshot::new();
+        assert_eq!(incoming.receive(args), (0, None));
+    }
+}
@@ -0,0 +1,153 @@
+//! What a Raft peer reports about itself, for health checks and debugging.
+//!
+//! `Node::status` is a snapshot of the state of a peer. `Node::subscribe`
+//! returns a stream of the events changing it, in the order they happen, so
+//! that a test can tell why a peer became leader or lost entries without
+//! going through the logs.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    /// Asking for pre-votes, before starting an election.
+    PreCandidate,
+    Candidate,
+    Leader,
+    /// A member which doesn't vote.
+    Learner,
+}
+
+/// How far the log of a follower matches the log of the leader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// The last entry known to be replicated on the follower.
+    pub match_index: u64,
+    /// The next entry to send to the follower.
+    pub next_index: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub id: u64,
+    pub term: u64,
+    pub role: Role,
+    /// The leader of `term`, if known.
+    pub leader: Option<u64>,
+    pub commit_index: u64,
+    /// The last entry sent to the service.
+    pub applied_index: u64,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+    /// The last entry compacted into the snapshot.
+    pub snapshot_index: u64,
+    /// The progress of every other member, on the leader only.
+    pub progress: HashMap<u64, Progress>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    RoleChanged {
+        term: u64,
+        from: Role,
+        to: Role,
+    },
+    /// The peer started an election, or asked for pre-votes.
+    ElectionStarted {
+        term: u64,
+        pre_vote: bool,
+    },
+    /// A vote was granted to the peer, or refused.
+    VoteReceived {
+        term: u64,
+        from: u64,
+        granted: bool,
+    },
+    /// The peer learned the leader of `term`.
+    LeaderChanged {
+        term: u64,
+        leader: u64,
+    },
+    /// The entries from `index` to `last_index`, conflicting with the leader,
+    /// were removed.
+    LogTruncated {
+        index: u64,
+        last_index: u64,
+    },
+    CommitAdvanced {
+        commit_index: u64,
+    },
+    /// A snapshot from the leader replaced the log up to `index`.
+    SnapshotInstalled {
+        index: u64,
+        term: u64,
+    },
+    /// The log was compacted up to `index` into a snapshot of the service.
+    LogCompacted {
+        index: u64,
+    },
+}
+
+/// Sends events to every subscriber, forgetting those which are dropped.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<UnboundedSender<Event>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// A stream of the events sent from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn send(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Ends the streams of the subscribers.
+    pub fn close(&self) {
+        self.subscribers.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::new();
+        bus.send(Event::LogCompacted { index: 1 });
+        let events1 = bus.subscribe();
+        let events2 = bus.subscribe();
+        let event = Event::ElectionStarted {
+            term: 2,
+            pre_vote: false,
+        };
+        bus.send(event.clone());
+
+        // a dropped subscriber is forgotten.
+        drop(events1);
+        bus.send(Event::CommitAdvanced { commit_index: 3 });
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        drop(bus);
+        let events: Vec<_> = block_on(events2.collect());
+        assert_eq!(
+            events,
+            vec![event, Event::CommitAdvanced { commit_index: 3 }]
+        );
+    }
+}
@@ -0,0 +1,991 @@
+//! Append-oriented storage of the Raft log.
+//!
+//! `Persister::save_raft_state` takes the whole encoded state, so every change
+//! to the log rewrites all of it. A `LogStorage` is told what changed instead:
+//! entries appended at the end, a conflicting suffix truncated, a prefix
+//! compacted into a snapshot, or a new term and vote. Each change is durable
+//! once the method returns.
+//!
+//! The snapshot the log is compacted into is saved along with it, so that Raft
+//! can send it to a peer lagging behind.
+//!
+//! `MemStorage` keeps the log in memory only, `FileLogStorage` in a directory,
+//! and `PersisterLogStorage` on top of a `Persister`, so that the tester can
+//! still crash and restart peers by copying their persister.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::persister::{write_atomically, Persister};
+use crate::proto::raftpb::{ConfState, LogEntry};
+
+/// The term and vote a peer must save before answering an RPC.
+#[derive(Clone, PartialEq, Message)]
+pub struct HardState {
+    #[prost(uint64, tag = "1")]
+    pub term: u64,
+    /// The peer voted for in `term`, if any.
+    #[prost(uint64, optional, tag = "2")]
+    pub voted_for: Option<u64>,
+    /// The members of the cluster as of the last compacted entry, the later
+    /// changes being in the log. It is saved before compacting the log, and
+    /// `None` until the first compaction.
+    #[prost(message, optional, tag = "3")]
+    pub conf_state: Option<ConfState>,
+}
+
+/// Durable storage of the Raft log and hard state.
+///
+/// The log holds the entries from `first_index` to `last_index`. The entries
+/// before are compacted into a snapshot, of which only the index and term of
+/// the last entry are kept. Before anything is compacted, the snapshot is at
+/// index and term 0.
+pub trait LogStorage: Send + 'static {
+    /// The hard state last saved.
+    fn hard_state(&self) -> HardState;
+
+    /// Saves the term and vote.
+    fn save_hard_state(&mut self, state: HardState) -> io::Result<()>;
+
+    /// The index of the first entry in the log, one past the snapshot.
+    fn first_index(&self) -> u64;
+
+    /// The index of the last entry in the log, or of the snapshot if the log is
+    /// empty.
+    fn last_index(&self) -> u64;
+
+    /// The term of the entry at `index`, also known for the last entry of the
+    /// snapshot. Returns `None` for other compacted entries and past the end.
+    fn term(&self, index: u64) -> Option<u64>;
+
+    /// The entries from `lo` to `hi`, exclusive, clamped to the log.
+    fn entries(&self, lo: u64, hi: u64) -> Vec<LogEntry>;
+
+    /// Appends entries at the end of the log.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `InvalidInput` unless the entries follow each other from
+    /// `last_index() + 1`.
+    fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()>;
+
+    /// Removes the entries from `index` to the end of the log.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `InvalidInput` if `index` is compacted.
+    fn truncate_suffix(&mut self, index: u64) -> io::Result<()>;
+
+    /// Discards the entries up to `index`, included, which a snapshot with the
+    /// given last term replaces. A snapshot past the end of the log empties it.
+    /// Nothing is done if `index` is already compacted.
+    fn compact_prefix(&mut self, index: u64, term: u64) -> io::Result<()>;
+
+    /// The snapshot last saved by `save_snapshot`, empty if there is none.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// The size of the snapshot in bytes.
+    fn snapshot_size(&self) -> u64 {
+        self.snapshot().len() as u64
+    }
+
+    /// The bytes of the snapshot from `offset`, at most `len` of them, so that
+    /// it can be sent in chunks without a copy of the whole. The default reads
+    /// the whole snapshot for each chunk.
+    fn snapshot_chunk(&self, offset: u64, len: usize) -> Vec<u8> {
+        chunk_of(&self.snapshot(), offset, len).to_vec()
+    }
+
+    /// Compacts the log like `compact_prefix` and saves the snapshot along
+    /// with it, and with the members as of `index`. The entries after `index`
+    /// are discarded too unless the log holds the entry at `index` in `term`.
+    fn save_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        conf_state: ConfState,
+        snapshot: Vec<u8>,
+    ) -> io::Result<()>;
+}
+
+impl<T: ?Sized + LogStorage> LogStorage for Box<T> {
+    fn hard_state(&self) -> HardState {
+        (**self).hard_state()
+    }
+    fn save_hard_state(&mut self, state: HardState) -> io::Result<()> {
+        (**self).save_hard_state(state)
+    }
+    fn first_index(&self) -> u64 {
+        (**self).first_index()
+    }
+    fn last_index(&self) -> u64 {
+        (**self).last_index()
+    }
+    fn term(&self, index: u64) -> Option<u64> {
+        (**self).term(index)
+    }
+    fn entries(&self, lo: u64, hi: u64) -> Vec<LogEntry> {
+        (**self).entries(lo, hi)
+    }
+    fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        (**self).append(entries)
+    }
+    fn truncate_suffix(&mut self, index: u64) -> io::Result<()> {
+        (**self).truncate_suffix(index)
+    }
+    fn compact_prefix(&mut self, index: u64, term: u64) -> io::Result<()> {
+        (**self).compact_prefix(index, term)
+    }
+    fn snapshot(&self) -> Vec<u8> {
+        (**self).snapshot()
+    }
+    fn snapshot_size(&self) -> u64 {
+        (**self).snapshot_size()
+    }
+    fn snapshot_chunk(&self, offset: u64, len: usize) -> Vec<u8> {
+        (**self).snapshot_chunk(offset, len)
+    }
+    fn save_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        conf_state: ConfState,
+        snapshot: Vec<u8>,
+    ) -> io::Result<()> {
+        (**self).save_snapshot(index, term, conf_state, snapshot)
+    }
+}
+
+/// A log kept in memory, lost with the process.
+#[derive(Clone, PartialEq, Message)]
+pub struct MemStorage {
+    #[prost(message, required, tag = "1")]
+    hard_state: HardState,
+    #[prost(uint64, tag = "2")]
+    snapshot_index: u64,
+    #[prost(uint64, tag = "3")]
+    snapshot_term: u64,
+    #[prost(message, repeated, tag = "4")]
+    entries: Vec<LogEntry>,
+    #[prost(bytes, tag = "5")]
+    snapshot: Vec<u8>,
+}
+
+impl MemStorage {
+    pub fn new() -> MemStorage {
+        MemStorage::default()
+    }
+
+    fn check_append(&self, entries: &[LogEntry]) -> io::Result<()> {
+        let next = self.last_index() + 1;
+        for (index, entry) in (next..).zip(entries) {
+            if entry.index != index {
+                return Err(invalid_input(format!(
+                    "entry {} appended at index {}",
+                    entry.index, index
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_truncate(&self, index: u64) -> io::Result<()> {
+        if index <= self.snapshot_index {
+            return Err(invalid_input(format!(
+                "entry {} is compacted up to {}",
+                index, self.snapshot_index
+            )));
+        }
+        Ok(())
+    }
+
+    fn offset(&self, index: u64) -> usize {
+        (index - self.first_index()) as usize
+    }
+}
+
+impl LogStorage for MemStorage {
+    fn hard_state(&self) -> HardState {
+        self.hard_state.clone()
+    }
+
+    fn save_hard_state(&mut self, state: HardState) -> io::Result<()> {
+        self.hard_state = state;
+        Ok(())
+    }
+
+    fn first_index(&self) -> u64 {
+        self.snapshot_index + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    fn term(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.first_index() || index > self.last_index() {
+            return None;
+        }
+        Some(self.entries[self.offset(index)].term)
+    }
+
+    fn entries(&self, lo: u64, hi: u64) -> Vec<LogEntry> {
+        let lo = lo.max(self.first_index());
+        let hi = hi.min(self.last_index() + 1);
+        if lo >= hi {
+            return Vec::new();
+        }
+        self.entries[self.offset(lo)..self.offset(hi)].to_vec()
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        self.check_append(&entries)?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    fn truncate_suffix(&mut self, index: u64) -> io::Result<()> {
+        self.check_truncate(index)?;
+        if index <= self.last_index() {
+            let offset = self.offset(index);
+            self.entries.truncate(offset);
+        }
+        Ok(())
+    }
+
+    fn compact_prefix(&mut self, index: u64, term: u64) -> io::Result<()> {
+        if index <= self.snapshot_index {
+            return Ok(());
+        }
+        if index >= self.last_index() {
+            self.entries.clear();
+        } else {
+            let offset = self.offset(index + 1);
+            self.entries.drain(..offset);
+        }
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.snapshot.clone()
+    }
+
+    fn snapshot_size(&self) -> u64 {
+        self.snapshot.len() as u64
+    }
+
+    fn snapshot_chunk(&self, offset: u64, len: usize) -> Vec<u8> {
+        chunk_of(&self.snapshot, offset, len).to_vec()
+    }
+
+    fn save_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        conf_state: ConfState,
+        snapshot: Vec<u8>,
+    ) -> io::Result<()> {
+        let first_index = self.first_index();
+        if index >= first_index && self.term(index) != Some(term) {
+            self.truncate_suffix(first_index)?;
+        }
+        self.compact_prefix(index, term)?;
+        self.hard_state.conf_state = Some(conf_state);
+        self.snapshot = snapshot;
+        Ok(())
+    }
+}
+
+const HARD_STATE_FILE: &str = "hard_state";
+const LOG_FILE: &str = "log";
+const SNAPSHOT_FILE: &str = "snapshot";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Enumeration)]
+enum RecordKind {
+    Append = 0,
+    Truncate = 1,
+    Compact = 2,
+}
+
+// A change of the log in the log file.
+#[derive(Clone, PartialEq, Message)]
+struct Record {
+    #[prost(enumeration = "RecordKind", tag = "1")]
+    kind: i32,
+    // the first truncated index, or the last compacted one
+    #[prost(uint64, tag = "2")]
+    index: u64,
+    // the term of the last compacted entry
+    #[prost(uint64, tag = "3")]
+    term: u64,
+    #[prost(message, repeated, tag = "4")]
+    entries: Vec<LogEntry>,
+}
+
+// The snapshot last saved in the snapshot file, with what is needed to compact
+// the log into it again if a crash came first.
+#[derive(Clone, PartialEq, Message)]
+struct SnapshotRecord {
+    #[prost(uint64, tag = "1")]
+    index: u64,
+    #[prost(uint64, tag = "2")]
+    term: u64,
+    #[prost(message, required, tag = "3")]
+    conf_state: ConfState,
+    #[prost(bytes, tag = "4")]
+    data: Vec<u8>,
+}
+
+/// A log saved in a directory.
+///
+/// Changes are appended to a log file as records, each framed by its length and
+/// a CRC-32 and synced before the method returns. A record torn by a crash is
+/// dropped on the next start. A record which fails to be written is cut off the
+/// file right away, so that the records written after it are not dropped with
+/// it, and no change is accepted until that succeeds. Compaction rewrites the
+/// log file with the remaining entries. The hard state is written to its own file atomically.
+/// A snapshot is written to its own file too, before the log is compacted into
+/// it, and the compaction is done again on the next start if a crash came in
+/// between.
+///
+/// The entries are also kept in memory to answer reads.
+pub struct FileLogStorage {
+    dir: PathBuf,
+    log: File,
+    // the length of the records written to `log`
+    len: u64,
+    // whether a failed write may have left part of a record after `len`
+    failed: bool,
+    mem: MemStorage,
+}
+
+impl FileLogStorage {
+    /// Opens the storage in the given directory, creating it if needed, and
+    /// loads the log and hard state saved in it.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FileLogStorage> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut mem = MemStorage::new();
+        match fs::read(dir.join(HARD_STATE_FILE)) {
+            Ok(data) => mem.hard_state = labcodec::decode(&data).map_err(invalid_data)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let log_path = dir.join(LOG_FILE);
+        let data = match fs::read(&log_path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut pos = 0;
+        while let Some((record, len)) = read_record(&data[pos..]) {
+            match RecordKind::from_i32(record.kind) {
+                Some(RecordKind::Append) => mem.append(record.entries)?,
+                Some(RecordKind::Truncate) => mem.truncate_suffix(record.index)?,
+                Some(RecordKind::Compact) => mem.compact_prefix(record.index, record.term)?,
+                None => return Err(invalid_data("unknown record kind")),
+            }
+            pos += len;
+        }
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        if pos < data.len() {
+            warn!(
+                "dropping {} bytes torn from the end of {:?}",
+                data.len() - pos,
+                log_path
+            );
+            log.set_len(pos as u64)?;
+            log.sync_all()?;
+        }
+        let mut storage = FileLogStorage {
+            dir,
+            log,
+            len: pos as u64,
+            failed: false,
+            mem,
+        };
+        match fs::read(storage.dir.join(SNAPSHOT_FILE)) {
+            Ok(data) => {
+                let record: SnapshotRecord = labcodec::decode(&data).map_err(invalid_data)?;
+                if record.index > storage.mem.snapshot_index {
+                    storage.compact_into(record)?;
+                } else {
+                    storage.mem.snapshot = record.data;
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(storage)
+    }
+
+    /// The directory holding the files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        let mut data = Vec::new();
+        encode_record(record, &mut data)?;
+        if self.failed {
+            self.cut_failed_write()?;
+        }
+        let written = self.log.write_all(&data);
+        if let Err(e) = written.and_then(|()| self.log.sync_data()) {
+            self.failed = true;
+            if let Err(e) = self.cut_failed_write() {
+                warn!("failed to cut a failed write off {:?}: {}", self.dir, e);
+            }
+            return Err(e);
+        }
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    // Compacts the log into a snapshot already saved in the snapshot file.
+    fn compact_into(&mut self, record: SnapshotRecord) -> io::Result<()> {
+        let mut mem = self.mem.clone();
+        mem.save_snapshot(record.index, record.term, record.conf_state, record.data)?;
+        self.save_hard_state(mem.hard_state())?;
+        self.rewrite_log(mem)
+    }
+
+    // Replaces the log file with the compaction and the entries of `mem`.
+    fn rewrite_log(&mut self, mem: MemStorage) -> io::Result<()> {
+        let mut data = Vec::new();
+        encode_record(
+            &Record {
+                kind: RecordKind::Compact as i32,
+                index: mem.snapshot_index,
+                term: mem.snapshot_term,
+                entries: Vec::new(),
+            },
+            &mut data,
+        )?;
+        if !mem.entries.is_empty() {
+            encode_record(
+                &Record {
+                    kind: RecordKind::Append as i32,
+                    index: 0,
+                    term: 0,
+                    entries: mem.entries.clone(),
+                },
+                &mut data,
+            )?;
+        }
+        write_atomically(&self.dir, LOG_FILE, &data)?;
+        self.log = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join(LOG_FILE))?;
+        self.len = data.len() as u64;
+        self.failed = false;
+        self.mem = mem;
+        Ok(())
+    }
+
+    // Truncates the log file to the records written, dropping the part of a
+    // record a failed write may have left.
+    fn cut_failed_write(&mut self) -> io::Result<()> {
+        self.log.set_len(self.len)?;
+        self.log.sync_all()?;
+        self.failed = false;
+        Ok(())
+    }
+}
+
+impl LogStorage for FileLogStorage {
+    fn hard_state(&self) -> HardState {
+        self.mem.hard_state()
+    }
+
+    fn save_hard_state(&mut self, state: HardState) -> io::Result<()> {
+        let mut data = Vec::new();
+        labcodec::encode(&state, &mut data).map_err(invalid_data)?;
+        write_atomically(&self.dir, HARD_STATE_FILE, &data)?;
+        self.mem.save_hard_state(state)
+    }
+
+    fn first_index(&self) -> u64 {
+        self.mem.first_index()
+    }
+
+    fn last_index(&self) -> u64 {
+        self.mem.last_index()
+    }
+
+    fn term(&self, index: u64) -> Option<u64> {
+        self.mem.term(index)
+    }
+
+    fn entries(&self, lo: u64, hi: u64) -> Vec<LogEntry> {
+        self.mem.entries(lo, hi)
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        self.mem.check_append(&entries)?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let record = Record {
+            kind: RecordKind::Append as i32,
+            index: 0,
+            term: 0,
+            entries,
+        };
+        self.write_record(&record)?;
+        self.mem.append(record.entries)
+    }
+
+    fn truncate_suffix(&mut self, index: u64) -> io::Result<()> {
+        self.mem.check_truncate(index)?;
+        if index > self.mem.last_index() {
+            return Ok(());
+        }
+        self.write_record(&Record {
+            kind: RecordKind::Truncate as i32,
+            index,
+            term: 0,
+            entries: Vec::new(),
+        })?;
+        self.mem.truncate_suffix(index)
+    }
+
+    fn compact_prefix(&mut self, index: u64, term: u64) -> io::Result<()> {
+        if index <= self.mem.snapshot_index {
+            return Ok(());
+        }
+        let mut mem = self.mem.clone();
+        mem.compact_prefix(index, term)?;
+        self.rewrite_log(mem)
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.mem.snapshot()
+    }
+
+    fn snapshot_size(&self) -> u64 {
+        self.mem.snapshot_size()
+    }
+
+    fn snapshot_chunk(&self, offset: u64, len: usize) -> Vec<u8> {
+        self.mem.snapshot_chunk(offset, len)
+    }
+
+    fn save_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        conf_state: ConfState,
+        snapshot: Vec<u8>,
+    ) -> io::Result<()> {
+        let record = SnapshotRecord {
+            index,
+            term,
+            conf_state,
+            data: snapshot,
+        };
+        let mut data = Vec::new();
+        labcodec::encode(&record, &mut data).map_err(invalid_data)?;
+        write_atomically(&self.dir, SNAPSHOT_FILE, &data)?;
+        self.compact_into(record)
+    }
+}
+
+// A record is framed by the length and the CRC-32 of its encoding.
+fn encode_record(record: &Record, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut data = Vec::new();
+    labcodec::encode(record, &mut data).map_err(invalid_data)?;
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(&data).to_le_bytes());
+    buf.extend_from_slice(&data);
+    Ok(())
+}
+
+// Returns the record at the start of `data` and its framed length, or `None`
+// if there is no complete and intact record.
+fn read_record(data: &[u8]) -> Option<(Record, usize)> {
+    if data.len() < 8 {
+        return None;
+    }
+    let mut word = [0; 4];
+    word.copy_from_slice(&data[..4]);
+    let len = u32::from_le_bytes(word) as usize;
+    word.copy_from_slice(&data[4..8]);
+    let crc = u32::from_le_bytes(word);
+    let payload = data.get(8..8 + len)?;
+    if crc32(payload) != crc {
+        return None;
+    }
+    let record = labcodec::decode(payload).ok()?;
+    Some((record, 8 + len))
+}
+
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// Continues the CRC-32 `crc` of some data with the bytes following it.
+pub(super) fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// A log saved in a `Persister` as its raft state.
+///
+/// Every change rewrites the whole log, like `Persister::save_raft_state`
+/// always did, so this is meant for the tester, which simulates crashes by
+/// copying persisters.
+pub struct PersisterLogStorage<P: Persister> {
+    persister: P,
+    mem: MemStorage,
+}
+
+impl<P: Persister> PersisterLogStorage<P> {
+    /// Loads the log saved in the persister.
+    pub fn new(persister: P) -> io::Result<PersisterLogStorage<P>> {
+        let state = persister.raft_state();
+        let mem = if state.is_empty() {
+            MemStorage::new()
+        } else {
+            labcodec::decode(&state).map_err(invalid_data)?
+        };
+        Ok(PersisterLogStorage { persister, mem })
+    }
+
+    /// The persister the log is saved in.
+    pub fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    fn save(&mut self, mem: MemStorage) -> io::Result<()> {
+        self.persister.save_raft_state(encode_mem(&mem)?);
+        self.mem = mem;
+        Ok(())
+    }
+}
+
+impl<P: Persister> LogStorage for PersisterLogStorage<P> {
+    fn hard_state(&self) -> HardState {
+        self.mem.hard_state()
+    }
+
+    fn save_hard_state(&mut self, state: HardState) -> io::Result<()> {
+        let mut mem = self.mem.clone();
+        mem.save_hard_state(state)?;
+        self.save(mem)
+    }
+
+    fn first_index(&self) -> u64 {
+        self.mem.first_index()
+    }
+
+    fn last_index(&self) -> u64 {
+        self.mem.last_index()
+    }
+
+    fn term(&self, index: u64) -> Option<u64> {
+        self.mem.term(index)
+    }
+
+    fn entries(&self, lo: u64, hi: u64) -> Vec<LogEntry> {
+        self.mem.entries(lo, hi)
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        let mut mem = self.mem.clone();
+        mem.append(entries)?;
+        self.save(mem)
+    }
+
+    fn truncate_suffix(&mut self, index: u64) -> io::Result<()> {
+        let mut mem = self.mem.clone();
+        mem.truncate_suffix(index)?;
+        self.save(mem)
+    }
+
+    fn compact_prefix(&mut self, index: u64, term: u64) -> io::Result<()> {
+        let mut mem = self.mem.clone();
+        mem.compact_prefix(index, term)?;
+        self.save(mem)
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.persister.snapshot()
+    }
+
+    // the snapshot is saved by the persister, not in the raft state.
+    fn save_snapshot(
+        &mut self,
+        index: u64,
+        term: u64,
+        conf_state: ConfState,
+        snapshot: Vec<u8>,
+    ) -> io::Result<()> {
+        let mut mem = self.mem.clone();
+        mem.save_snapshot(index, term, conf_state, Vec::new())?;
+        self.persister
+            .save_state_and_snapshot(encode_mem(&mem)?, snapshot);
+        self.mem = mem;
+        Ok(())
+    }
+}
+
+// The bytes of `data` from `offset`, at most `len` of them.
+fn chunk_of(data: &[u8], offset: u64, len: usize) -> &[u8] {
+    let start = (offset as usize).min(data.len());
+    let end = start.saturating_add(len).min(data.len());
+    &data[start..end]
+}
+
+fn encode_mem(mem: &MemStorage) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    labcodec::encode(mem, &mut data).map_err(invalid_data)?;
+    Ok(data)
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::raft::persister::SimplePersister;
+
+    fn entry(index: u64, term: u64) -> LogEntry {
+        LogEntry {
+            index,
+            term,
+            data: vec![index as u8],
+            ..Default::default()
+        }
+    }
+
+    fn entries(lo: u64, hi: u64, term: u64) -> Vec<LogEntry> {
+        (lo..hi).map(|index| entry(index, term)).collect()
+    }
+
+    // Goes through all the changes, reopening the storage after each.
+    fn check_storage<S, F>(mut reopen: F)
+    where
+        S: LogStorage,
+        F: FnMut(Option<S>) -> S,
+    {
+        let mut storage = reopen(None);
+        assert_eq!(storage.hard_state(), HardState::default());
+        assert_eq!((storage.first_index(), storage.last_index()), (1, 0));
+        assert_eq!(storage.term(0), Some(0));
+
+        let state = HardState {
+            term: 2,
+            voted_for: Some(1),
+            conf_state: Some(ConfState {
+                voters: vec![0, 1, 2],
+                learners: vec![3],
+            }),
+        };
+        storage.save_hard_state(state.clone()).unwrap();
+        storage.append(entries(1, 4, 1)).unwrap();
+        storage.append(entries(4, 6, 2)).unwrap();
+        let mut storage = reopen(Some(storage));
+        assert_eq!(storage.hard_state(), state);
+        assert_eq!((storage.first_index(), storage.last_index()), (1, 5));
+        assert_eq!(storage.term(3), Some(1));
+        assert_eq!(storage.term(5), Some(2));
+        assert_eq!(storage.term(6), None);
+        assert_eq!(storage.entries(3, 5), vec![entry(3, 1), entry(4, 2)]);
+        assert_eq!(storage.entries(0, 100).len(), 5);
+
+        // appends must follow the log
+        let err = storage.append(entries(7, 8, 2)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        storage.truncate_suffix(4).unwrap();
+        storage.append(entries(4, 7, 3)).unwrap();
+        let mut storage = reopen(Some(storage));
+        assert_eq!(storage.last_index(), 6);
+        assert_eq!(storage.term(4), Some(3));
+
+        storage.compact_prefix(4, 3).unwrap();
+        storage.compact_prefix(2, 1).unwrap();
+        let mut storage = reopen(Some(storage));
+        assert_eq!((storage.first_index(), storage.last_index()), (5, 6));
+        assert_eq!(storage.term(4), Some(3));
+        assert_eq!(storage.term(3), None);
+        assert_eq!(storage.entries(1, 6), vec![entry(5, 3)]);
+        let err = storage.truncate_suffix(4).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        storage.append(entries(7, 8, 4)).unwrap();
+        // a snapshot past the end of the log
+        storage.compact_prefix(10, 5).unwrap();
+        let mut storage = reopen(Some(storage));
+        assert_eq!((storage.first_index(), storage.last_index()), (11, 10));
+        assert_eq!(storage.term(10), Some(5));
+        storage.append(entries(11, 12, 5)).unwrap();
+        let mut storage = reopen(Some(storage));
+        assert_eq!(storage.entries(11, 12), vec![entry(11, 5)]);
+        assert!(storage.snapshot().is_empty());
+
+        let conf_state = ConfState {
+            voters: vec![1, 2, 3],
+            learners: vec![],
+        };
+        storage
+            .save_snapshot(11, 5, conf_state.clone(), vec![42])
+            .unwrap();
+        let mut storage = reopen(Some(storage));
+        assert_eq!((storage.first_index(), storage.last_index()), (12, 11));
+        assert_eq!(storage.snapshot(), vec![42]);
+        assert_eq!(storage.hard_state().conf_state, Some(conf_state.clone()));
+
+        // the entries after a snapshot of the same log are kept.
+        storage.append(entries(12, 16, 5)).unwrap();
+        storage
+            .save_snapshot(13, 5, conf_state.clone(), vec![43])
+            .unwrap();
+        assert_eq!((storage.first_index(), storage.last_index()), (14, 15));
+        // but not after a snapshot of another log.
+        storage.save_snapshot(14, 6, conf_state, vec![44]).unwrap();
+        let storage = reopen(Some(storage));
+        assert_eq!((storage.first_index(), storage.last_index()), (15, 14));
+        assert_eq!(storage.term(14), Some(6));
+        assert_eq!(storage.snapshot(), vec![44]);
+    }
+
+    #[test]
+    fn test_mem_storage() {
+        check_storage(|storage| storage.unwrap_or_else(MemStorage::new));
+    }
+
+    #[test]
+    fn test_file_log_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        check_storage(|storage| {
+            drop(storage);
+            FileLogStorage::new(dir.path()).unwrap()
+        });
+    }
+
+    #[test]
+    fn test_persister_log_storage() {
+        let persister = Arc::new(SimplePersister::new());
+        check_storage(|storage| {
+            drop(storage);
+            PersisterLogStorage::new(persister.clone()).unwrap()
+        });
+        assert_eq!(persister.snapshot(), vec![44]);
+    }
+
+    #[test]
+    fn test_file_log_storage_snapshot_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileLogStorage::new(dir.path()).unwrap();
+        storage.append(entries(1, 5, 1)).unwrap();
+        drop(storage);
+
+        // a crash after writing the snapshot, before compacting the log
+        let record = SnapshotRecord {
+            index: 3,
+            term: 1,
+            conf_state: ConfState {
+                voters: vec![0, 1, 2],
+                learners: vec![],
+            },
+            data: vec![42],
+        };
+        let mut data = Vec::new();
+        labcodec::encode(&record, &mut data).unwrap();
+        fs::write(dir.path().join(SNAPSHOT_FILE), data).unwrap();
+        let storage = FileLogStorage::new(dir.path()).unwrap();
+        assert_eq!((storage.first_index(), storage.last_index()), (4, 4));
+        assert_eq!(storage.snapshot(), vec![42]);
+        assert_eq!(storage.hard_state().conf_state, Some(record.conf_state));
+        drop(storage);
+        let storage = FileLogStorage::new(dir.path()).unwrap();
+        assert_eq!((storage.first_index(), storage.last_index()), (4, 4));
+    }
+
+    #[test]
+    fn test_file_log_storage_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = FileLogStorage::new(dir.path()).unwrap();
+        storage.append(entries(1, 3, 1)).unwrap();
+        storage.append(entries(3, 5, 1)).unwrap();
+        drop(storage);
+
+        // a crash in the middle of the second append
+        let path = dir.path().join(LOG_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let mut storage = FileLogStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.last_index(), 2);
+
+        storage.append(entries(3, 4, 2)).unwrap();
+        let storage = FileLogStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.entries(1, 10).len(), 3);
+        assert_eq!(storage.term(3), Some(2));
+    }
+
+    #[test]
+    fn test_file_log_storage_failed_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let mut storage = FileLogStorage::new(dir.path()).unwrap();
+        storage.append(entries(1, 3, 1)).unwrap();
+
+        // a write failing after writing half of the record, which can't be
+        // cut off the file either.
+        let mut data = Vec::new();
+        let record = Record {
+            kind: RecordKind::Append as i32,
+            index: 0,
+            term: 0,
+            entries: entries(3, 5, 1),
+        };
+        encode_record(&record, &mut data).unwrap();
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(&data[..data.len() / 2]).unwrap();
+        storage.log = File::open(&path).unwrap();
+        assert!(storage.append(entries(3, 5, 1)).is_err());
+        assert!(storage.append(entries(3, 4, 1)).is_err());
+        assert_eq!(storage.last_index(), 2);
+
+        // the partial record is cut off before the next write.
+        storage.log = log;
+        storage.append(entries(3, 5, 2)).unwrap();
+        storage.append(entries(5, 6, 2)).unwrap();
+        let storage = FileLogStorage::new(dir.path()).unwrap();
+        assert_eq!(storage.last_index(), 5);
+        assert_eq!(storage.term(3), Some(2));
+        assert_eq!(storage.entries(1, 10).len(), 5);
+    }
+}
@@ -4,15 +4,18 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::channel::oneshot;
 use futures::executor::block_on;
 use futures::future;
 use rand::{rngs::ThreadRng, Rng};
 
-use crate::raft::config::{Config, Entry, Storage};
-use crate::raft::Node;
+use crate::proto::raftpb::ConfChangeType;
+use crate::raft::config::{Config, Entry, Storage, SNAPSHOT_INTERVAL};
+use crate::raft::errors::Error;
+use crate::raft::status::{Event, Role};
+use crate::raft::{Node, RaftConfig, ReadMode};
 
 /// The tester generously allows solutions to complete elections in one second
 /// (much more than the paper's range of timeouts).
@@ -81,7 +84,18 @@ fn test_reelection_2a() {
 
     // re-join of last node shouldn't prevent leader from existing.
     cfg.connect(leader2);
-    cfg.check_one_leader();
+    let leader3 = cfg.check_one_leader();
+
+    // a follower partitioned away shouldn't inflate its term, nor
+    // disturb the leader when it rejoins.
+    let term = cfg.check_terms();
+    let follower = (leader3 + 1) % servers;
+    cfg.disconnect(follower);
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    cfg.connect(follower);
+    thread::sleep(RAFT_ELECTION_TIMEOUT);
+    assert_eq!(cfg.check_one_leader(), leader3, "the leader was disturbed");
+    assert_eq!(cfg.check_terms(), term, "the term was inflated");
 
     cfg.end();
 }
@@ -1014,3 +1028,683 @@ fn test_reliable_churn_2c() {
 fn test_unreliable_churn_2c() {
     internal_churn(true);
 }
+
+#[test]
+fn test_transfer_leadership_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): leadership transfer");
+
+    cfg.one(Entry { x: 101 }, servers, false);
+
+    let leader1 = cfg.check_one_leader();
+    let target = (leader1 + 1) % servers;
+    let node = cfg.rafts.lock().unwrap()[leader1].clone().unwrap();
+    let start = Instant::now();
+    block_on(node.transfer_leadership(target)).unwrap();
+    // the target should not wait for its election timeout.
+    assert!(
+        start.elapsed() < RAFT_ELECTION_TIMEOUT,
+        "transfer took {:?}",
+        start.elapsed()
+    );
+    let leader2 = cfg.check_one_leader();
+    assert_eq!(leader2, target, "expected {} to lead", target);
+
+    // the old leader should no longer accept commands.
+    assert_eq!(node.start(&Entry { x: 102 }), Err(Error::NotLeader));
+    cfg.one(Entry { x: 103 }, servers, false);
+
+    // a disconnected target can't be elected.
+    let target = (leader2 + 1) % servers;
+    cfg.disconnect(target);
+    let node = cfg.rafts.lock().unwrap()[leader2].clone().unwrap();
+    assert_eq!(
+        block_on(node.transfer_leadership(target)),
+        Err(Error::TransferFailed)
+    );
+    // and the leader takes commands again after the failed transfer.
+    cfg.one(Entry { x: 104 }, servers - 1, true);
+
+    cfg.connect(target);
+    cfg.one(Entry { x: 105 }, servers, true);
+
+    cfg.end();
+}
+
+fn term_of(cfg: &Config, i: usize) -> u64 {
+    cfg.rafts.lock().unwrap()[i].as_ref().unwrap().term()
+}
+
+#[test]
+fn test_pre_vote_2a() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2A): no term inflation with pre-vote");
+
+    cfg.one(Entry { x: 101 }, servers, false);
+    let leader = cfg.check_one_leader();
+    let term = cfg.check_terms();
+
+    // isolated followers keep trying to become candidates, but can't
+    // gather pre-votes, so their terms stay the same.
+    let isolated = [(leader + 1) % servers, (leader + 2) % servers];
+    for &i in &isolated {
+        cfg.disconnect(i);
+    }
+    thread::sleep(3 * RAFT_ELECTION_TIMEOUT);
+    for &i in &isolated {
+        assert_eq!(term_of(&cfg, i), term, "server {} inflated its term", i);
+    }
+    cfg.one(Entry { x: 102 }, servers - 2, false);
+
+    // the leader keeps its leadership when they rejoin.
+    for &i in &isolated {
+        cfg.connect(i);
+    }
+    cfg.one(Entry { x: 103 }, servers, true);
+    assert_eq!(cfg.check_one_leader(), leader, "the leader was disturbed");
+    assert_eq!(cfg.check_terms(), term, "the term was inflated");
+
+    cfg.end();
+}
+
+#[test]
+fn test_check_quorum_2a() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2A): leader steps down without a quorum");
+
+    cfg.one(Entry { x: 101 }, servers, false);
+    let leader1 = cfg.check_one_leader();
+
+    // a leader cut off from a majority should step down.
+    let minority = vec![leader1, (leader1 + 1) % servers];
+    let majority: Vec<_> = (0..servers).filter(|i| !minority.contains(i)).collect();
+    for &i in &majority {
+        cfg.disconnect(i);
+    }
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    cfg.check_no_leader();
+
+    // the majority elects a new leader meanwhile.
+    for &i in &minority {
+        cfg.disconnect(i);
+    }
+    for &i in &majority {
+        cfg.connect(i);
+    }
+    let leader2 = cfg.check_one_leader();
+    cfg.one(Entry { x: 102 }, majority.len(), true);
+
+    // the old leader follows the new one when the partition heals.
+    for &i in &minority {
+        cfg.connect(i);
+    }
+    assert_eq!(cfg.check_one_leader(), leader2);
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_without_pre_vote_2a() {
+    let servers = 3;
+    let raft_config = RaftConfig {
+        pre_vote: false,
+        check_quorum: false,
+        ..RaftConfig::default()
+    };
+    let mut cfg = Config::with_raft_config(servers, false, raft_config);
+    cfg.begin("Test (2A): election without pre-vote and check-quorum");
+
+    let leader1 = cfg.check_one_leader();
+    cfg.one(Entry { x: 101 }, servers, false);
+
+    // without check-quorum, an isolated leader doesn't step down.
+    cfg.disconnect(leader1);
+    let leader2 = cfg.check_one_leader();
+    assert!(
+        cfg.rafts.lock().unwrap()[leader1]
+            .as_ref()
+            .unwrap()
+            .is_leader(),
+        "{} stepped down",
+        leader1
+    );
+    cfg.one(Entry { x: 102 }, servers - 1, true);
+
+    // without pre-vote, an isolated follower inflates its term.
+    let term = term_of(&cfg, leader2);
+    let follower = (0..servers)
+        .find(|&i| i != leader1 && i != leader2)
+        .unwrap();
+    cfg.disconnect(follower);
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    assert!(term_of(&cfg, follower) > term, "the term was not inflated");
+
+    cfg.connect(follower);
+    cfg.connect(leader1);
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_add_server_2b() {
+    let servers = 3;
+    let mut cfg = Config::with_spares(servers, 2, false);
+    cfg.begin("Test (2B): add servers");
+
+    cfg.one(Entry { x: 101 }, servers, true);
+    let leader1 = cfg.check_one_leader();
+
+    // the new servers catch up and take part in agreement.
+    cfg.add_server(3);
+    cfg.one(Entry { x: 102 }, servers + 1, true);
+    cfg.add_server(4);
+    cfg.one(Entry { x: 103 }, servers + 2, true);
+
+    // a majority of the five servers is enough.
+    cfg.disconnect(leader1);
+    cfg.disconnect((leader1 + 1) % servers);
+    cfg.one(Entry { x: 104 }, servers, true);
+
+    // but not two of them.
+    let leader2 = cfg.check_one_leader();
+    cfg.disconnect(leader2);
+    let index = cfg.rafts.lock().unwrap()[leader2]
+        .as_ref()
+        .unwrap()
+        .start(&Entry { x: 105 })
+        .map(|(index, _)| index);
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    if let Ok(index) = index {
+        let (n, _) = cfg.n_committed(index);
+        assert_eq!(n, 0, "{} committed without a majority", n);
+    }
+
+    for i in 0..servers + 2 {
+        cfg.connect(i);
+    }
+    cfg.one(Entry { x: 106 }, servers + 2, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_remove_server_2b() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): remove servers");
+
+    cfg.one(Entry { x: 101 }, servers, true);
+
+    // removing a follower.
+    let leader1 = cfg.check_one_leader();
+    cfg.remove_server((leader1 + 1) % servers);
+    cfg.one(Entry { x: 102 }, servers - 1, true);
+
+    // removing the leader, which steps down.
+    cfg.remove_server(leader1);
+    let leader2 = cfg.check_one_leader();
+    assert_ne!(leader2, leader1);
+    cfg.one(Entry { x: 103 }, servers - 2, true);
+
+    // two of the three remaining servers are a majority.
+    let follower = (0..servers)
+        .find(|&i| cfg.connected[i] && i != leader2)
+        .unwrap();
+    cfg.disconnect(follower);
+    cfg.one(Entry { x: 104 }, servers - 3, true);
+    cfg.connect(follower);
+    cfg.one(Entry { x: 105 }, servers - 2, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_learner_2b() {
+    let servers = 3;
+    let mut cfg = Config::with_spares(servers, 1, false);
+    cfg.begin("Test (2B): learners don't vote");
+
+    cfg.one(Entry { x: 101 }, servers, true);
+    cfg.start1(servers);
+    cfg.connect(servers);
+    cfg.change_conf(ConfChangeType::AddLearner, servers);
+
+    // the learner receives the log.
+    cfg.one(Entry { x: 102 }, servers + 1, true);
+
+    // but doesn't count towards commitment.
+    let leader = cfg.check_one_leader();
+    cfg.disconnect((leader + 1) % servers);
+    cfg.disconnect((leader + 2) % servers);
+    let (index, _) = cfg.rafts.lock().unwrap()[leader]
+        .as_ref()
+        .unwrap()
+        .start(&Entry { x: 103 })
+        .unwrap();
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    let (n, _) = cfg.n_committed(index);
+    assert_eq!(n, 0, "{} committed with a single voter", n);
+
+    // nor becomes a candidate.
+    cfg.disconnect(leader);
+    thread::sleep(2 * RAFT_ELECTION_TIMEOUT);
+    cfg.check_no_leader();
+
+    for i in 0..servers {
+        cfg.connect(i);
+    }
+    cfg.one(Entry { x: 104 }, servers + 1, true);
+    cfg.change_conf(ConfChangeType::AddNode, servers);
+    cfg.one(Entry { x: 105 }, servers + 1, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_persist_conf_2c() {
+    let servers = 3;
+    let mut cfg = Config::with_spares(servers, 1, false);
+    cfg.begin("Test (2C): members persist across crashes");
+
+    cfg.one(Entry { x: 101 }, servers, true);
+    cfg.add_server(servers);
+    cfg.one(Entry { x: 102 }, servers + 1, true);
+
+    // every server remembers the new member after a restart.
+    for i in 0..=servers {
+        cfg.start1(i);
+    }
+    for i in 0..=servers {
+        cfg.connect(i);
+    }
+    cfg.one(Entry { x: 103 }, servers + 1, true);
+    let leader = cfg.check_one_leader();
+    let conf = cfg.rafts.lock().unwrap()[leader]
+        .as_ref()
+        .unwrap()
+        .conf_state();
+    assert_eq!(conf.voters.len(), servers + 1, "members {:?}", conf);
+
+    cfg.end();
+}
+
+fn check_read_index(cfg: &mut Config, servers: usize) {
+    let index1 = cfg.one(Entry { x: 101 }, servers, true);
+    let leader1 = cfg.check_one_leader();
+
+    // the leader's read index covers the committed entries.
+    let node = cfg.rafts.lock().unwrap()[leader1].clone().unwrap();
+    let read = block_on(node.read_index()).unwrap();
+    assert!(read >= index1, "read index {} < {}", read, index1);
+
+    // followers don't serve reads.
+    let follower = (leader1 + 1) % servers;
+    let node = cfg.rafts.lock().unwrap()[follower].clone().unwrap();
+    assert_eq!(block_on(node.read_index()), Err(Error::NotLeader));
+
+    // a leader in the minority can't serve reads once the majority may
+    // have elected another leader.
+    for i in 1..=servers / 2 {
+        cfg.disconnect((leader1 + i) % servers);
+    }
+    cfg.disconnect(leader1);
+    for i in 1..=servers / 2 {
+        cfg.connect((leader1 + i) % servers);
+    }
+    let leader2 = cfg.check_one_leader();
+    let index2 = cfg.one(Entry { x: 102 }, servers / 2 + 1, true);
+    let node = cfg.rafts.lock().unwrap()[leader1].clone().unwrap();
+    assert_eq!(block_on(node.read_index()), Err(Error::NotLeader));
+
+    let node = cfg.rafts.lock().unwrap()[leader2].clone().unwrap();
+    let read = block_on(node.read_index()).unwrap();
+    assert!(read >= index2, "read index {} < {}", read, index2);
+
+    cfg.connect(leader1);
+    cfg.one(Entry { x: 103 }, servers, true);
+}
+
+#[test]
+fn test_read_index_2b() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): reads with read index");
+
+    check_read_index(&mut cfg, servers);
+
+    // reads don't append to the log.
+    let leader = cfg.check_one_leader();
+    let node = cfg.rafts.lock().unwrap()[leader].clone().unwrap();
+    let index = cfg.one(Entry { x: 104 }, servers, true);
+    for _ in 0..10 {
+        assert_eq!(block_on(node.read_index()), Ok(index));
+    }
+    assert_eq!(cfg.one(Entry { x: 105 }, servers, true), index + 1);
+
+    cfg.end();
+}
+
+#[test]
+fn test_lease_read_2b() {
+    let servers = 5;
+    let raft_config = RaftConfig {
+        read_mode: ReadMode::Lease {
+            max_clock_drift: Duration::from_millis(50),
+        },
+        ..RaftConfig::default()
+    };
+    let mut cfg = Config::with_raft_config(servers, false, raft_config);
+    cfg.begin("Test (2B): reads with leases");
+
+    check_read_index(&mut cfg, servers);
+
+    cfg.end();
+}
+
+#[test]
+#[should_panic(expected = "lease reads require check_quorum")]
+fn test_lease_read_without_check_quorum_2b() {
+    let raft_config = RaftConfig {
+        check_quorum: false,
+        read_mode: ReadMode::Lease {
+            max_clock_drift: Duration::from_millis(50),
+        },
+        ..RaftConfig::default()
+    };
+    Config::with_raft_config(3, false, raft_config);
+}
+
+#[test]
+fn test_batching_2b() {
+    const SERVERS: usize = 3;
+    fn rpcs(cfg: &Config) -> usize {
+        (0..SERVERS).map(|j| cfg.rpc_count(j)).sum()
+    }
+
+    let mut cfg = Config::new(SERVERS, false);
+    cfg.begin("Test (2B): commands are batched");
+
+    cfg.one(Entry { x: 100 }, SERVERS, true);
+    let leader = cfg.check_one_leader();
+    let total1 = rpcs(&cfg);
+
+    // commands started together share AppendEntries.
+    let iters = 200;
+    let mut last = 0;
+    for x in 1..=iters {
+        let (index, _) = cfg.rafts.lock().unwrap()[leader]
+            .as_ref()
+            .unwrap()
+            .start(&Entry { x: 100 + x })
+            .expect("leader changed");
+        last = index;
+    }
+    cfg.wait(last, SERVERS, None);
+
+    let total2 = rpcs(&cfg);
+    let max = iters as usize * (SERVERS - 1) / 4;
+    if total2 - total1 > max {
+        panic!(
+            "too many RPCs ({}) for {} commands, expected at most {}",
+            total2 - total1,
+            iters,
+            max
+        );
+    }
+
+    cfg.end();
+}
+
+#[test]
+fn test_pipelining_2b() {
+    let servers = 5;
+    let mut cfg = Config::new(servers, true);
+    cfg.net.set_long_reordering(true);
+    cfg.begin("Test (2B): pipelined appends with reordering");
+
+    // appends in flight together may arrive out of order, or be lost.
+    let leader = cfg.check_one_leader();
+    for x in 1..=50 {
+        let _ = cfg.rafts.lock().unwrap()[leader]
+            .as_ref()
+            .unwrap()
+            .start(&Entry { x });
+    }
+    cfg.one(Entry { x: 51 }, servers, true);
+
+    // a lagging follower is probed, then catches up.
+    let follower = (leader + 1) % servers;
+    cfg.disconnect(follower);
+    for x in 52..=100 {
+        cfg.one(Entry { x }, servers - 1, true);
+    }
+    cfg.connect(follower);
+    cfg.one(Entry { x: 101 }, servers, true);
+
+    cfg.end();
+}
+
+// the largest raft state a server may save with snapshots.
+const MAX_LOG_SIZE: usize = 2000;
+
+fn snap_common(name: &str, disconnect: bool, reliable: bool, crash: bool) {
+    let iters = 30;
+    let servers = 3;
+    let mut cfg = Config::with_snapshots(servers, !reliable);
+    cfg.begin(name);
+
+    let mut random = rand::thread_rng();
+    cfg.one(random_entry(&mut random), servers, true);
+    let mut leader1 = cfg.check_one_leader();
+
+    for i in 0..iters {
+        let mut victim = (leader1 + 1) % servers;
+        let mut sender = leader1;
+        if i % 3 == 1 {
+            sender = (leader1 + 1) % servers;
+            victim = leader1;
+        }
+
+        if disconnect {
+            cfg.disconnect(victim);
+            cfg.one(random_entry(&mut random), servers - 1, true);
+        }
+        if crash {
+            cfg.crash1(victim);
+            cfg.one(random_entry(&mut random), servers - 1, true);
+        }
+
+        // perhaps send enough to get a snapshot
+        let nn = (SNAPSHOT_INTERVAL / 2) + (random.gen::<u64>() % SNAPSHOT_INTERVAL);
+        for _ in 0..nn {
+            if let Some(ref rf) = cfg.rafts.lock().unwrap()[sender] {
+                let _ = rf.start(&random_entry(&mut random));
+            }
+        }
+
+        // let applier threads catch up with the start()'s
+        if !disconnect && !crash {
+            // make sure all followers have caught up, so that
+            // an InstallSnapshot RPC isn't required for
+            // test_snapshot_basic_2d().
+            cfg.one(random_entry(&mut random), servers, true);
+        } else {
+            cfg.one(random_entry(&mut random), servers - 1, true);
+        }
+
+        if cfg.log_size() >= MAX_LOG_SIZE {
+            panic!("log size too large");
+        }
+        if disconnect {
+            // reconnect a follower, who maybe behind and
+            // needs to receive a snapshot to catch up.
+            cfg.connect(victim);
+            cfg.one(random_entry(&mut random), servers, true);
+            leader1 = cfg.check_one_leader();
+        }
+        if crash {
+            cfg.start1(victim);
+            cfg.connect(victim);
+            cfg.one(random_entry(&mut random), servers, true);
+            leader1 = cfg.check_one_leader();
+        }
+    }
+    cfg.end();
+}
+
+#[test]
+fn test_snapshot_basic_2d() {
+    snap_common("Test (2D): snapshots basic", false, true, false);
+}
+
+#[test]
+fn test_snapshot_install_2d() {
+    snap_common(
+        "Test (2D): install snapshots (disconnect)",
+        true,
+        true,
+        false,
+    );
+}
+
+#[test]
+fn test_snapshot_install_unreliable_2d() {
+    snap_common(
+        "Test (2D): install snapshots (disconnect+unreliable)",
+        true,
+        false,
+        false,
+    );
+}
+
+#[test]
+fn test_snapshot_install_crash_2d() {
+    snap_common("Test (2D): install snapshots (crash)", false, true, true);
+}
+
+#[test]
+fn test_snapshot_install_unreliable_crash_2d() {
+    snap_common(
+        "Test (2D): install snapshots (unreliable+crash)",
+        false,
+        false,
+        true,
+    );
+}
+
+#[test]
+fn test_status_2b() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2B): status");
+
+    let index = cfg.one(Entry { x: 101 }, servers, true);
+    let leader = cfg.check_one_leader();
+    // let the followers learn the commit index.
+    thread::sleep(RAFT_ELECTION_TIMEOUT / 2);
+
+    let term = cfg.check_terms();
+    for i in 0..servers {
+        let status = cfg.rafts.lock().unwrap()[i].as_ref().unwrap().status();
+        assert_eq!(status.id, i as u64);
+        assert_eq!(status.term, term);
+        assert_eq!(status.leader, Some(leader as u64));
+        assert_eq!(status.commit_index, index);
+        assert_eq!(status.applied_index, index);
+        assert_eq!((status.last_log_index, status.last_log_term), (index, term));
+        if i == leader {
+            assert_eq!(status.role, Role::Leader);
+            assert_eq!(status.progress.len(), servers - 1);
+            for (id, progress) in &status.progress {
+                assert_eq!(progress.match_index, index, "progress of {}", id);
+                assert_eq!(progress.next_index, index + 1, "progress of {}", id);
+            }
+        } else {
+            assert_eq!(status.role, Role::Follower);
+            assert!(status.progress.is_empty());
+        }
+    }
+
+    // a lagging follower shows in the progress.
+    let follower = (leader + 1) % servers;
+    cfg.disconnect(follower);
+    let index = cfg.one(Entry { x: 102 }, servers - 1, true);
+    let status = cfg.rafts.lock().unwrap()[leader].as_ref().unwrap().status();
+    assert_eq!(status.commit_index, index);
+    assert!(status.progress[&(follower as u64)].match_index < index);
+
+    cfg.connect(follower);
+    cfg.one(Entry { x: 103 }, servers, true);
+
+    cfg.end();
+}
+
+#[test]
+fn test_events_2a() {
+    let servers = 3;
+    let mut cfg = Config::new(servers, false);
+    cfg.begin("Test (2A): events");
+
+    let leader1 = cfg.check_one_leader();
+    let mut events: Vec<_> = (0..servers)
+        .map(|i| cfg.rafts.lock().unwrap()[i].as_ref().unwrap().subscribe())
+        .collect();
+
+    // a new election after the leader fails.
+    cfg.disconnect(leader1);
+    let leader2 = cfg.check_one_leader();
+    let term = cfg.rafts.lock().unwrap()[leader2].as_ref().unwrap().term();
+    cfg.connect(leader1);
+    cfg.check_one_leader();
+
+    let mut drain = |i: usize| {
+        let mut drained = vec![];
+        while let Ok(Some(event)) = events[i].try_next() {
+            drained.push(event);
+        }
+        drained
+    };
+    let events2 = drain(leader2);
+    assert!(
+        events2.contains(&Event::ElectionStarted {
+            term,
+            pre_vote: false
+        }),
+        "{:?}",
+        events2
+    );
+    assert!(
+        events2.contains(&Event::RoleChanged {
+            term,
+            from: Role::Candidate,
+            to: Role::Leader
+        }),
+        "{:?}",
+        events2
+    );
+    // the old leader steps down when it rejoins.
+    let events1 = drain(leader1);
+    assert!(
+        events1.iter().any(|event| matches!(
+            event,
+            Event::RoleChanged {
+                from: Role::Leader,
+                to: Role::Follower,
+                ..
+            }
+        )),
+        "{:?}",
+        events1
+    );
+    assert!(events1.contains(&Event::LeaderChanged {
+        term,
+        leader: leader2 as u64
+    }));
+
+    cfg.end();
+}
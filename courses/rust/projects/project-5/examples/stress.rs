@@ -0,0 +1,302 @@
+//! A long-running randomized stress test of `KvStore`.
+//!
+//! Every thread owns a range of keys, which it sets, removes and reads while
+//! checking the results against its own model. It also reads the keys of the
+//! other threads, checking only that the value belongs to the key. The values
+//! are large, so compactions run often while the reads are in flight. At the
+//! end, the store is reopened and compared against all the models.
+//!
+//! ```text
+//! cargo run --release --example stress -- --duration 60 --threads 8
+//! ```
+//!
+//! The seed is printed at start. Passing it back with `--seed` replays the same
+//! operations, although the interleaving of the threads differs.
+
+#[macro_use]
+extern crate clap;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::executor::block_on;
+use kvs::thread_pool::*;
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use structopt::StructOpt;
+use tempfile::TempDir;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "stress")]
+struct Opt {
+    #[structopt(long, help = "Seconds to run", default_value = "10")]
+    duration: u64,
+    #[structopt(long, help = "Number of client threads", default_value = "4")]
+    threads: u64,
+    #[structopt(
+        long,
+        help = "Number of keys owned by each thread",
+        default_value = "100"
+    )]
+    keys: u64,
+    #[structopt(
+        long = "value-size",
+        help = "Bytes of padding in each value",
+        default_value = "2048"
+    )]
+    value_size: usize,
+    #[structopt(long, help = "Seed of the random operations")]
+    seed: Option<u64>,
+    #[structopt(
+        long,
+        help = "Thread pool of the store",
+        default_value = "shared_queue",
+        raw(possible_values = "&Pool::variants()")
+    )]
+    pool: Pool,
+    #[structopt(
+        long,
+        help = "Directory of the store, a temporary one by default",
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Pool {
+        shared_queue,
+        rayon,
+        work_stealing,
+        priority
+    }
+}
+
+// The expected values of the keys owned by a thread.
+type Model = BTreeMap<String, String>;
+
+#[derive(Default)]
+struct Stats {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    scans: AtomicU64,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    let res = match opt.pool {
+        Pool::shared_queue => run::<SharedQueueThreadPool>(opt),
+        Pool::rayon => run::<RayonThreadPool>(opt),
+        Pool::work_stealing => run::<WorkStealingThreadPool>(opt),
+        Pool::priority => run::<PriorityThreadPool>(opt),
+    };
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run<P: ThreadPool>(opt: Opt) -> Result<()> {
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    println!("seed: {}", seed);
+    let temp_dir;
+    let dir = match opt.dir {
+        Some(ref dir) => dir.clone(),
+        None => {
+            temp_dir = TempDir::new()?;
+            temp_dir.path().to_owned()
+        }
+    };
+
+    let store = KvStore::<P>::open(&dir, opt.threads as u32)?;
+    let deadline = Instant::now() + Duration::from_secs(opt.duration);
+    let failed = Arc::new(AtomicBool::new(false));
+    let stats = Arc::new(Stats::default());
+    let workers: Vec<_> = (0..opt.threads)
+        .map(|id| {
+            let worker = Worker {
+                id,
+                threads: opt.threads,
+                keys: opt.keys,
+                value_size: opt.value_size,
+                store: store.clone(),
+                rng: StdRng::seed_from_u64(seed.wrapping_add(id)),
+                model: Model::new(),
+                stats: Arc::clone(&stats),
+            };
+            let failed = Arc::clone(&failed);
+            thread::spawn(move || worker.run(deadline, &failed))
+        })
+        .collect();
+
+    let mut models = Vec::new();
+    for worker in workers {
+        match worker.join().expect("worker panicked") {
+            Ok(model) => models.push(model),
+            Err(e) => {
+                failed.store(true, Ordering::SeqCst);
+                return Err(KvsError::StringError(format!("seed {}: {}", seed, e)));
+            }
+        }
+    }
+    println!(
+        "gets: {}, sets: {}, removes: {}, scans: {}",
+        stats.gets.load(Ordering::SeqCst),
+        stats.sets.load(Ordering::SeqCst),
+        stats.removes.load(Ordering::SeqCst),
+        stats.scans.load(Ordering::SeqCst),
+    );
+
+    drop(store);
+    verify_reopened::<P>(&dir, opt.threads as u32, &models)
+        .map_err(|e| KvsError::StringError(format!("seed {}: after reopening: {}", seed, e)))?;
+    println!("ok");
+    Ok(())
+}
+
+fn verify_reopened<P: ThreadPool>(dir: &Path, concurrency: u32, models: &[Model]) -> Result<()> {
+    let store = KvStore::<P>::open(dir, concurrency)?;
+    let keys = block_on(store.scan(String::new()))?;
+    let mut expected: Vec<&String> = models.iter().flat_map(|model| model.keys()).collect();
+    expected.sort();
+    check(keys.iter().eq(expected.iter().cloned()), || {
+        format!(
+            "scan returned {} keys, expected {}",
+            keys.len(),
+            expected.len()
+        )
+    })?;
+    for (key, value) in models.iter().flatten() {
+        let actual = block_on(store.get(key.clone()))?;
+        check(actual.as_ref() == Some(value), || {
+            format!("get {} returned {}", key, describe(&actual))
+        })?;
+    }
+    Ok(())
+}
+
+struct Worker<P: ThreadPool> {
+    id: u64,
+    threads: u64,
+    keys: u64,
+    value_size: usize,
+    store: KvStore<P>,
+    rng: StdRng,
+    model: Model,
+    stats: Arc<Stats>,
+}
+
+impl<P: ThreadPool> Worker<P> {
+    fn run(mut self, deadline: Instant, failed: &AtomicBool) -> Result<Model> {
+        let mut version = 0;
+        while Instant::now() < deadline && !failed.load(Ordering::SeqCst) {
+            let key = self.key(self.id);
+            match self.rng.gen_range(0, 100) {
+                0..=39 => self.get_own(key)?,
+                40..=59 => {
+                    let other = self.rng.gen_range(0, self.threads);
+                    let key = self.key(other);
+                    self.get_other(key)?;
+                }
+                60..=89 => {
+                    version += 1;
+                    self.set(key, version)?;
+                }
+                90..=98 => self.remove(key)?,
+                _ => self.scan()?,
+            }
+        }
+        Ok(self.model)
+    }
+
+    fn key(&mut self, owner: u64) -> String {
+        format!("t{}-k{}", owner, self.rng.gen_range(0, self.keys))
+    }
+
+    fn get_own(&mut self, key: String) -> Result<()> {
+        self.stats.gets.fetch_add(1, Ordering::Relaxed);
+        let actual = block_on(self.store.get(key.clone()))?;
+        let expected = self.model.get(&key);
+        check(actual.as_ref() == expected, || {
+            format!(
+                "get {} returned {}, expected {}",
+                key,
+                describe(&actual),
+                describe(&expected)
+            )
+        })
+    }
+
+    // Another thread may be changing the key, so only check that the value
+    // was written for it.
+    fn get_other(&mut self, key: String) -> Result<()> {
+        self.stats.gets.fetch_add(1, Ordering::Relaxed);
+        let actual = block_on(self.store.get(key.clone()))?;
+        check(
+            actual
+                .as_ref()
+                .is_none_or(|value| value.starts_with(&format!("{}:", key))),
+            || format!("get {} returned {}", key, describe(&actual)),
+        )
+    }
+
+    fn set(&mut self, key: String, version: u64) -> Result<()> {
+        self.stats.sets.fetch_add(1, Ordering::Relaxed);
+        let value = format!("{}:{}:{}", key, version, "x".repeat(self.value_size));
+        block_on(self.store.set(key.clone(), value.clone()))?;
+        self.model.insert(key, value);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.stats.removes.fetch_add(1, Ordering::Relaxed);
+        let res = block_on(self.store.remove(key.clone()));
+        match (self.model.remove(&key), res) {
+            (Some(_), Ok(())) | (None, Err(KvsError::KeyNotFound)) => Ok(()),
+            (expected, res) => Err(KvsError::StringError(format!(
+                "remove {} returned {:?}, expected {}",
+                key,
+                res,
+                describe(&expected)
+            ))),
+        }
+    }
+
+    fn scan(&mut self) -> Result<()> {
+        self.stats.scans.fetch_add(1, Ordering::Relaxed);
+        let prefix = format!("t{}-", self.id);
+        let keys = block_on(self.store.scan(prefix.clone()))?;
+        check(keys.iter().eq(self.model.keys()), || {
+            format!(
+                "scan {} returned {:?}, expected {:?}",
+                prefix,
+                keys,
+                self.model.keys().collect::<Vec<_>>()
+            )
+        })
+    }
+}
+
+fn check<F: FnOnce() -> String>(ok: bool, msg: F) -> Result<()> {
+    if ok {
+        Ok(())
+    } else {
+        Err(KvsError::StringError(msg()))
+    }
+}
+
+// Describes a value without its padding.
+fn describe<S: AsRef<str>>(value: &Option<S>) -> String {
+    match value {
+        Some(value) => format!("{:?}", value.as_ref().rsplitn(2, ':').last().unwrap()),
+        None => "None".to_owned(),
+    }
+}
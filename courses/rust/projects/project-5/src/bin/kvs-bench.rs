@@ -0,0 +1,383 @@
+//! A load generator measuring a running `kvs-server` over TCP.
+//!
+//! Every connection runs a closed loop of gets and sets for the given duration,
+//! picking keys uniformly or along a zipfian distribution. At the end, the
+//! throughput and the latency percentiles of each kind of request are printed.
+//!
+//! ```text
+//! cargo run --release --bin kvs-bench -- --addr 127.0.0.1:4000 --prefill \
+//!     --concurrency 32 --distribution zipfian --read-ratio 0.95 --duration 30
+//! ```
+
+#[macro_use]
+extern crate clap;
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use hdrhistogram::Histogram;
+use kvs::{KvsClient, KvsError, Result};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use structopt::StructOpt;
+use tokio::task::JoinHandle;
+
+// latencies are recorded in microseconds, up to a minute
+const MAX_LATENCY_US: u64 = 60_000_000;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-bench")]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the server address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000",
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        short = "n",
+        long,
+        help = "Sets the namespace",
+        value_name = "NAMESPACE",
+        default_value = ""
+    )]
+    namespace: String,
+    #[structopt(
+        long,
+        help = "Number of connections sending requests",
+        default_value = "16"
+    )]
+    concurrency: u32,
+    #[structopt(long, help = "Seconds to measure", default_value = "10")]
+    duration: f64,
+    #[structopt(long, help = "Seconds to run before measuring", default_value = "0")]
+    warmup: f64,
+    #[structopt(long, help = "Number of distinct keys", default_value = "10000")]
+    keys: u64,
+    #[structopt(
+        long,
+        help = "Distribution of the keys",
+        default_value = "uniform",
+        raw(possible_values = "&Distribution::variants()")
+    )]
+    distribution: Distribution,
+    #[structopt(
+        long,
+        help = "Skew of the zipfian distribution, between 0 and 1",
+        default_value = "0.99"
+    )]
+    theta: f64,
+    #[structopt(
+        long = "value-size",
+        help = "Bytes in each value written",
+        default_value = "100"
+    )]
+    value_size: usize,
+    #[structopt(
+        long = "max-value-size",
+        help = "Picks the size of each value up to this one"
+    )]
+    max_value_size: Option<usize>,
+    #[structopt(
+        long = "read-ratio",
+        help = "Fraction of the requests that are gets, the others are sets",
+        default_value = "0.9"
+    )]
+    read_ratio: f64,
+    #[structopt(long, help = "Sets every key before measuring")]
+    prefill: bool,
+    #[structopt(long, help = "Seed of the random requests")]
+    seed: Option<u64>,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Distribution {
+        uniform,
+        zipfian
+    }
+}
+
+// What a connection measured.
+struct Report {
+    gets: Histogram<u64>,
+    sets: Histogram<u64>,
+    errors: u64,
+}
+
+impl Report {
+    fn new() -> Report {
+        let histogram = || Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).unwrap();
+        Report {
+            gets: histogram(),
+            sets: histogram(),
+            errors: 0,
+        }
+    }
+
+    fn merge(&mut self, other: &Report) {
+        self.gets.add(&other.gets).unwrap();
+        self.sets.add(&other.sets).unwrap();
+        self.errors += other.errors;
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt).await {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    validate(&opt)?;
+    let seed = opt.seed.unwrap_or_else(rand::random);
+    println!("seed: {}", seed);
+    let keys = Keys::new(opt.distribution, opt.keys, opt.theta);
+    let max_value_size = opt.max_value_size.unwrap_or(opt.value_size);
+    let values = Values(
+        StdRng::seed_from_u64(seed)
+            .sample_iter(&Alphanumeric)
+            .take(max_value_size)
+            .collect(),
+    );
+
+    if opt.prefill {
+        let start = Instant::now();
+        prefill(&opt, &values).await?;
+        println!(
+            "prefilled {} keys in {:.2} s",
+            opt.keys,
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    let measure_start = Instant::now() + Duration::from_secs_f64(opt.warmup);
+    let deadline = measure_start + Duration::from_secs_f64(opt.duration);
+    let connections = (0..opt.concurrency).map(|i| {
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(u64::from(i) + 1));
+        let keys = keys.clone();
+        let values = values.clone();
+        let (addr, namespace) = (opt.addr, opt.namespace.clone());
+        let (read_ratio, value_size) = (opt.read_ratio, opt.value_size);
+        tokio::spawn(async move {
+            let mut client = KvsClient::connect(addr).await?.namespace(namespace.clone());
+            let mut report = Report::new();
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok::<_, KvsError>(report);
+                }
+                let key = keys.key(&mut rng);
+                let read = rng.gen::<f64>() < read_ratio;
+                let res = if read {
+                    client.get(key).await.map(|_| ())
+                } else {
+                    let size = rng.gen_range(value_size, max_value_size + 1);
+                    client.set(key, values.get(size)).await
+                };
+                let latency = now.elapsed().as_micros() as u64;
+                match res {
+                    Ok(()) if now < measure_start => {}
+                    Ok(()) if read => report.gets.saturating_record(latency),
+                    Ok(()) => report.sets.saturating_record(latency),
+                    Err(KvsError::Io(e)) => {
+                        // the connection is lost, a new one is used
+                        report.errors += 1;
+                        warn_error(&e);
+                        client = KvsClient::connect(addr).await?.namespace(namespace.clone());
+                    }
+                    Err(e) => {
+                        report.errors += 1;
+                        warn_error(&e);
+                    }
+                }
+            }
+        })
+    });
+
+    let mut total = Report::new();
+    for report in join_all(connections).await? {
+        total.merge(&report);
+    }
+    print_report(&opt, &total);
+    Ok(())
+}
+
+fn validate(opt: &Opt) -> Result<()> {
+    let error = |msg: &str| Err(KvsError::StringError(msg.to_owned()));
+    if opt.concurrency == 0 {
+        return error("The concurrency must be positive");
+    }
+    if opt.keys == 0 {
+        return error("The number of keys must be positive");
+    }
+    if !(opt.duration > 0.0 && opt.warmup >= 0.0) {
+        return error("The duration must be positive");
+    }
+    if !(0.0..=1.0).contains(&opt.read_ratio) {
+        return error("The read ratio must be between 0 and 1");
+    }
+    if opt.distribution == Distribution::zipfian && !(opt.theta > 0.0 && opt.theta < 1.0) {
+        return error("The skew must be between 0 and 1, exclusive");
+    }
+    if opt.max_value_size.is_some_and(|max| max < opt.value_size) {
+        return error("The maximum value size must not be less than the value size");
+    }
+    Ok(())
+}
+
+// Sets every key once, spreading the keys over the connections.
+async fn prefill(opt: &Opt, values: &Values) -> Result<()> {
+    let connections = (0..u64::from(opt.concurrency)).map(|i| {
+        let (addr, namespace) = (opt.addr, opt.namespace.clone());
+        let (keys, concurrency) = (opt.keys, u64::from(opt.concurrency));
+        let value = values.get(opt.value_size);
+        tokio::spawn(async move {
+            let mut client = KvsClient::connect(addr).await?.namespace(namespace);
+            for index in (i..keys).step_by(concurrency as usize) {
+                client.set(key_name(index), value.clone()).await?;
+            }
+            Ok::<_, KvsError>(())
+        })
+    });
+    join_all(connections).await?;
+    Ok(())
+}
+
+fn print_report(opt: &Opt, report: &Report) {
+    let mut all = report.gets.clone();
+    all.add(&report.sets).unwrap();
+    println!("connections: {}", opt.concurrency);
+    println!("duration: {:.2} s", opt.duration);
+    println!(
+        "requests: {} ({:.1} req/s)",
+        all.len(),
+        all.len() as f64 / opt.duration
+    );
+    println!("errors: {}", report.errors);
+    println!("latency (ms):");
+    for (name, histogram) in &[("get", &report.gets), ("set", &report.sets), ("all", &all)] {
+        print_latency(name, histogram);
+    }
+}
+
+fn print_latency(name: &str, histogram: &Histogram<u64>) {
+    if histogram.is_empty() {
+        println!("  {}: no requests", name);
+        return;
+    }
+    let ms = |us: u64| us as f64 / 1000.0;
+    println!(
+        "  {}: count {} mean {:.3} p50 {:.3} p99 {:.3} p999 {:.3} max {:.3}",
+        name,
+        histogram.len(),
+        histogram.mean() / 1000.0,
+        ms(histogram.value_at_quantile(0.5)),
+        ms(histogram.value_at_quantile(0.99)),
+        ms(histogram.value_at_quantile(0.999)),
+        ms(histogram.max()),
+    );
+}
+
+fn warn_error(e: &dyn Display) {
+    eprintln!("request failed: {}", e);
+}
+
+// Waits for all the tasks, failing if any of them fails.
+async fn join_all<T, I>(tasks: I) -> Result<Vec<T>>
+where
+    I: IntoIterator<Item = JoinHandle<Result<T>>>,
+{
+    let results = future::join_all(tasks).await;
+    results
+        .into_iter()
+        .map(|res| res.map_err(|e| KvsError::StringError(format!("{}", e)))?)
+        .collect()
+}
+
+fn key_name(index: u64) -> String {
+    format!("key{:010}", index)
+}
+
+// The keys requested, `key0000000000` being the most popular with the zipfian
+// distribution.
+#[derive(Clone)]
+enum Keys {
+    Uniform(u64),
+    Zipfian(Zipfian),
+}
+
+impl Keys {
+    fn new(distribution: Distribution, keys: u64, theta: f64) -> Keys {
+        match distribution {
+            Distribution::uniform => Keys::Uniform(keys),
+            Distribution::zipfian => Keys::Zipfian(Zipfian::new(keys, theta)),
+        }
+    }
+
+    fn key<R: Rng>(&self, rng: &mut R) -> String {
+        let index = match self {
+            Keys::Uniform(keys) => rng.gen_range(0, *keys),
+            Keys::Zipfian(zipfian) => zipfian.sample(rng),
+        };
+        key_name(index)
+    }
+}
+
+// The zipfian generator of YCSB, from "Quickly Generating Billion-Record
+// Synthetic Databases" by Gray et al.
+#[derive(Clone)]
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    zetan: f64,
+    alpha: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64, theta: f64) -> Zipfian {
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(items);
+        Zipfian {
+            items,
+            theta,
+            zetan,
+            alpha: 1.0 / (1.0 - theta),
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan),
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.items - 1);
+        }
+        let index = self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (index as u64).min(self.items - 1)
+    }
+}
+
+// A random string the values are cut from.
+#[derive(Clone)]
+struct Values(String);
+
+impl Values {
+    fn get(&self, size: usize) -> String {
+        self.0[..size].to_owned()
+    }
+}
@@ -0,0 +1,262 @@
+//! Dumps of the keys of a namespace, in line-delimited JSON or CSV.
+//!
+//! A JSON line holds a record, `{"key":"k","value":"v"}`, with a `null` value
+//! for a removed key. A CSV dump starts with a `key,value` header, and a
+//! removed key is a row with the key only. Removed keys only show up in the
+//! changes made during an export.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Stdout, Write};
+use std::path::Path;
+
+use futures::TryStreamExt;
+use kvs::{ExportChunk, KvsClient, KvsError, Record, Result};
+
+const PROGRESS_INTERVAL: u64 = 10_000;
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Format {
+        jsonl,
+        csv
+    }
+}
+
+impl Format {
+    /// Guesses the format of a file from its extension, JSON lines by default.
+    pub fn of(path: &Path) -> Format {
+        match path.extension() {
+            Some(ext) if ext == "csv" => Format::csv,
+            _ => Format::jsonl,
+        }
+    }
+}
+
+/// Writes the keys starting with `prefix` to the standard output.
+///
+/// `after` and `since` resume an interrupted export, which prints them on
+/// failure.
+pub async fn export(
+    client: KvsClient,
+    format: Format,
+    prefix: String,
+    after: Option<String>,
+    since: Option<u64>,
+) -> Result<()> {
+    // the header is already written by the interrupted export
+    let mut writer = Writer::new(format, after.is_none())?;
+    let mut progress = ExportProgress {
+        since,
+        last_key: after.clone(),
+        records: 0,
+    };
+    let res = progress.run(client, &mut writer, prefix, after).await;
+    if res.is_err() {
+        if let Some(since) = progress.since {
+            eprintln!(
+                "Exported {} records before the failure, resume with {}--since {}",
+                progress.records,
+                progress
+                    .last_key
+                    .map(|key| format!("--after {} ", shlex::try_quote(&key).unwrap_or_default()))
+                    .unwrap_or_default(),
+                since
+            );
+        }
+    }
+    res
+}
+
+struct ExportProgress {
+    since: Option<u64>,
+    // the greatest key written, every key before it is written
+    last_key: Option<String>,
+    records: u64,
+}
+
+impl ExportProgress {
+    async fn run(
+        &mut self,
+        client: KvsClient,
+        writer: &mut Writer,
+        prefix: String,
+        after: Option<String>,
+    ) -> Result<()> {
+        let mut chunks = Box::pin(client.export(prefix, after, self.since).await?);
+        while let Some(chunk) = chunks.try_next().await? {
+            match chunk {
+                ExportChunk::Started { seq } => {
+                    self.since.get_or_insert(seq);
+                }
+                ExportChunk::Records(records) => {
+                    for record in records {
+                        writer.write(&record)?;
+                        if self.last_key.as_ref().is_none_or(|key| record.key > *key) {
+                            self.last_key = Some(record.key);
+                        }
+                        self.records += 1;
+                        if self.records.is_multiple_of(PROGRESS_INTERVAL) {
+                            eprintln!("Exported {} records", self.records);
+                        }
+                    }
+                    writer.flush()?;
+                }
+                ExportChunk::Done { seq } => {
+                    eprintln!(
+                        "Exported {} records as of sequence number {}",
+                        self.records, seq
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        Err(KvsError::StringError(
+            "The export is interrupted".to_owned(),
+        ))
+    }
+}
+
+enum Writer {
+    Jsonl(BufWriter<Stdout>),
+    Csv(Box<csv::Writer<Stdout>>),
+}
+
+impl Writer {
+    fn new(format: Format, header: bool) -> Result<Writer> {
+        Ok(match format {
+            Format::jsonl => Writer::Jsonl(BufWriter::new(io::stdout())),
+            Format::csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .flexible(true)
+                    .from_writer(io::stdout());
+                if header {
+                    writer.write_record(["key", "value"]).map_err(csv_error)?;
+                }
+                Writer::Csv(Box::new(writer))
+            }
+        })
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        match self {
+            Writer::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+            Writer::Csv(writer) => match record.value {
+                Some(ref value) => writer.write_record([&record.key, value]),
+                None => writer.write_record([&record.key]),
+            }
+            .map_err(csv_error)?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Writer::Jsonl(writer) => writer.flush()?,
+            Writer::Csv(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Applies the records of a file, or of the standard input if it is `-`, in
+/// batches.
+///
+/// The first `skip` records are left out to resume an interrupted import,
+/// which prints the number to skip on failure.
+pub async fn import(
+    mut client: KvsClient,
+    path: &Path,
+    format: Format,
+    batch_size: usize,
+    skip: u64,
+) -> Result<()> {
+    if batch_size == 0 {
+        return Err(KvsError::StringError(
+            "The batch size must be positive".to_owned(),
+        ));
+    }
+    let input: Box<dyn Read> = if path.as_os_str() == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut imported = 0;
+    let res = async {
+        let mut records = read_records(BufReader::new(input), format).skip(skip as usize);
+        loop {
+            let batch = records
+                .by_ref()
+                .take(batch_size)
+                .collect::<Result<Vec<Record>>>()?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let len = batch.len() as u64;
+            client.import(batch).await?;
+            if imported / PROGRESS_INTERVAL != (imported + len) / PROGRESS_INTERVAL {
+                eprintln!("Imported {} records", imported + len);
+            }
+            imported += len;
+        }
+    }
+    .await;
+    match res {
+        Ok(()) => eprintln!("Imported {} records", imported),
+        Err(_) => eprintln!(
+            "Imported {} records before the failure, resume with --skip {}",
+            imported,
+            skip + imported
+        ),
+    }
+    res
+}
+
+fn read_records<'a, R: BufRead + 'a>(
+    input: R,
+    format: Format,
+) -> Box<dyn Iterator<Item = Result<Record>> + 'a> {
+    match format {
+        Format::jsonl => Box::new(
+            input
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                .map(|(i, line)| {
+                    serde_json::from_str(&line?)
+                        .map_err(|e| KvsError::StringError(format!("line {}: {}", i + 1, e)))
+                }),
+        ),
+        Format::csv => Box::new(
+            csv::ReaderBuilder::new()
+                .flexible(true)
+                .from_reader(input)
+                .into_records()
+                .map(|row| {
+                    let row = row.map_err(csv_error)?;
+                    let value = match row.len() {
+                        1 => None,
+                        2 => Some(row[1].to_owned()),
+                        _ => {
+                            let line = row.position().map_or(0, |pos| pos.line());
+                            return Err(KvsError::StringError(format!(
+                                "line {}: expected a key and an optional value",
+                                line
+                            )));
+                        }
+                    };
+                    Ok(Record {
+                        key: row[0].to_owned(),
+                        value,
+                    })
+                }),
+        ),
+    }
+}
+
+fn csv_error(e: csv::Error) -> KvsError {
+    KvsError::StringError(format!("{}", e))
+}
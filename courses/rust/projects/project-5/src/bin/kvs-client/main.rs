@@ -0,0 +1,562 @@
+#[macro_use]
+extern crate clap;
+
+use clap::AppSettings;
+use dump::Format;
+use futures::TryStreamExt;
+use kvs::{KvsClient, KvsError, ReplicationStatus, Result};
+use script::{Line, Session};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::time;
+
+mod dump;
+mod script;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-client",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
+struct Opt {
+    #[structopt(
+        long,
+        help = "Sets the output format",
+        value_name = "FORMAT",
+        default_value = "text",
+        raw(possible_values = "&Output::variants()", global = "true")
+    )]
+    output: Output,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum Output {
+        text,
+        json
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    Get {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long = "timeout-ms",
+            help = "Gives up if the server can't start the get in time",
+            value_name = "MS"
+        )]
+        timeout_ms: Option<u64>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "The string value of the key")]
+        value: String,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "watch",
+        about = "Print the changes of keys starting with a given prefix"
+    )]
+    Watch {
+        #[structopt(name = "PREFIX", help = "A string prefix", default_value = "")]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Resumes after the given sequence number",
+            value_name = "SEQ"
+        )]
+        after: Option<u64>,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "promote", about = "Promote a replica to a primary")]
+    Promote {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "replication",
+        about = "Print the replication role and lag of a server"
+    )]
+    Replication {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "namespaces", about = "List the namespaces holding keys")]
+    Namespaces {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "drop-namespace",
+        about = "Remove all the keys of a given namespace"
+    )]
+    DropNamespace {
+        #[structopt(name = "NAMESPACE", help = "A namespace")]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "stats",
+        about = "Print the number of keys and the size of a namespace"
+    )]
+    Stats {
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "shell",
+        about = "Run commands typed interactively over one connection"
+    )]
+    Shell {
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "exec",
+        about = "Run the commands of a script over one connection"
+    )]
+    Exec {
+        #[structopt(
+            short = "f",
+            long,
+            help = "Reads the script from a file, or the standard input if `-`",
+            value_name = "FILE",
+            parse(from_os_str)
+        )]
+        file: Option<PathBuf>,
+        #[structopt(long = "keep-going", help = "Runs the script past failed commands")]
+        keep_going: bool,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "export",
+        about = "Write the keys starting with a given prefix to the standard output"
+    )]
+    Export {
+        #[structopt(
+            long,
+            help = "Sets the format of the dump",
+            value_name = "FORMAT",
+            default_value = "jsonl",
+            raw(possible_values = "&Format::variants()")
+        )]
+        format: Format,
+        #[structopt(long, help = "Sets the key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Resumes an export after the given key",
+            value_name = "KEY"
+        )]
+        after: Option<String>,
+        #[structopt(
+            long,
+            help = "Resumes an export started at the given sequence number",
+            value_name = "SEQ"
+        )]
+        since: Option<u64>,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "import", about = "Load the keys of a dump written by export")]
+    Import {
+        #[structopt(
+            name = "FILE",
+            help = "The dump, or `-` for the standard input",
+            parse(from_os_str)
+        )]
+        file: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the format of the dump, guessed from the file extension by default",
+            value_name = "FORMAT",
+            raw(possible_values = "&Format::variants()")
+        )]
+        format: Option<Format>,
+        #[structopt(
+            long = "batch-size",
+            help = "Sets the number of records written at once",
+            value_name = "N",
+            default_value = "100"
+        )]
+        batch_size: usize,
+        #[structopt(
+            long,
+            help = "Resumes an import after the given number of records",
+            value_name = "N",
+            default_value = "0"
+        )]
+        skip: u64,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "rm", about = "Remove a given string key")]
+    Remove {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            short = "n",
+            long,
+            help = "Sets the namespace",
+            value_name = "NAMESPACE",
+            default_value = ""
+        )]
+        namespace: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let opt = Opt::from_args();
+    let output = opt.output;
+    if let Err(e) = run(opt).await {
+        match output {
+            Output::text => eprintln!("{}", e),
+            Output::json => println!("{}", serde_json::json!({ "error": format!("{}", e) })),
+        }
+        exit(1);
+    }
+}
+
+async fn run(opt: Opt) -> Result<()> {
+    let output = opt.output;
+    match opt.command {
+        Command::Get {
+            key,
+            namespace,
+            timeout_ms,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr)
+                .await?
+                .timeout(timeout_ms.map(Duration::from_millis));
+            run_session(client, namespace, Line::Get(key), output).await?
+        }
+        Command::Set {
+            key,
+            value,
+            namespace,
+            addr,
+        } => run_line(addr, namespace, Line::Set(key, value), output).await?,
+        Command::Remove {
+            key,
+            namespace,
+            addr,
+        } => run_line(addr, namespace, Line::Remove(key), output).await?,
+        Command::Namespaces { addr } => {
+            run_line(addr, String::new(), Line::Namespaces, output).await?
+        }
+        Command::DropNamespace { namespace, addr } => {
+            let line = Line::DropNamespace(namespace.clone());
+            run_line(addr, namespace, line, output).await?
+        }
+        Command::Stats { namespace, addr } => {
+            run_line(addr, namespace, Line::Stats, output).await?
+        }
+        Command::Shell { namespace, addr } => {
+            let client = KvsClient::connect(addr).await?;
+            Session::new(client, namespace, output).shell().await?;
+        }
+        Command::Exec {
+            file,
+            keep_going,
+            namespace,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr).await?;
+            let mut session = Session::new(client, namespace, output);
+            match file {
+                Some(ref file) if file.as_os_str() != "-" => {
+                    let script = BufReader::new(File::open(file)?);
+                    session.exec(script, keep_going).await?;
+                }
+                _ => session.exec(io::stdin().lock(), keep_going).await?,
+            }
+        }
+        Command::Export {
+            format,
+            prefix,
+            after,
+            since,
+            namespace,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr).await?.namespace(namespace);
+            dump::export(client, format, prefix, after, since).await?;
+        }
+        Command::Import {
+            file,
+            format,
+            batch_size,
+            skip,
+            namespace,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr).await?.namespace(namespace);
+            let format = format.unwrap_or_else(|| Format::of(&file));
+            dump::import(client, &file, format, batch_size, skip).await?;
+        }
+        Command::Promote { addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            client.promote().await?;
+        }
+        Command::Replication { addr } => {
+            let mut client = KvsClient::connect(addr).await?;
+            let status = client.replication_status().await?;
+            match output {
+                Output::text => print_status(&status),
+                Output::json => println!("{}", serde_json::to_string(&status)?),
+            }
+        }
+        Command::Watch {
+            prefix,
+            mut after,
+            namespace,
+            addr,
+        } => loop {
+            // Reconnect and resume from the last event if the connection is lost
+            match watch(addr, &namespace, prefix.clone(), &mut after, output).await {
+                Err(KvsError::Io(e)) => eprintln!("Connection lost: {}", e),
+                Err(e) => return Err(e),
+                Ok(()) => {}
+            }
+            time::sleep(RECONNECT_INTERVAL).await;
+        },
+    }
+    Ok(())
+}
+
+// Runs a single command and prints its result.
+async fn run_line(addr: SocketAddr, namespace: String, line: Line, output: Output) -> Result<()> {
+    run_session(KvsClient::connect(addr).await?, namespace, line, output).await
+}
+
+async fn run_session(
+    client: KvsClient,
+    namespace: String,
+    line: Line,
+    output: Output,
+) -> Result<()> {
+    let mut session = Session::new(client, namespace, output);
+    let reply = session.run(line).await?;
+    session.print(&Ok(reply), None);
+    Ok(())
+}
+
+// Prints the events until the connection is closed, updating `after` to the
+// last printed sequence number.
+async fn watch(
+    addr: SocketAddr,
+    namespace: &str,
+    prefix: String,
+    after: &mut Option<u64>,
+    output: Output,
+) -> Result<()> {
+    let client = KvsClient::connect(addr).await?.namespace(namespace);
+    let mut events = Box::pin(client.watch(prefix, *after).await?);
+    while let Some(event) = events.try_next().await? {
+        match (output, &event.value) {
+            (Output::json, _) => println!("{}", serde_json::to_string(&event)?),
+            (Output::text, Some(value)) => println!("{} set {} {}", event.seq, event.key, value),
+            (Output::text, None) => println!("{} rm {}", event.seq, event.key),
+        }
+        *after = Some(event.seq);
+    }
+    Ok(())
+}
+
+fn print_status(status: &ReplicationStatus) {
+    match *status {
+        ReplicationStatus::Primary { last_seq } => {
+            println!("role: primary");
+            println!("last_seq: {}", last_seq);
+        }
+        ReplicationStatus::Replica {
+            primary,
+            connected,
+            synced,
+            applied_seq,
+            primary_seq,
+            last_contact_ms,
+        } => {
+            println!("role: replica");
+            println!("primary: {}", primary);
+            println!("connected: {}", connected);
+            println!("synced: {}", synced);
+            println!("applied_seq: {}", applied_seq);
+            println!("primary_seq: {}", primary_seq);
+            println!("lag: {}", status.lag());
+            match last_contact_ms {
+                Some(ms) => println!("last_contact_ms: {}", ms),
+                None => println!("last_contact_ms: never"),
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Runs commands over a single connection, typed in a shell or read from a script.
+//!
+//! A script has one command per line, with the words split like in a POSIX
+//! shell. Blank lines and lines starting with `#` are skipped:
+//!
+//! ```text
+//! use users
+//! set "user 1" '{"name": "Alice"}'
+//! get "user 1"
+//! ```
+
+use std::env;
+use std::io::{self, BufRead, IsTerminal};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use kvs::{KvsClient, KvsError, NamespaceStats, Result};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::json;
+
+use crate::Output;
+
+const HISTORY_FILE: &str = ".kvs_client_history";
+
+const HELP: &str = "\
+get KEY              Get the value of a key
+set KEY VALUE        Set the value of a key
+rm KEY               Remove a key
+use NAMESPACE        Switch to a namespace, \"\" for the default one
+namespaces           List the namespaces holding keys
+stats                Print the number of keys and the size of the namespace
+drop-namespace NAME  Remove all the keys of a namespace
+help                 Print this help
+exit                 Leave the shell";
+
+/// A command of a script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+    Use(String),
+    Namespaces,
+    Stats,
+    DropNamespace(String),
+    Help,
+    Exit,
+}
+
+impl Line {
+    /// Parses a line of a script, returning `None` for a blank line or a comment.
+    pub fn parse(line: &str) -> Result<Option<Line>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let words = shlex::split(line)
+            .ok_or_else(|| KvsError::StringError("Unbalanced quotes".to_owned()))?;
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let line = match words[..] {
+            ["get", key] => Line::Get(key.to_owned()),
+            ["set", key, value] => Line::Set(key.to_owned(), value.to_owned()),
+            ["rm", key] => Line::Remove(key.to_owned()),
+            ["use", namespace] => Line::Use(namespace.to_owned()),
+            ["namespaces"] => Line::Namespaces,
+            ["stats"] => Line::Stats,
+            ["drop-namespace", namespace] => Line::DropNamespace(namespace.to_owned()),
+            ["help"] => Line::Help,
+            ["exit"] | ["quit"] => Line::Exit,
+            _ => {
+                return Err(KvsError::StringError(format!(
+                    "Invalid command: {}, see help",
+                    line
+                )))
+            }
+        };
+        Ok(Some(line))
+    }
+}
+
+/// The result of a command.
+#[derive(Debug)]
+pub enum Reply {
+    Value { key: String, value: Option<String> },
+    Done,
+    Namespaces(Vec<String>),
+    Stats(NamespaceStats),
+    Help,
+}
+
+/// A connection running commands in its current namespace.
+pub struct Session {
+    client: KvsClient,
+    namespace: String,
+    output: Output,
+}
+
+impl Session {
+    pub fn new(client: KvsClient, namespace: String, output: Output) -> Session {
+        Session {
+            client: client.namespace(namespace.clone()),
+            namespace,
+            output,
+        }
+    }
+
+    /// Runs a command other than `exit`.
+    pub async fn run(&mut self, line: Line) -> Result<Reply> {
+        let reply = match line {
+            Line::Get(key) => {
+                let value = self.client.get(key.clone()).await?;
+                Reply::Value { key, value }
+            }
+            Line::Set(key, value) => {
+                self.client.set(key, value).await?;
+                Reply::Done
+            }
+            Line::Remove(key) => {
+                self.client.remove(key).await?;
+                Reply::Done
+            }
+            Line::Use(namespace) => {
+                self.client.set_namespace(namespace.clone());
+                self.namespace = namespace;
+                Reply::Done
+            }
+            Line::Namespaces => Reply::Namespaces(self.client.namespaces().await?),
+            Line::Stats => Reply::Stats(self.client.stats().await?),
+            Line::DropNamespace(namespace) => {
+                self.client.set_namespace(namespace);
+                let res = self.client.drop_namespace().await;
+                self.client.set_namespace(self.namespace.clone());
+                res?;
+                Reply::Done
+            }
+            Line::Help => Reply::Help,
+            Line::Exit => unreachable!("exit is handled by the caller"),
+        };
+        Ok(reply)
+    }
+
+    /// Prints the result of a command, with the time it took if given.
+    pub fn print(&self, res: &Result<Reply>, elapsed: Option<Duration>) {
+        match self.output {
+            Output::text => {
+                match res {
+                    Ok(reply) => print_text(reply),
+                    Err(e) => eprintln!("{}", e),
+                }
+                if let Some(elapsed) = elapsed {
+                    println!("({:.3} ms)", millis(elapsed));
+                }
+            }
+            Output::json => {
+                let mut value = match res {
+                    Ok(reply) => reply_json(reply),
+                    Err(e) => json!({ "error": format!("{}", e) }),
+                };
+                if let Some(elapsed) = elapsed {
+                    value["elapsed_ms"] = json!(millis(elapsed));
+                }
+                println!("{}", value);
+            }
+        }
+    }
+
+    /// Reads commands from the terminal until `exit` or end of input.
+    ///
+    /// The history is kept in `~/.kvs_client_history`. If the standard input
+    /// is not a terminal, it is run as a script instead.
+    pub async fn shell(&mut self) -> Result<()> {
+        if !io::stdin().is_terminal() {
+            return self.exec(io::stdin().lock(), false).await;
+        }
+        let mut editor = DefaultEditor::new().map_err(readline_error)?;
+        let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(ref history) = history {
+            // there is no history on the first run
+            let _ = editor.load_history(history);
+        }
+        loop {
+            let prompt = if self.namespace.is_empty() {
+                "kvs> ".to_owned()
+            } else {
+                format!("kvs:{}> ", self.namespace)
+            };
+            let input = match editor.readline(&prompt) {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(readline_error(e)),
+            };
+            let _ = editor.add_history_entry(input.as_str());
+            let line = match Line::parse(&input) {
+                Ok(Some(Line::Exit)) => break,
+                Ok(Some(line)) => line,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.print(&Err(e), None);
+                    continue;
+                }
+            };
+            let start = Instant::now();
+            let res = self.run(line).await;
+            self.print(&res, Some(start.elapsed()));
+            if let Err(KvsError::Io(_)) = res {
+                // the connection is lost
+                break;
+            }
+        }
+        if let Some(ref history) = history {
+            if let Err(e) = editor.save_history(history) {
+                eprintln!("Failed to save the history: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the commands of a script until `exit` or the end of the script.
+    ///
+    /// It stops at the first failed command unless `keep_going` is set, and
+    /// then fails with the line number of the command.
+    pub async fn exec<R: BufRead>(&mut self, script: R, keep_going: bool) -> Result<()> {
+        let mut failed = None;
+        for (i, input) in script.lines().enumerate() {
+            let line_no = i + 1;
+            let res = match Line::parse(&input?) {
+                Ok(Some(Line::Exit)) => break,
+                Ok(Some(line)) => self.run(line).await,
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(_) => self.print(&res, None),
+                Err(e) => {
+                    let e = KvsError::StringError(format!("line {}: {}", line_no, e));
+                    if !keep_going {
+                        return Err(e);
+                    }
+                    self.print(&Err(e), None);
+                    failed.get_or_insert(line_no);
+                }
+            }
+        }
+        match failed {
+            Some(line_no) => Err(KvsError::StringError(format!(
+                "The script failed, first at line {}",
+                line_no
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn print_text(reply: &Reply) {
+    match reply {
+        Reply::Value {
+            value: Some(value), ..
+        } => println!("{}", value),
+        Reply::Value { value: None, .. } => println!("Key not found"),
+        Reply::Done => {}
+        Reply::Namespaces(namespaces) => {
+            for namespace in namespaces {
+                if namespace.is_empty() {
+                    println!("(default)");
+                } else {
+                    println!("{}", namespace);
+                }
+            }
+        }
+        Reply::Stats(stats) => {
+            println!("keys: {}", stats.keys);
+            println!("bytes: {}", stats.bytes);
+        }
+        Reply::Help => println!("{}", HELP),
+    }
+}
+
+fn reply_json(reply: &Reply) -> serde_json::Value {
+    match reply {
+        Reply::Value { key, value } => json!({ "key": key, "value": value }),
+        Reply::Done => json!({ "ok": true }),
+        Reply::Namespaces(namespaces) => json!({ "namespaces": namespaces }),
+        Reply::Stats(stats) => json!(stats),
+        Reply::Help => json!({ "help": HELP }),
+    }
+}
+
+fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+fn readline_error(e: ReadlineError) -> KvsError {
+    match e {
+        ReadlineError::Io(e) => KvsError::Io(e),
+        e => KvsError::StringError(format!("{}", e)),
+    }
+}
@@ -11,10 +11,16 @@ use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio::runtime::Runtime;
+use tokio::{signal, time};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_PROTOCOL: Protocol = Protocol::kvs;
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const METRICS_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
@@ -34,6 +40,39 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets the wire protocol",
+        value_name = "PROTOCOL-NAME",
+        raw(possible_values = "&Protocol::variants()")
+    )]
+    protocol: Option<Protocol>,
+    #[structopt(
+        long = "http-addr",
+        help = "Sets the listening address of the HTTP gateway",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "replica-of",
+        help = "Replicates from the primary at the given address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    replica_of: Option<SocketAddr>,
+    #[structopt(
+        long = "queue-capacity",
+        help = "Answers \"server busy\" when this many requests wait for a thread",
+        value_name = "N"
+    )]
+    queue_capacity: Option<usize>,
+    #[structopt(
+        long = "priority",
+        help = "Runs gets ahead of writes and scans",
+        raw(conflicts_with = "\"queue_capacity\"")
+    )]
+    priority: bool,
 }
 
 arg_enum! {
@@ -45,6 +84,15 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Protocol {
+        kvs,
+        resp
+    }
+}
+
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
@@ -66,32 +114,141 @@ fn main() {
 
 fn run(opt: Opt) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let protocol = opt.protocol.unwrap_or(DEFAULT_PROTOCOL);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
+    info!("Protocol: {}", protocol);
+    if let Some(http_addr) = opt.http_addr {
+        info!("HTTP gateway listening on {}", http_addr);
+    }
+    if let Some(primary) = opt.replica_of {
+        info!("Replica of {}", primary);
+    }
+    if let Some(capacity) = opt.queue_capacity {
+        info!("Queue capacity: {}", capacity);
+    }
     info!("Listening on {}", opt.addr);
 
     // write engine to engine file
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
     let concurrency = num_cpus::get() as u32;
+    match opt.queue_capacity {
+        Some(capacity) => {
+            let pool =
+                SharedQueueThreadPool::bounded(concurrency, capacity, QueueFullPolicy::Reject)?;
+            run_engine(engine, pool, concurrency, opt, protocol)
+        }
+        None if opt.priority => {
+            let pool = PriorityThreadPool::new(concurrency)?;
+            run_engine(engine, pool, concurrency, opt, protocol)
+        }
+        None => {
+            let pool = RayonThreadPool::new(concurrency)?;
+            run_engine(engine, pool, concurrency, opt, protocol)
+        }
+    }
+}
+
+fn run_engine<P: ThreadPool>(
+    engine: Engine,
+    pool: P,
+    concurrency: u32,
+    opt: Opt,
+    protocol: Protocol,
+) -> Result<()> {
     match engine {
-        Engine::kvs => run_with(
-            KvStore::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
-            opt.addr,
-        ),
-        Engine::sled => run_with(
-            SledKvsEngine::<RayonThreadPool>::new(
-                sled::Db::start_default(env::current_dir()?)?,
-                concurrency,
-            )?,
-            opt.addr,
-        ),
+        Engine::kvs => {
+            let engine = KvStore::open_with_pool(env::current_dir()?, concurrency, pool.clone())?;
+            run_with(engine, pool, opt, protocol)
+        }
+        Engine::sled => {
+            let db = sled::Db::start_default(env::current_dir()?)?;
+            run_with(
+                SledKvsEngine::with_pool(db, pool.clone()),
+                pool,
+                opt,
+                protocol,
+            )
+        }
+    }
+}
+
+fn run_with<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    pool: P,
+    opt: Opt,
+    protocol: Protocol,
+) -> Result<()> {
+    let mut server = KvsServer::new(engine);
+    if let Some(http_addr) = opt.http_addr {
+        server = server.with_http(http_addr);
+    }
+    match opt.replica_of {
+        Some(primary) => start(server.replica_of(primary), pool, opt.addr, protocol),
+        None => start(server, pool, opt.addr, protocol),
     }
 }
 
-pub fn run_with<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+fn start<E: KvsEngine, P: ThreadPool>(
+    server: KvsServer<E>,
+    pool: P,
+    addr: SocketAddr,
+    protocol: Protocol,
+) -> Result<()> {
+    let runtime = Runtime::new()?;
+    runtime.block_on(async {
+        tokio::spawn(report_saturation(pool.clone()));
+        let serving = async {
+            match protocol {
+                Protocol::kvs => server.run(addr).await,
+                Protocol::resp => server.run_resp(addr).await,
+            }
+        };
+        tokio::select! {
+            res = serving => res,
+            res = shutdown_signal() => res,
+        }
+    })?;
+
+    info!("Shutting down");
+    // close the connections first, then let the running operations finish
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    if !pool.shutdown(SHUTDOWN_TIMEOUT) {
+        warn!("Operations still running after {:?}", SHUTDOWN_TIMEOUT);
+    }
+    info!("Thread pool: {:?}", pool.metrics());
+    Ok(())
+}
+
+/// Resolves when the process is asked to terminate.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            res = signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Logs the state of the thread pool, warning when every thread is busy and
+/// operations are waiting.
+async fn report_saturation<P: ThreadPool>(pool: P) {
+    let mut interval = time::interval(METRICS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let metrics = pool.metrics();
+        if metrics.is_saturated() {
+            warn!("Thread pool is saturated: {:?}", metrics);
+        } else {
+            debug!("Thread pool: {:?}", metrics);
+        }
+    }
 }
 
 fn current_engine() -> Result<Option<Engine>> {
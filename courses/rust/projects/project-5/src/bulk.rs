@@ -0,0 +1,152 @@
+//! Bulk export and import of the keys of a namespace.
+//!
+//! A client sends `Request::Export` and the server answers with
+//! `ExportChunk::Started`, the entries with the requested prefix in ascending
+//! key order, the changes made to those keys while the entries were read, and
+//! `ExportChunk::Done`. Applying the records in order gives the keys as they
+//! were at the sequence number of `Done`, even if they were written to during
+//! the export.
+//!
+//! An interrupted export is resumed by asking for the keys after the last
+//! received one, since the sequence number of the first `Started`. The changes
+//! are then replayed from that sequence number, as long as the server still
+//! retains them.
+//!
+//! `Request::Import` applies a batch of records in order.
+
+use futures::stream::{self, BoxStream};
+use futures::{future, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::common::Response;
+use crate::{KvsEngine, KvsError, Result};
+
+const EXPORT_CHUNK_SIZE: usize = 100;
+
+/// A key and its value, or `None` if the key is removed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// The key.
+    pub key: String,
+    /// The value of the key, or `None` if it is removed.
+    pub value: Option<String>,
+}
+
+/// A part of the response to an export.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExportChunk {
+    /// The export reads the keys as of this sequence number.
+    Started {
+        /// Sequence number to resume the export from.
+        seq: u64,
+    },
+    /// Records to apply in order.
+    Records(Vec<Record>),
+    /// The records so far give the keys as of this sequence number.
+    Done {
+        /// Sequence number of the last change in the export.
+        seq: u64,
+    },
+}
+
+/// Returns the stream of responses to a `Request::Export`.
+pub(crate) fn export<E: KvsEngine>(
+    engine: E,
+    namespace: String,
+    prefix: String,
+    after: Option<String>,
+    since: Option<u64>,
+) -> BoxStream<'static, Result<Response>> {
+    // Subscribe before reading the keys, to all the namespaces so that every
+    // sequence number is seen.
+    let since = since.unwrap_or_else(|| engine.last_seq());
+    let mut changes = engine.watch_all(Some(since));
+
+    // the keys are read a page at a time, resuming after the last key of the
+    // previous page.
+    let snapshot_engine = engine.clone();
+    let snapshot_prefix = prefix.clone();
+    let snapshot = stream::try_unfold(Some(after), move |cursor| {
+        let engine = snapshot_engine.clone();
+        let prefix = snapshot_prefix.clone();
+        async move {
+            let after = match cursor {
+                Some(after) => after,
+                None => return Ok(None),
+            };
+            let keys = engine.scan_page(prefix, after, EXPORT_CHUNK_SIZE).await?;
+            if keys.is_empty() {
+                return Ok(None);
+            }
+            let next = match keys.len() {
+                EXPORT_CHUNK_SIZE => Some(keys.last().cloned()),
+                _ => None,
+            };
+            let records = read_records(engine, keys).await?;
+            Ok::<_, KvsError>(Some((records, next)))
+        }
+    })
+    .try_filter(|records| future::ready(!records.is_empty()))
+    .map_ok(ExportChunk::Records);
+
+    // the changes are read once all the entries are sent
+    let replay = stream::once(async move {
+        let last_seq = engine.last_seq();
+        let mut records = Vec::new();
+        if last_seq > since {
+            while let Some(event) = changes.try_next().await? {
+                if event.namespace == namespace && event.key.starts_with(&prefix) {
+                    records.push(Record {
+                        key: event.key,
+                        value: event.value,
+                    });
+                }
+                if event.seq >= last_seq {
+                    break;
+                }
+            }
+        }
+        let chunks: Vec<Result<ExportChunk>> = records
+            .chunks(EXPORT_CHUNK_SIZE)
+            .map(|chunk| Ok(ExportChunk::Records(chunk.to_vec())))
+            .chain(Some(Ok(ExportChunk::Done { seq: last_seq })))
+            .collect();
+        Ok::<_, KvsError>(stream::iter(chunks))
+    })
+    .try_flatten();
+
+    stream::once(future::ready(Ok(ExportChunk::Started { seq: since })))
+        .chain(snapshot)
+        .chain(replay)
+        .map_ok(Response::Export)
+        .boxed()
+}
+
+async fn read_records<E: KvsEngine>(engine: E, keys: Vec<String>) -> Result<Vec<Record>> {
+    let values = future::try_join_all(keys.iter().map(|key| engine.get(key.clone()))).await?;
+    let records = keys
+        .into_iter()
+        .zip(values)
+        // skip the keys removed since they were listed
+        .filter(|(_, value)| value.is_some())
+        .map(|(key, value)| Record { key, value })
+        .collect();
+    Ok(records)
+}
+
+/// Applies the records of a `Request::Import` in order.
+///
+/// Removing a key that does not exist is not an error, so that a batch can be
+/// applied again.
+pub(crate) async fn import<E: KvsEngine>(engine: E, records: Vec<Record>) -> Result<()> {
+    for record in records {
+        match record.value {
+            Some(value) => engine.set(record.key, value).await?,
+            None => match engine.remove(record.key).await {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+    }
+    Ok(())
+}
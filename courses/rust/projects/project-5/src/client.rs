@@ -1,88 +1,234 @@
-use crate::common::{Request, Response};
-use crate::KvsError;
+use crate::common::{JsonCodec, Request, Response};
+use crate::{Event, ExportChunk, KvsError, NamespaceStats, Record, ReplicationStatus, Result};
+use futures::{future, SinkExt, Stream, StreamExt, TryStreamExt};
 use std::net::SocketAddr;
-use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use tokio::io::{ReadHalf, WriteHalf};
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio::prelude::*;
-use tokio_serde_json::{ReadJson, WriteJson};
+use tokio_util::codec::Framed;
 
 /// Key value store client
+///
+/// Keys are read and written in the default namespace unless another one is
+/// chosen with `namespace`.
 pub struct KvsClient {
-    read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>,
-    write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>,
+    framed: Framed<TcpStream, JsonCodec<Response, Request>>,
+    namespace: String,
+    timeout: Option<Duration>,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
-    pub fn connect(addr: SocketAddr) -> impl Future<Item = Self, Error = KvsError> {
-        TcpStream::connect(&addr)
-            .map(|tcp| {
-                let (read_half, write_half) = tcp.split();
-                let read_json =
-                    ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-                let write_json =
-                    WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
-                KvsClient {
-                    read_json,
-                    write_json,
-                }
-            })
-            .map_err(|e| e.into())
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let tcp = TcpStream::connect(addr).await?;
+        Ok(KvsClient {
+            framed: Framed::new(tcp, JsonCodec::default()),
+            namespace: String::new(),
+            timeout: None,
+        })
+    }
+
+    /// Use the given namespace for the following requests.
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.set_namespace(namespace);
+        self
+    }
+
+    /// Switch to the given namespace for the following requests.
+    pub fn set_namespace(&mut self, namespace: impl Into<String>) {
+        self.namespace = namespace.into();
+    }
+
+    /// Give up on the following gets the server can't start within `timeout`.
+    ///
+    /// The server then drops them, and they fail with
+    /// `KvsError::DeadlineExceeded`.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
     }
 
     /// Get the value of a given key from the server.
-    pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
-        self.send_request(Request::Get { key })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Get(value)) => Ok((value, client)),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
-            })
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        let namespace = self.namespace.clone();
+        let timeout_ms = self.timeout.map(|timeout| timeout.as_millis() as u64);
+        let req = Request::Get {
+            namespace,
+            key,
+            timeout_ms,
+        };
+        match self.send_request(req).await? {
+            Response::Get(value) => Ok(value),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
     }
 
     /// Set the value of a string key in the server.
-    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Set { key, value })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Set) => Ok(client),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        let namespace = self.namespace.clone();
+        match self
+            .send_request(Request::Set {
+                namespace,
+                key,
+                value,
             })
+            .await?
+        {
+            Response::Set => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
     }
 
     /// Remove a string key in the server.
-    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Remove { key })
-            .and_then(move |(resp, client)| match resp {
-                Some(Response::Remove) => Ok(client),
-                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
-                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        let namespace = self.namespace.clone();
+        match self
+            .send_request(Request::Remove { namespace, key })
+            .await?
+        {
+            Response::Remove => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Watch the changes of keys starting with the given prefix.
+    ///
+    /// If `after` is given, the server first replays its recent changes after
+    /// that sequence number.
+    pub async fn watch(
+        mut self,
+        prefix: String,
+        after: Option<u64>,
+    ) -> Result<impl Stream<Item = Result<Event>>> {
+        let namespace = self.namespace.clone();
+        self.framed
+            .send(Request::Watch {
+                namespace,
+                prefix,
+                after,
             })
+            .await?;
+        Ok(self.framed.and_then(|resp| async move {
+            match resp {
+                Response::Event(event) => Ok(event),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            }
+        }))
+    }
+
+    /// Promote the server from a replica to a primary.
+    pub async fn promote(&mut self) -> Result<()> {
+        match self.send_request(Request::Promote).await? {
+            Response::Promote => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Get the replication role and progress of the server.
+    pub async fn replication_status(&mut self) -> Result<ReplicationStatus> {
+        match self.send_request(Request::ReplicationStatus).await? {
+            Response::ReplicationStatus(status) => Ok(status),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// List the namespaces of the server holding keys.
+    pub async fn namespaces(&mut self) -> Result<Vec<String>> {
+        match self.send_request(Request::Namespaces).await? {
+            Response::Namespaces(namespaces) => Ok(namespaces),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Remove all the keys of the namespace in the server.
+    pub async fn drop_namespace(&mut self) -> Result<()> {
+        let namespace = self.namespace.clone();
+        match self
+            .send_request(Request::DropNamespace { namespace })
+            .await?
+        {
+            Response::DropNamespace => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Get the number of keys and the size of the namespace in the server.
+    pub async fn stats(&mut self) -> Result<NamespaceStats> {
+        let namespace = self.namespace.clone();
+        match self.send_request(Request::Stats { namespace }).await? {
+            Response::Stats(stats) => Ok(stats),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
     }
 
-    fn send_request(
-        self,
-        req: Request,
-    ) -> impl Future<Item = (Option<Response>, Self), Error = KvsError> {
-        let read_json = self.read_json;
-        self.write_json
-            .send(req)
-            .and_then(move |write_json| {
-                read_json
-                    .into_future()
-                    .map(move |(resp, read_json)| {
-                        let client = KvsClient {
-                            read_json,
-                            write_json,
-                        };
-                        (resp, client)
-                    })
-                    .map_err(|(err, _)| err)
+    /// Export the keys starting with the given prefix. See `ExportChunk`.
+    ///
+    /// An interrupted export is resumed with the last received key as `after`
+    /// and the sequence number of `ExportChunk::Started` as `since`. The stream
+    /// ends after `ExportChunk::Done`.
+    pub async fn export(
+        mut self,
+        prefix: String,
+        after: Option<String>,
+        since: Option<u64>,
+    ) -> Result<impl Stream<Item = Result<ExportChunk>>> {
+        let namespace = self.namespace.clone();
+        self.framed
+            .send(Request::Export {
+                namespace,
+                prefix,
+                after,
+                since,
             })
-            .map_err(|e| e.into())
+            .await?;
+        let chunks = self.framed.map(|resp| match resp? {
+            Response::Export(chunk) => Ok(chunk),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        });
+        Ok(chunks.scan(false, |done, chunk| {
+            if *done {
+                return future::ready(None);
+            }
+            *done = matches!(chunk, Ok(ExportChunk::Done { .. }));
+            future::ready(Some(chunk))
+        }))
+    }
+
+    /// Apply a batch of records in order in the server.
+    pub async fn import(&mut self, records: Vec<Record>) -> Result<()> {
+        let namespace = self.namespace.clone();
+        match self
+            .send_request(Request::Import { namespace, records })
+            .await?
+        {
+            Response::Import => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Start replicating from the server, resuming after `applied_seq` if
+    /// `epoch` is still the one of the server. See `crate::replication`.
+    pub(crate) async fn replicate(
+        mut self,
+        epoch: Option<u64>,
+        applied_seq: u64,
+    ) -> Result<impl Stream<Item = Result<Response>>> {
+        self.framed
+            .send(Request::Replicate { epoch, applied_seq })
+            .await?;
+        Ok(self.framed.map(|resp| match resp {
+            Ok(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+            resp => resp,
+        }))
+    }
+
+    async fn send_request(&mut self, req: Request) -> Result<Response> {
+        self.framed.send(req).await?;
+        match self.framed.next().await {
+            Some(Ok(Response::Err(msg))) => Err(KvsError::StringError(msg)),
+            Some(resp) => resp,
+            None => Err(KvsError::StringError("No response received".to_owned())),
+        }
     }
 }
@@ -1,10 +1,80 @@
+use std::marker::PhantomData;
+
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+use crate::{
+    Event, ExportChunk, KvsError, LogRecord, NamespaceStats, Record, ReplicationStatus,
+};
 
+/// The `namespace` fields are left out for the default namespace.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        key: String,
+        /// How long the client waits for the value. The server drops the get
+        /// if it can't start it in time.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_ms: Option<u64>,
+    },
+    Set {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        key: String,
+        value: String,
+    },
+    Remove {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        key: String,
+    },
+    /// Turns the connection into a stream of `Response::Event`s.
+    Watch {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        prefix: String,
+        after: Option<u64>,
+    },
+    /// Turns the connection into a replication stream: `Response::SyncRecords`
+    /// until `Response::Synced`, or `Response::Resumed`, followed by
+    /// `Response::Record`s and `Response::Heartbeat`s.
+    Replicate {
+        /// The epoch of the primary the replica synced with before, if any.
+        #[serde(default)]
+        epoch: Option<u64>,
+        /// The sequence number of the latest change of that primary applied
+        /// by the replica.
+        #[serde(default)]
+        applied_seq: u64,
+    },
+    Promote,
+    ReplicationStatus,
+    Namespaces,
+    DropNamespace {
+        namespace: String,
+    },
+    Stats {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+    },
+    /// Answered by `Response::Export`s, from `ExportChunk::Started` to
+    /// `ExportChunk::Done`.
+    Export {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        prefix: String,
+        after: Option<String>,
+        since: Option<u64>,
+    },
+    Import {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        records: Vec<Record>,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,5 +82,62 @@ pub enum Response {
     Get(Option<String>),
     Set,
     Remove,
+    Event(Event),
+    SyncRecords(Vec<LogRecord>),
+    Synced {
+        #[serde(default)]
+        epoch: u64,
+        seq: u64,
+    },
+    Resumed {
+        seq: u64,
+    },
+    Record(LogRecord),
+    Heartbeat {
+        seq: u64,
+    },
+    Promote,
+    ReplicationStatus(ReplicationStatus),
+    Namespaces(Vec<String>),
+    DropNamespace,
+    Stats(NamespaceStats),
+    Export(ExportChunk),
+    Import,
     Err(String),
 }
+
+/// Length-delimited JSON frames, decoding `D`s and encoding `E`s.
+pub struct JsonCodec<D, E> {
+    inner: LengthDelimitedCodec,
+    _marker: PhantomData<fn(E) -> D>,
+}
+
+impl<D, E> Default for JsonCodec<D, E> {
+    fn default() -> Self {
+        JsonCodec {
+            inner: LengthDelimitedCodec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<D: DeserializeOwned, E> Decoder for JsonCodec<D, E> {
+    type Item = D;
+    type Error = KvsError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, KvsError> {
+        match self.inner.decode(src)? {
+            Some(frame) => Ok(Some(serde_json::from_slice(&frame)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<D, E: Serialize> Encoder<E> for JsonCodec<D, E> {
+    type Error = KvsError;
+
+    fn encode(&mut self, item: E, dst: &mut BytesMut) -> Result<(), KvsError> {
+        let frame = Bytes::from(serde_json::to_vec(&item)?);
+        Ok(self.inner.encode(frame, dst)?)
+    }
+}
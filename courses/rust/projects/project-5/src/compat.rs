@@ -0,0 +1,219 @@
+//! Compatibility with code written against the futures 0.1 `KvsEngine`.
+//!
+//! `compat::KvsEngine` is the engine trait as it was before the move to
+//! `async fn`. It is implemented for every `crate::KvsEngine`, so callers still
+//! on futures 0.1 only need to import this trait instead. The other way round,
+//! an engine implementing only the old trait can be wrapped in `Compat` to be
+//! served by `KvsServer`.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::compat::{Future01CompatExt, Stream01CompatExt};
+use futures::stream::{self, BoxStream};
+use futures::{future, FutureExt, StreamExt, TryFutureExt, TryStreamExt};
+use futures01::{Future, Stream};
+
+use crate::{Event, KvsError, NamespaceStats, Result};
+
+/// The futures 0.1 version of `crate::KvsEngine`.
+///
+/// See `crate::KvsEngine` for the meaning of each method.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a string key to a string.
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Gets the string value of a given string key.
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send>;
+
+    /// Removes a given key.
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Lists the keys starting with the given prefix in ascending order.
+    fn scan(&self, prefix: String) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send>;
+
+    /// Returns the sequence number of the latest change, or 0 if there is none.
+    fn last_seq(&self) -> u64;
+
+    /// Watches the changes of keys starting with the given prefix.
+    fn watch(
+        &self,
+        prefix: String,
+        after: Option<u64>,
+    ) -> Box<dyn Stream<Item = Event, Error = KvsError> + Send>;
+}
+
+impl<E: crate::KvsEngine> KvsEngine for E {
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let engine = self.clone();
+        let fut = async move { crate::KvsEngine::set(&engine, key, value).await };
+        Box::new(fut.boxed().compat())
+    }
+
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        let engine = self.clone();
+        let fut = async move { crate::KvsEngine::get(&engine, key).await };
+        Box::new(fut.boxed().compat())
+    }
+
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let engine = self.clone();
+        let fut = async move { crate::KvsEngine::remove(&engine, key).await };
+        Box::new(fut.boxed().compat())
+    }
+
+    fn scan(&self, prefix: String) -> Box<dyn Future<Item = Vec<String>, Error = KvsError> + Send> {
+        let engine = self.clone();
+        let fut = async move { crate::KvsEngine::scan(&engine, prefix).await };
+        Box::new(fut.boxed().compat())
+    }
+
+    fn last_seq(&self) -> u64 {
+        crate::KvsEngine::last_seq(self)
+    }
+
+    fn watch(
+        &self,
+        prefix: String,
+        after: Option<u64>,
+    ) -> Box<dyn Stream<Item = Event, Error = KvsError> + Send> {
+        Box::new(crate::KvsEngine::watch(self, prefix, after).compat())
+    }
+}
+
+/// Adapts an engine implementing the futures 0.1 trait to `crate::KvsEngine`.
+///
+/// The old trait has no namespaces, so only the default one is supported and
+/// the operations on the others fail. Dropping the default namespace removes
+/// its keys one by one.
+#[derive(Clone)]
+pub struct Compat<E> {
+    engine: E,
+    namespace: Arc<str>,
+}
+
+impl<E> Compat<E> {
+    /// Wraps an engine, starting in the default namespace.
+    pub fn new(engine: E) -> Compat<E> {
+        Compat {
+            engine,
+            namespace: Arc::from(""),
+        }
+    }
+
+    /// Returns the wrapped engine, or an error outside the default namespace.
+    fn engine(&self) -> Result<&E> {
+        if self.namespace.is_empty() {
+            Ok(&self.engine)
+        } else {
+            Err(KvsError::StringError(format!(
+                "Namespace {} is not supported by this engine",
+                self.namespace
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine + Sync> crate::KvsEngine for Compat<E> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine()?.set(key, value).compat().await
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine()?.get(key).compat().await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        self.engine()?.remove(key).compat().await
+    }
+
+    async fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        self.engine()?.scan(prefix).compat().await
+    }
+
+    // the old trait can't start from a key, so the page is cut from all the
+    // keys with the prefix.
+    async fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let keys = self.engine()?.scan(prefix).compat().await?;
+        Ok(keys
+            .into_iter()
+            .filter(|key| match after {
+                Some(ref after) => key > after,
+                None => true,
+            })
+            .take(limit)
+            .collect())
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.engine.last_seq()
+    }
+
+    fn watch(&self, prefix: String, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        match self.engine() {
+            Ok(engine) => engine.watch(prefix, after).compat().boxed(),
+            Err(e) => stream::once(future::err(e)).boxed(),
+        }
+    }
+
+    fn watch_all(&self, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        self.engine.watch(String::new(), after).compat().boxed()
+    }
+
+    fn namespace(&self, name: &str) -> Self {
+        Compat {
+            engine: self.engine.clone(),
+            namespace: Arc::from(name),
+        }
+    }
+
+    async fn namespaces(&self) -> Result<Vec<String>> {
+        let keys = self.engine.scan(String::new()).compat().await?;
+        Ok(if keys.is_empty() {
+            Vec::new()
+        } else {
+            vec![String::new()]
+        })
+    }
+
+    async fn drop_namespace(&self) -> Result<()> {
+        let engine = self.engine()?;
+        let keys = engine.scan(String::new()).compat().await?;
+        if keys.is_empty() {
+            return Err(KvsError::NamespaceNotFound);
+        }
+        for key in keys {
+            match engine.remove(key).compat().await {
+                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<NamespaceStats> {
+        let engine = self.engine()?;
+        let mut stats = NamespaceStats::default();
+        for key in engine.scan(String::new()).compat().await? {
+            if let Some(value) = engine.get(key.clone()).compat().await? {
+                stats.keys += 1;
+                stats.bytes += (key.len() + value.len()) as u64;
+            }
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::channel::mpsc::{self, Receiver, Sender};
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{KvsError, Result};
+
+// number of recent events kept for subscribers resuming from a sequence number
+const HISTORY_CAPACITY: usize = 1024;
+// number of events queued for a subscriber before it is dropped for lagging.
+// it holds the whole history, which is replayed on subscription.
+const SUBSCRIBER_CAPACITY: usize = HISTORY_CAPACITY;
+
+/// A change of a key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// Sequence number of the change, starting from 1 when the engine is opened.
+    pub seq: u64,
+    /// The namespace of the key, empty for the default one.
+    #[serde(default)]
+    pub namespace: String,
+    /// The changed key.
+    pub key: String,
+    /// The new value, or `None` if the key is removed.
+    pub value: Option<String>,
+}
+
+/// Broadcasts changes made by an engine to its watchers.
+#[derive(Clone, Default)]
+pub struct Broadcast(Arc<Mutex<History>>);
+
+impl Broadcast {
+    /// Locks the broadcast for publishing.
+    ///
+    /// Writers should hold the lock while applying a change so that events are
+    /// published in the order the changes are applied.
+    pub fn lock(&self) -> MutexGuard<'_, History> {
+        self.0.lock().unwrap()
+    }
+
+    /// Returns the sequence number of the latest event, or 0 if there is none.
+    pub fn last_seq(&self) -> u64 {
+        self.lock().next_seq - 1
+    }
+
+    /// Returns a receiver of the sequence number of the latest event.
+    pub fn seqs(&self) -> watch::Receiver<u64> {
+        self.lock().seqs.1.clone()
+    }
+
+    /// Subscribes to changes of keys starting with `prefix`, in the given
+    /// namespace or in all of them.
+    ///
+    /// If `after` is given, the retained events after that sequence number are
+    /// replayed first. The stream fails if those events are no longer retained.
+    ///
+    /// A subscriber falling `SUBSCRIBER_CAPACITY` events behind is dropped,
+    /// and its stream ends with an error telling the sequence number of the
+    /// last event delivered, so that it can subscribe again after it.
+    pub fn subscribe(
+        &self,
+        namespace: Option<String>,
+        prefix: String,
+        after: Option<u64>,
+    ) -> BoxStream<'static, Result<Event>> {
+        let filter = Filter { namespace, prefix };
+        let mut history = self.lock();
+        let (mut tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        if let Some(after) = after {
+            let oldest = history.events.front().map_or(history.next_seq, |e| e.seq);
+            if after >= history.next_seq || after + 1 < oldest {
+                let err = KvsError::StringError(format!(
+                    "Events after sequence number {} are not available",
+                    after
+                ));
+                return stream::iter(Some(Err(err))).boxed();
+            }
+            for event in history.events.iter().filter(|e| e.seq > after) {
+                if filter.matches(event) {
+                    // the channel holds the whole history
+                    let _ = tx.try_send(event.clone());
+                }
+            }
+        }
+        let lagged = Arc::new(AtomicBool::new(false));
+        let last = after.unwrap_or(history.next_seq - 1);
+        history.subscribers.push(Subscriber {
+            filter,
+            tx,
+            lagged: Arc::clone(&lagged),
+        });
+        events(rx, last, lagged)
+    }
+}
+
+// Streams the events received by a subscriber, `last` being the sequence
+// number it starts after.
+fn events(
+    rx: Receiver<Event>,
+    last: u64,
+    lagged: Arc<AtomicBool>,
+) -> BoxStream<'static, Result<Event>> {
+    stream::unfold(Some((rx, last)), move |state| {
+        let lagged = Arc::clone(&lagged);
+        async move {
+            let (mut rx, last) = state?;
+            match rx.next().await {
+                Some(event) => {
+                    let seq = event.seq;
+                    Some((Ok(event), Some((rx, seq))))
+                }
+                None if lagged.load(Ordering::SeqCst) => {
+                    let err = KvsError::StringError(format!(
+                        "Watcher lagged behind, the last event delivered has sequence number {}",
+                        last
+                    ));
+                    Some((Err(err), None))
+                }
+                None => None,
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Recent events and the subscribers of a `Broadcast`.
+pub struct History {
+    next_seq: u64,
+    events: VecDeque<Event>,
+    subscribers: Vec<Subscriber>,
+    // the receiver is kept so that sending never fails
+    seqs: (watch::Sender<u64>, watch::Receiver<u64>),
+}
+
+impl Default for History {
+    fn default() -> History {
+        History {
+            next_seq: 1,
+            events: VecDeque::new(),
+            subscribers: Vec::new(),
+            seqs: watch::channel(0),
+        }
+    }
+}
+
+impl History {
+    /// Publishes a change to all subscribers watching the key.
+    pub fn publish(&mut self, namespace: &str, key: String, value: Option<String>) {
+        let event = Event {
+            seq: self.next_seq,
+            namespace: namespace.to_owned(),
+            key,
+            value,
+        };
+        self.next_seq += 1;
+        // drop the subscribers whose receiving end is gone, or which lag
+        self.subscribers.retain_mut(|subscriber| {
+            if !subscriber.filter.matches(&event) {
+                return true;
+            }
+            match subscriber.tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    if e.is_full() {
+                        subscriber.lagged.store(true, Ordering::SeqCst);
+                    }
+                    false
+                }
+            }
+        });
+        if self.events.len() == HISTORY_CAPACITY {
+            self.events.pop_front();
+        }
+        let _ = self.seqs.0.send(event.seq);
+        self.events.push_back(event);
+    }
+}
+
+struct Subscriber {
+    filter: Filter,
+    tx: Sender<Event>,
+    // set before the sender is dropped for lagging
+    lagged: Arc<AtomicBool>,
+}
+
+// The events a subscriber watches.
+struct Filter {
+    namespace: Option<String>,
+    prefix: String,
+}
+
+impl Filter {
+    fn matches(&self, event: &Event) -> bool {
+        self.namespace
+            .as_ref()
+            .is_none_or(|namespace| *namespace == event.namespace)
+            && event.key.starts_with(&self.prefix)
+    }
+}
@@ -1,25 +1,41 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
+use async_trait::async_trait;
+use crossbeam::atomic::AtomicCell;
 use crossbeam::queue::ArrayQueue;
 use crossbeam_skiplist::SkipMap;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use tokio::prelude::*;
-use tokio::sync::oneshot;
+use tokio::sync::watch;
 
-use super::KvsEngine;
-use crate::thread_pool::ThreadPool;
+use super::broadcast::Broadcast;
+use super::{schedule, Event, KvsEngine, LogRecord, NamespaceStats, Snapshot};
+use crate::thread_pool::{Priority, Schedule, ScheduleExt, ThreadPool};
 use crate::{KvsError, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// number of records read from the log by a job of a snapshot or a tail
+const LOG_CHUNK_SIZE: usize = 100;
+
+// Positions are updated in place, see `update_index`.
+type Index = SkipMap<String, AtomicCell<CommandPos>>;
+
+// The index of each namespace. Dropping a namespace removes its index at once.
+type Namespaces = SkipMap<String, Arc<Index>>;
+
+// The generations written to since the store was opened, in order, with the
+// sequence number of their first change. Compaction files are left out.
+type Generations = Mutex<Vec<(u64, u64)>>;
 
 /// The `KvStore` stores string key/value pairs.
 ///
@@ -27,16 +43,29 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A skip list in memory stores the keys and the value locations for fast query.
 ///
+/// Every namespace has its own skip list, while the log files are shared and
+/// each command records its namespace. Dropping a namespace appends a single
+/// command, and its commands are discarded by the next compaction.
+///
+/// Operations run in the thread pool. Gets are scheduled with `Priority::High`,
+/// and scans and compactions with `Priority::Low`, which a `PriorityThreadPool`
+/// honors. A compaction is scheduled after the write making enough of the log
+/// stale, instead of running as part of it.
+///
+/// Every command records the sequence number of its change. A snapshot for a
+/// replica starts a new generation and replays the earlier ones, which are no
+/// longer written to, and the replica then tails the generations after it.
+///
 /// ```rust
 /// # use kvs::{KvStore, Result};
 /// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
-/// # use tokio::prelude::*;
+/// # use futures::executor::block_on;
 /// # fn try_main() -> Result<()> {
 /// use std::env::current_dir;
 /// use kvs::KvsEngine;
 /// let mut store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
-/// store.set("key".to_owned(), "value".to_owned()).wait()?;
-/// let val = store.get("key".to_owned()).wait()?;
+/// block_on(store.set("key".to_owned(), "value".to_owned()))?;
+/// let val = block_on(store.get("key".to_owned()))?;
 /// assert_eq!(val, Some("value".to_owned()));
 /// # Ok(())
 /// # }
@@ -45,11 +74,16 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 pub struct KvStore<P: ThreadPool> {
     // directory for the log and other data
     path: Arc<PathBuf>,
-    // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    // the namespace this handle works on
+    namespace: Arc<str>,
+    namespaces: Arc<Namespaces>,
     writer: Arc<Mutex<KvStoreWriter>>,
     thread_pool: P,
     reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    // shared by the readers created when the pool is empty
+    safe_point: Arc<AtomicU64>,
+    broadcast: Broadcast,
+    generations: Arc<Generations>,
 }
 
 impl<P: ThreadPool> KvStore<P> {
@@ -63,28 +97,42 @@ impl<P: ThreadPool> KvStore<P> {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        KvStore::open_with_pool(path, concurrency, P::new(concurrency)?)
+    }
+
+    /// Opens a `KvStore` with the given path, running its operations in the
+    /// given thread pool.
+    ///
+    /// See `KvStore::open`.
+    pub fn open_with_pool(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        thread_pool: P,
+    ) -> Result<Self> {
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
 
         let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+        let namespaces = Arc::new(SkipMap::new());
 
         let gen_list = sorted_gen_list(&path)?;
         let mut uncompacted = 0;
 
         for &gen in &gen_list {
             let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+            uncompacted += load(gen, &mut reader, &*namespaces)?;
             readers.insert(gen, reader);
         }
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
         let writer = new_log_file(&path, current_gen)?;
         let safe_point = Arc::new(AtomicU64::new(0));
+        let broadcast = Broadcast::default();
+        let generations = Arc::new(Mutex::new(vec![(current_gen, 1)]));
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
-            safe_point,
+            safe_point: Arc::clone(&safe_point),
             readers: RefCell::new(BTreeMap::new()),
         };
 
@@ -93,11 +141,13 @@ impl<P: ThreadPool> KvStore<P> {
             writer,
             current_gen,
             uncompacted,
+            compaction_scheduled: false,
             path: Arc::clone(&path),
-            index: Arc::clone(&index),
+            namespaces: Arc::clone(&namespaces),
+            broadcast: broadcast.clone(),
+            generations: Arc::clone(&generations),
         };
 
-        let thread_pool = P::new(concurrency)?;
         let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
         for _ in 1..concurrency {
             reader_pool.push(reader.clone()).unwrap();
@@ -106,14 +156,84 @@ impl<P: ThreadPool> KvStore<P> {
 
         Ok(KvStore {
             path,
-            index,
+            namespace: Arc::from(""),
+            namespaces,
             writer: Arc::new(Mutex::new(writer)),
             thread_pool,
             reader_pool,
+            safe_point,
+            broadcast,
+            generations,
         })
     }
+
+    /// Returns the thread pool running the operations of the store.
+    pub fn thread_pool(&self) -> &P {
+        &self.thread_pool
+    }
+
+    // Runs a write in the thread pool, then schedules a compaction if the
+    // write made enough of the log stale.
+    async fn write<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<()> + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        let thread_pool = self.thread_pool.clone();
+        self.thread_pool
+            .spawn_with_handle(move || {
+                let compact = {
+                    let mut writer = writer.lock().unwrap();
+                    f(&mut writer)?;
+                    writer.schedule_compaction()
+                };
+                // spawned without the lock, as a full pool may run it right away
+                if compact {
+                    spawn_compaction(&thread_pool, writer);
+                }
+                Ok(())
+            })
+            .await?
+    }
 }
 
+// Reads the next commands of the log files of a snapshot, which are no longer
+// written to.
+async fn read_snapshot<P: ThreadPool>(
+    thread_pool: P,
+    mut files: VecDeque<LogReader>,
+) -> Result<Option<(Vec<Command>, VecDeque<LogReader>)>> {
+    thread_pool
+        .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+            while let Some(file) = files.front_mut() {
+                let commands = file.read(LOG_CHUNK_SIZE)?;
+                if !commands.is_empty() {
+                    return Ok(Some((commands, files)));
+                }
+                files.pop_front();
+            }
+            Ok(None)
+        })
+        .await?
+}
+
+// Compacts the log in a job with `Priority::Low`.
+fn spawn_compaction<P: ThreadPool>(thread_pool: &P, writer: Arc<Mutex<KvStoreWriter>>) {
+    let job_writer = Arc::clone(&writer);
+    let res = thread_pool.spawn_scheduled(Schedule::new(Priority::Low), move || {
+        let mut writer = job_writer.lock().unwrap();
+        writer.compaction_scheduled = false;
+        if let Err(e) = writer.compact() {
+            error!("Compaction failed: {}", e);
+        }
+    });
+    if let Err(e) = res {
+        warn!("Compaction is not scheduled: {}", e);
+        writer.lock().unwrap().compaction_scheduled = false;
+    }
+}
+
+#[async_trait]
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// Sets the value of a string key to a string.
     ///
@@ -122,53 +242,35 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        let namespace = self.namespace.clone();
+        self.write(move |writer| writer.set(&namespace, key, value))
+            .await
     }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+    async fn get(&self, key: String) -> Result<Option<String>> {
         let reader_pool = self.reader_pool.clone();
-        let index = self.index.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = (|| {
-                if let Some(cmd_pos) = index.get(&key) {
-                    let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    };
-                    reader_pool.push(reader).unwrap();
-                    res
-                } else {
-                    Ok(None)
-                }
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+        let path = self.path.clone();
+        let safe_point = self.safe_point.clone();
+        let namespaces = self.namespaces.clone();
+        let namespace = self.namespace.clone();
+        self.thread_pool
+            .spawn_scheduled_with_handle(schedule(Priority::High), move || {
+                // the pool may run more jobs at once than there are readers
+                let reader = reader_pool.pop().unwrap_or_else(|_| KvStoreReader {
+                    path,
+                    safe_point,
+                    readers: RefCell::new(BTreeMap::new()),
+                });
+                let res = reader.get(&namespaces, &namespace, &key);
+                // a reader created above is dropped if the pool is full again
+                let _ = reader_pool.push(reader);
+                res
+            })
+            .await?
     }
 
     /// Removes a given key.
@@ -178,19 +280,209 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    async fn remove(&self, key: String) -> Result<()> {
+        let namespace = self.namespace.clone();
+        self.write(move |writer| writer.remove(&namespace, key))
+            .await
+    }
+
+    /// Lists the keys starting with the given prefix in ascending order.
+    async fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        let namespaces = self.namespaces.clone();
+        let namespace = self.namespace.clone();
+        self.thread_pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                let index = match find_index(&namespaces, &namespace) {
+                    Some(index) => index,
+                    None => return Ok(Vec::new()),
+                };
+                let keys = index
+                    .range(prefix.clone()..)
+                    .map(|entry| entry.key().clone())
+                    .take_while(|key| key.starts_with(&prefix))
+                    .collect();
+                Ok(keys)
+            })
+            .await?
+    }
+
+    /// Lists at most `limit` keys starting with the given prefix and after the
+    /// key `after`, in ascending order.
+    async fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        let namespaces = self.namespaces.clone();
+        let namespace = self.namespace.clone();
+        self.thread_pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                let index = match find_index(&namespaces, &namespace) {
+                    Some(index) => index,
+                    None => return Ok(Vec::new()),
+                };
+                let start = match after {
+                    Some(after) if after >= prefix => Bound::Excluded(after),
+                    _ => Bound::Included(prefix.clone()),
+                };
+                let keys = index
+                    .range((start, Bound::Unbounded))
+                    .map(|entry| entry.key().clone())
+                    .take_while(|key| key.starts_with(&prefix))
+                    .take(limit)
+                    .collect();
+                Ok(keys)
+            })
+            .await?
+    }
+
+    /// Returns the sequence number of the latest change, or 0 if there is none.
+    fn last_seq(&self) -> u64 {
+        self.broadcast.last_seq()
+    }
+
+    /// Watches the changes of keys starting with the given prefix.
+    fn watch(&self, prefix: String, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        self.broadcast
+            .subscribe(Some(self.namespace.to_string()), prefix, after)
+    }
+
+    /// Watches the changes of keys in all the namespaces.
+    fn watch_all(&self, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        self.broadcast.subscribe(None, String::new(), after)
+    }
+
+    /// Returns a handle to the namespace with the given name.
+    fn namespace(&self, name: &str) -> Self {
+        KvStore {
+            namespace: Arc::from(name),
+            ..self.clone()
+        }
+    }
+
+    /// Lists the namespaces holding keys in ascending order.
+    async fn namespaces(&self) -> Result<Vec<String>> {
+        let namespaces = self.namespaces.clone();
+        self.thread_pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                Ok(namespaces
+                    .iter()
+                    .filter(|entry| !entry.value().is_empty())
+                    .map(|entry| entry.key().clone())
+                    .collect())
+            })
+            .await?
+    }
+
+    /// Removes all the keys of the namespace at once.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace holds no key.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    async fn drop_namespace(&self) -> Result<()> {
+        let namespace = self.namespace.clone();
+        self.write(move |writer| writer.drop_namespace(&namespace))
+            .await
+    }
+
+    /// Returns the number of keys of the namespace and the size of their
+    /// commands in the log.
+    async fn stats(&self) -> Result<NamespaceStats> {
+        let namespaces = self.namespaces.clone();
+        let namespace = self.namespace.clone();
+        self.thread_pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                let mut stats = NamespaceStats::default();
+                if let Some(index) = find_index(&namespaces, &namespace) {
+                    for entry in index.iter() {
+                        stats.keys += 1;
+                        stats.bytes += entry.value().load().len;
+                    }
+                }
+                Ok(stats)
+            })
+            .await?
+    }
+
+    /// Starts a new generation and replays the earlier ones.
+    ///
+    /// The log files of the snapshot are opened before the writer is
+    /// unlocked, so a compaction can't remove them while they are read.
+    async fn snapshot(&self) -> Result<Snapshot> {
         let writer = self.writer.clone();
-        let (tx, rx) = oneshot::channel();
-        self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
+        let (seq, files, tail) = self
+            .thread_pool
+            .spawn_with_handle(move || {
+                let mut writer = writer.lock().unwrap();
+                let seq = writer.rotate()?;
+                let files = sorted_gen_list(&writer.path)?
+                    .into_iter()
+                    .filter(|&gen| gen < writer.current_gen)
+                    .map(|gen| LogReader::open(&writer.path, gen))
+                    .collect::<Result<VecDeque<_>>>()?;
+                let tail = LogReader::open(&writer.path, writer.current_gen)?;
+                Ok::<_, KvsError>((seq, files, tail))
+            })
+            .await??;
+        let thread_pool = self.thread_pool.clone();
+        let records = stream::try_unfold(files, move |files| {
+            read_snapshot(thread_pool.clone(), files)
+        })
+        .map_ok(|commands| stream::iter(commands.into_iter().map(|cmd| Ok(cmd.into()))))
+        .try_flatten()
+        .boxed();
+        let tail = LogTail {
+            reader: tail,
+            path: Arc::clone(&self.path),
+            generations: Arc::clone(&self.generations),
+            after: seq,
+        };
+        Ok(Snapshot {
+            seq,
+            records,
+            changes: tail.stream(self.thread_pool.clone(), self.broadcast.seqs()),
+        })
+    }
+
+    /// Tails the log from the generation holding the change after `after`.
+    ///
+    /// The changes are retained until a compaction removes their generation.
+    fn tail(&self, after: u64) -> Result<BoxStream<'static, Result<LogRecord>>> {
+        let not_retained = || {
+            KvsError::StringError(format!(
+                "Changes after sequence number {} are not available",
+                after
+            ))
+        };
+        if after > self.last_seq() {
+            return Err(not_retained());
+        }
+        let gen = self
+            .generations
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|&&(_, first_seq)| first_seq <= after + 1)
+            .map(|&(gen, _)| gen)
+            .ok_or_else(not_retained)?;
+        let reader = match LogReader::open(&self.path, gen) {
+            Ok(reader) => reader,
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(not_retained())
             }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+            Err(e) => return Err(e),
+        };
+        let tail = LogTail {
+            reader,
+            path: Arc::clone(&self.path),
+            generations: Arc::clone(&self.generations),
+            after,
+        };
+        Ok(tail.stream(self.thread_pool.clone(), self.broadcast.seqs()))
     }
 }
 
@@ -251,6 +543,31 @@ impl KvStoreReader {
             Ok(serde_json::from_reader(cmd_reader)?)
         })
     }
+
+    /// Gets the value of a key in a namespace.
+    ///
+    /// A compaction may finish between looking the key up and reading the log,
+    /// removing the log file the position points to. The command has then been
+    /// copied to the compaction file, unless the namespace has been dropped,
+    /// so the key is looked up again.
+    fn get(&self, namespaces: &Namespaces, namespace: &str, key: &str) -> Result<Option<String>> {
+        loop {
+            let cmd_pos = match find_index(namespaces, namespace)
+                .and_then(|index| index.get(key).map(|entry| entry.value().load()))
+            {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+            match self.read_command(cmd_pos) {
+                Ok(Command::Set { value, .. }) => return Ok(Some(value)),
+                Ok(_) => return Err(KvsError::UnexpectedCommandType),
+                Err(KvsError::Io(ref e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && cmd_pos.gen < self.safe_point.load(Ordering::SeqCst) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
 }
 
 impl Clone for KvStoreReader {
@@ -271,75 +588,144 @@ struct KvStoreWriter {
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // whether a compaction is waiting in the thread pool
+    compaction_scheduled: bool,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    namespaces: Arc<Namespaces>,
+    broadcast: Broadcast,
+    generations: Arc<Generations>,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
+    fn set(&mut self, namespace: &str, key: String, value: String) -> Result<()> {
+        let seq = self.broadcast.last_seq() + 1;
+        let cmd = Command::set(namespace, key, value, seq);
         let pos = self.writer.pos;
         serde_json::to_writer(&mut self.writer, &cmd)?;
         self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+        if let Command::Set { key, value, .. } = cmd {
+            let cmd_pos = (self.current_gen, pos..self.writer.pos).into();
+            let index = find_or_insert_index(&self.namespaces, namespace);
+            if let Some(old_cmd) = update_index(&index, key.clone(), cmd_pos) {
+                self.uncompacted += old_cmd.len;
             }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
-        }
-
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+            self.broadcast.lock().publish(namespace, key, Some(value));
         }
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
+    fn remove(&mut self, namespace: &str, key: String) -> Result<()> {
+        let index = find_index(&self.namespaces, namespace);
+        if let Some(index) = index.filter(|index| index.contains_key(&key)) {
+            let seq = self.broadcast.last_seq() + 1;
+            let cmd = Command::remove(namespace, key, seq);
             let pos = self.writer.pos;
             serde_json::to_writer(&mut self.writer, &cmd)?;
             self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
+            if let Command::Remove { key, .. } = cmd {
+                let old_cmd = index.remove(&key).expect("key not found");
+                self.uncompacted += old_cmd.value().load().len;
+                self.broadcast.lock().publish(namespace, key, None);
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
                 self.uncompacted += self.writer.pos - pos;
             }
-
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
             Ok(())
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
+    fn drop_namespace(&mut self, namespace: &str) -> Result<()> {
+        let index = find_index(&self.namespaces, namespace)
+            .filter(|index| !index.is_empty())
+            .ok_or(KvsError::NamespaceNotFound)?;
+        // a removal is published for each key
+        let seq = self.broadcast.last_seq() + index.len() as u64;
+        let cmd = Command::drop_namespace(namespace, seq);
+        let pos = self.writer.pos;
+        serde_json::to_writer(&mut self.writer, &cmd)?;
+        self.writer.flush()?;
+        self.namespaces.remove(namespace);
+
+        let mut history = self.broadcast.lock();
+        for entry in index.iter() {
+            self.uncompacted += entry.value().load().len;
+            history.publish(namespace, entry.key().clone(), None);
+        }
+        drop(history);
+        // the "drop" command itself can be deleted in the next compaction
+        self.uncompacted += self.writer.pos - pos;
+        Ok(())
+    }
+
+    /// Starts a new generation, so that the earlier ones are no longer written
+    /// to, and returns the sequence number of the latest change.
+    fn rotate(&mut self) -> Result<u64> {
+        self.current_gen += 1;
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+        let seq = self.broadcast.last_seq();
+        self.start_generation(seq + 1);
+        Ok(seq)
+    }
+
+    // Records that the changes from `first_seq` go to the current generation.
+    fn start_generation(&self, first_seq: u64) {
+        self.generations
+            .lock()
+            .unwrap()
+            .push((self.current_gen, first_seq));
+    }
+
+    /// Returns whether a compaction should be scheduled, which it then is
+    /// until it runs.
+    fn schedule_compaction(&mut self) -> bool {
+        if self.uncompacted > COMPACTION_THRESHOLD && !self.compaction_scheduled {
+            self.compaction_scheduled = true;
+            true
+        } else {
+            false
+        }
+    }
+
     /// Clears stale entries in the log.
+    ///
+    /// The namespaces are copied one after another to the compaction file.
     fn compact(&mut self) -> Result<()> {
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
         self.writer = new_log_file(&self.path, self.current_gen)?;
+        self.start_generation(self.broadcast.last_seq() + 1);
 
         let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
 
         let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+        let mut new_index: Vec<(Arc<Index>, String, CommandPos)> = Vec::new();
+        for namespace in self.namespaces.iter() {
+            let index = namespace.value();
+            for entry in index.iter() {
+                let len = self
+                    .reader
+                    .read_and(entry.value().load(), |mut entry_reader| {
+                        Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+                    })?;
+                new_index.push((
+                    Arc::clone(index),
+                    entry.key().clone(),
+                    (compaction_gen, new_pos..new_pos + len).into(),
+                ));
+                new_pos += len;
+            }
         }
         compaction_writer.flush()?;
 
+        // Readers look the index up without locking, so it can only point to
+        // the compaction file once the file is flushed.
+        for (index, key, cmd_pos) in new_index {
+            update_index(&index, key, cmd_pos);
+        }
+
         self.reader
             .safe_point
             .store(compaction_gen, Ordering::SeqCst);
@@ -399,14 +785,10 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Load the whole log file and store value locations in the index of each namespace.
 ///
 /// Returns how many bytes can be saved after a compaction.
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
+fn load(gen: u64, reader: &mut BufReaderWithPos<File>, namespaces: &Namespaces) -> Result<u64> {
     // To make sure we read from the beginning of the file
     let mut pos = reader.seek(SeekFrom::Start(0))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
@@ -414,44 +796,276 @@ fn load(
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
         match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+            Command::Set { namespace, key, .. } => {
+                let index = find_or_insert_index(namespaces, &namespace);
+                if let Some(old_cmd) = update_index(&index, key, (gen, pos..new_pos).into()) {
+                    uncompacted += old_cmd.len;
                 }
-                index.insert(key, (gen, pos..new_pos).into());
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+            Command::Remove { namespace, key, .. } => {
+                if let Some(index) = find_index(namespaces, &namespace) {
+                    if let Some(old_cmd) = index.remove(&key) {
+                        uncompacted += old_cmd.value().load().len;
+                    }
                 }
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
                 uncompacted += new_pos - pos;
             }
+            Command::DropNamespace { namespace, .. } => {
+                if let Some(index) = namespaces.remove(&namespace) {
+                    for entry in index.value().iter() {
+                        uncompacted += entry.value().load().len;
+                    }
+                }
+                uncompacted += new_pos - pos;
+            }
         }
         pos = new_pos;
     }
     Ok(uncompacted)
 }
 
+/// Points a key to a new position in the log, returning the old position.
+///
+/// An existing entry is updated in place, because replacing it in the skip list
+/// removes it first and concurrent readers could miss the key.
+fn update_index(index: &Index, key: String, cmd_pos: CommandPos) -> Option<CommandPos> {
+    match index.get(&key) {
+        Some(entry) => Some(entry.value().swap(cmd_pos)),
+        None => {
+            index.insert(key, AtomicCell::new(cmd_pos));
+            None
+        }
+    }
+}
+
+fn find_index(namespaces: &Namespaces, namespace: &str) -> Option<Arc<Index>> {
+    namespaces
+        .get(namespace)
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+/// Returns the index of a namespace, creating it on the first set.
+fn find_or_insert_index(namespaces: &Namespaces, namespace: &str) -> Arc<Index> {
+    match find_index(namespaces, namespace) {
+        Some(index) => index,
+        None => {
+            let index = Arc::new(SkipMap::new());
+            namespaces.insert(namespace.to_owned(), Arc::clone(&index));
+            index
+        }
+    }
+}
+
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
 /// Struct representing a command
+///
+/// The namespace is left out for the default one, as in the logs written before
+/// namespaces existed.
+/// The sequence number is that of the change in the run of the store writing
+/// the command, and is 0 in the logs written before it was recorded.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        key: String,
+        value: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    Remove {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        namespace: String,
+        key: String,
+        #[serde(default)]
+        seq: u64,
+    },
+    DropNamespace {
+        namespace: String,
+        // the sequence number of the removal of the last key
+        #[serde(default)]
+        seq: u64,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(namespace: &str, key: String, value: String, seq: u64) -> Command {
+        Command::Set {
+            namespace: namespace.to_owned(),
+            key,
+            value,
+            seq,
+        }
     }
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
+    fn remove(namespace: &str, key: String, seq: u64) -> Command {
+        Command::Remove {
+            namespace: namespace.to_owned(),
+            key,
+            seq,
+        }
+    }
+
+    fn drop_namespace(namespace: &str, seq: u64) -> Command {
+        Command::DropNamespace {
+            namespace: namespace.to_owned(),
+            seq,
+        }
+    }
+}
+
+impl From<Command> for LogRecord {
+    fn from(cmd: Command) -> LogRecord {
+        let (seq, namespace, key, value) = match cmd {
+            Command::Set {
+                namespace,
+                key,
+                value,
+                seq,
+            } => (seq, namespace, key, Some(value)),
+            Command::Remove {
+                namespace,
+                key,
+                seq,
+            } => (seq, namespace, key, None),
+            Command::DropNamespace { namespace, seq } => {
+                return LogRecord::DropNamespace { seq, namespace }
+            }
+        };
+        LogRecord::Write(Event {
+            seq,
+            namespace,
+            key,
+            value,
+        })
+    }
+}
+
+/// Reads the commands of a log file in order.
+struct LogReader {
+    gen: u64,
+    reader: BufReaderWithPos<File>,
+    // the end of the last command read
+    pos: u64,
+}
+
+impl LogReader {
+    fn open(path: &Path, gen: u64) -> Result<LogReader> {
+        Ok(LogReader {
+            gen,
+            reader: BufReaderWithPos::new(File::open(log_path(path, gen))?)?,
+            pos: 0,
+        })
+    }
+
+    /// Reads up to `max` commands, stopping at the end of the file or at a
+    /// command not completely written yet.
+    fn read(&mut self, max: usize) -> Result<Vec<Command>> {
+        let start = self.reader.seek(SeekFrom::Start(self.pos))?;
+        let mut stream = Deserializer::from_reader(&mut self.reader).into_iter::<Command>();
+        let mut commands = Vec::new();
+        while commands.len() < max {
+            match stream.next() {
+                Some(Ok(cmd)) => {
+                    commands.push(cmd);
+                    self.pos = start + stream.byte_offset() as u64;
+                }
+                Some(Err(ref e)) if e.is_eof() => break,
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        Ok(commands)
+    }
+}
+
+/// Follows the generations of the log from a change, moving on to the next
+/// generation once the writer did.
+struct LogTail {
+    reader: LogReader,
+    path: Arc<PathBuf>,
+    generations: Arc<Generations>,
+    // the sequence number of the latest change read
+    after: u64,
+}
+
+impl LogTail {
+    /// Reads up to `max` changes after the latest one read.
+    fn read(&mut self, max: usize) -> Result<Vec<LogRecord>> {
+        loop {
+            // the generation is complete once a later one exists, so check
+            // that before reading it
+            let next = self
+                .generations
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|&(gen, _)| gen)
+                .find(|&gen| gen > self.reader.gen);
+            let commands = self.reader.read(max)?;
+            let done = commands.len() < max;
+            let after = self.after;
+            let records: Vec<LogRecord> = commands
+                .into_iter()
+                .map(LogRecord::from)
+                .filter(|record| record.seq() > after)
+                .collect();
+            if let Some(record) = records.last() {
+                self.after = record.seq();
+                return Ok(records);
+            }
+            match next {
+                _ if !done => {}
+                Some(gen) => self.reader = LogReader::open(&self.path, gen)?,
+                None => return Ok(records),
+            }
+        }
+    }
+
+    /// Streams the changes, reading them in the thread pool as they are
+    /// published.
+    fn stream<P: ThreadPool>(
+        self,
+        thread_pool: P,
+        seqs: watch::Receiver<u64>,
+    ) -> BoxStream<'static, Result<LogRecord>> {
+        stream::try_unfold((self, seqs), move |(tail, seqs)| {
+            tail.next(thread_pool.clone(), seqs)
+        })
+        .map_ok(|records| stream::iter(records.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+    }
+
+    // Waits for changes after the latest one read, and reads them. Returns
+    // `None` once the store is closed.
+    async fn next<P: ThreadPool>(
+        mut self,
+        thread_pool: P,
+        mut seqs: watch::Receiver<u64>,
+    ) -> Result<Option<(Vec<LogRecord>, (LogTail, watch::Receiver<u64>))>> {
+        loop {
+            while *seqs.borrow() <= self.after {
+                if seqs.changed().await.is_err() {
+                    return Ok(None);
+                }
+            }
+            let (tail, records) = thread_pool
+                .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                    let records = self.read(LOG_CHUNK_SIZE)?;
+                    Ok::<_, KvsError>((self, records))
+                })
+                .await??;
+            if !records.is_empty() {
+                return Ok(Some((records, (tail, seqs))));
+            }
+            self = tail;
+        }
     }
 }
 
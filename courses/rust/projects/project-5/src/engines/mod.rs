@@ -1,28 +1,222 @@
+pub use self::broadcast::Event;
 pub use self::kvs::KvStore;
 pub use self::sled::SledKvsEngine;
-use crate::KvsError;
+use crate::thread_pool::{Priority, Schedule};
+use crate::{KvsError, Result};
 
-use tokio::prelude::Future;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Instant;
 
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+mod broadcast;
 mod kvs;
 mod sled;
 
 /// Trait for a key value storage engine.
-pub trait KvsEngine: Clone + Send + 'static {
+///
+/// The keys are grouped in namespaces. An engine handle works on a single
+/// namespace, the default one unless it is returned by `namespace`.
+#[async_trait]
+pub trait KvsEngine: Clone + Send + Sync + 'static {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    async fn set(&self, key: String, value: String) -> Result<()>;
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send>;
+    async fn get(&self, key: String) -> Result<Option<String>>;
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    async fn remove(&self, key: String) -> Result<()>;
+
+    /// Lists the keys starting with the given prefix in ascending order.
+    async fn scan(&self, prefix: String) -> Result<Vec<String>>;
+
+    /// Lists at most `limit` keys starting with the given prefix and after the
+    /// key `after`, in ascending order.
+    ///
+    /// Listing the keys after the last one of a page gives the next page.
+    async fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>>;
+
+    /// Returns the sequence number of the latest change, or 0 if there is none.
+    fn last_seq(&self) -> u64;
+
+    /// Watches the changes of keys starting with the given prefix.
+    ///
+    /// If `after` is given, the stream starts with the recent changes after that
+    /// sequence number, so a watcher can resume where it left off.
+    fn watch(&self, prefix: String, after: Option<u64>) -> BoxStream<'static, Result<Event>>;
+
+    /// Watches the changes of keys in all the namespaces.
+    ///
+    /// Sequence numbers are shared by the namespaces, so `after` works like in
+    /// `watch`.
+    fn watch_all(&self, after: Option<u64>) -> BoxStream<'static, Result<Event>>;
+
+    /// Returns a handle to the namespace with the given name.
+    ///
+    /// The handle shares the storage of this one, but has its own keys. The
+    /// default namespace is named by the empty string. A namespace exists as
+    /// long as it holds keys.
+    fn namespace(&self, name: &str) -> Self;
+
+    /// Lists the namespaces holding keys in ascending order.
+    async fn namespaces(&self) -> Result<Vec<String>>;
+
+    /// Removes all the keys of the namespace at once.
+    ///
+    /// Watchers see a removal for each key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NamespaceNotFound` if the namespace holds no key.
+    async fn drop_namespace(&self) -> Result<()>;
+
+    /// Returns the number of keys and the size of the namespace.
+    async fn stats(&self) -> Result<NamespaceStats>;
+
+    /// Takes a snapshot of all the namespaces for a replica to sync from.
+    ///
+    /// The default implementation reads the keys one by one while watching
+    /// the changes, so the records only give a consistent state once the
+    /// changes made meanwhile are applied too.
+    async fn snapshot(&self) -> Result<Snapshot> {
+        let seq = self.last_seq();
+        // subscribe before reading the keys so that no change is missed
+        let changes = self.tail(seq)?;
+        let engine = self.clone();
+        let records = stream::once(async move {
+            let mut keys = Vec::new();
+            for namespace in engine.namespaces().await? {
+                for key in engine.namespace(&namespace).scan(String::new()).await? {
+                    keys.push((namespace.clone(), key));
+                }
+            }
+            let records = stream::iter(keys)
+                .map(move |(namespace, key)| {
+                    let engine = engine.namespace(&namespace);
+                    async move {
+                        // a key removed meanwhile is replayed as a removal
+                        let value = engine.get(key.clone()).await?;
+                        Ok(LogRecord::Write(Event {
+                            seq,
+                            namespace,
+                            key,
+                            value,
+                        }))
+                    }
+                })
+                .buffered(SNAPSHOT_CONCURRENCY);
+            Ok::<_, KvsError>(records)
+        })
+        .try_flatten()
+        .boxed();
+        Ok(Snapshot {
+            seq,
+            records,
+            changes,
+        })
+    }
+
+    /// Streams the changes after the given sequence number, for a replica to
+    /// resume from.
+    ///
+    /// # Errors
+    ///
+    /// It fails if the engine no longer retains those changes. The default
+    /// implementation replays the recent changes kept for watchers.
+    fn tail(&self, after: u64) -> Result<BoxStream<'static, Result<LogRecord>>> {
+        let mut events = self.watch_all(Some(after)).peekable();
+        if let Some(Some(Err(_))) = Pin::new(&mut events).peek().now_or_never() {
+            if let Some(Some(Err(e))) = events.next().now_or_never() {
+                return Err(e);
+            }
+        }
+        Ok(events.map_ok(LogRecord::Write).boxed())
+    }
+}
+
+// number of keys the default `KvsEngine::snapshot` reads at the same time
+const SNAPSHOT_CONCURRENCY: usize = 16;
+
+/// A change of an engine, as replayed by its replicas.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogRecord {
+    /// A key is set, or removed if the event has no value.
+    Write(Event),
+    /// All the keys of a namespace are removed.
+    DropNamespace {
+        /// Sequence number of the removal of the last key.
+        seq: u64,
+        /// The dropped namespace.
+        namespace: String,
+    },
+}
+
+impl LogRecord {
+    /// Returns the sequence number of the latest change of the record.
+    pub fn seq(&self) -> u64 {
+        match *self {
+            LogRecord::Write(ref event) => event.seq,
+            LogRecord::DropNamespace { seq, .. } => seq,
+        }
+    }
+}
+
+/// A copy of all the namespaces of an engine, for a replica to sync from.
+pub struct Snapshot {
+    /// Sequence number of the latest change in the snapshot.
+    pub seq: u64,
+    /// Applying the records in order gives the keys as of `seq`.
+    pub records: BoxStream<'static, Result<LogRecord>>,
+    /// The changes after `seq`.
+    pub changes: BoxStream<'static, Result<LogRecord>>,
+}
+
+/// The size of a namespace.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceStats {
+    /// Number of keys.
+    pub keys: u64,
+    /// Bytes taken by the keys and values. `KvStore` counts the records in its
+    /// log, other engines the keys and values themselves.
+    pub bytes: u64,
+}
+
+tokio::task_local! {
+    // the deadline of the request of a client being served
+    static DEADLINE: Instant;
+}
+
+/// Runs `f` with the deadline of the request of a client.
+///
+/// The reads the engines schedule for it are dropped if the deadline passes
+/// before they run, failing with `KvsError::DeadlineExceeded`.
+pub(crate) async fn with_deadline<F: Future>(deadline: Instant, f: F) -> F::Output {
+    DEADLINE.scope(deadline, f).await
+}
+
+// A schedule with the given priority, and the deadline of the request being
+// served if there is one.
+fn schedule(priority: Priority) -> Schedule {
+    match DEADLINE.try_with(|&deadline| deadline) {
+        Ok(deadline) => Schedule::new(priority).deadline(deadline),
+        Err(_) => Schedule::new(priority),
+    }
 }
@@ -1,14 +1,30 @@
-use crate::thread_pool::ThreadPool;
+use super::broadcast::Broadcast;
+use super::{schedule, Event, NamespaceStats};
+use crate::thread_pool::{Priority, ScheduleExt, ThreadPool};
 use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
-use tokio::prelude::*;
-use tokio::sync::oneshot;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use sled::{Db, Tree};
+use std::sync::Arc;
+
+// name of the tree sled opens by default, which holds the default namespace
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+// prefix of the names of the trees holding the other namespaces
+const TREE_PREFIX: &str = "ns/";
 
 /// Wrapper of `sled::Db`
+///
+/// Like `KvStore`, gets are scheduled with `Priority::High` and scans with
+/// `Priority::Low`.
+///
+/// Each namespace is stored in its own `sled::Tree`.
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
     db: Db,
+    // the namespace this handle works on
+    namespace: Arc<str>,
+    broadcast: Broadcast,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
@@ -17,68 +33,272 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     /// Operations are run in the given thread pool. `concurrency` specifies the number of
     /// threads in the thread pool.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
-        let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        Ok(SledKvsEngine::with_pool(db, P::new(concurrency)?))
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db`, running its operations in the
+    /// given thread pool.
+    pub fn with_pool(db: Db, pool: P) -> Self {
+        SledKvsEngine {
+            pool,
+            db,
+            namespace: Arc::from(""),
+            broadcast: Broadcast::default(),
+        }
+    }
+
+    /// Returns the thread pool running the operations of the engine.
+    pub fn thread_pool(&self) -> &P {
+        &self.pool
     }
 }
 
+#[async_trait]
 impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
         let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = db
-                .set(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
-    }
-
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        let namespace = self.namespace.clone();
+        let broadcast = self.broadcast.clone();
+        self.pool
+            .spawn_with_handle(move || {
+                let tree = {
+                    let mut history = broadcast.lock();
+                    let tree = open_tree(&db, &namespace)?;
+                    tree.set(key.as_bytes(), value.clone().into_bytes())?;
+                    history.publish(&namespace, key, Some(value));
+                    tree
+                };
+                tree.flush()?;
+                Ok(())
+            })
+            .await?
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
         let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
+        let namespace = self.namespace.clone();
+        let broadcast = self.broadcast.clone();
+        self.pool
+            .spawn_scheduled_with_handle(schedule(Priority::High), move || {
+                let tree = match find_tree(&db, &broadcast, &namespace)? {
+                    Some(tree) => tree,
+                    None => return Ok(None),
+                };
+                Ok(tree
                     .get(key)?
                     .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
                     .map(String::from_utf8)
                     .transpose()?)
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
-    }
-
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+            })
+            .await?
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        let db = self.db.clone();
+        let namespace = self.namespace.clone();
+        let broadcast = self.broadcast.clone();
+        self.pool
+            .spawn_with_handle(move || {
+                let tree = {
+                    let mut history = broadcast.lock();
+                    let tree = open_tree(&db, &namespace)?;
+                    tree.del(&key)?.ok_or(KvsError::KeyNotFound)?;
+                    history.publish(&namespace, key, None);
+                    tree
+                };
+                tree.flush()?;
+                Ok(())
+            })
+            .await?
+    }
+
+    async fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        let db = self.db.clone();
+        let namespace = self.namespace.clone();
+        let broadcast = self.broadcast.clone();
+        self.pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                let tree = match find_tree(&db, &broadcast, &namespace)? {
+                    Some(tree) => tree,
+                    None => return Ok(Vec::new()),
+                };
+                tree.scan(&prefix)
+                    .keys()
+                    .take_while(|key| match key {
+                        Ok(key) => key.starts_with(prefix.as_bytes()),
+                        Err(_) => true,
+                    })
+                    .map(|key| Ok(String::from_utf8(key?)?))
+                    .collect()
+            })
+            .await?
+    }
+
+    async fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
         let db = self.db.clone();
-        let (tx, rx) = oneshot::channel();
-        self.pool.spawn(move || {
-            let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
+        let namespace = self.namespace.clone();
+        let broadcast = self.broadcast.clone();
+        self.pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                let tree = match find_tree(&db, &broadcast, &namespace)? {
+                    Some(tree) => tree,
+                    None => return Ok(Vec::new()),
+                };
+                let after = after.filter(|after| *after >= prefix);
+                let start = after.as_ref().unwrap_or(&prefix);
+                tree.scan(start)
+                    .keys()
+                    // the scan starts at `after` itself
+                    .skip_while(|key| match (key, &after) {
+                        (Ok(key), Some(after)) => key == after.as_bytes(),
+                        _ => false,
+                    })
+                    .take_while(|key| match key {
+                        Ok(key) => key.starts_with(prefix.as_bytes()),
+                        Err(_) => true,
+                    })
+                    .take(limit)
+                    .map(|key| Ok(String::from_utf8(key?)?))
+                    .collect()
+            })
+            .await?
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.broadcast.last_seq()
+    }
+
+    fn watch(&self, prefix: String, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        self.broadcast
+            .subscribe(Some(self.namespace.to_string()), prefix, after)
+    }
+
+    fn watch_all(&self, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        self.broadcast.subscribe(None, String::new(), after)
+    }
+
+    fn namespace(&self, name: &str) -> Self {
+        SledKvsEngine {
+            namespace: Arc::from(name),
+            ..self.clone()
+        }
+    }
+
+    async fn namespaces(&self) -> Result<Vec<String>> {
+        let db = self.db.clone();
+        self.pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                let mut namespaces = Vec::new();
+                for tree_name in db.tree_names() {
+                    let namespace = if tree_name == DEFAULT_TREE {
+                        String::new()
+                    } else {
+                        match String::from_utf8(tree_name.clone())?.strip_prefix(TREE_PREFIX) {
+                            Some(namespace) => namespace.to_owned(),
+                            None => continue,
+                        }
+                    };
+                    if !db.open_tree(tree_name)?.is_empty() {
+                        namespaces.push(namespace);
+                    }
+                }
+                namespaces.sort();
+                Ok(namespaces)
+            })
+            .await?
+    }
+
+    /// Removes all the keys of the namespace at once.
+    ///
+    /// The tree of the namespace is dropped, except for the default namespace,
+    /// whose tree is cleared.
+    async fn drop_namespace(&self) -> Result<()> {
+        let db = self.db.clone();
+        let namespace = self.namespace.clone();
+        let broadcast = self.broadcast.clone();
+        self.pool
+            .spawn_with_handle(move || {
+                {
+                    let mut history = broadcast.lock();
+                    let tree = open_tree(&db, &namespace)?;
+                    let keys = tree
+                        .iter()
+                        .keys()
+                        .map(|key| Ok(String::from_utf8(key?)?))
+                        .collect::<Result<Vec<_>>>()?;
+                    if keys.is_empty() {
+                        return Err(KvsError::NamespaceNotFound);
+                    }
+                    if namespace.is_empty() {
+                        tree.clear()?;
+                    } else {
+                        db.drop_tree(&tree_name(&namespace))?;
+                    }
+                    for key in keys {
+                        history.publish(&namespace, key, None);
+                    }
+                }
                 db.flush()?;
                 Ok(())
-            })();
-            if tx.send(res).is_err() {
-                error!("Receiving end is dropped");
-            }
-        });
-        Box::new(
-            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
-                .flatten(),
-        )
+            })
+            .await?
+    }
+
+    async fn stats(&self) -> Result<NamespaceStats> {
+        let db = self.db.clone();
+        let namespace = self.namespace.clone();
+        let broadcast = self.broadcast.clone();
+        self.pool
+            .spawn_scheduled_with_handle(schedule(Priority::Low), move || {
+                let mut stats = NamespaceStats::default();
+                let tree = match find_tree(&db, &broadcast, &namespace)? {
+                    Some(tree) => tree,
+                    None => return Ok(stats),
+                };
+                for entry in tree.iter() {
+                    let (key, value) = entry?;
+                    stats.keys += 1;
+                    stats.bytes += (key.len() + value.len()) as u64;
+                }
+                Ok(stats)
+            })
+            .await?
+    }
+}
+
+/// Opens the tree of a namespace, creating it if needed.
+///
+/// Writers open it while holding the broadcast lock, so that it is not dropped
+/// in the meantime. They only hold the lock to apply the change and publish
+/// its event, and flush afterwards, so that writers don't wait on each other's
+/// disk writes.
+fn open_tree(db: &Db, namespace: &str) -> Result<Arc<Tree>> {
+    Ok(db.open_tree(tree_name(namespace))?)
+}
+
+/// Opens the tree of a namespace if it exists, without creating it.
+///
+/// Reads use it so that they don't leave an empty tree behind for every
+/// namespace they look at. The broadcast lock keeps writers from dropping the
+/// tree between checking it exists and opening it, which would create it again.
+fn find_tree(db: &Db, broadcast: &Broadcast, namespace: &str) -> Result<Option<Arc<Tree>>> {
+    let name = tree_name(namespace);
+    let _history = broadcast.lock();
+    if name != DEFAULT_TREE && !db.tree_names().contains(&name) {
+        return Ok(None);
+    }
+    Ok(Some(db.open_tree(name)?))
+}
+
+fn tree_name(namespace: &str) -> Vec<u8> {
+    if namespace.is_empty() {
+        DEFAULT_TREE.to_vec()
+    } else {
+        format!("{}{}", TREE_PREFIX, namespace).into_bytes()
     }
 }
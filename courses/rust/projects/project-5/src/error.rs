@@ -14,6 +14,9 @@ pub enum KvsError {
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
+    /// Dropping a namespace holding no key
+    #[fail(display = "Namespace not found")]
+    NamespaceNotFound,
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
@@ -24,6 +27,18 @@ pub enum KvsError {
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// A thread pool rejected a task because its queue is full
+    #[fail(display = "server busy")]
+    Busy,
+    /// A task in the thread pool was dropped because its deadline passed
+    #[fail(display = "deadline exceeded")]
+    DeadlineExceeded,
+    /// A task in the thread pool panicked before returning its result
+    #[fail(display = "Task panicked")]
+    TaskPanicked,
+    /// Writing to a replica that has not been promoted
+    #[fail(display = "Writes are not allowed on a replica")]
+    ReadOnlyReplica,
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
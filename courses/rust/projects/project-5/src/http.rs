@@ -0,0 +1,151 @@
+//! An HTTP/JSON gateway to the key value store.
+//!
+//! Routes:
+//!
+//! - `GET /kv/{key}` returns `{"key": ..., "value": ...}`
+//! - `PUT /kv/{key}` sets the key to the request body
+//! - `DELETE /kv/{key}` removes the key
+//! - `GET /kv?prefix={prefix}` returns `{"keys": [...]}`
+//!
+//! Errors are returned as `{"error": ...}`. A value longer than 64 MiB is
+//! rejected with `413 Payload Too Large`.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use futures::Future;
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+
+use crate::{KvsEngine, KvsError, Result};
+
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Returns a future serving the HTTP gateway on `addr`.
+pub fn listen<E: KvsEngine>(addr: &SocketAddr, engine: E) -> Result<impl Future<Output = ()>> {
+    let server = Server::try_bind(addr)
+        .map_err(|e| KvsError::StringError(format!("{}", e)))?
+        .serve(make_service_fn(move |_| {
+            let engine = engine.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let engine = engine.clone();
+                    async move { handle(&engine, req).await }
+                }))
+            }
+        }));
+    Ok(async move {
+        if let Err(e) = server.await {
+            error!("HTTP server error: {}", e);
+        }
+    })
+}
+
+async fn handle<E: KvsEngine>(
+    engine: &E,
+    req: Request<Body>,
+) -> std::result::Result<Response<Body>, hyper::Error> {
+    let path = req.uri().path();
+    if path == "/kv" || path == "/kv/" {
+        return Ok(match *req.method() {
+            Method::GET => {
+                let prefix = req
+                    .uri()
+                    .query()
+                    .and_then(|query| {
+                        form_urlencoded::parse(query.as_bytes())
+                            .find(|(name, _)| name == "prefix")
+                            .map(|(_, prefix)| prefix.into_owned())
+                    })
+                    .unwrap_or_default();
+                match engine.scan(prefix).await {
+                    Ok(keys) => json_response(StatusCode::OK, json!({ "keys": keys })),
+                    Err(e) => error_response(e),
+                }
+            }
+            _ => reply(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+        });
+    }
+
+    let key = match path.strip_prefix("/kv/") {
+        Some(key) => match percent_decode_str(key).decode_utf8() {
+            Ok(key) => key.into_owned(),
+            Err(_) => return Ok(reply(StatusCode::BAD_REQUEST, "Key is not valid UTF-8")),
+        },
+        None => return Ok(reply(StatusCode::NOT_FOUND, "Not found")),
+    };
+    Ok(match *req.method() {
+        Method::GET => match engine.get(key.clone()).await {
+            Ok(Some(value)) => json_response(StatusCode::OK, json!({ "key": key, "value": value })),
+            Ok(None) => error_response(KvsError::KeyNotFound),
+            Err(e) => error_response(e),
+        },
+        Method::PUT => {
+            let body = match read_body(req.into_body()).await? {
+                Some(body) => body,
+                None => return Ok(reply(StatusCode::PAYLOAD_TOO_LARGE, "Value too large")),
+            };
+            match String::from_utf8(body) {
+                Ok(value) => match engine.set(key, value).await {
+                    Ok(()) => empty_response(StatusCode::NO_CONTENT),
+                    Err(e) => error_response(e),
+                },
+                Err(_) => reply(StatusCode::BAD_REQUEST, "Value is not valid UTF-8"),
+            }
+        }
+        Method::DELETE => match engine.remove(key).await {
+            Ok(()) => empty_response(StatusCode::NO_CONTENT),
+            Err(e) => error_response(e),
+        },
+        _ => reply(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    })
+}
+
+/// Reads a request body, unless it is longer than `MAX_BODY_SIZE`.
+async fn read_body(mut body: Body) -> std::result::Result<Option<Vec<u8>>, hyper::Error> {
+    // a larger `Content-Length` is rejected before reading anything
+    if body.size_hint().lower() > MAX_BODY_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Some(buf))
+}
+
+fn reply(status: StatusCode, msg: &str) -> Response<Body> {
+    json_response(status, json!({ "error": msg }))
+}
+
+fn error_response(e: KvsError) -> Response<Body> {
+    let status = match e {
+        KvsError::KeyNotFound => StatusCode::NOT_FOUND,
+        KvsError::ReadOnlyReplica => StatusCode::FORBIDDEN,
+        KvsError::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    json_response(status, json!({ "error": format!("{}", e) }))
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
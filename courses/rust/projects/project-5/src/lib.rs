@@ -4,14 +4,23 @@
 #[macro_use]
 extern crate log;
 
+pub use bulk::{ExportChunk, Record};
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    Event, KvStore, KvsEngine, LogRecord, NamespaceStats, SledKvsEngine, Snapshot,
+};
 pub use error::{KvsError, Result};
+pub use replication::{Replica, ReplicationStatus};
 pub use server::KvsServer;
 
+mod bulk;
 mod client;
 mod common;
+pub mod compat;
 mod engines;
 mod error;
+mod http;
+mod replication;
+mod resp;
 mod server;
 pub mod thread_pool;
@@ -0,0 +1,459 @@
+//! Asynchronous primary/replica replication.
+//!
+//! A replica connects to its primary and sends `Request::Replicate`. The
+//! primary takes a snapshot with `KvsEngine::snapshot`, sends its records in
+//! `Response::SyncRecords` chunks, marks the end of the snapshot with
+//! `Response::Synced`, and then streams every later change as a
+//! `Response::Record`. `Response::Heartbeat`s carry the latest sequence number
+//! of the primary so that the replica can report its lag.
+//!
+//! `KvStore` snapshots its log generations and then tails the log, while other
+//! engines read their keys and replay the changes kept for watchers.
+//!
+//! When a replica reconnects, it sends the sequence number of the latest
+//! change it applied, along with the epoch of the primary, which tells apart
+//! the sequence numbers of its runs. If the primary still retains the changes
+//! after it, see `KvsEngine::tail`, it answers with `Response::Resumed` and
+//! streams them instead of a full snapshot. The replica stops replicating once
+//! it is promoted.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::{future, Future, Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+
+use crate::common::Response;
+use crate::{
+    Event, KvsClient, KvsEngine, KvsError, LogRecord, NamespaceStats, Result, Snapshot,
+};
+
+const SYNC_CHUNK_SIZE: usize = 100;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The replication role and progress of a server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReplicationStatus {
+    /// The server accepts writes.
+    Primary {
+        /// Sequence number of the latest change.
+        last_seq: u64,
+    },
+    /// The server replicates from a primary and rejects writes.
+    Replica {
+        /// Address of the primary.
+        primary: SocketAddr,
+        /// Whether the replica is connected to the primary.
+        connected: bool,
+        /// Whether the initial sync of the current connection is done.
+        synced: bool,
+        /// Sequence number of the latest change of the primary applied locally.
+        applied_seq: u64,
+        /// Sequence number of the latest change of the primary known locally.
+        primary_seq: u64,
+        /// Milliseconds since the last message from the primary.
+        last_contact_ms: Option<u64>,
+    },
+}
+
+impl ReplicationStatus {
+    /// Returns the number of changes of the primary not yet applied locally.
+    pub fn lag(&self) -> u64 {
+        match *self {
+            ReplicationStatus::Primary { .. } => 0,
+            ReplicationStatus::Replica {
+                applied_seq,
+                primary_seq,
+                ..
+            } => primary_seq.saturating_sub(applied_seq),
+        }
+    }
+}
+
+/// Replication progress shared by a `Replica` and its replicator.
+pub(crate) struct ReplicaState {
+    primary: SocketAddr,
+    promoted: AtomicBool,
+    connected: AtomicBool,
+    synced: AtomicBool,
+    // the epoch of the primary `applied_seq` belongs to, once synced with it
+    epoch: Mutex<Option<u64>>,
+    applied_seq: AtomicU64,
+    primary_seq: AtomicU64,
+    last_contact: Mutex<Option<Instant>>,
+}
+
+impl ReplicaState {
+    fn new(primary: SocketAddr) -> ReplicaState {
+        ReplicaState {
+            primary,
+            promoted: AtomicBool::new(false),
+            connected: AtomicBool::new(false),
+            synced: AtomicBool::new(false),
+            epoch: Mutex::new(None),
+            applied_seq: AtomicU64::new(0),
+            primary_seq: AtomicU64::new(0),
+            last_contact: Mutex::new(None),
+        }
+    }
+
+    /// Stops replicating and starts accepting writes.
+    pub(crate) fn promote(&self) {
+        if !self.promoted.swap(true, Ordering::SeqCst) {
+            info!("Promoted to primary");
+        }
+    }
+
+    fn is_promoted(&self) -> bool {
+        self.promoted.load(Ordering::SeqCst)
+    }
+
+    /// Returns the status of the replica, or `None` once it is promoted.
+    pub(crate) fn status(&self) -> Option<ReplicationStatus> {
+        if self.is_promoted() {
+            return None;
+        }
+        let last_contact_ms = self
+            .last_contact
+            .lock()
+            .unwrap()
+            .map(|instant| instant.elapsed().as_millis() as u64);
+        Some(ReplicationStatus::Replica {
+            primary: self.primary,
+            connected: self.connected.load(Ordering::SeqCst),
+            synced: self.synced.load(Ordering::SeqCst),
+            applied_seq: self.applied_seq.load(Ordering::SeqCst),
+            primary_seq: self.primary_seq.load(Ordering::SeqCst),
+            last_contact_ms,
+        })
+    }
+
+    fn touch(&self, primary_seq: u64) {
+        *self.last_contact.lock().unwrap() = Some(Instant::now());
+        self.primary_seq.fetch_max(primary_seq, Ordering::SeqCst);
+    }
+
+    fn disconnected(&self) {
+        self.connected.store(false, Ordering::SeqCst);
+        self.synced.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A storage engine replicating from a primary.
+///
+/// Reads are served from the local engine. Writes are rejected with
+/// `KvsError::ReadOnlyReplica` until the replica is promoted.
+#[derive(Clone)]
+pub struct Replica<E: KvsEngine> {
+    engine: E,
+    state: Arc<ReplicaState>,
+}
+
+impl<E: KvsEngine> Replica<E> {
+    pub(crate) fn new(engine: E, primary: SocketAddr) -> Replica<E> {
+        Replica {
+            engine,
+            state: Arc::new(ReplicaState::new(primary)),
+        }
+    }
+
+    pub(crate) fn state(&self) -> Arc<ReplicaState> {
+        self.state.clone()
+    }
+
+    /// Returns a future replicating from the primary until the replica is
+    /// promoted.
+    pub(crate) fn replicate(&self) -> impl Future<Output = ()> {
+        let engine = self.engine.clone();
+        let state = self.state.clone();
+        async move {
+            loop {
+                let res = sync(&engine, &state).await;
+                state.disconnected();
+                if state.is_promoted() {
+                    return;
+                }
+                match res {
+                    Ok(()) => warn!("Primary {} closed the connection", state.primary),
+                    Err(e) => warn!("Replicating from {} failed: {}", state.primary, e),
+                }
+                time::sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<E: KvsEngine> KvsEngine for Replica<E> {
+    async fn set(&self, key: String, value: String) -> Result<()> {
+        if self.state.is_promoted() {
+            self.engine.set(key, value).await
+        } else {
+            Err(KvsError::ReadOnlyReplica)
+        }
+    }
+
+    async fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key).await
+    }
+
+    async fn remove(&self, key: String) -> Result<()> {
+        if self.state.is_promoted() {
+            self.engine.remove(key).await
+        } else {
+            Err(KvsError::ReadOnlyReplica)
+        }
+    }
+
+    async fn scan(&self, prefix: String) -> Result<Vec<String>> {
+        self.engine.scan(prefix).await
+    }
+
+    async fn scan_page(
+        &self,
+        prefix: String,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<String>> {
+        self.engine.scan_page(prefix, after, limit).await
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.engine.last_seq()
+    }
+
+    fn watch(&self, prefix: String, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        self.engine.watch(prefix, after)
+    }
+
+    fn watch_all(&self, after: Option<u64>) -> BoxStream<'static, Result<Event>> {
+        self.engine.watch_all(after)
+    }
+
+    fn namespace(&self, name: &str) -> Self {
+        Replica {
+            engine: self.engine.namespace(name),
+            state: self.state.clone(),
+        }
+    }
+
+    async fn namespaces(&self) -> Result<Vec<String>> {
+        self.engine.namespaces().await
+    }
+
+    async fn drop_namespace(&self) -> Result<()> {
+        if self.state.is_promoted() {
+            self.engine.drop_namespace().await
+        } else {
+            Err(KvsError::ReadOnlyReplica)
+        }
+    }
+
+    async fn stats(&self) -> Result<NamespaceStats> {
+        self.engine.stats().await
+    }
+
+    async fn snapshot(&self) -> Result<Snapshot> {
+        self.engine.snapshot().await
+    }
+
+    fn tail(&self, after: u64) -> Result<BoxStream<'static, Result<LogRecord>>> {
+        self.engine.tail(after)
+    }
+}
+
+/// Returns the stream of responses to a `Request::Replicate`.
+///
+/// The changes after `after` are streamed right away if the engine still
+/// retains them, otherwise a full sync is done first.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: E,
+    epoch: u64,
+    after: Option<u64>,
+) -> BoxStream<'static, Result<Response>> {
+    if let Some(after) = after {
+        match engine.tail(after) {
+            Ok(changes) => {
+                info!("Resuming replication after sequence number {}", after);
+                return stream::once(future::ready(Ok(Response::Resumed { seq: after })))
+                    .chain(stream::select(
+                        changes.map_ok(Response::Record),
+                        heartbeats(engine),
+                    ))
+                    .boxed();
+            }
+            Err(e) => info!("Replication can't resume: {}", e),
+        }
+    }
+
+    stream::once(async move {
+        let Snapshot {
+            seq,
+            records,
+            changes,
+        } = engine.snapshot().await?;
+        let chunks = records
+            .chunks(SYNC_CHUNK_SIZE)
+            .map(|chunk| chunk.into_iter().collect::<Result<_>>().map(Response::SyncRecords));
+        let responses = chunks
+            .chain(stream::once(future::ready(Ok(Response::Synced {
+                epoch,
+                seq,
+            }))))
+            .chain(stream::select(
+                changes.map_ok(Response::Record),
+                heartbeats(engine),
+            ));
+        Ok::<_, KvsError>(responses)
+    })
+    .try_flatten()
+    .boxed()
+}
+
+// Sends the latest sequence number of the primary every `HEARTBEAT_INTERVAL`.
+fn heartbeats<E: KvsEngine>(engine: E) -> impl Stream<Item = Result<Response>> {
+    let start = time::Instant::now() + HEARTBEAT_INTERVAL;
+    stream::unfold(
+        time::interval_at(start, HEARTBEAT_INTERVAL),
+        move |mut interval| {
+            let engine = engine.clone();
+            async move {
+                interval.tick().await;
+                let heartbeat = Response::Heartbeat {
+                    seq: engine.last_seq(),
+                };
+                Some((Ok(heartbeat), interval))
+            }
+        },
+    )
+}
+
+// Connects to the primary, does a full sync or resumes from the latest change
+// applied, and applies the changes until the connection is closed or the
+// replica is promoted.
+async fn sync<E: KvsEngine>(engine: &E, state: &ReplicaState) -> Result<()> {
+    let client = KvsClient::connect(state.primary).await?;
+    info!("Replicating from {}", state.primary);
+    state.connected.store(true, Ordering::SeqCst);
+    let epoch = *state.epoch.lock().unwrap();
+    let applied_seq = state.applied_seq.load(Ordering::SeqCst);
+    let mut responses = Box::pin(client.replicate(epoch, applied_seq).await?);
+    // namespaces and keys received during the full sync, or `None` after it
+    let mut synced_keys = Some(HashSet::new());
+    while let Some(resp) = responses.try_next().await? {
+        if state.is_promoted() {
+            break;
+        }
+        synced_keys = apply(engine, state, synced_keys, resp).await?;
+    }
+    Ok(())
+}
+
+async fn apply<E: KvsEngine>(
+    engine: &E,
+    state: &ReplicaState,
+    synced_keys: Option<HashSet<(String, String)>>,
+    resp: Response,
+) -> Result<Option<HashSet<(String, String)>>> {
+    match (synced_keys, resp) {
+        (Some(mut synced_keys), Response::SyncRecords(records)) => {
+            state.touch(0);
+            for record in records {
+                match record {
+                    LogRecord::Write(Event {
+                        ref namespace,
+                        ref key,
+                        ref value,
+                        ..
+                    }) => {
+                        let synced_key = (namespace.clone(), key.clone());
+                        if value.is_some() {
+                            synced_keys.insert(synced_key);
+                        } else {
+                            synced_keys.remove(&synced_key);
+                        }
+                    }
+                    LogRecord::DropNamespace { ref namespace, .. } => {
+                        synced_keys.retain(|(synced, _)| synced != namespace)
+                    }
+                }
+                replay(engine, record).await?;
+            }
+            Ok(Some(synced_keys))
+        }
+        (Some(synced_keys), Response::Synced { epoch, seq }) => {
+            // remove the local keys that are not on the primary
+            for namespace in engine.namespaces().await? {
+                let engine = engine.namespace(&namespace);
+                let keys = engine.scan(String::new()).await?;
+                let removes = keys
+                    .into_iter()
+                    .filter(|key| !synced_keys.contains(&(namespace.clone(), key.clone())))
+                    .map(|key| remove(&engine, key));
+                future::try_join_all(removes).await?;
+            }
+            *state.epoch.lock().unwrap() = Some(epoch);
+            state.applied_seq.store(seq, Ordering::SeqCst);
+            state.touch(seq);
+            state.synced.store(true, Ordering::SeqCst);
+            info!("Synced with {} at sequence number {}", state.primary, seq);
+            Ok(None)
+        }
+        (Some(_), Response::Resumed { seq }) => {
+            state.touch(seq);
+            state.synced.store(true, Ordering::SeqCst);
+            info!(
+                "Resumed from {} after sequence number {}",
+                state.primary, seq
+            );
+            Ok(None)
+        }
+        (None, Response::Record(record)) => {
+            let seq = record.seq();
+            replay(engine, record).await?;
+            state.applied_seq.store(seq, Ordering::SeqCst);
+            state.touch(seq);
+            Ok(None)
+        }
+        (None, Response::Heartbeat { seq }) => {
+            state.touch(seq);
+            Ok(None)
+        }
+        _ => Err(KvsError::StringError("Invalid response".to_owned())),
+    }
+}
+
+// Applies a change of the primary, ignoring the keys and namespaces that don't
+// exist locally.
+async fn replay<E: KvsEngine>(engine: &E, record: LogRecord) -> Result<()> {
+    match record {
+        LogRecord::Write(event) => {
+            let engine = engine.namespace(&event.namespace);
+            match event.value {
+                Some(value) => engine.set(event.key, value).await,
+                None => remove(&engine, event.key).await,
+            }
+        }
+        LogRecord::DropNamespace { namespace, .. } => {
+            match engine.namespace(&namespace).drop_namespace().await {
+                Err(KvsError::NamespaceNotFound) => Ok(()),
+                res => res,
+            }
+        }
+    }
+}
+
+// Removes a key, ignoring that it does not exist locally.
+async fn remove<E: KvsEngine>(engine: &E, key: String) -> Result<()> {
+    match engine.remove(key).await {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        res => res,
+    }
+}
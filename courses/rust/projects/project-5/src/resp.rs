@@ -0,0 +1,584 @@
+//! A front-end speaking the Redis serialization protocol (RESP).
+//!
+//! Only the commands that map onto `KvsEngine` are supported: GET, SET, DEL,
+//! EXISTS, SCAN, PING, INFO and EXPIRE. Expirations are kept in memory by the
+//! front-end, so they are lost when the server restarts. A write to the key
+//! through any protocol cancels its expiration.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use futures::{future, FutureExt, SinkExt, StreamExt, TryStreamExt};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::{Event, KvsEngine, KvsError, Result};
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+// a request, or a value within it, can't be longer than this
+const MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
+// an element of an array takes at least 4 bytes, like `:0\r\n`
+const MAX_ARRAY_LEN: usize = MAX_REQUEST_SIZE / 4;
+// arrays can't be nested deeper than this
+const MAX_DEPTH: usize = 8;
+
+/// A value of the Redis serialization protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RespValue {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    BulkString(Option<Vec<u8>>),
+    Array(Option<Vec<RespValue>>),
+}
+
+impl RespValue {
+    fn bulk(s: impl Into<Vec<u8>>) -> RespValue {
+        RespValue::BulkString(Some(s.into()))
+    }
+
+    fn err(msg: impl Into<String>) -> RespValue {
+        RespValue::Error(format!("ERR {}", msg.into()))
+    }
+}
+
+/// Frames `RespValue`s on a byte stream.
+///
+/// Besides RESP arrays, the decoder also accepts the inline command format
+/// (space separated words ending with a newline) so that the server can be
+/// used with `telnet` or `nc`.
+///
+/// Each element of a request is taken off the buffer as soon as it is
+/// complete, and the arrays it belongs to are kept in the codec, so a request
+/// received in many pieces is not parsed again from its start.
+#[derive(Default)]
+pub struct RespCodec {
+    // the arrays being decoded, outermost first, with the number of elements
+    // each one still expects
+    arrays: Vec<(Vec<RespValue>, usize)>,
+    // the length of the elements of the current request already decoded
+    consumed: usize,
+    // how far the buffer was searched for the end of the next line
+    scanned: usize,
+}
+
+/// An element of a request.
+enum Element {
+    Value(RespValue),
+    // the header of a non-empty array, with its length
+    Array(usize),
+}
+
+impl RespCodec {
+    /// Parses the element at the start of `buf`, which can't end past `limit`.
+    ///
+    /// Returns the element and its length, or `None` if the buffer does not
+    /// contain a complete element yet.
+    fn parse(&mut self, buf: &[u8], limit: usize) -> Result<Option<(Element, usize)>> {
+        let end = buf.len().min(limit);
+        let start = self.scanned.min(end);
+        let line_end = match buf[start..end].iter().position(|&b| b == b'\n') {
+            Some(i) => start + i,
+            None if buf.len() >= limit => return Err(protocol_error("request too large")),
+            None => {
+                self.scanned = end;
+                return Ok(None);
+            }
+        };
+        self.scanned = line_end;
+        let next = line_end + 1;
+        let line = &buf[1..line_end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let value = match buf[0] {
+            b'+' => RespValue::SimpleString(String::from_utf8(line.to_vec())?),
+            b'-' => RespValue::Error(String::from_utf8(line.to_vec())?),
+            b':' => RespValue::Integer(parse_int(line)?),
+            b'$' => {
+                let len = parse_int(line)?;
+                if len < 0 {
+                    return Ok(Some((Element::Value(RespValue::BulkString(None)), next)));
+                }
+                let end = parse_len(len, MAX_REQUEST_SIZE)
+                    .ok_or_else(|| protocol_error("invalid bulk length"))?
+                    + next;
+                if end + 2 > limit {
+                    return Err(protocol_error("request too large"));
+                }
+                if buf.len() < end + 2 {
+                    return Ok(None);
+                }
+                if &buf[end..end + 2] != b"\r\n" {
+                    return Err(protocol_error("expected CRLF after bulk string"));
+                }
+                let value = RespValue::bulk(&buf[next..end]);
+                return Ok(Some((Element::Value(value), end + 2)));
+            }
+            b'*' => {
+                let len = parse_int(line)?;
+                if len < 0 {
+                    return Ok(Some((Element::Value(RespValue::Array(None)), next)));
+                }
+                let len = parse_len(len, MAX_ARRAY_LEN)
+                    .ok_or_else(|| protocol_error("invalid multibulk length"))?;
+                if len == 0 {
+                    return Ok(Some((Element::Value(RespValue::Array(Some(vec![]))), next)));
+                }
+                return Ok(Some((Element::Array(len), next)));
+            }
+            _ => {
+                // inline command
+                let line = &buf[..line_end];
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                let words = line
+                    .split(|b| b.is_ascii_whitespace())
+                    .filter(|word| !word.is_empty())
+                    .map(RespValue::bulk)
+                    .collect();
+                RespValue::Array(Some(words))
+            }
+        };
+        Ok(Some((Element::Value(value), next)))
+    }
+
+    /// Adds a decoded value to the array it belongs to.
+    ///
+    /// Returns the request if the value completes it.
+    fn complete(&mut self, mut value: RespValue) -> Option<RespValue> {
+        while let Some((values, remaining)) = self.arrays.last_mut() {
+            values.push(value);
+            *remaining -= 1;
+            if *remaining > 0 {
+                return None;
+            }
+            value = RespValue::Array(self.arrays.pop().map(|(values, _)| values));
+        }
+        self.consumed = 0;
+        Some(value)
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = RespValue;
+    type Error = KvsError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<RespValue>> {
+        loop {
+            let limit = MAX_REQUEST_SIZE - self.consumed;
+            let (element, len) = match self.parse(src, limit)? {
+                Some(parsed) => parsed,
+                None => return Ok(None),
+            };
+            src.advance(len);
+            self.consumed += len;
+            self.scanned = 0;
+            match element {
+                Element::Value(value) => {
+                    if let Some(request) = self.complete(value) {
+                        return Ok(Some(request));
+                    }
+                }
+                Element::Array(_) if self.arrays.len() >= MAX_DEPTH => {
+                    return Err(protocol_error("arrays nested too deep"));
+                }
+                Element::Array(len) => {
+                    // the elements may not have been received, so the length
+                    // is not trusted for the allocation
+                    self.arrays.push((Vec::with_capacity(len.min(64)), len));
+                }
+            }
+        }
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    type Error = KvsError;
+
+    fn encode(&mut self, value: RespValue, dst: &mut BytesMut) -> Result<()> {
+        let mut buf = Vec::new();
+        write_value(&mut buf, &value)?;
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
+
+fn write_value(buf: &mut Vec<u8>, value: &RespValue) -> Result<()> {
+    match value {
+        RespValue::SimpleString(s) => write!(buf, "+{}\r\n", s)?,
+        RespValue::Error(s) => write!(buf, "-{}\r\n", s)?,
+        RespValue::Integer(i) => write!(buf, ":{}\r\n", i)?,
+        RespValue::BulkString(None) => buf.extend_from_slice(b"$-1\r\n"),
+        RespValue::BulkString(Some(data)) => {
+            write!(buf, "${}\r\n", data.len())?;
+            buf.extend_from_slice(data);
+            buf.extend_from_slice(b"\r\n");
+        }
+        RespValue::Array(None) => buf.extend_from_slice(b"*-1\r\n"),
+        RespValue::Array(Some(values)) => {
+            write!(buf, "*{}\r\n", values.len())?;
+            for value in values {
+                write_value(buf, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_int(line: &[u8]) -> Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error("invalid integer"))
+}
+
+// Converts a length read from a request, unless it is above `max`.
+fn parse_len(len: i64, max: usize) -> Option<usize> {
+    usize::try_from(len).ok().filter(|&len| len <= max)
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::StringError(format!("Protocol error: {}", msg))
+}
+
+/// Serves RESP clients on top of a `KvsEngine`.
+#[derive(Clone)]
+pub struct RespService<E: KvsEngine> {
+    engine: E,
+    expirations: Arc<Expirations>,
+}
+
+impl<E: KvsEngine> RespService<E> {
+    pub fn new(engine: E) -> Self {
+        RespService {
+            engine,
+            expirations: Arc::default(),
+        }
+    }
+
+    /// Serves a single client connection until it is closed.
+    ///
+    /// Errors returned by the engine are sent back as error replies, while a
+    /// malformed request closes the connection.
+    pub async fn serve(self, tcp: TcpStream) -> Result<()> {
+        let mut framed = Framed::new(tcp, RespCodec::default());
+        while let Some(frame) = framed.try_next().await? {
+            let reply = match self.call(frame).await {
+                Ok(reply) => reply,
+                Err(KvsError::ReadOnlyReplica) => RespValue::Error(
+                    "READONLY You can't write against a read only replica.".to_owned(),
+                ),
+                Err(e) => RespValue::err(format!("{}", e)),
+            };
+            framed.send(reply).await?;
+        }
+        Ok(())
+    }
+
+    async fn call(&self, frame: RespValue) -> Result<RespValue> {
+        let args = match into_args(frame) {
+            Ok(args) => args,
+            Err(e) => return Ok(RespValue::err(format!("{}", e))),
+        };
+        let name = match args.first() {
+            Some(name) => name.to_ascii_uppercase(),
+            None => return Ok(RespValue::err("empty command")),
+        };
+        let mut args = args.into_iter().skip(1);
+        match (name.as_str(), args.len()) {
+            ("PING", 0) => Ok(RespValue::SimpleString("PONG".to_owned())),
+            ("PING", 1) => Ok(RespValue::bulk(args.next().unwrap())),
+            ("INFO", 0) | ("INFO", 1) => Ok(RespValue::bulk(info())),
+            ("GET", 1) => {
+                let value = self.engine.get(args.next().unwrap()).await?;
+                Ok(RespValue::BulkString(value.map(String::into_bytes)))
+            }
+            ("SET", 2) => {
+                let key = args.next().unwrap();
+                let value = args.next().unwrap();
+                self.expirations.persist(&key);
+                self.engine.set(key, value).await?;
+                Ok(RespValue::SimpleString("OK".to_owned()))
+            }
+            ("DEL", n) if n > 0 => {
+                let removed = args.map(|key| {
+                    self.expirations.persist(&key);
+                    async move {
+                        match self.engine.remove(key).await {
+                            Ok(()) => Ok(1),
+                            Err(KvsError::KeyNotFound) => Ok(0),
+                            Err(e) => Err(e),
+                        }
+                    }
+                });
+                let n = future::try_join_all(removed).await?;
+                Ok(RespValue::Integer(n.iter().sum()))
+            }
+            ("EXISTS", n) if n > 0 => {
+                let found = future::try_join_all(args.map(|key| self.engine.get(key))).await?;
+                Ok(RespValue::Integer(
+                    found.iter().filter(|value| value.is_some()).count() as i64,
+                ))
+            }
+            ("SCAN", n) if n % 2 == 1 => self.scan(args.collect()).await,
+            ("EXPIRE", 2) => {
+                let key = args.next().unwrap();
+                match args.next().unwrap().parse::<i64>() {
+                    Ok(seconds) => self.expire(key, seconds).await,
+                    Err(_) => Ok(RespValue::err("value is not an integer or out of range")),
+                }
+            }
+            ("PING", _) | ("INFO", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _)
+            | ("SCAN", _) | ("EXPIRE", _) => Ok(RespValue::err(format!(
+                "wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            ))),
+            _ => Ok(RespValue::err(format!(
+                "unknown command '{}'",
+                name.to_ascii_lowercase()
+            ))),
+        }
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`
+    ///
+    /// The cursor is the hex encoded last key looked at by the previous call,
+    /// or `0` to start. Each call looks at up to `count` keys, so a page may
+    /// hold fewer matching keys, or none, before the scan ends.
+    async fn scan(&self, args: Vec<String>) -> Result<RespValue> {
+        let after = match args[0].as_str() {
+            "0" => None,
+            cursor => match decode_cursor(cursor) {
+                Some(after) => Some(after),
+                None => return Ok(RespValue::err("invalid cursor")),
+            },
+        };
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in args[1..].chunks(2) {
+            match option[0].to_ascii_uppercase().as_str() {
+                "MATCH" => pattern = Some(option[1].clone()),
+                "COUNT" => match option[1].parse() {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return Ok(RespValue::err("value is not an integer or out of range")),
+                },
+                _ => return Ok(RespValue::err("syntax error")),
+            }
+        }
+        let prefix = pattern
+            .as_ref()
+            .map(|p| literal_prefix(p))
+            .unwrap_or_default();
+        let keys = self.engine.scan_page(prefix, after, count).await?;
+        let next = match keys.last() {
+            Some(last) if keys.len() == count => encode_cursor(last),
+            _ => "0".to_owned(),
+        };
+        let page = keys
+            .into_iter()
+            .filter(|key| match pattern {
+                Some(ref p) => glob_match(p.as_bytes(), key.as_bytes()),
+                None => true,
+            })
+            .map(RespValue::bulk)
+            .collect();
+        Ok(RespValue::Array(Some(vec![
+            RespValue::bulk(next),
+            RespValue::Array(Some(page)),
+        ])))
+    }
+
+    /// `EXPIRE key seconds`
+    async fn expire(&self, key: String, seconds: i64) -> Result<RespValue> {
+        // watch the key before checking it exists, so that no later write is
+        // missed. the writes up to `since` happened before the EXPIRE.
+        let changes = self.engine.watch(key.clone(), None);
+        if self.engine.get(key.clone()).await?.is_none() {
+            return Ok(RespValue::Integer(0));
+        }
+        let since = self.engine.last_seq();
+        if seconds <= 0 {
+            self.expirations.persist(&key);
+            return match self.engine.remove(key).await {
+                Ok(()) | Err(KvsError::KeyNotFound) => Ok(RespValue::Integer(1)),
+                Err(e) => Err(e),
+            };
+        }
+        let engine = self.engine.clone();
+        let expirations = Arc::clone(&self.expirations);
+        let id = expirations.register(key.clone());
+        tokio::spawn(async move {
+            let written = |event: &Result<Event>| match event {
+                Ok(event) => event.key == key && event.seq > since,
+                Err(_) => false,
+            };
+            let mut writes = changes.filter(|event| future::ready(written(event)));
+            tokio::select! {
+                _ = time::sleep(Duration::from_secs(seconds as u64)) => {}
+                // the key was written by another request, through any
+                // protocol, which cancels the expiration
+                Some(_) = writes.next() => {
+                    expirations.take(&key, id);
+                    return;
+                }
+            }
+            // the events of the writes done by now are already published
+            if let Some(Some(_)) = writes.next().now_or_never() {
+                expirations.take(&key, id);
+                return;
+            }
+            drop(writes);
+            if expirations.take(&key, id) {
+                let _ = engine.remove(key).await;
+            }
+        });
+        Ok(RespValue::Integer(1))
+    }
+}
+
+/// Pending key expirations.
+///
+/// Each `EXPIRE` registers a new id for the key. When the timer fires, the key
+/// is removed only if its id is still the latest one, so a later `SET`, `DEL`
+/// or `EXPIRE` cancels the earlier timer.
+#[derive(Default)]
+struct Expirations {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<String, u64>>,
+}
+
+impl Expirations {
+    fn register(&self, key: String) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.pending.lock().unwrap().insert(key, id);
+        id
+    }
+
+    fn persist(&self, key: &str) {
+        self.pending.lock().unwrap().remove(key);
+    }
+
+    fn take(&self, key: &str, id: u64) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(key) == Some(&id) {
+            pending.remove(key);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn into_args(frame: RespValue) -> Result<Vec<String>> {
+    match frame {
+        RespValue::Array(Some(values)) => values
+            .into_iter()
+            .map(|value| match value {
+                RespValue::BulkString(Some(data)) => Ok(String::from_utf8(data)?),
+                _ => Err(protocol_error("expected bulk string")),
+            })
+            .collect(),
+        _ => Err(protocol_error("expected array of bulk strings")),
+    }
+}
+
+fn info() -> String {
+    format!(
+        "# Server\r\nkvs_version:{}\r\nredis_mode:standalone\r\n",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+fn encode_cursor(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    if cursor.len() % 2 == 1 || !cursor.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let key = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(key).ok()
+}
+
+/// Returns the part of a glob pattern before the first special character.
+fn literal_prefix(pattern: &str) -> String {
+    pattern
+        .chars()
+        .take_while(|c| !matches!(c, '*' | '?' | '[' | '\\'))
+        .collect()
+}
+
+/// Matches `s` against a glob pattern supporting `*`, `?`, `[...]` and `\`.
+///
+/// On a mismatch, the last `*` is retried from one more byte of `s`, as the
+/// earlier ones can't do better, so the match takes `O(pattern * s)` steps.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the position after the last `*`, and where its match in `s` ends
+    let mut star = None;
+    while i < s.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, i));
+            continue;
+        }
+        match match_one(&pattern[p..], s[i]) {
+            Some((true, len)) => {
+                p += len;
+                i += 1;
+            }
+            Some((false, _)) => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    star = Some((star_p, i));
+                }
+                None => return false,
+            },
+            // an unclosed `[` matches nothing
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Matches `c` against the first character of a pattern, other than `*`.
+///
+/// Returns whether it matches and the length of that character in the
+/// pattern, or `None` for an unclosed `[`. An empty pattern matches nothing.
+fn match_one(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    match pattern.split_first() {
+        None => Some((false, 0)),
+        Some((b'?', _)) => Some((true, 1)),
+        Some((b'[', rest)) => {
+            let end = rest.iter().position(|&b| b == b']')?;
+            let class = &rest[..end];
+            let (negate, class) = match class.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, class),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            Some((matched != negate, end + 2))
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => Some((rest[0] == c, 2)),
+        Some((&p, _)) => Some((p == c, 1)),
+    }
+}
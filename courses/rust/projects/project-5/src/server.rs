@@ -1,64 +1,239 @@
-use crate::common::{Request, Response};
-use crate::{KvsEngine, KvsError, Result};
+use crate::bulk;
+use crate::common::{JsonCodec, Request, Response};
+use crate::engines::with_deadline;
+use crate::http;
+use crate::replication::{self, ReplicaState};
+use crate::resp::RespService;
+use crate::{KvsEngine, KvsError, Replica, ReplicationStatus, Result};
+use futures::future::{BoxFuture, FutureExt};
+use futures::{Future, SinkExt, Stream, StreamExt, TryStreamExt};
 use std::net::SocketAddr;
-use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::prelude::*;
-use tokio_serde_json::{ReadJson, WriteJson};
+use tokio_util::codec::Framed;
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    // identifies the sequence numbers of the engine to the replicas, as they
+    // start over when the server restarts
+    epoch: u64,
+    http_addr: Option<SocketAddr>,
+    replica: Option<Arc<ReplicaState>>,
+    replicator: Option<BoxFuture<'static, ()>>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            epoch: rand::random(),
+            http_addr: None,
+            replica: None,
+            replicator: None,
+        }
+    }
+
+    /// Also serve the HTTP/JSON gateway on the given address.
+    ///
+    /// The gateway shares the storage engine with the main listener.
+    pub fn with_http(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Replicate from the primary at the given address.
+    ///
+    /// The server rejects writes until it is promoted with `KvsClient::promote`.
+    pub fn replica_of(self, primary: SocketAddr) -> KvsServer<Replica<E>> {
+        let engine = Replica::new(self.engine, primary);
+        KvsServer {
+            replica: Some(engine.state()),
+            replicator: Some(engine.replicate().boxed()),
+            engine,
+            epoch: self.epoch,
+            http_addr: self.http_addr,
+        }
     }
 
     /// Run the server listening on the given address
-    pub fn run(self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(&addr)?;
-        let server = listener
-            .incoming()
-            .map_err(|e| error!("IO error: {}", e))
-            .for_each(move |tcp| {
-                let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
-            });
-        tokio::run(server);
+    pub async fn run(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let engine = self.engine.clone();
+        let replica = self.replica.clone();
+        let epoch = self.epoch;
+        let listener = listen(listener, move |tcp| {
+            serve(engine.clone(), epoch, replica.clone(), tcp)
+        });
+        self.run_with(listener).await
+    }
+
+    /// Run the server listening on the given address and speaking the Redis
+    /// serialization protocol (RESP) instead of the kvs protocol.
+    pub async fn run_resp(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let service = RespService::new(self.engine.clone());
+        let listener = listen(listener, move |tcp| service.clone().serve(tcp));
+        self.run_with(listener).await
+    }
+
+    async fn run_with<F>(self, listener: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        if let Some(http_addr) = self.http_addr {
+            tokio::spawn(http::listen(&http_addr, self.engine)?);
+        }
+        if let Some(replicator) = self.replicator {
+            tokio::spawn(replicator);
+        }
+        listener.await;
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
-    let (read_half, write_half) = tcp.split();
-    let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-    let resp_stream = read_json
-        .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
+/// Accepts connections from `listener` and serves each of them in its own task.
+async fn listen<F, S>(listener: TcpListener, serve: F)
+where
+    F: Fn(TcpStream) -> S,
+    S: Future<Output = Result<()>> + Send + 'static,
+{
+    loop {
+        match listener.accept().await {
+            Ok((tcp, _)) => {
+                let serving = serve(tcp);
+                tokio::spawn(async move {
+                    if let Err(e) = serving.await {
+                        error!("Error on serving client: {}", e);
                     }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
+                });
+            }
+            Err(e) => error!("IO error: {}", e),
+        }
+    }
+}
+
+type ServerFramed = Framed<TcpStream, JsonCodec<Request, Response>>;
+
+async fn serve<E: KvsEngine>(
+    engine: E,
+    epoch: u64,
+    replica: Option<Arc<ReplicaState>>,
+    tcp: TcpStream,
+) -> Result<()> {
+    let mut framed = ServerFramed::new(tcp, JsonCodec::default());
+    while let Some(req) = framed.try_next().await? {
+        let resp = match req {
+            Request::Get {
+                namespace,
+                key,
+                timeout_ms,
+            } => {
+                let engine = engine.namespace(&namespace);
+                let get = engine.get(key);
+                match timeout_ms {
+                    Some(ms) => {
+                        let deadline = Instant::now() + Duration::from_millis(ms);
+                        with_deadline(deadline, get).await
                     }
+                    None => get.await,
                 }
+                .map(Response::Get)
+            }
+            Request::Set {
+                namespace,
+                key,
+                value,
+            } => engine
+                .namespace(&namespace)
+                .set(key, value)
+                .await
+                .map(|_| Response::Set),
+            Request::Remove { namespace, key } => engine
+                .namespace(&namespace)
+                .remove(key)
+                .await
+                .map(|_| Response::Remove),
+            // the event stream never ends, so later requests are not served
+            Request::Watch {
+                namespace,
+                prefix,
+                after,
+            } => {
+                let events = engine
+                    .namespace(&namespace)
+                    .watch(prefix, after)
+                    .map_ok(Response::Event);
+                return send_all(&mut framed, events).await;
+            }
+            Request::Replicate {
+                epoch: replica_epoch,
+                applied_seq,
+            } => {
+                let after = Some(applied_seq).filter(|_| replica_epoch == Some(epoch));
+                let responses = replication::serve(engine.clone(), epoch, after);
+                return send_all(&mut framed, responses).await;
+            }
+            Request::Promote => match replica {
+                Some(ref replica) => {
+                    replica.promote();
+                    Ok(Response::Promote)
+                }
+                None => Err(KvsError::StringError("Not a replica".to_owned())),
             },
-        )
-        .then(|resp| -> Result<Response> {
-            match resp {
-                Ok(resp) => Ok(resp),
-                Err(e) => Ok(Response::Err(format!("{}", e))),
+            Request::ReplicationStatus => {
+                let status = replica
+                    .as_ref()
+                    .and_then(|replica| replica.status())
+                    .unwrap_or_else(|| ReplicationStatus::Primary {
+                        last_seq: engine.last_seq(),
+                    });
+                Ok(Response::ReplicationStatus(status))
             }
-        });
-    let write_json = WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
-    write_json
-        .sink_map_err(KvsError::from)
-        .send_all(resp_stream)
-        .map(|_| ())
+            Request::Namespaces => engine.namespaces().await.map(Response::Namespaces),
+            Request::DropNamespace { namespace } => engine
+                .namespace(&namespace)
+                .drop_namespace()
+                .await
+                .map(|_| Response::DropNamespace),
+            Request::Stats { namespace } => engine
+                .namespace(&namespace)
+                .stats()
+                .await
+                .map(Response::Stats),
+            Request::Export {
+                namespace,
+                prefix,
+                after,
+                since,
+            } => {
+                let engine = engine.namespace(&namespace);
+                let chunks = bulk::export(engine, namespace, prefix, after, since);
+                send_all(&mut framed, chunks).await?;
+                continue;
+            }
+            Request::Import { namespace, records } => {
+                bulk::import(engine.namespace(&namespace), records)
+                    .await
+                    .map(|_| Response::Import)
+            }
+        };
+        framed.send(reply(resp)).await?;
+    }
+    Ok(())
+}
+
+async fn send_all<S>(framed: &mut ServerFramed, responses: S) -> Result<()>
+where
+    S: Stream<Item = Result<Response>> + Unpin,
+{
+    framed
+        .send_all(&mut responses.map(|resp| Ok(reply(resp))))
+        .await
+}
+
+fn reply(resp: Result<Response>) -> Response {
+    resp.unwrap_or_else(|e| Response::Err(format!("{}", e)))
 }
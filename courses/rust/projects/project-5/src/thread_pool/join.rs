@@ -0,0 +1,184 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+
+use crossbeam::sync::WaitGroup;
+use futures::channel::oneshot;
+use futures::executor;
+
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+/// A handle to the result of a task spawned by `ThreadPool::spawn_with_handle`.
+///
+/// It is a future resolving to the return value of the task, or to
+/// `KvsError::TaskPanicked` if the task panicked.
+pub struct JoinHandle<T> {
+    rx: oneshot::Receiver<Result<T>>,
+    // the error of spawning the task, if the pool rejected it
+    error: Option<KvsError>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new<P, F>(pool: &P, job: F) -> JoinHandle<T>
+    where
+        P: ThreadPool,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        JoinHandle::with_spawner(move || Ok(job()), |job| pool.try_spawn(job))
+    }
+
+    // Spawns the job with `spawn`, which runs the returned function or drops it.
+    pub(super) fn with_spawner<F, S>(job: F, spawn: S) -> JoinHandle<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+        S: FnOnce(Box<dyn FnOnce() + Send + 'static>) -> Result<()>,
+    {
+        let (tx, rx) = oneshot::channel();
+        let res = spawn(Box::new(move || {
+            // the sender is dropped without sending if the job panics
+            let _ = tx.send(job());
+        }));
+        JoinHandle {
+            rx,
+            error: res.err(),
+        }
+    }
+
+    /// Blocks the current thread until the task finishes.
+    pub fn join(self) -> Result<T> {
+        executor::block_on(self)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        let this = self.get_mut();
+        if let Some(e) = this.error.take() {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut this.rx)
+            .poll(cx)
+            .map(|res| res.unwrap_or(Err(KvsError::TaskPanicked)))
+    }
+}
+
+/// A scope for spawning tasks that borrow data from the stack.
+///
+/// See `ThreadPool::scope`.
+pub struct Scope<'env, P: ThreadPool> {
+    pool: P,
+    wait_group: Option<WaitGroup>,
+    panicked: Arc<AtomicBool>,
+    // the first error of spawning a task
+    error: Mutex<Option<KvsError>>,
+    // invariant over 'env, like `crossbeam::thread::Scope`
+    _marker: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'env, P: ThreadPool> Scope<'env, P> {
+    pub(super) fn new(pool: P) -> Self {
+        Scope {
+            pool,
+            wait_group: Some(WaitGroup::new()),
+            panicked: Arc::default(),
+            error: Mutex::new(None),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Spawns a function borrowing data that outlives the scope into the pool.
+    ///
+    /// If the pool rejects it, the function is dropped and the scope returns
+    /// the error.
+    pub fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        let task = ScopedTask {
+            job: Some(Box::new(job)),
+            _guard: TaskGuard {
+                _wait_group: self.wait_group.clone().unwrap(),
+                panicked: Arc::clone(&self.panicked),
+            },
+        };
+        let job: Box<dyn FnOnce() + Send + 'env> = Box::new(move || {
+            // moved as a whole, so that the guard is dropped with it
+            let mut task = task;
+            if let Some(job) = task.job.take() {
+                job();
+            }
+        });
+        // SAFETY: the scope waits for all its tasks before `'env` ends, even
+        // if the scope function panics. A task discarded by the pool drops the
+        // function before it signals the scope, see `ScopedTask`.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { mem::transmute(job) };
+        if let Err(e) = self.pool.try_spawn(job) {
+            self.error.lock().unwrap().get_or_insert(e);
+        }
+    }
+
+    /// Waits for all the tasks, returning an error if any of them was rejected
+    /// or panicked.
+    pub(super) fn join(mut self) -> Result<()> {
+        self.wait();
+        if let Some(e) = self.error.lock().unwrap().take() {
+            Err(e)
+        } else if self.panicked.load(Ordering::SeqCst) {
+            Err(KvsError::TaskPanicked)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn wait(&mut self) {
+        if let Some(wait_group) = self.wait_group.take() {
+            wait_group.wait();
+        }
+    }
+}
+
+impl<'env, P: ThreadPool> Drop for Scope<'env, P> {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+// A function spawned in a scope, which it borrows from.
+//
+// The pool may drop it without running it, on shutdown or when the function
+// is rejected or expired. Its function is then dropped before the guard
+// signals the scope, so the borrows can't outlive the scope.
+struct ScopedTask<'env> {
+    job: Option<Box<dyn FnOnce() + Send + 'env>>,
+    _guard: TaskGuard,
+}
+
+impl Drop for ScopedTask<'_> {
+    fn drop(&mut self) {
+        self.job.take();
+    }
+}
+
+// Marks the end of a scoped task, and whether it panicked.
+struct TaskGuard {
+    _wait_group: WaitGroup,
+    panicked: Arc<AtomicBool>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.panicked.store(true, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::sync::{thread, Condvar, Mutex, MutexGuard};
+
+pub(super) type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A snapshot of the state of a thread pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolMetrics {
+    /// Number of live threads.
+    pub threads: usize,
+    /// Number of jobs waiting for a thread.
+    pub queued: usize,
+    /// Number of jobs being run.
+    pub active: usize,
+    /// Number of jobs finished normally.
+    pub completed: u64,
+    /// Number of jobs that panicked.
+    pub panicked: u64,
+}
+
+impl PoolMetrics {
+    /// Returns whether every thread is busy and jobs are waiting.
+    pub fn is_saturated(&self) -> bool {
+        self.queued > 0 && self.active >= self.threads
+    }
+}
+
+/// Counts the jobs and threads of a pool, and remembers whether it is shut down.
+#[derive(Default)]
+pub(super) struct Tracker {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    metrics: PoolMetrics,
+    shutdown: bool,
+}
+
+impl Tracker {
+    /// Wraps a job to count it, or returns `None` if the pool is shut down.
+    pub(super) fn track<F>(self: &Arc<Self>, job: F) -> Option<Job>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        {
+            let mut state = self.lock();
+            if state.shutdown {
+                warn!("A job is discarded because the thread pool is shut down.");
+                return None;
+            }
+            state.metrics.queued += 1;
+        }
+        let queued = Queued(Some(Arc::clone(self)));
+        Some(Box::new(move || {
+            let _running = queued.start();
+            job();
+        }))
+    }
+
+    /// Stops accepting jobs.
+    pub(super) fn shutdown(&self) {
+        self.lock().shutdown = true;
+    }
+
+    pub(super) fn is_shutdown(&self) -> bool {
+        self.lock().shutdown
+    }
+
+    pub(super) fn thread_started(&self) {
+        self.lock().metrics.threads += 1;
+    }
+
+    pub(super) fn thread_exited(&self) {
+        self.lock().metrics.threads -= 1;
+        self.changed.notify_all();
+    }
+
+    pub(super) fn metrics(&self) -> PoolMetrics {
+        self.lock().metrics
+    }
+
+    /// Blocks until no job is queued or running.
+    pub(super) fn join(&self) {
+        let mut state = self.lock();
+        while !is_idle(&state.metrics) {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Blocks at most `timeout` until no job is queued or running and, if
+    /// `threads` is true, until all the threads have exited.
+    ///
+    /// Returns whether it happened in time.
+    pub(super) fn wait_exit(&self, timeout: Duration, threads: bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if is_idle(&state.metrics) && (!threads || state.metrics.threads == 0) {
+                return true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn is_idle(metrics: &PoolMetrics) -> bool {
+    metrics.queued == 0 && metrics.active == 0
+}
+
+// A job waiting for a thread. It is uncounted if dropped without being run.
+struct Queued(Option<Arc<Tracker>>);
+
+impl Queued {
+    fn start(mut self) -> Running {
+        let tracker = self.0.take().unwrap();
+        {
+            let mut state = tracker.lock();
+            state.metrics.queued -= 1;
+            state.metrics.active += 1;
+        }
+        Running(tracker)
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        if let Some(ref tracker) = self.0 {
+            tracker.lock().metrics.queued -= 1;
+            tracker.changed.notify_all();
+        }
+    }
+}
+
+// A running job. It is counted as panicked if dropped while unwinding.
+struct Running(Arc<Tracker>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        {
+            let mut state = self.0.lock();
+            state.metrics.active -= 1;
+            if thread::panicking() {
+                state.metrics.panicked += 1;
+            } else {
+                state.metrics.completed += 1;
+            }
+        }
+        self.0.changed.notify_all();
+    }
+}
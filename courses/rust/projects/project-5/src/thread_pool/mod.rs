@@ -1,18 +1,31 @@
 //! This module provides various thread pools. All thread pools should implement
 //! the `ThreadPool` trait.
 
+use std::time::Duration;
+
 use crate::Result;
 
+mod join;
+mod metrics;
 mod naive;
+mod priority;
 mod rayon;
+mod schedule;
 mod shared_queue;
+mod sync;
+mod work_stealing;
 
+pub use self::join::{JoinHandle, Scope};
+pub use self::metrics::PoolMetrics;
 pub use self::naive::NaiveThreadPool;
+pub use self::priority::PriorityThreadPool;
 pub use self::rayon::RayonThreadPool;
-pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::schedule::{Priority, Schedule, ScheduleExt};
+pub use self::shared_queue::{QueueFullPolicy, SharedQueueThreadPool};
+pub use self::work_stealing::WorkStealingThreadPool;
 
 /// The trait that all thread pools should implement.
-pub trait ThreadPool: Clone + Send + 'static {
+pub trait ThreadPool: Clone + Send + Sync + 'static {
     /// Creates a new thread pool, immediately spawning the specified number of
     /// threads.
     ///
@@ -27,7 +40,86 @@ pub trait ThreadPool: Clone + Send + 'static {
     /// Spawning always succeeds, but if the function panics the threadpool continues
     /// to operate with the same number of threads &mdash; the thread count is not
     /// reduced nor is the thread pool destroyed, corrupted or invalidated.
+    ///
+    /// Functions spawned after `shutdown` are discarded, and so are the ones
+    /// rejected by a busy pool. Use `try_spawn` to detect the latter.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool, unless the pool is too busy.
+    ///
+    /// Returns `KvsError::Busy` if the function is rejected. By default, it
+    /// never fails and is the same as `spawn`.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Spawns a function into the thread pool, ordered by the given schedule.
+    ///
+    /// By default, the schedule is ignored and it is the same as `try_spawn`.
+    /// `PriorityThreadPool` runs the functions in the order of their
+    /// priorities and deadlines. Use `ScheduleExt` to drop a function once its
+    /// deadline has passed.
+    fn try_spawn_scheduled<F>(&self, schedule: Schedule, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = schedule;
+        self.try_spawn(job)
+    }
+
+    /// Spawns a function into the thread pool and returns a handle to its result.
+    ///
+    /// The handle resolves to `KvsError::TaskPanicked` if the function panics or
+    /// is discarded because the pool is shut down, and to `KvsError::Busy` if
+    /// the pool rejects it.
+    fn spawn_with_handle<F, T>(&self, job: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+        Self: Sized,
+    {
+        JoinHandle::new(self, job)
+    }
+
+    /// Creates a scope for spawning functions that borrow non-`'static` data.
+    ///
+    /// All the functions spawned in the scope have finished when `scope`
+    /// returns. It returns the error of spawning a function if the pool
+    /// rejected any, or `KvsError::TaskPanicked` if any of them panicked.
+    fn scope<'env, F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Scope<'env, Self>) -> R,
+        Self: Sized,
+    {
+        let scope = Scope::new(self.clone());
+        let res = f(&scope);
+        scope.join()?;
+        Ok(res)
+    }
+
+    /// Stops accepting new functions and waits at most `timeout` for the queued
+    /// and running ones to finish and for the threads to exit.
+    ///
+    /// Returns `false` if the timeout elapsed first. The remaining functions
+    /// still run to completion in the background.
+    fn shutdown(&self, timeout: Duration) -> bool;
+
+    /// Blocks until all the spawned functions have finished.
+    ///
+    /// Unlike `shutdown`, the pool keeps accepting new functions.
+    fn join(&self);
+
+    /// Changes the number of threads in the pool.
+    ///
+    /// Surplus threads exit once they finish their current function.
+    fn resize(&self, threads: u32) -> Result<()>;
+
+    /// Returns a snapshot of the state of the pool.
+    fn metrics(&self) -> PoolMetrics;
 }
@@ -1,22 +1,69 @@
-use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
 
-use super::ThreadPool;
+use super::metrics::Tracker;
+use super::sync::thread;
+use super::{PoolMetrics, ThreadPool};
 use crate::Result;
 
 /// It is actually not a thread pool. It spawns a new thread every time
 /// the `spawn` method is called.
 #[derive(Clone)]
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    tracker: Arc<Tracker>,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u32) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool {
+            tracker: Arc::default(),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        if let Some(job) = self.tracker.track(job) {
+            let thread = ThreadGuard::new(&self.tracker);
+            thread::spawn(move || {
+                let _thread = thread;
+                job();
+            });
+        }
+    }
+
+    fn shutdown(&self, timeout: Duration) -> bool {
+        self.tracker.shutdown();
+        self.tracker.wait_exit(timeout, true)
+    }
+
+    fn join(&self) {
+        self.tracker.join();
+    }
+
+    /// Does nothing because there is a thread for every spawned function.
+    fn resize(&self, _threads: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.tracker.metrics()
+    }
+}
+
+// Counts the thread as live until it exits.
+struct ThreadGuard(Arc<Tracker>);
+
+impl ThreadGuard {
+    fn new(tracker: &Arc<Tracker>) -> ThreadGuard {
+        tracker.thread_started();
+        ThreadGuard(Arc::clone(tracker))
+    }
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        self.0.thread_exited();
     }
 }
@@ -0,0 +1,258 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::metrics::{Job, Tracker};
+use super::sync::{thread, Condvar, Mutex, MutexGuard};
+use super::{PoolMetrics, Schedule, ThreadPool};
+use crate::{KvsError, Result};
+
+/// A thread pool running jobs in the order of their `Schedule`s.
+///
+/// Jobs with a higher priority run first. Among jobs with the same priority,
+/// the ones with an earlier deadline run first, then the ones without a
+/// deadline, in the order they are spawned. Use `ScheduleExt` to spawn jobs
+/// with a schedule; `spawn` uses the default one.
+///
+/// Like `SharedQueueThreadPool`, if a spawned task panics, the old thread will be
+/// destroyed and a new one will be created.
+#[derive(Clone)]
+pub struct PriorityThreadPool {
+    handle: Arc<PoolHandle>,
+}
+
+impl PriorityThreadPool {
+    /// Spawns a function into the thread pool with the given schedule.
+    ///
+    /// The deadline only orders the function. Use `ScheduleExt` to drop it
+    /// once the deadline has passed.
+    pub fn spawn_prioritized<F>(&self, schedule: Schedule, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = &self.handle.0;
+        // the job is queued under the lock, so that no thread exits before it runs
+        let mut queue = shared.lock();
+        if queue.shutdown {
+            warn!("A job is discarded because the thread pool is shut down.");
+            return;
+        }
+        if let Some(job) = shared.tracker.track(job) {
+            let seq = queue.next_seq;
+            queue.next_seq += 1;
+            queue.jobs.push(Entry { schedule, seq, job });
+            shared.available.notify_one();
+        }
+    }
+}
+
+impl ThreadPool for PriorityThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: BinaryHeap::new(),
+                next_seq: 0,
+                threads,
+                retiring: 0,
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            tracker: Arc::default(),
+        });
+        // dropping the handle on error terminates the spawned threads
+        let handle = Arc::new(PoolHandle(Arc::clone(&shared)));
+        for _ in 0..threads {
+            shared.spawn_thread()?;
+        }
+        Ok(PriorityThreadPool { handle })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn_prioritized(Schedule::default(), job);
+    }
+
+    fn try_spawn_scheduled<F>(&self, schedule: Schedule, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn_prioritized(schedule, job);
+        Ok(())
+    }
+
+    fn shutdown(&self, timeout: Duration) -> bool {
+        let shared = &self.handle.0;
+        shared.tracker.shutdown();
+        shared.terminate();
+        shared.tracker.wait_exit(timeout, true)
+    }
+
+    fn join(&self) {
+        self.handle.0.tracker.join();
+    }
+
+    fn resize(&self, threads: u32) -> Result<()> {
+        let shared = &self.handle.0;
+        let mut queue = shared.lock();
+        if queue.shutdown {
+            return Err(KvsError::StringError(
+                "The thread pool is shut down".to_owned(),
+            ));
+        }
+        if threads > queue.threads {
+            // cancel the retirements no thread has taken yet
+            let extra = threads - queue.threads;
+            let cancelled = queue.retiring.min(extra);
+            queue.retiring -= cancelled;
+            queue.threads += cancelled;
+            for _ in cancelled..extra {
+                shared.spawn_thread()?;
+                queue.threads += 1;
+            }
+        } else {
+            queue.retiring += queue.threads - threads;
+            queue.threads = threads;
+            shared.available.notify_all();
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.handle.0.tracker.metrics()
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    tracker: Arc<Tracker>,
+}
+
+struct Queue {
+    jobs: BinaryHeap<Entry>,
+    next_seq: u64,
+    // the number of threads the pool is resized to
+    threads: u32,
+    // number of threads asked to exit because the pool shrinks
+    retiring: u32,
+    shutdown: bool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+
+    fn spawn_thread(self: &Arc<Self>) -> Result<()> {
+        self.tracker.thread_started();
+        if let Err(e) = spawn_worker(Arc::clone(self)) {
+            self.tracker.thread_exited();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Blocks until there is a job to run.
+    ///
+    /// Returns `None` if the thread should exit.
+    fn next_job(&self) -> Option<Job> {
+        let mut queue = self.lock();
+        loop {
+            if queue.retiring > 0 {
+                queue.retiring -= 1;
+                debug!("Thread exits because the thread pool shrinks.");
+                return None;
+            }
+            if let Some(entry) = queue.jobs.pop() {
+                return Some(entry.job);
+            }
+            if queue.shutdown {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return None;
+            }
+            queue = self.available.wait(queue).unwrap();
+        }
+    }
+
+    /// Lets the threads exit once no job is left.
+    fn terminate(&self) {
+        self.lock().shutdown = true;
+        self.available.notify_all();
+    }
+}
+
+// Shuts down the threads when the last `PriorityThreadPool` is dropped.
+struct PoolHandle(Arc<Shared>);
+
+impl Drop for PoolHandle {
+    fn drop(&mut self) {
+        self.0.terminate();
+    }
+}
+
+struct Entry {
+    schedule: Schedule,
+    seq: u64,
+    job: Job,
+}
+
+// `BinaryHeap` is a max-heap, so the entry to run first is the greatest.
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        let deadline = match (self.schedule.deadline, other.schedule.deadline) {
+            (Some(a), Some(b)) => b.cmp(&a),
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (None, None) => Ordering::Equal,
+        };
+        self.schedule
+            .priority
+            .cmp(&other.schedule.priority)
+            .then(deadline)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Entry {}
+
+struct TaskWorker(Arc<Shared>);
+
+impl Drop for TaskWorker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // the new thread takes the place of this one
+            match spawn_worker(Arc::clone(&self.0)) {
+                Ok(()) => return,
+                Err(e) => error!("Failed to spawn a thread: {}", e),
+            }
+        }
+        self.0.tracker.thread_exited();
+    }
+}
+
+fn spawn_worker(shared: Arc<Shared>) -> io::Result<()> {
+    thread::Builder::new().spawn(move || run_tasks(TaskWorker(shared)))?;
+    Ok(())
+}
+
+fn run_tasks(worker: TaskWorker) {
+    while let Some(job) = worker.0.next_job() {
+        job();
+    }
+}
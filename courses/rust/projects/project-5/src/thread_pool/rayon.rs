@@ -1,24 +1,71 @@
-use super::ThreadPool;
+use super::metrics::Tracker;
+use super::{PoolMetrics, ThreadPool};
 use crate::{KvsError, Result};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 /// Wrapper of rayon::ThreadPool
+///
+/// Resizing replaces the inner pool. The threads of the old pool exit after
+/// finishing the functions already spawned into it.
+///
+/// A panicking function is counted in the metrics and reported to its handle
+/// or scope like in the other pools, instead of aborting the process as rayon
+/// does by default.
 #[derive(Clone)]
-pub struct RayonThreadPool(Arc<rayon::ThreadPool>);
+pub struct RayonThreadPool {
+    pool: Arc<RwLock<Arc<rayon::ThreadPool>>>,
+    tracker: Arc<Tracker>,
+}
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(threads as usize)
-            .build()
-            .map_err(|e| KvsError::StringError(format!("{}", e)))?;
-        Ok(RayonThreadPool(Arc::new(pool)))
+        Ok(RayonThreadPool {
+            pool: Arc::new(RwLock::new(build(threads)?)),
+            tracker: Arc::default(),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.0.spawn(job)
+        if let Some(job) = self.tracker.track(job) {
+            self.pool.read().unwrap().spawn(job)
+        }
+    }
+
+    /// Rayon threads exit when the last clone of the pool is dropped, so this
+    /// only waits for the spawned functions.
+    fn shutdown(&self, timeout: Duration) -> bool {
+        self.tracker.shutdown();
+        self.tracker.wait_exit(timeout, false)
     }
+
+    fn join(&self) {
+        self.tracker.join();
+    }
+
+    fn resize(&self, threads: u32) -> Result<()> {
+        let pool = build(threads)?;
+        *self.pool.write().unwrap() = pool;
+        Ok(())
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            threads: self.pool.read().unwrap().current_num_threads(),
+            ..self.tracker.metrics()
+        }
+    }
+}
+
+fn build(threads: u32) -> Result<Arc<rayon::ThreadPool>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads as usize)
+        // the tracker and the handles record the panic while it unwinds
+        .panic_handler(|_| error!("A job panicked in the rayon thread pool"))
+        .build()
+        .map_err(|e| KvsError::StringError(format!("{}", e)))?;
+    Ok(Arc::new(pool))
 }
@@ -0,0 +1,107 @@
+use std::time::Instant;
+
+use super::{JoinHandle, ThreadPool};
+use crate::{KvsError, Result};
+
+/// The priority of a job. Higher priority jobs run first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Background work such as bulk loads, which may wait.
+    Low,
+    /// Ordinary work.
+    #[default]
+    Normal,
+    /// Latency-sensitive work such as interactive reads.
+    High,
+}
+
+/// When a job should run: its priority and an optional deadline.
+///
+/// A job spawned by `ScheduleExt` whose deadline has passed when a thread picks
+/// it up is dropped without running.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// The priority of the job.
+    pub priority: Priority,
+    /// The instant after which the job is no longer worth running.
+    pub deadline: Option<Instant>,
+}
+
+impl Schedule {
+    /// Creates a schedule with the given priority and no deadline.
+    pub fn new(priority: Priority) -> Schedule {
+        Schedule {
+            priority,
+            deadline: None,
+        }
+    }
+
+    /// Sets the deadline of the schedule.
+    pub fn deadline(mut self, deadline: Instant) -> Schedule {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Returns whether the deadline has passed.
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
+
+/// Extension of `ThreadPool` for spawning jobs with a `Schedule`.
+///
+/// It is implemented for every thread pool. `PriorityThreadPool` runs the jobs
+/// in the order of their priorities and deadlines. The other pools ignore the
+/// priority, but still drop the jobs whose deadline has passed.
+pub trait ScheduleExt: ThreadPool {
+    /// Spawns a function into the thread pool with the given schedule.
+    ///
+    /// Like `ThreadPool::try_spawn`, it returns `KvsError::Busy` if the pool
+    /// rejects the function.
+    fn spawn_scheduled<F>(&self, schedule: Schedule, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static;
+
+    /// Spawns a function into the thread pool with the given schedule and
+    /// returns a handle to its result.
+    ///
+    /// The handle resolves to `KvsError::DeadlineExceeded` if the function is
+    /// dropped because its deadline has passed.
+    fn spawn_scheduled_with_handle<F, T>(&self, schedule: Schedule, job: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+}
+
+impl<P: ThreadPool> ScheduleExt for P {
+    fn spawn_scheduled<F>(&self, schedule: Schedule, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_spawn_scheduled(schedule, move || {
+            if schedule.is_expired() {
+                debug!("A job is dropped because its deadline has passed.");
+            } else {
+                job();
+            }
+        })
+    }
+
+    fn spawn_scheduled_with_handle<F, T>(&self, schedule: Schedule, job: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        JoinHandle::with_spawner(
+            move || {
+                if schedule.is_expired() {
+                    Err(KvsError::DeadlineExceeded)
+                } else {
+                    Ok(job())
+                }
+            },
+            |job| self.try_spawn_scheduled(schedule, job),
+        )
+    }
+}
@@ -1,9 +1,13 @@
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 
-use super::ThreadPool;
-use crate::Result;
+use super::metrics::{Job, Tracker};
+use super::{PoolMetrics, ThreadPool};
+use crate::{KvsError, Result};
 
-use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
 
 // Note for Rust training course: the thread pool is not implemented using
 // `catch_unwind` because it would require the task to be `UnwindSafe`.
@@ -13,58 +17,202 @@ use crossbeam::channel::{self, Receiver, Sender};
 /// If a spawned task panics, the old thread will be destroyed and a new one will be
 /// created. It fails silently when any failure to create the thread at the OS level
 /// is captured after the thread pool is created. So, the thread number in the pool
-/// can decrease to zero, then spawning a task to the thread pool will panic.
+/// can decrease to zero, then spawned tasks wait in the queue until the pool is
+/// resized.
+///
+/// The queue is unbounded unless the pool is created by `bounded`.
 #[derive(Clone)]
 pub struct SharedQueueThreadPool {
-    tx: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    inner: Arc<Inner>,
 }
 
-impl ThreadPool for SharedQueueThreadPool {
-    fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+struct Inner {
+    // `None` after shutdown, so that the threads exit once the queue is empty
+    tx: RwLock<Option<Sender<Message>>>,
+    rx: Receiver<Message>,
+    // the number of threads the pool is resized to
+    threads: Mutex<u32>,
+    policy: QueueFullPolicy,
+    tracker: Arc<Tracker>,
+}
+
+/// What `SharedQueueThreadPool::try_spawn` does when a bounded queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Blocks the spawning thread until there is room in the queue.
+    Block,
+    /// Rejects the task with `KvsError::Busy`.
+    Reject,
+    /// Runs the task on the spawning thread.
+    CallerRuns,
+}
+
+enum Message {
+    Run(Job),
+    // asks one thread to exit when the pool shrinks
+    Exit,
+}
+
+impl SharedQueueThreadPool {
+    /// Creates a thread pool whose queue holds at most `capacity` tasks.
+    ///
+    /// `policy` decides what happens to the tasks spawned when the queue is full.
+    /// The threads are spawned like in `ThreadPool::new`.
+    pub fn bounded(threads: u32, capacity: usize, policy: QueueFullPolicy) -> Result<Self> {
+        let (tx, rx) = channel::bounded(capacity);
+        SharedQueueThreadPool::with_channel(threads, tx, rx, policy)
+    }
+
+    fn with_channel(
+        threads: u32,
+        tx: Sender<Message>,
+        rx: Receiver<Message>,
+        policy: QueueFullPolicy,
+    ) -> Result<Self> {
+        let inner = Inner {
+            tx: RwLock::new(Some(tx)),
+            rx,
+            threads: Mutex::new(threads),
+            policy,
+            tracker: Arc::default(),
+        };
+        // dropping `inner` on error terminates the spawned threads
         for _ in 0..threads {
-            let rx = TaskReceiver(rx.clone());
-            thread::Builder::new().spawn(move || run_tasks(rx))?;
+            inner.spawn_thread()?;
         }
-        Ok(SharedQueueThreadPool { tx })
+        Ok(SharedQueueThreadPool {
+            inner: Arc::new(inner),
+        })
+    }
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (tx, rx) = channel::unbounded();
+        SharedQueueThreadPool::with_channel(threads, tx, rx, QueueFullPolicy::Block)
     }
 
-    /// Spawns a function into the thread pool.
-    ///
-    /// # Panics
-    ///
-    /// Panics if the thread pool has no thread.
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.tx
-            .send(Box::new(job))
-            .expect("The thread pool has no thread.");
+        if let Err(e) = self.try_spawn(job) {
+            warn!("A job is discarded: {}", e);
+        }
+    }
+
+    /// Spawns a function into the thread pool, following the `QueueFullPolicy`
+    /// if the queue is full.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job = match self.inner.tracker.track(job) {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+        // a clone, so that a blocked sender doesn't hold up `shutdown`
+        let tx = match *self.inner.tx.read().unwrap() {
+            Some(ref tx) => tx.clone(),
+            None => return Ok(()),
+        };
+        // the pool holds a receiver, so sending never fails for disconnection
+        match self.inner.policy {
+            QueueFullPolicy::Block => tx.send(Message::Run(job)).unwrap(),
+            policy => match tx.try_send(Message::Run(job)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(Message::Run(job))) => {
+                    if policy == QueueFullPolicy::Reject {
+                        return Err(KvsError::Busy);
+                    }
+                    job();
+                }
+                Err(_) => unreachable!(),
+            },
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self, timeout: Duration) -> bool {
+        self.inner.tracker.shutdown();
+        self.inner.tx.write().unwrap().take();
+        self.inner.tracker.wait_exit(timeout, true)
+    }
+
+    fn join(&self) {
+        self.inner.tracker.join();
+    }
+
+    fn resize(&self, threads: u32) -> Result<()> {
+        let mut current = self.inner.threads.lock().unwrap();
+        let tx = self.inner.tx.read().unwrap();
+        let tx = tx
+            .as_ref()
+            .ok_or_else(|| KvsError::StringError("The thread pool is shut down".to_owned()))?;
+        while *current < threads {
+            self.inner.spawn_thread()?;
+            *current += 1;
+        }
+        while *current > threads {
+            tx.send(Message::Exit).unwrap();
+            *current -= 1;
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.inner.tracker.metrics()
     }
 }
 
-#[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+impl Inner {
+    fn spawn_thread(&self) -> Result<()> {
+        self.tracker.thread_started();
+        if let Err(e) = spawn_worker(self.rx.clone(), Arc::clone(&self.tracker)) {
+            self.tracker.thread_exited();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+struct TaskReceiver {
+    rx: Receiver<Message>,
+    tracker: Arc<Tracker>,
+}
 
 impl Drop for TaskReceiver {
     fn drop(&mut self) {
         if thread::panicking() {
-            let rx = self.clone();
-            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
-                error!("Failed to spawn a thread: {}", e);
+            // the new thread takes the place of this one
+            match spawn_worker(self.rx.clone(), Arc::clone(&self.tracker)) {
+                Ok(()) => return,
+                Err(e) => error!("Failed to spawn a thread: {}", e),
             }
         }
+        self.tracker.thread_exited();
     }
 }
 
+fn spawn_worker(rx: Receiver<Message>, tracker: Arc<Tracker>) -> io::Result<()> {
+    thread::Builder::new().spawn(move || run_tasks(TaskReceiver { rx, tracker }))?;
+    Ok(())
+}
+
 fn run_tasks(rx: TaskReceiver) {
     loop {
-        match rx.0.recv() {
-            Ok(task) => {
+        match rx.rx.recv() {
+            Ok(Message::Run(task)) => {
                 task();
             }
-            Err(_) => debug!("Thread exits because the thread pool is destroyed."),
+            Ok(Message::Exit) => {
+                debug!("Thread exits because the thread pool shrinks.");
+                return;
+            }
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
+            }
         }
     }
 }
@@ -0,0 +1,15 @@
+//! The synchronization primitives of the pools built on `std::sync`.
+//!
+//! With the `loom` feature, they are replaced by the ones of loom so that the
+//! pools can be model checked. See `tests/loom.rs`. `Arc` is always the one of
+//! the standard library because it is used as a method receiver.
+
+#[cfg(feature = "loom")]
+pub(super) use loom::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(feature = "loom")]
+pub(super) use loom::thread;
+
+#[cfg(not(feature = "loom"))]
+pub(super) use std::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(not(feature = "loom"))]
+pub(super) use std::thread;
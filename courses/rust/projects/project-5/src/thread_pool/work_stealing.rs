@@ -0,0 +1,270 @@
+use std::io;
+use std::iter;
+use std::mem;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use super::metrics::{Job, Tracker};
+use super::{PoolMetrics, ThreadPool};
+use crate::{KvsError, Result};
+
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+
+/// A thread pool where each thread has its own task queue.
+///
+/// Spawned tasks are pushed into a global injector queue. An idle thread first
+/// takes tasks from its own queue, then moves a batch of tasks from the injector
+/// into its own queue, and finally steals from the queues of the other threads.
+///
+/// Like `SharedQueueThreadPool`, if a spawned task panics, the old thread will be
+/// destroyed and a new one taking over its queue will be created.
+#[derive(Clone)]
+pub struct WorkStealingThreadPool {
+    handle: Arc<PoolHandle>,
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: RwLock::new(Vec::new()),
+            next_id: AtomicUsize::new(0),
+            retiring: AtomicUsize::new(0),
+            sleepers: AtomicUsize::new(0),
+            threads: Mutex::new(threads),
+            shutdown: Mutex::new(false),
+            wakeup: Condvar::new(),
+            tracker: Arc::default(),
+        });
+        // dropping the handle on error terminates the spawned threads
+        let handle = Arc::new(PoolHandle(Arc::clone(&shared)));
+        for _ in 0..threads {
+            shared.spawn_thread()?;
+        }
+        Ok(WorkStealingThreadPool { handle })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = &self.handle.0;
+        if let Some(job) = shared.tracker.track(job) {
+            shared.injector.push(job);
+            shared.notify();
+        }
+    }
+
+    fn shutdown(&self, timeout: Duration) -> bool {
+        let shared = &self.handle.0;
+        shared.tracker.shutdown();
+        shared.terminate();
+        shared.tracker.wait_exit(timeout, true)
+    }
+
+    fn join(&self) {
+        self.handle.0.tracker.join();
+    }
+
+    fn resize(&self, threads: u32) -> Result<()> {
+        let shared = &self.handle.0;
+        let mut current = shared.threads.lock().unwrap();
+        if shared.tracker.is_shutdown() {
+            return Err(KvsError::StringError(
+                "The thread pool is shut down".to_owned(),
+            ));
+        }
+        if threads > *current {
+            // cancel the retirements no thread has taken yet
+            let extra = (threads - *current) as usize;
+            let cancelled = shared
+                .retiring
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retiring| {
+                    Some(retiring.saturating_sub(extra))
+                })
+                .unwrap();
+            for _ in cancelled.min(extra)..extra {
+                shared.spawn_thread()?;
+                *current += 1;
+            }
+            *current += cancelled.min(extra) as u32;
+        } else {
+            shared
+                .retiring
+                .fetch_add((*current - threads) as usize, Ordering::SeqCst);
+            *current = threads;
+            let _lock = shared.shutdown.lock().unwrap();
+            shared.wakeup.notify_all();
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> PoolMetrics {
+        self.handle.0.tracker.metrics()
+    }
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    // the queues of the live threads, by thread id
+    stealers: RwLock<Vec<(usize, Stealer<Job>)>>,
+    next_id: AtomicUsize,
+    // number of threads asked to exit because the pool shrinks
+    retiring: AtomicUsize,
+    // number of threads waiting for `wakeup`
+    sleepers: AtomicUsize,
+    // the number of threads the pool is resized to
+    threads: Mutex<u32>,
+    shutdown: Mutex<bool>,
+    wakeup: Condvar,
+    tracker: Arc<Tracker>,
+}
+
+impl Shared {
+    fn spawn_thread(self: &Arc<Self>) -> Result<()> {
+        let local = Worker::new_fifo();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.stealers.write().unwrap().push((id, local.stealer()));
+        self.tracker.thread_started();
+        if let Err(e) = spawn_worker(id, local, Arc::clone(self)) {
+            self.stealers.write().unwrap().retain(|&(i, _)| i != id);
+            self.tracker.thread_exited();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Wakes up a sleeping thread after a task is pushed into the injector.
+    fn notify(&self) {
+        // pairs with the fence in `Shared::wait`, so that either the sleeping
+        // thread sees the new task or we see the sleeping thread
+        atomic::fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _lock = self.shutdown.lock().unwrap();
+            self.wakeup.notify_one();
+        }
+    }
+
+    /// Blocks until there may be a task in the injector or a thread should
+    /// retire.
+    ///
+    /// Returns `false` if the pool is destroyed and no task is left.
+    fn wait(&self) -> bool {
+        let mut shutdown = self.shutdown.lock().unwrap();
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        while self.injector.is_empty() && !*shutdown && self.retiring.load(Ordering::SeqCst) == 0 {
+            shutdown = self.wakeup.wait(shutdown).unwrap();
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        !self.injector.is_empty() || !*shutdown
+    }
+
+    /// Takes one of the pending retirements, if any.
+    fn take_retirement(&self) -> bool {
+        self.retiring
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retiring| {
+                retiring.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Lets the threads exit once no task is left.
+    fn terminate(&self) {
+        *self.shutdown.lock().unwrap() = true;
+        self.wakeup.notify_all();
+    }
+}
+
+// Shuts down the threads when the last `WorkStealingThreadPool` is dropped.
+struct PoolHandle(Arc<Shared>);
+
+impl Drop for PoolHandle {
+    fn drop(&mut self) {
+        self.0.terminate();
+    }
+}
+
+struct TaskWorker {
+    id: usize,
+    local: Worker<Job>,
+    shared: Arc<Shared>,
+}
+
+impl TaskWorker {
+    fn find_task(&self) -> Option<Job> {
+        self.local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.shared
+                    .injector
+                    .steal_batch_and_pop(&self.local)
+                    .or_else(|| {
+                        self.shared
+                            .stealers
+                            .read()
+                            .unwrap()
+                            .iter()
+                            .filter(|&&(id, _)| id != self.id)
+                            .map(|(_, stealer)| stealer.steal())
+                            .collect::<Steal<Job>>()
+                    })
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    /// Hands the local tasks over to the other threads before exiting.
+    fn retire(&self) {
+        let shared = &self.shared;
+        shared
+            .stealers
+            .write()
+            .unwrap()
+            .retain(|&(id, _)| id != self.id);
+        while let Some(task) = self.local.pop() {
+            shared.injector.push(task);
+            shared.notify();
+        }
+    }
+}
+
+impl Drop for TaskWorker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            // the new thread takes over the queue, whose stealer is still shared
+            let local = mem::replace(&mut self.local, Worker::new_fifo());
+            match spawn_worker(self.id, local, Arc::clone(&self.shared)) {
+                Ok(()) => return,
+                Err(e) => error!("Failed to spawn a thread: {}", e),
+            }
+        }
+        self.shared.tracker.thread_exited();
+    }
+}
+
+fn spawn_worker(id: usize, local: Worker<Job>, shared: Arc<Shared>) -> io::Result<()> {
+    thread::Builder::new().spawn(move || run_tasks(TaskWorker { id, local, shared }))?;
+    Ok(())
+}
+
+fn run_tasks(worker: TaskWorker) {
+    loop {
+        if worker.shared.take_retirement() {
+            debug!("Thread exits because the thread pool shrinks.");
+            worker.retire();
+            return;
+        }
+        match worker.find_task() {
+            Some(task) => task(),
+            None => {
+                if !worker.shared.wait() {
+                    debug!("Thread exits because the thread pool is destroyed.");
+                    return;
+                }
+            }
+        }
+    }
+}
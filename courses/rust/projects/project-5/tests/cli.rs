@@ -1,7 +1,8 @@
 use assert_cmd::prelude::*;
-use predicates::str::{contains, is_empty};
+use predicates::str::{contains, is_empty, is_match, starts_with};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -335,3 +336,423 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+fn cli_namespaces(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        client
+    };
+
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["set", "key1", "value2", "-n", "users"])
+        .assert()
+        .success();
+    client(&["set", "key2", "value3", "--namespace", "users"])
+        .assert()
+        .success();
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "key1", "-n", "users"])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["namespaces"])
+        .assert()
+        .success()
+        .stdout("(default)\nusers\n");
+    client(&["stats", "-n", "users"])
+        .assert()
+        .success()
+        .stdout(contains("keys: 2\n"));
+
+    client(&["drop-namespace", "users"]).assert().success();
+    client(&["get", "key1", "-n", "users"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["namespaces"])
+        .assert()
+        .success()
+        .stdout("(default)\n");
+    client(&["drop-namespace", "users"])
+        .assert()
+        .failure()
+        .stderr(contains("Namespace not found"));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_namespaces_kvs_engine() {
+    cli_namespaces("kvs", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_namespaces_sled_engine() {
+    cli_namespaces("sled", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_script() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        client
+    };
+
+    let script = temp_dir.path().join("script.txt");
+    fs::write(
+        &script,
+        "# a comment\n\
+         set key1 value1\n\
+         \n\
+         set \"key 2\" 'value 2'\n\
+         get \"key 2\"\n\
+         use users\n\
+         set key1 value3\n\
+         get key1\n\
+         namespaces\n",
+    )
+    .unwrap();
+    client(&["exec", "-f", script.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout("value 2\nvalue3\n(default)\nusers\n");
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // the script stops at the first failed command
+    client(&["exec"])
+        .with_stdin()
+        .buffer("get key1\nrm key3\nget key1\n")
+        .assert()
+        .failure()
+        .stdout("value1\n")
+        .stderr(contains("line 2: Key not found"));
+    client(&["exec", "-f", "-", "--keep-going"])
+        .with_stdin()
+        .buffer("rm key3\nbogus\nget key1\nexit\nget key1\n")
+        .assert()
+        .failure()
+        .stdout("value1\n")
+        .stderr(contains("line 1: Key not found"))
+        .stderr(contains("line 2: Invalid command"))
+        .stderr(contains("first at line 1"));
+
+    // the shell runs the standard input as a script if it is not a terminal
+    client(&["shell", "-n", "users"])
+        .with_stdin()
+        .buffer("get key1\nstats\n")
+        .assert()
+        .success()
+        .stdout(starts_with("value3\nkeys: 1\nbytes: "));
+
+    client(&["--output", "json", "get", "key1"])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value1\"}\n");
+    client(&["get", "key3", "--output", "json"])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key3\",\"value\":null}\n");
+    client(&["rm", "key3", "--output", "json"])
+        .assert()
+        .failure()
+        .stdout("{\"error\":\"Key not found\"}\n");
+    client(&["--output", "json", "exec"])
+        .with_stdin()
+        .buffer("set key4 value4\nnamespaces\nstats\n")
+        .assert()
+        .success()
+        .stdout(starts_with(
+            "{\"ok\":true}\n\
+             {\"namespaces\":[\"\",\"users\"]}\n\
+             {\"bytes\":",
+        ))
+        .stdout(contains(",\"keys\":3}\n"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_export_import() {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        client
+    };
+
+    client(&["set", "a1", "v1", "-n", "users"])
+        .assert()
+        .success();
+    client(&["set", "a2", "x,y", "-n", "users"])
+        .assert()
+        .success();
+    client(&["set", "b1", "v3", "-n", "users"])
+        .assert()
+        .success();
+    client(&["set", "a3", "v4"]).assert().success();
+
+    client(&["export", "-n", "users", "--prefix", "a"])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"a1\",\"value\":\"v1\"}\n{\"key\":\"a2\",\"value\":\"x,y\"}\n")
+        .stderr(contains("Exported 2 records as of sequence number 4"));
+    let output = client(&["export", "-n", "users", "--format", "csv"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout.clone()).unwrap(),
+        "key,value\na1,v1\na2,\"x,y\"\nb1,v3\n"
+    );
+    fs::write(temp_dir.path().join("users.csv"), output.stdout).unwrap();
+
+    // a resumed export replays the changes since it started
+    client(&[
+        "export", "-n", "users", "--prefix", "a", "--after", "a1", "--since", "0",
+    ])
+    .assert()
+    .success()
+    .stdout(
+        "{\"key\":\"a2\",\"value\":\"x,y\"}\n\
+         {\"key\":\"a1\",\"value\":\"v1\"}\n\
+         {\"key\":\"a2\",\"value\":\"x,y\"}\n",
+    );
+
+    client(&["import", "users.csv", "-n", "copy", "--batch-size", "2"])
+        .assert()
+        .success()
+        .stderr(contains("Imported 3 records"));
+    client(&["get", "a2", "-n", "copy"])
+        .assert()
+        .success()
+        .stdout("x,y\n");
+    client(&["get", "b1", "-n", "copy"])
+        .assert()
+        .success()
+        .stdout("v3\n");
+
+    // an import stops at an invalid record and resumes after it
+    fs::write(
+        temp_dir.path().join("changes.jsonl"),
+        "{\"key\":\"c1\",\"value\":\"v5\"}\n\
+         {\"key\":\"a1\",\"value\":null}\n\
+         bogus\n\
+         {\"key\":\"c2\",\"value\":\"v6\"}\n",
+    )
+    .unwrap();
+    client(&["import", "changes.jsonl", "-n", "copy", "--batch-size", "1"])
+        .assert()
+        .failure()
+        .stderr(contains("line 3"))
+        .stderr(contains("resume with --skip 2"));
+    client(&["get", "a1", "-n", "copy"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    client(&["import", "-", "--skip", "3", "-n", "copy"])
+        .with_stdin()
+        .path(temp_dir.path().join("changes.jsonl"))
+        .unwrap()
+        .assert()
+        .success()
+        .stderr(contains("Imported 1 records"));
+    client(&["exec", "-n", "copy"])
+        .with_stdin()
+        .buffer("get c1\nget c2\nget a3\n")
+        .assert()
+        .success()
+        .stdout("v5\nv6\nKey not found\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_bench() {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--duration", "1"])
+        .args(["--keys", "100", "--prefill"])
+        .args(["--distribution", "zipfian", "--max-value-size", "200"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("prefilled 100 keys"))
+        .stdout(contains("errors: 0\n"))
+        .stdout(contains("  get: count "))
+        .stdout(contains(" p999 "));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key0000000099", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_match("^[0-9A-Za-z]{100,200}\n$").unwrap());
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--read-ratio", "1.5"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read ratio"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for args in &[
+        vec!["set", "key1", "value1"],
+        vec!["set", "other", "value2"],
+        vec!["rm", "key1"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "1 set key1 value1");
+    assert_eq!(lines.next().unwrap().unwrap(), "3 rm key1");
+
+    // resume after the first event
+    let mut resumed = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "--after", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(resumed.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "2 set other value2");
+    assert_eq!(lines.next().unwrap().unwrap(), "3 rm key1");
+
+    for child in &mut [resumed, watcher, server] {
+        child.kill().expect("process exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+// A get the server can't start within the timeout of the client is dropped.
+#[test]
+fn cli_get_timeout() {
+    let addr = "127.0.0.1:4012";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--timeout-ms", "0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("deadline exceeded"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--timeout-ms", "10000", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
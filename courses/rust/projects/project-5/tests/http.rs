@@ -0,0 +1,182 @@
+use assert_cmd::prelude::*;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct Server {
+    child: Child,
+    temp_dir: TempDir,
+}
+
+impl Server {
+    fn start(engine: &str, addr: &str, http_addr: &str) -> Server {
+        let temp_dir = TempDir::new().unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr, "--http-addr", http_addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Server { child, temp_dir }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().expect("server exited before killed");
+    }
+}
+
+// Sends a single HTTP/1.1 request and returns the status code and the body.
+fn request(addr: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+    (status, body)
+}
+
+fn json(body: &str) -> Value {
+    serde_json::from_str(body).unwrap()
+}
+
+fn http_gateway(engine: &str, addr: &str, http_addr: &str) {
+    let _server = Server::start(engine, addr, http_addr);
+
+    let (status, body) = request(http_addr, "GET", "/kv/key1", "");
+    assert_eq!(status, 404);
+    assert_eq!(json(&body), json!({ "error": "Key not found" }));
+
+    let (status, _) = request(http_addr, "PUT", "/kv/key1", "value1");
+    assert_eq!(status, 204);
+    let (status, body) = request(http_addr, "GET", "/kv/key1", "");
+    assert_eq!(status, 200);
+    assert_eq!(json(&body), json!({ "key": "key1", "value": "value1" }));
+
+    let (status, _) = request(http_addr, "PUT", "/kv/key%202", "value 2");
+    assert_eq!(status, 204);
+    let (status, body) = request(http_addr, "GET", "/kv/key%202", "");
+    assert_eq!(status, 200);
+    assert_eq!(json(&body), json!({ "key": "key 2", "value": "value 2" }));
+    request(http_addr, "PUT", "/kv/other", "value3");
+
+    let (status, body) = request(http_addr, "GET", "/kv?prefix=key", "");
+    assert_eq!(status, 200);
+    assert_eq!(json(&body), json!({ "keys": ["key 2", "key1"] }));
+    let (status, body) = request(http_addr, "GET", "/kv", "");
+    assert_eq!(status, 200);
+    assert_eq!(json(&body), json!({ "keys": ["key 2", "key1", "other"] }));
+
+    let (status, _) = request(http_addr, "DELETE", "/kv/key1", "");
+    assert_eq!(status, 204);
+    let (status, _) = request(http_addr, "DELETE", "/kv/key1", "");
+    assert_eq!(status, 404);
+    let (status, _) = request(http_addr, "GET", "/kv/key1", "");
+    assert_eq!(status, 404);
+
+    let (status, _) = request(http_addr, "POST", "/kv/key1", "value1");
+    assert_eq!(status, 405);
+    let (status, _) = request(http_addr, "GET", "/unknown", "");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn http_gateway_kvs_engine() {
+    http_gateway("kvs", "127.0.0.1:4020", "127.0.0.1:4021");
+}
+
+#[test]
+fn http_gateway_sled_engine() {
+    http_gateway("sled", "127.0.0.1:4022", "127.0.0.1:4023");
+}
+
+// The gateway and the kvs protocol are served from the same engine.
+#[test]
+fn http_gateway_shares_engine() {
+    let addr = "127.0.0.1:4024";
+    let http_addr = "127.0.0.1:4025";
+    let server = Server::start("kvs", addr, http_addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&server.temp_dir)
+        .assert()
+        .success();
+    let (status, body) = request(http_addr, "GET", "/kv/key1", "");
+    assert_eq!(status, 200);
+    assert_eq!(json(&body), json!({ "key": "key1", "value": "value1" }));
+
+    request(http_addr, "PUT", "/kv/key1", "value2");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&server.temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+}
+
+#[test]
+fn http_gateway_body_limit() {
+    let addr = "127.0.0.1:4026";
+    let http_addr = "127.0.0.1:4027";
+    let _server = Server::start("kvs", addr, http_addr);
+
+    // a `Content-Length` above the limit is rejected before the body is sent
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    write!(
+        stream,
+        "PUT /kv/key1 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+        http_addr,
+        1u64 << 40
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+    // so is a chunked body once it grows above the limit
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    write!(
+        stream,
+        "PUT /kv/key1 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n",
+        http_addr
+    )
+    .unwrap();
+    let chunk = format!("100000\r\n{}\r\n", "a".repeat(0x100000));
+    for _ in 0..65 {
+        // the server may close the connection before the end of the body
+        if stream.write_all(chunk.as_bytes()).is_err() {
+            break;
+        }
+    }
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+    let (status, _) = request(http_addr, "GET", "/kv/key1", "");
+    assert_eq!(status, 404);
+}
@@ -1,25 +1,26 @@
-use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use futures::executor::block_on;
+use futures::{StreamExt, TryStreamExt};
+use kvs::thread_pool::{QueueFullPolicy, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{Event, KvStore, KvsEngine, KvsError, NamespaceStats, Result, SledKvsEngine};
+use std::sync::mpsc;
 use tempfile::TempDir;
-use tokio::prelude::*;
-use tokio::runtime::Runtime;
 use walkdir::WalkDir;
 
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+#[tokio::test]
+async fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
 
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
+        store.get("key1".to_owned()).await?,
         Some("value1".to_owned())
     );
     assert_eq!(
-        store.get("key2".to_owned()).wait()?,
+        store.get("key2".to_owned()).await?,
         Some("value2".to_owned())
     );
 
@@ -27,11 +28,11 @@ fn get_stored_value() -> Result<()> {
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
+        store.get("key1".to_owned()).await?,
         Some("value1".to_owned())
     );
     assert_eq!(
-        store.get("key2".to_owned()).wait()?,
+        store.get("key2".to_owned()).await?,
         Some("value2".to_owned())
     );
 
@@ -39,19 +40,19 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+#[tokio::test]
+async fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
+        store.get("key1".to_owned()).await?,
         Some("value1".to_owned())
     );
-    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    store.set("key1".to_owned(), "value2".to_owned()).await?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
+        store.get("key1".to_owned()).await?,
         Some("value2".to_owned())
     );
 
@@ -59,12 +60,12 @@ fn overwrite_value() -> Result<()> {
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
+        store.get("key1".to_owned()).await?,
         Some("value2".to_owned())
     );
-    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    store.set("key1".to_owned(), "value3".to_owned()).await?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
+        store.get("key1".to_owned()).await?,
         Some("value3".to_owned())
     );
 
@@ -72,44 +73,282 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+#[tokio::test]
+async fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(store.get("key2".to_owned()).await?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(store.get("key2".to_owned()).await?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+#[tokio::test]
+async fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert!(store.remove("key1".to_owned()).wait().is_err());
+    assert!(store.remove("key1".to_owned()).await.is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+#[tokio::test]
+async fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert!(store.remove("key1".to_owned()).await.is_ok());
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+    Ok(())
+}
+
+fn event(seq: u64, key: &str, value: Option<&str>) -> Event {
+    Event {
+        seq,
+        namespace: String::new(),
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+// Should stream changes of watched keys and resume from a sequence number
+#[tokio::test]
+async fn watch_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let events = store.watch("key".to_owned(), None);
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    store.set("other".to_owned(), "value2".to_owned()).await?;
+    store.set("key1".to_owned(), "value3".to_owned()).await?;
+    store.remove("key1".to_owned()).await?;
+
+    let expected = vec![
+        event(1, "key1", Some("value1")),
+        event(3, "key1", Some("value3")),
+        event(4, "key1", None),
+    ];
+    let received: Vec<Event> = events.take(3).try_collect().await?;
+    assert_eq!(received, expected);
+
+    // resume after the first event
+    let resumed = store.watch("key".to_owned(), Some(1));
+    let received: Vec<Event> = resumed.take(2).try_collect().await?;
+    assert_eq!(received, expected[1..]);
+
+    // an unknown sequence number cannot be resumed from
+    assert!(store
+        .watch("key".to_owned(), Some(10))
+        .next()
+        .await
+        .unwrap()
+        .is_err());
+    Ok(())
+}
+
+// Should end the stream of a watcher falling behind, telling where to resume
+#[tokio::test]
+async fn watch_lagging() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let events = store.watch("key".to_owned(), None);
+    for i in 0..1100 {
+        store.set(format!("key{}", i), "value".to_owned()).await?;
+    }
+
+    let received: Vec<Result<Event>> = events.collect().await;
+    let (last, received) = received.split_last().unwrap();
+    assert!(received.len() < 1100);
+    let last_seq = received.last().unwrap().as_ref().unwrap().seq;
+    match last {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains(&last_seq.to_string())),
+        _ => panic!("Unexpected end of stream: {:?}", last),
+    }
+
+    // the watcher can resume after the last event delivered
+    let resumed = store.watch("key".to_owned(), Some(last_seq));
+    let next = resumed.take(1).try_collect::<Vec<Event>>().await?;
+    assert_eq!(next[0].seq, last_seq + 1);
+    Ok(())
+}
+
+// Namespaces should have their own keys, which can be dropped at once
+#[tokio::test]
+async fn namespaces() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert!(store.remove("key1".to_owned()).wait().is_ok());
-    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    let users = store.namespace("users");
+    let events = store.watch_all(None);
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    users.set("key1".to_owned(), "value2".to_owned()).await?;
+    users.set("key2".to_owned(), "value3".to_owned()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        users.get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+    assert_eq!(store.scan(String::new()).await?, vec!["key1"]);
+    assert_eq!(users.scan(String::new()).await?, vec!["key1", "key2"]);
+    assert_eq!(store.namespaces().await?, vec!["", "users"]);
+    assert_eq!(users.stats().await?.keys, 2);
+    assert!(matches!(
+        store.namespace("other").remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    users.drop_namespace().await?;
+    assert_eq!(users.get("key1".to_owned()).await?, None);
+    assert_eq!(users.stats().await?, NamespaceStats::default());
+    assert_eq!(store.namespaces().await?, vec![""]);
+    assert!(matches!(
+        users.drop_namespace().await,
+        Err(KvsError::NamespaceNotFound)
+    ));
+
+    let received: Vec<Event> = events.take(5).try_collect().await?;
+    let namespaces: Vec<(&str, &str, bool)> = received
+        .iter()
+        .map(|e| (&*e.namespace, &*e.key, e.value.is_some()))
+        .collect();
+    assert_eq!(
+        namespaces,
+        vec![
+            ("", "key1", true),
+            ("users", "key1", true),
+            ("users", "key2", true),
+            ("users", "key1", false),
+            ("users", "key2", false),
+        ]
+    );
+
+    // Open from disk again and check persistent data
+    users.set("key3".to_owned(), "value4".to_owned()).await?;
+    drop(store);
+    drop(users);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let users = store.namespace("users");
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(users.scan(String::new()).await?, vec!["key3"]);
+    assert_eq!(store.namespaces().await?, vec!["", "users"]);
+    Ok(())
+}
+
+// Reads on a namespace holding no key should not create it
+#[tokio::test]
+async fn sled_reads_create_no_namespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    let engine = SledKvsEngine::<RayonThreadPool>::new(db.clone(), 1)?;
+    let tree_count = db.tree_names().len();
+    let other = engine.namespace("other");
+    assert_eq!(other.get("key1".to_owned()).await?, None);
+    assert!(other.scan(String::new()).await?.is_empty());
+    assert_eq!(other.stats().await?, NamespaceStats::default());
+    assert_eq!(db.tree_names().len(), tree_count);
+    assert!(engine.namespaces().await?.is_empty());
+
+    other.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        other.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.namespaces().await?, vec!["other"]);
+    Ok(())
+}
+
+// Should list the keys with a prefix a page at a time
+#[tokio::test]
+async fn scan_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    scan_pages_of(store).await?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    scan_pages_of(SledKvsEngine::<RayonThreadPool>::new(db, 1)?).await
+}
+
+async fn scan_pages_of<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in &["a", "b1", "b2", "b3", "b4", "c"] {
+        engine.set(key.to_string(), "value".to_owned()).await?;
+    }
+    let b = || "b".to_owned();
+    assert_eq!(engine.scan_page(b(), None, 3).await?, vec!["b1", "b2", "b3"]);
+    assert_eq!(
+        engine.scan_page(b(), Some("b3".to_owned()), 3).await?,
+        vec!["b4"]
+    );
+    assert!(engine
+        .scan_page(b(), Some("b4".to_owned()), 3)
+        .await?
+        .is_empty());
+    // a cursor which is not a key, or before the prefix
+    assert_eq!(
+        engine.scan_page(b(), Some("b2a".to_owned()), 3).await?,
+        vec!["b3", "b4"]
+    );
+    assert_eq!(
+        engine.scan_page(b(), Some("a".to_owned()), 2).await?,
+        vec!["b1", "b2"]
+    );
+    assert!(engine
+        .namespace("other")
+        .scan_page(b(), None, 3)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+// Dropping a namespace should make its data reclaimable by a compaction
+#[tokio::test]
+async fn drop_namespace_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let bulk = store.namespace("bulk");
+    let value = "x".repeat(1000);
+    for key_id in 0..1100 {
+        bulk.set(format!("key{}", key_id), value.clone()).await?;
+    }
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let size = dir_size();
+    assert!(size > 1024 * 1024);
+
+    // the dropped namespace pushes the stale data over the compaction threshold
+    bulk.drop_namespace().await?;
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    assert!(dir_size() < size / 10);
+
+    drop(store);
+    drop(bulk);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.scan(String::new()).await?, vec!["key1", "key2"]);
+    assert_eq!(store.namespaces().await?, vec![""]);
     Ok(())
 }
 
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
-#[test]
-fn compaction() -> Result<()> {
+#[tokio::test]
+async fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
@@ -129,7 +368,7 @@ fn compaction() -> Result<()> {
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             let value = format!("{}", iter);
-            store.set(key, value).wait()?;
+            store.set(key, value).await?;
         }
 
         let new_size = dir_size();
@@ -144,7 +383,7 @@ fn compaction() -> Result<()> {
         let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key).wait()?, Some(format!("{}", iter)));
+            assert_eq!(store.get(key).await?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
@@ -152,30 +391,28 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
-#[test]
-fn concurrent_set() -> Result<()> {
+#[tokio::test]
+async fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
     // concurrent set in 8 threads
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for i in 0..10000 {
-            executor.spawn(
-                store
-                    .set(format!("key{}", i), format!("value{}", i))
-                    .map_err(|_| ()),
-            );
-        }
-        future::ok::<(), KvsError>(())
-    }))?;
+    let handles: Vec<_> = (0..10000)
+        .map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move { store.set(format!("key{}", i), format!("value{}", i)).await })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap()?;
+    }
+    drop(store);
 
     // We only check concurrent set in this test, so we check sequentially here
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     for i in 0..10000 {
         assert_eq!(
-            store.get(format!("key{}", i)).wait()?,
+            store.get(format!("key{}", i)).await?,
             Some(format!("value{}", i))
         );
     }
@@ -183,57 +420,98 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
-#[test]
-fn concurrent_get() -> Result<()> {
+#[tokio::test]
+async fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
     // We only check concurrent get in this test, so we set sequentially here
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
-            .wait()
+            .await
             .unwrap();
     }
-
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for thread_id in 0..100 {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                executor.spawn(
-                    store
-                        .get(format!("key{}", key_id))
-                        .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
-                        })
-                        .map_err(|_| ()),
-                );
-            }
-        }
-        future::ok::<(), KvsError>(())
-    }))?;
+    concurrent_get_all(&store).await?;
+    drop(store);
 
     // reload from disk and test again
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for thread_id in 0..100 {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                executor.spawn(
-                    store
-                        .get(format!("key{}", key_id))
-                        .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
-                        })
-                        .map_err(|_| ()),
-                );
-            }
+    concurrent_get_all(&store).await?;
+
+    Ok(())
+}
+
+// Operations are rejected instead of queued when the thread pool is full
+#[tokio::test]
+async fn busy_thread_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::bounded(1, 1, QueueFullPolicy::Reject)?;
+    let store = KvStore::open_with_pool(temp_dir.path(), 1, pool.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+
+    // keep the thread busy and fill the queue
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        let _ = release_rx.recv();
+    });
+    started_rx.recv().unwrap();
+    pool.try_spawn(|| {})?;
+    assert!(matches!(
+        store.get("key1".to_owned()).await,
+        Err(KvsError::Busy)
+    ));
+
+    drop(release_tx);
+    pool.join();
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+async fn concurrent_get_all(store: &KvStore<RayonThreadPool>) -> Result<()> {
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        for i in 0..100 {
+            let key_id = (i + thread_id) % 100;
+            let store = store.clone();
+            handles.push(tokio::spawn(async move {
+                let res = store.get(format!("key{}", key_id)).await?;
+                assert_eq!(res, Some(format!("value{}", key_id)));
+                Ok::<_, KvsError>(())
+            }));
         }
-        future::ok::<(), KvsError>(())
-    }))?;
+    }
+    for handle in handles {
+        handle.await.unwrap()?;
+    }
+    Ok(())
+}
+
+// Engines on the new trait can still be driven through the futures 0.1 shim
+#[test]
+fn compat_engine() -> Result<()> {
+    use futures01::{Future, Stream};
+    use kvs::compat::{self, Compat};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let events = compat::KvsEngine::watch(&store, "key".to_owned(), None);
+    compat::KvsEngine::set(&store, "key1".to_owned(), "value1".to_owned()).wait()?;
+    assert_eq!(
+        compat::KvsEngine::get(&store, "key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(compat::KvsEngine::last_seq(&store), 1);
+    let received = events.wait().next().unwrap()?;
+    assert_eq!(received, event(1, "key1", Some("value1")));
 
+    // and the other way round
+    let wrapped = Compat::new(store);
+    block_on(KvsEngine::remove(&wrapped, "key1".to_owned()))?;
+    assert_eq!(block_on(KvsEngine::get(&wrapped, "key1".to_owned()))?, None);
     Ok(())
 }
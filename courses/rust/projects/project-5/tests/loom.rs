@@ -0,0 +1,105 @@
+//! Model checks of the thread pools built on `std::sync`, exploring the
+//! interleavings of their threads with loom.
+//!
+//! The pools use the primitives of loom only with the `loom` feature, and then
+//! only work inside a model, so these tests are run on their own:
+//!
+//! ```text
+//! cargo test --release --features loom --test loom
+//! ```
+//!
+//! The pools built on crossbeam and the `KvStore` itself are exercised by the
+//! `stress` example instead.
+#![cfg(feature = "loom")]
+
+use std::time::Duration;
+
+use kvs::thread_pool::*;
+use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::sync::Arc;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+fn model<F>(f: F)
+where
+    F: Fn() + Sync + Send + 'static,
+{
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(3);
+    builder.check(f);
+}
+
+fn spawn_counter<P: ThreadPool>(pool: &P, counter: &Arc<AtomicUsize>) {
+    let counter = Arc::clone(counter);
+    pool.spawn(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+}
+
+fn join_and_shutdown<P: ThreadPool>(threads: u32) {
+    let pool = P::new(threads).unwrap();
+    let counter = Arc::new(AtomicUsize::new(0));
+    spawn_counter(&pool, &counter);
+    spawn_counter(&pool, &counter);
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+    let metrics = pool.metrics();
+    assert_eq!(
+        (metrics.queued, metrics.active, metrics.completed),
+        (0, 0, 2)
+    );
+    assert!(pool.shutdown(TIMEOUT));
+    assert_eq!(pool.metrics().threads, 0);
+}
+
+// A job spawned while the pool shuts down either runs before the threads exit
+// or is discarded, but is never left in the queue.
+fn spawn_during_shutdown<P: ThreadPool>(threads: u32) {
+    let pool = P::new(threads).unwrap();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let spawner = {
+        let pool = pool.clone();
+        let counter = Arc::clone(&counter);
+        loom::thread::spawn(move || spawn_counter(&pool, &counter))
+    };
+    assert!(pool.shutdown(TIMEOUT));
+    spawner.join().unwrap();
+    let metrics = pool.metrics();
+    assert_eq!((metrics.threads, metrics.queued, metrics.active), (0, 0, 0));
+    assert_eq!(metrics.completed as usize, counter.load(Ordering::SeqCst));
+}
+
+#[test]
+fn naive_thread_pool_join_and_shutdown() {
+    model(|| join_and_shutdown::<NaiveThreadPool>(0));
+}
+
+#[test]
+fn naive_thread_pool_spawn_during_shutdown() {
+    model(|| spawn_during_shutdown::<NaiveThreadPool>(0));
+}
+
+#[test]
+fn priority_thread_pool_join_and_shutdown() {
+    model(|| join_and_shutdown::<PriorityThreadPool>(1));
+}
+
+#[test]
+fn priority_thread_pool_spawn_during_shutdown() {
+    model(|| spawn_during_shutdown::<PriorityThreadPool>(1));
+}
+
+// Shrinking the pool while a job is queued still runs the job, and both
+// threads exit.
+#[test]
+fn priority_thread_pool_shrink() {
+    model(|| {
+        let pool = PriorityThreadPool::new(2).unwrap();
+        let counter = Arc::new(AtomicUsize::new(0));
+        spawn_counter(&pool, &counter);
+        pool.resize(1).unwrap();
+        assert!(pool.shutdown(TIMEOUT));
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.metrics().threads, 0);
+    });
+}
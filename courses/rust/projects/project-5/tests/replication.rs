@@ -0,0 +1,298 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+struct Server {
+    child: Child,
+    _temp_dir: TempDir,
+}
+
+impl Server {
+    fn start(engine: &str, addr: &str, replica_of: Option<&str>) -> Server {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--engine", engine, "--addr", addr]);
+        if let Some(primary) = replica_of {
+            cmd.args(["--replica-of", primary]);
+        }
+        let child = cmd.current_dir(&temp_dir).spawn().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Server {
+            child,
+            _temp_dir: temp_dir,
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().expect("server exited before killed");
+        self.child.wait().unwrap();
+    }
+}
+
+fn client(args: &[&str], addr: &str) -> Output {
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(args)
+        .args(["--addr", addr])
+        .output()
+        .unwrap()
+}
+
+// Retries the client command until its stdout matches, as replication is asynchronous.
+fn eventually(args: &[&str], addr: &str, expected: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stdout = String::from_utf8(client(args, addr).stdout).unwrap();
+        if stdout.contains(expected) {
+            return;
+        }
+        if Instant::now() > deadline {
+            panic!("{:?} printed {:?}, expected {:?}", args, stdout, expected);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn replication(primary_engine: &str, replica_engine: &str, addr: &str, replica_addr: &str) {
+    let _primary = Server::start(primary_engine, addr, None);
+    client(&["set", "key1", "value1"], addr);
+    client(&["set", "key2", "value2"], addr);
+    client(&["rm", "key2"], addr);
+    client(&["set", "key1", "value6", "-n", "users"], addr);
+
+    // initial full sync
+    let _replica = Server::start(replica_engine, replica_addr, Some(addr));
+    eventually(&["get", "key1"], replica_addr, "value1\n");
+    eventually(&["get", "key2"], replica_addr, "Key not found\n");
+    eventually(&["get", "key1", "-n", "users"], replica_addr, "value6\n");
+
+    // tailing changes
+    client(&["set", "key3", "value3"], addr);
+    client(&["set", "key1", "value4"], addr);
+    client(&["rm", "key3"], addr);
+    eventually(&["get", "key1"], replica_addr, "value4\n");
+    eventually(&["get", "key3"], replica_addr, "Key not found\n");
+
+    // writes to the replica are rejected
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value5", "--addr", replica_addr])
+        .assert()
+        .failure()
+        .stderr(contains("Writes are not allowed on a replica"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", replica_addr])
+        .assert()
+        .failure();
+
+    eventually(&["replication"], replica_addr, "role: replica\n");
+    eventually(&["replication"], replica_addr, "synced: true\n");
+    eventually(&["replication"], replica_addr, "applied_seq: 7\n");
+    eventually(&["replication"], replica_addr, "lag: 0\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["replication", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("role: primary\nlast_seq: 7\n");
+
+    // dropping a namespace is replicated
+    client(&["drop-namespace", "users"], addr);
+    eventually(
+        &["get", "key1", "-n", "users"],
+        replica_addr,
+        "Key not found\n",
+    );
+
+    // only a replica can be promoted
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["promote", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Not a replica"));
+
+    // a promoted replica accepts writes and stops replicating
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["promote", "--addr", replica_addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value5", "--addr", replica_addr])
+        .assert()
+        .success();
+    client(&["set", "key4", "value4"], addr);
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key4", "--addr", replica_addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value4\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["replication", "--addr", replica_addr])
+        .assert()
+        .success()
+        .stdout(contains("role: primary\n"));
+}
+
+#[test]
+fn replication_kvs_to_sled() {
+    replication("kvs", "sled", "127.0.0.1:4030", "127.0.0.1:4031");
+}
+
+#[test]
+fn replication_sled_to_kvs() {
+    replication("sled", "kvs", "127.0.0.1:4032", "127.0.0.1:4033");
+}
+
+// Forwards the connections to a server, which can be cut to make the client
+// reconnect.
+struct Proxy {
+    conns: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    fn start(addr: &str, target: &str) -> Proxy {
+        let listener = TcpListener::bind(addr).unwrap();
+        let target = target.to_owned();
+        let conns = Arc::new(Mutex::new(Vec::new()));
+        let proxy_conns = conns.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = match TcpStream::connect(&target) {
+                    Ok(server) => server,
+                    Err(_) => continue,
+                };
+                let mut conns = proxy_conns.lock().unwrap();
+                conns.push(client.try_clone().unwrap());
+                conns.push(server.try_clone().unwrap());
+                pipe(client.try_clone().unwrap(), server.try_clone().unwrap());
+                pipe(server, client);
+            }
+        });
+        Proxy { conns }
+    }
+
+    fn cut(&self) {
+        for conn in self.conns.lock().unwrap().drain(..) {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    thread::spawn(move || {
+        let _ = io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Both);
+    });
+}
+
+// A replica reconnecting to its primary resumes from the latest change it
+// applied while the primary retains the changes after it, and does a full sync
+// otherwise.
+#[test]
+fn replication_resume() {
+    let addr = "127.0.0.1:4034";
+    let proxy_addr = "127.0.0.1:4035";
+    let replica_addr = "127.0.0.1:4036";
+    let _primary = Server::start("kvs", addr, None);
+    let proxy = Proxy::start(proxy_addr, addr);
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", replica_addr, "--replica-of", proxy_addr])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let stderr = BufReader::new(replica.stderr.take().unwrap());
+    let replica_logs = logs.clone();
+    thread::spawn(move || {
+        for line in stderr.lines() {
+            replica_logs.lock().unwrap().push(line.unwrap());
+        }
+    });
+    let _replica = Server {
+        child: replica,
+        _temp_dir: temp_dir,
+    };
+    // waits until the replica logged `count` lines containing `pattern`
+    let logged = |pattern: &str, count: usize| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let logs = logs.lock().unwrap();
+            let n = logs.iter().filter(|line| line.contains(pattern)).count();
+            if n == count {
+                return;
+            }
+            if Instant::now() > deadline {
+                panic!("{:?} logged {} times, expected {}", pattern, n, count);
+            }
+            drop(logs);
+            thread::sleep(Duration::from_millis(100));
+        }
+    };
+
+    client(&["set", "key1", "value1"], addr);
+    eventually(&["get", "key1"], replica_addr, "value1\n");
+    logged("Synced with", 1);
+
+    // the changes made while disconnected are replayed
+    proxy.cut();
+    client(&["set", "key2", "value2"], addr);
+    client(&["rm", "key1"], addr);
+    eventually(&["get", "key2"], replica_addr, "value2\n");
+    eventually(&["get", "key1"], replica_addr, "Key not found\n");
+    logged("Resumed from", 1);
+    logged("Synced with", 1);
+
+    // many changes are replayed from the log of the primary
+    proxy.cut();
+    let dump_dir = TempDir::new().unwrap();
+    let dump = dump_dir.path().join("dump.jsonl");
+    let records: String = (0..1100)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"value{}\"}}\n", i % 10, i))
+        .collect();
+    fs::write(&dump, records).unwrap();
+    client(&["import", dump.to_str().unwrap()], addr);
+    eventually(&["get", "key9"], replica_addr, "value1099\n");
+    eventually(&["get", "key2"], replica_addr, "value1092\n");
+    logged("Resumed from", 2);
+    logged("Synced with", 1);
+
+    // the changes removed by a compaction can't be replayed
+    proxy.cut();
+    let value = "v".repeat(2048);
+    let records: String = (0..1100)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"{}{}\"}}\n", i % 10, value, i))
+        .collect();
+    fs::write(&dump, records).unwrap();
+    client(&["import", dump.to_str().unwrap()], addr);
+    eventually(&["get", "key9"], replica_addr, "v1099\n");
+    logged("Synced with", 2);
+    logged("Resumed from", 2);
+    eventually(&["replication"], replica_addr, "lag: 0\n");
+}
@@ -0,0 +1,291 @@
+use assert_cmd::prelude::*;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+// A minimal hand-written RESP client.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: &str) -> RespClient {
+        let writer = TcpStream::connect(addr).unwrap();
+        let reader = BufReader::new(writer.try_clone().unwrap());
+        RespClient { reader, writer }
+    }
+
+    fn command(&mut self, args: &[&str]) -> Reply {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf += &format!("${}\r\n{}\r\n", arg.len(), arg);
+        }
+        self.writer.write_all(buf.as_bytes()).unwrap();
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> Reply {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let (kind, rest) = line.trim_end().split_at(1);
+        match kind {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => {
+                let len: i64 = rest.parse().unwrap();
+                if len < 0 {
+                    return Reply::Bulk(None);
+                }
+                let mut data = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut data).unwrap();
+                data.truncate(len as usize);
+                Reply::Bulk(Some(String::from_utf8(data).unwrap()))
+            }
+            "*" => {
+                let len: usize = rest.parse().unwrap();
+                Reply::Array((0..len).map(|_| self.read_reply()).collect())
+            }
+            _ => panic!("Unexpected reply: {:?}", line),
+        }
+    }
+}
+
+struct Server {
+    child: Child,
+    _temp_dir: TempDir,
+}
+
+impl Server {
+    fn start(engine: &str, addr: &str) -> Server {
+        Server::start_with(engine, addr, &[])
+    }
+
+    fn start_with(engine: &str, addr: &str, args: &[&str]) -> Server {
+        let temp_dir = TempDir::new().unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", engine, "--addr", addr, "--protocol", "resp"])
+            .args(args)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Server {
+            child,
+            _temp_dir: temp_dir,
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().expect("server exited before killed");
+    }
+}
+
+fn resp_commands(engine: &str, addr: &str) {
+    let _server = Server::start(engine, addr);
+    let mut client = RespClient::connect(addr);
+
+    assert_eq!(client.command(&["PING"]), Reply::Simple("PONG".to_owned()));
+    assert_eq!(client.command(&["ping", "hello"]), bulk("hello"));
+
+    assert_eq!(
+        client.command(&["SET", "key1", "value1"]),
+        Reply::Simple("OK".to_owned())
+    );
+    assert_eq!(client.command(&["GET", "key1"]), bulk("value1"));
+    assert_eq!(client.command(&["GET", "key2"]), Reply::Bulk(None));
+    assert_eq!(
+        client.command(&["SET", "key2", "value2"]),
+        Reply::Simple("OK".to_owned())
+    );
+    assert_eq!(
+        client.command(&["EXISTS", "key1", "key2", "key3"]),
+        Reply::Integer(2)
+    );
+    assert_eq!(client.command(&["DEL", "key1", "key3"]), Reply::Integer(1));
+    assert_eq!(client.command(&["GET", "key1"]), Reply::Bulk(None));
+
+    match client.command(&["INFO"]) {
+        Reply::Bulk(Some(info)) => assert!(info.contains("kvs_version")),
+        reply => panic!("Unexpected reply: {:?}", reply),
+    }
+    match client.command(&["GET"]) {
+        Reply::Error(msg) => assert!(msg.contains("wrong number of arguments")),
+        reply => panic!("Unexpected reply: {:?}", reply),
+    }
+    match client.command(&["FLUSHALL"]) {
+        Reply::Error(msg) => assert!(msg.contains("unknown command")),
+        reply => panic!("Unexpected reply: {:?}", reply),
+    }
+}
+
+#[test]
+fn resp_commands_kvs_engine() {
+    resp_commands("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn resp_commands_sled_engine() {
+    resp_commands("sled", "127.0.0.1:4011");
+}
+
+#[test]
+fn resp_scan() {
+    let addr = "127.0.0.1:4012";
+    let _server = Server::start("kvs", addr);
+    let mut client = RespClient::connect(addr);
+
+    for i in 0..15 {
+        client.command(&["SET", &format!("user:{:02}", i), "v"]);
+    }
+    client.command(&["SET", "other", "v"]);
+
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        match client.command(&["SCAN", &cursor, "MATCH", "user:*", "COUNT", "4"]) {
+            Reply::Array(mut reply) => {
+                let page = reply.pop().unwrap();
+                match (reply.pop().unwrap(), page) {
+                    (Reply::Bulk(Some(next)), Reply::Array(page)) => {
+                        assert!(page.len() <= 4);
+                        keys.extend(page);
+                        cursor = next;
+                    }
+                    reply => panic!("Unexpected reply: {:?}", reply),
+                }
+            }
+            reply => panic!("Unexpected reply: {:?}", reply),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    let expected: Vec<Reply> = (0..15).map(|i| bulk(&format!("user:{:02}", i))).collect();
+    assert_eq!(keys, expected);
+
+    // a cursor past the last key, whatever the count, ends the scan
+    let max = u64::MAX.to_string();
+    assert_eq!(
+        client.command(&["SCAN", "7e", "COUNT", &max]),
+        Reply::Array(vec![bulk("0"), Reply::Array(vec![])])
+    );
+    match client.command(&["SCAN", "user:00"]) {
+        Reply::Error(msg) => assert!(msg.contains("invalid cursor")),
+        reply => panic!("Unexpected reply: {:?}", reply),
+    }
+
+    // a pattern with many stars doesn't backtrack exponentially
+    let key = "a".repeat(100);
+    client.command(&["SET", &key, "v"]);
+    let pattern = "*a".repeat(20) + "b";
+    assert_eq!(
+        client.command(&["SCAN", "0", "MATCH", &pattern, "COUNT", "100"]),
+        Reply::Array(vec![bulk("0"), Reply::Array(vec![])])
+    );
+    let pattern = "*a".repeat(20) + "*";
+    assert_eq!(
+        client.command(&["SCAN", "0", "MATCH", &pattern, "COUNT", "100"]),
+        Reply::Array(vec![bulk("0"), Reply::Array(vec![bulk(&key)])])
+    );
+}
+
+#[test]
+fn resp_expire() {
+    let addr = "127.0.0.1:4013";
+    let _server = Server::start("kvs", addr);
+    let mut client = RespClient::connect(addr);
+
+    client.command(&["SET", "key1", "value1"]);
+    client.command(&["SET", "key2", "value2"]);
+    assert_eq!(client.command(&["EXPIRE", "key1", "1"]), Reply::Integer(1));
+    assert_eq!(client.command(&["EXPIRE", "key2", "1"]), Reply::Integer(1));
+    assert_eq!(client.command(&["EXPIRE", "key3", "1"]), Reply::Integer(0));
+    // SET clears the timeout
+    client.command(&["SET", "key2", "value3"]);
+
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(client.command(&["GET", "key1"]), Reply::Bulk(None));
+    assert_eq!(client.command(&["GET", "key2"]), bulk("value3"));
+
+    assert_eq!(client.command(&["EXPIRE", "key2", "0"]), Reply::Integer(1));
+    assert_eq!(client.command(&["GET", "key2"]), Reply::Bulk(None));
+}
+
+#[test]
+fn resp_expire_cancelled_by_http() {
+    let addr = "127.0.0.1:4014";
+    let http_addr = "127.0.0.1:4015";
+    let _server = Server::start_with("kvs", addr, &["--http-addr", http_addr]);
+    let mut client = RespClient::connect(addr);
+
+    client.command(&["SET", "key1", "value1"]);
+    assert_eq!(client.command(&["EXPIRE", "key1", "1"]), Reply::Integer(1));
+    // a write through the HTTP gateway clears the timeout too
+    let mut stream = TcpStream::connect(http_addr).unwrap();
+    write!(
+        stream,
+        "PUT /kv/key1 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: 6\r\n\r\nvalue2",
+        http_addr
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 2"), "{}", response);
+
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(client.command(&["GET", "key1"]), bulk("value2"));
+}
+
+#[test]
+fn resp_malformed_requests() {
+    let addr = "127.0.0.1:4016";
+    let _server = Server::start("kvs", addr);
+
+    let requests = [
+        // lengths overflowing, or too large to be allocated
+        "*1\r\n$18446744073709551615\r\n".to_owned(),
+        "*1\r\n$9223372036854775807\r\n".to_owned(),
+        "*9223372036854775807\r\n".to_owned(),
+        "*1\r\n$abc\r\n".to_owned(),
+        // arrays nested too deep
+        "*1\r\n".repeat(1000),
+    ];
+    for request in &requests {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // the server closes the connection, maybe before reading all of it
+        let mut reply = String::new();
+        match stream.read_to_string(&mut reply) {
+            Ok(_) => assert_eq!(reply, ""),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+        }
+    }
+
+    // the server still serves other clients
+    let mut client = RespClient::connect(addr);
+    assert_eq!(client.command(&["PING"]), Reply::Simple("PONG".to_owned()));
+}
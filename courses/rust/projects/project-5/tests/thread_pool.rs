@@ -1,8 +1,10 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvsError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,6 +48,175 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+fn spawn_with_handle<P: ThreadPool>(pool: P) -> Result<()> {
+    let handles: Vec<_> = (0..20)
+        .map(|i| pool.spawn_with_handle(move || i * 2))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join()?, i * 2);
+    }
+    Ok(())
+}
+
+fn scope<P: ThreadPool>(pool: P) -> Result<()> {
+    let mut chunks = vec![vec![1; 100]; 8];
+    let sum = AtomicUsize::new(0);
+    pool.scope(|scope| {
+        for chunk in chunks.iter_mut() {
+            let sum = &sum;
+            scope.spawn(move || {
+                for x in chunk.iter_mut() {
+                    *x *= 2;
+                }
+                sum.fetch_add(chunk.iter().sum(), Ordering::SeqCst);
+            });
+        }
+    })?;
+    assert!(chunks.iter().flatten().all(|&x| x == 2));
+    assert_eq!(sum.load(Ordering::SeqCst), 8 * 100 * 2);
+    Ok(())
+}
+
+// The panics are reported through the handle and the scope, and the pool keeps working.
+fn report_panics<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let handle = pool.spawn_with_handle(|| -> usize {
+        panic_control::disable_hook_in_current_thread();
+        panic!();
+    });
+    assert!(matches!(handle.join(), Err(KvsError::TaskPanicked)));
+
+    let res = pool.scope(|scope| {
+        scope.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            panic!();
+        });
+        scope.spawn(|| {});
+    });
+    assert!(matches!(res, Err(KvsError::TaskPanicked)));
+
+    pool.join();
+    assert_eq!(pool.metrics().panicked, 2);
+    spawn_with_handle(pool)
+}
+
+fn join_and_metrics<P: ThreadPool>(pool: P) -> Result<()> {
+    const TASK_NUM: usize = 100;
+
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(1));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    pool.join();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    let metrics = pool.metrics();
+    assert_eq!(metrics.queued, 0);
+    assert_eq!(metrics.active, 0);
+    assert_eq!(metrics.completed, TASK_NUM as u64);
+    assert_eq!(metrics.panicked, 0);
+    Ok(())
+}
+
+// Waits for the threads to follow a resize.
+fn wait_threads<P: ThreadPool>(pool: &P, threads: usize) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while pool.metrics().threads != threads {
+        assert!(Instant::now() < deadline, "{:?}", pool.metrics());
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn resize<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    wait_threads(&pool, 2);
+    pool.resize(6)?;
+    wait_threads(&pool, 6);
+    spawn_counter(pool.clone())?;
+    pool.resize(1)?;
+    wait_threads(&pool, 1);
+    spawn_counter(pool.clone())?;
+    // shrinking and growing back before the threads exit
+    pool.resize(0)?;
+    pool.resize(3)?;
+    wait_threads(&pool, 3);
+    spawn_counter(pool)
+}
+
+fn shutdown<P: ThreadPool>(exits: bool) -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = P::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    assert!(pool.shutdown(Duration::from_secs(10)));
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+
+    // discarded after shutdown
+    pool.spawn(|| unreachable!());
+    let metrics = pool.metrics();
+    assert_eq!(metrics.queued, 0);
+    assert_eq!(metrics.completed, TASK_NUM as u64);
+    if exits {
+        assert_eq!(metrics.threads, 0);
+    }
+    Ok(())
+}
+
+// The scoped functions a shut down pool discards are dropped before the scope
+// returns, as they may borrow from it.
+fn scope_after_shutdown<P: ThreadPool>() -> Result<()> {
+    struct CountDrop<'a>(&'a AtomicUsize);
+    impl Drop for CountDrop<'_> {
+        fn drop(&mut self) {
+            thread::sleep(Duration::from_millis(10));
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let pool = P::new(2)?;
+    assert!(pool.shutdown(Duration::from_secs(10)));
+    let dropped = AtomicUsize::new(0);
+    let _ = pool.scope(|scope| {
+        for _ in 0..4 {
+            let count = CountDrop(&dropped);
+            scope.spawn(move || drop(count));
+        }
+    });
+    assert_eq!(dropped.load(Ordering::SeqCst), 4);
+    Ok(())
+}
+
+// Keeps the only thread of the pool busy until the returned sender is dropped.
+fn occupy<P: ThreadPool>(pool: &P) -> mpsc::Sender<()> {
+    let (started_tx, started_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    pool.spawn(move || {
+        started_tx.send(()).unwrap();
+        let _ = release_rx.recv();
+    });
+    started_rx.recv().unwrap();
+    release_tx
+}
+
+fn shutdown_timeout<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(1)?;
+    pool.spawn(|| thread::sleep(Duration::from_millis(500)));
+    assert!(!pool.shutdown(Duration::from_millis(10)));
+    assert!(pool.shutdown(Duration::from_secs(10)));
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -64,7 +235,316 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn work_stealing_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(WorkStealingThreadPool::new(4)?)
+}
+
+#[test]
+fn naive_thread_pool_scope() -> Result<()> {
+    scope(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_scope() -> Result<()> {
+    scope(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_scope() -> Result<()> {
+    scope(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn work_stealing_thread_pool_scope() -> Result<()> {
+    scope(WorkStealingThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_report_panics() -> Result<()> {
+    report_panics::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_report_panics() -> Result<()> {
+    report_panics::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_report_panics() -> Result<()> {
+    report_panics::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_join_and_metrics() -> Result<()> {
+    join_and_metrics(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_join_and_metrics() -> Result<()> {
+    join_and_metrics(SharedQueueThreadPool::new(4)?)
+}
+
+#[test]
+fn rayon_thread_pool_join_and_metrics() -> Result<()> {
+    join_and_metrics(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn work_stealing_thread_pool_join_and_metrics() -> Result<()> {
+    join_and_metrics(WorkStealingThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_resize() -> Result<()> {
+    resize::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_resize() -> Result<()> {
+    resize::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_resize() -> Result<()> {
+    resize::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_shutdown() -> Result<()> {
+    shutdown::<NaiveThreadPool>(true)?;
+    shutdown_timeout::<NaiveThreadPool>()?;
+    scope_after_shutdown::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown() -> Result<()> {
+    shutdown::<SharedQueueThreadPool>(true)?;
+    shutdown_timeout::<SharedQueueThreadPool>()?;
+    scope_after_shutdown::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_shutdown() -> Result<()> {
+    shutdown::<RayonThreadPool>(false)?;
+    shutdown_timeout::<RayonThreadPool>()?;
+    scope_after_shutdown::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_shutdown() -> Result<()> {
+    shutdown::<WorkStealingThreadPool>(true)?;
+    shutdown_timeout::<WorkStealingThreadPool>()?;
+    scope_after_shutdown::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn bounded_shared_queue_thread_pool_reject() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 1, QueueFullPolicy::Reject)?;
+    let release = occupy(&pool);
+    pool.try_spawn(|| {})?;
+    assert!(matches!(pool.try_spawn(|| {}), Err(KvsError::Busy)));
+    assert!(matches!(
+        pool.spawn_with_handle(|| 1).join(),
+        Err(KvsError::Busy)
+    ));
+    assert_eq!(pool.metrics().queued, 1);
+
+    // a scope returns the error of a rejected function, which is dropped
+    let ran = AtomicBool::new(false);
+    let res = pool.scope(|scope| scope.spawn(|| ran.store(true, Ordering::SeqCst)));
+    assert!(matches!(res, Err(KvsError::Busy)));
+    assert!(!ran.load(Ordering::SeqCst));
+
+    drop(release);
+    pool.join();
+    assert_eq!(pool.metrics().completed, 2);
+    Ok(())
+}
+
+#[test]
+fn bounded_shared_queue_thread_pool_caller_runs() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 1, QueueFullPolicy::CallerRuns)?;
+    let release = occupy(&pool);
+    pool.try_spawn(|| {})?;
+    let caller = thread::current().id();
+    let handle = pool.spawn_with_handle(move || thread::current().id() == caller);
+
+    drop(release);
+    assert!(handle.join()?);
+    Ok(())
+}
+
+#[test]
+fn bounded_shared_queue_thread_pool_block() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(1, 1, QueueFullPolicy::Block)?;
+    let release = occupy(&pool);
+    pool.try_spawn(|| {})?;
+    let spawned = Arc::new(AtomicBool::new(false));
+    let spawner = {
+        let pool = pool.clone();
+        let spawned = Arc::clone(&spawned);
+        thread::spawn(move || {
+            pool.spawn(|| {});
+            spawned.store(true, Ordering::SeqCst);
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    assert!(!spawned.load(Ordering::SeqCst));
+    // the blocked spawner doesn't hold up the shutdown
+    assert!(!pool.shutdown(Duration::from_millis(10)));
+
+    drop(release);
+    spawner.join().unwrap();
+    pool.join();
+    assert_eq!(pool.metrics().completed, 3);
+    Ok(())
+}
+
+#[test]
+fn bounded_shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    let pool = SharedQueueThreadPool::bounded(4, 2, QueueFullPolicy::Block)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn priority_thread_pool_spawn_counter() -> Result<()> {
+    spawn_counter(PriorityThreadPool::new(4)?)
+}
+
+#[test]
+fn priority_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<PriorityThreadPool>()
+}
+
+#[test]
+fn priority_thread_pool_spawn_with_handle() -> Result<()> {
+    spawn_with_handle(PriorityThreadPool::new(4)?)
+}
+
+#[test]
+fn priority_thread_pool_scope() -> Result<()> {
+    scope(PriorityThreadPool::new(4)?)
+}
+
+#[test]
+fn priority_thread_pool_report_panics() -> Result<()> {
+    report_panics::<PriorityThreadPool>()
+}
+
+#[test]
+fn priority_thread_pool_join_and_metrics() -> Result<()> {
+    join_and_metrics(PriorityThreadPool::new(4)?)
+}
+
+#[test]
+fn priority_thread_pool_resize() -> Result<()> {
+    resize::<PriorityThreadPool>()
+}
+
+#[test]
+fn priority_thread_pool_shutdown() -> Result<()> {
+    shutdown::<PriorityThreadPool>(true)?;
+    shutdown_timeout::<PriorityThreadPool>()
+}
+
+#[test]
+fn priority_thread_pool_order() -> Result<()> {
+    let pool = PriorityThreadPool::new(1)?;
+    let release = occupy(&pool);
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let soon = Instant::now() + Duration::from_secs(60);
+    let later = soon + Duration::from_secs(60);
+    let schedules = vec![
+        ("low", Schedule::new(Priority::Low)),
+        ("normal", Schedule::default()),
+        ("high", Schedule::new(Priority::High)),
+        ("high later", Schedule::new(Priority::High).deadline(later)),
+        ("high soon", Schedule::new(Priority::High).deadline(soon)),
+        ("normal soon", Schedule::default().deadline(soon)),
+        ("high 2", Schedule::new(Priority::High)),
+    ];
+    for (name, schedule) in schedules {
+        let order = Arc::clone(&order);
+        pool.spawn_scheduled(schedule, move || order.lock().unwrap().push(name))?;
+    }
+
+    drop(release);
+    pool.join();
+    assert_eq!(
+        *order.lock().unwrap(),
+        vec![
+            "high soon",
+            "high later",
+            "high",
+            "high 2",
+            "normal soon",
+            "normal",
+            "low"
+        ]
+    );
+    Ok(())
+}
+
+fn deadline_exceeded<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(1)?;
+    let release = occupy(&pool);
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let expired = pool.spawn_scheduled_with_handle(Schedule::default().deadline(deadline), || 1);
+    let ran = Arc::new(AtomicBool::new(false));
+    {
+        let ran = Arc::clone(&ran);
+        pool.spawn_scheduled(Schedule::default().deadline(deadline), move || {
+            ran.store(true, Ordering::SeqCst)
+        })?;
+    }
+    let in_time = pool.spawn_scheduled_with_handle(Schedule::new(Priority::Low), || 2);
+    thread::sleep(Duration::from_millis(100));
+
+    drop(release);
+    assert!(matches!(expired.join(), Err(KvsError::DeadlineExceeded)));
+    assert_eq!(in_time.join()?, 2);
+    assert!(!ran.load(Ordering::SeqCst));
+    Ok(())
+}
+
+#[test]
+fn priority_thread_pool_deadline_exceeded() -> Result<()> {
+    deadline_exceeded::<PriorityThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_deadline_exceeded() -> Result<()> {
+    deadline_exceeded::<SharedQueueThreadPool>()
+}